@@ -0,0 +1,263 @@
+use std::ops::Range;
+
+use crate::parser::is_sql_identifier;
+
+/*
+    ALTER TABLE edits the CREATE statements stored in sqlite_schema in place, as SQLite does,
+    so everything except the renamed or removed names keeps the spelling it was written with.
+    The statement is split into tokens that remember where they are in the text, and the
+    edits are made by replacing byte ranges.
+*/
+#[derive(Debug)]
+struct Token {
+    span: Range<usize>,
+    kind: TokenKind,
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    // A keyword or an identifier, bare or quoted, holding the name without its quotes.
+    Name(String),
+    Literal,
+    Symbol(char),
+}
+
+impl Token {
+    fn is_name(&self, name: &str) -> bool {
+        matches!(&self.kind, TokenKind::Name(n) if n.eq_ignore_ascii_case(name))
+    }
+}
+
+fn tokenize(sql: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while let Some(c) = sql[i..].chars().next() {
+        let start = i;
+        let kind = match c {
+            c if c.is_whitespace() => {
+                i += c.len_utf8();
+                continue;
+            }
+            '-' if sql[i..].starts_with("--") => {
+                i = sql[i..].find('\n').map_or(sql.len(), |n| i + n);
+                continue;
+            }
+            '/' if sql[i..].starts_with("/*") => {
+                i = sql[i + 2..].find("*/").map_or(sql.len(), |n| i + n + 4);
+                continue;
+            }
+            '\'' => {
+                i = closing_quote(sql, i, '\'');
+                TokenKind::Literal
+            }
+            '"' | '`' | '[' => {
+                let close = if c == '[' { ']' } else { c };
+                i = closing_quote(sql, i, close);
+                let inner = &sql[start + 1..i.saturating_sub(1).max(start + 1)];
+                TokenKind::Name(inner.replace(&format!("{close}{close}"), &close.to_string()))
+            }
+            c if is_sql_identifier(c) => {
+                i = sql[i..]
+                    .find(|c: char| !is_sql_identifier(c))
+                    .map_or(sql.len(), |n| i + n);
+                TokenKind::Name(sql[start..i].to_string())
+            }
+            c => {
+                i += c.len_utf8();
+                TokenKind::Symbol(c)
+            }
+        };
+        tokens.push(Token {
+            span: start..i,
+            kind,
+        });
+    }
+    tokens
+}
+
+// The position just past the quote closing the one at `start`, a doubled quote is escaped.
+fn closing_quote(sql: &str, start: usize, close: char) -> usize {
+    let mut chars = sql[start + 1..].char_indices().peekable();
+    while let Some((offset, c)) = chars.next() {
+        if c == close {
+            if close != ']' && chars.peek().is_some_and(|(_, next)| *next == close) {
+                chars.next();
+                continue;
+            }
+            return start + 1 + offset + c.len_utf8();
+        }
+    }
+    sql.len()
+}
+
+pub fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Column names are only quoted when they would not parse as a bare identifier.
+fn quote_if_needed(name: &str) -> String {
+    let keywords = [
+        "select",
+        "from",
+        "where",
+        "table",
+        "index",
+        "create",
+        "primary",
+        "key",
+        "unique",
+        "not",
+        "null",
+        "default",
+        "constraint",
+        "check",
+        "foreign",
+        "references",
+        "collate",
+        "on",
+        "and",
+        "or",
+        "is",
+    ];
+    let bare = name.chars().all(is_sql_identifier)
+        && name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+        && !keywords.iter().any(|k| k.eq_ignore_ascii_case(name));
+    if bare { name.to_string() } else { quote(name) }
+}
+
+/*
+    The comma separated items between the outermost parentheses of a CREATE statement: the
+    column definitions and table constraints of a table, or the indexed columns of an index.
+    Each item is a range of token positions.
+*/
+fn items(tokens: &[Token]) -> Vec<Range<usize>> {
+    let mut items = Vec::new();
+    let Some(open) = tokens.iter().position(|t| t.kind == TokenKind::Symbol('(')) else {
+        return items;
+    };
+    let mut depth = 0;
+    let mut start = open + 1;
+    for (i, token) in tokens.iter().enumerate().skip(open + 1) {
+        match token.kind {
+            TokenKind::Symbol('(') => depth += 1,
+            TokenKind::Symbol(')') if depth == 0 => {
+                items.push(start..i);
+                break;
+            }
+            TokenKind::Symbol(')') => depth -= 1,
+            TokenKind::Symbol(',') if depth == 0 => {
+                items.push(start..i);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    items
+}
+
+fn is_table_constraint(tokens: &[Token], item: &Range<usize>) -> bool {
+    ["constraint", "primary", "unique", "check", "foreign"]
+        .iter()
+        .any(|k| tokens[item.start].is_name(k))
+}
+
+fn apply(sql: &str, mut edits: Vec<(Range<usize>, String)>) -> String {
+    let mut sql = sql.to_string();
+    edits.sort_by_key(|(span, _)| span.start);
+    for (span, text) in edits.into_iter().rev() {
+        sql.replace_range(span, &text);
+    }
+    sql
+}
+
+// Point a CREATE TABLE, CREATE INDEX or CREATE TRIGGER statement at the renamed table.
+pub fn rename_table(sql: &str, new_name: &str) -> String {
+    let tokens = tokenize(sql);
+    let Some(mut position) = tokens
+        .iter()
+        .position(|t| t.is_name("table") || t.is_name("index") || t.is_name("trigger"))
+    else {
+        return sql.to_string();
+    };
+    if !tokens[position].is_name("table") {
+        match tokens.iter().skip(position).position(|t| t.is_name("on")) {
+            Some(on) => position += on,
+            None => return sql.to_string(),
+        }
+    } else if tokens.get(position + 1).is_some_and(|t| t.is_name("if")) {
+        position += 3;
+    }
+    match tokens.get(position + 1) {
+        Some(name) => apply(sql, vec![(name.span.clone(), quote(new_name))]),
+        None => sql.to_string(),
+    }
+}
+
+/*
+    Rename a column everywhere the statement names it: in its own definition and in table
+    constraints of a CREATE TABLE, or in the indexed columns of a CREATE INDEX. Names after
+    REFERENCES belong to another table and collation names are not columns.
+*/
+pub fn rename_column(sql: &str, old_name: &str, new_name: &str) -> String {
+    let tokens = tokenize(sql);
+    let mut edits = Vec::new();
+    for item in items(&tokens) {
+        if item.is_empty() {
+            continue;
+        }
+        if !is_table_constraint(&tokens, &item) {
+            if tokens[item.start].is_name(old_name) {
+                edits.push((tokens[item.start].span.clone(), quote_if_needed(new_name)));
+            }
+            continue;
+        }
+        let mut depth = 0;
+        for i in item {
+            match tokens[i].kind {
+                TokenKind::Symbol('(') => depth += 1,
+                TokenKind::Symbol(')') => depth -= 1,
+                _ if tokens[i].is_name("references") => break,
+                _ if depth > 0
+                    && tokens[i].is_name(old_name)
+                    && !(i > 0 && tokens[i - 1].is_name("collate")) =>
+                {
+                    edits.push((tokens[i].span.clone(), quote_if_needed(new_name)));
+                }
+                _ => {}
+            }
+        }
+    }
+    apply(sql, edits)
+}
+
+// The new column definition goes after the last column, before any table constraint.
+pub fn add_column(sql: &str, definition: &str) -> String {
+    let tokens = tokenize(sql);
+    let Some(last) = items(&tokens)
+        .into_iter()
+        .rfind(|item| !item.is_empty() && !is_table_constraint(&tokens, item))
+    else {
+        return sql.to_string();
+    };
+    let end = tokens[last.end - 1].span.end;
+    apply(sql, vec![(end..end, format!(", {definition}"))])
+}
+
+// Remove a column definition together with the comma that separates it from its neighbour.
+pub fn drop_column(sql: &str, name: &str) -> String {
+    let tokens = tokenize(sql);
+    let items = items(&tokens);
+    let Some(n) = items.iter().position(|item| {
+        !item.is_empty() && !is_table_constraint(&tokens, item) && tokens[item.start].is_name(name)
+    }) else {
+        return sql.to_string();
+    };
+    let span = if n > 0 {
+        tokens[items[n - 1].end - 1].span.end..tokens[items[n].end - 1].span.end
+    } else if let Some(next) = items.get(1).filter(|item| !item.is_empty()) {
+        tokens[items[0].start].span.start..tokens[next.start].span.start
+    } else {
+        return sql.to_string();
+    };
+    apply(sql, vec![(span, String::new())])
+}
@@ -0,0 +1,279 @@
+use std::collections::{BTreeSet, HashMap};
+
+use crate::btree;
+use crate::database::Database;
+use crate::page::{MyError, Result};
+
+/*
+    In an auto-vacuum database (a non-zero largest root page at header offset 52) the file can
+    shrink without a full VACUUM: pages at the end of the file are moved into free pages closer
+    to the start. To move a page, whatever points to it has to be found, which is what the
+    pointer map is for.
+
+    Pointer-map pages start at page 2. Each one describes the usable_size/5 pages following it,
+    after which comes the next pointer-map page. Every entry is 5 bytes:
+        Offset  Size    Description
+        0       1       The page type, see PtrmapType
+        1       4       The parent page, zero for root pages and free pages
+
+    Root pages are never moved, they are kept together at the start of the file: a new b-tree
+    takes the page after the largest root, and a dropped root is replaced by the largest one.
+    With header offset 64 set, free pages are only given back by PRAGMA incremental_vacuum,
+    otherwise every commit truncates the file.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AutoVacuum {
+    None,
+    Full,
+    Incremental,
+}
+
+impl AutoVacuum {
+    pub fn from(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "none" | "0" => Some(AutoVacuum::None),
+            "full" | "1" => Some(AutoVacuum::Full),
+            "incremental" | "2" => Some(AutoVacuum::Incremental),
+            _ => None,
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        match self {
+            AutoVacuum::None => 0,
+            AutoVacuum::Full => 1,
+            AutoVacuum::Incremental => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PtrmapType {
+    // A b-tree root page, the parent is zero.
+    RootPage = 1,
+    // A page on the freelist, the parent is zero.
+    FreePage = 2,
+    // The first page of an overflow chain, the parent is the b-tree page holding the cell.
+    Overflow1 = 3,
+    // Any later page of an overflow chain, the parent is the previous overflow page.
+    Overflow2 = 4,
+    // A non-root b-tree page, the parent is its parent b-tree page.
+    BTree = 5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PtrmapEntry {
+    pub kind: PtrmapType,
+    pub parent: u32,
+}
+
+impl PtrmapEntry {
+    pub fn new(kind: PtrmapType, parent: u32) -> Self {
+        Self { kind, parent }
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let kind = match bytes[0] {
+            1 => PtrmapType::RootPage,
+            2 => PtrmapType::FreePage,
+            3 => PtrmapType::Overflow1,
+            4 => PtrmapType::Overflow2,
+            5 => PtrmapType::BTree,
+            _ => return Err(MyError::Corrupt),
+        };
+        Ok(Self {
+            kind,
+            parent: u32::from_be_bytes(bytes[1..5].try_into()?),
+        })
+    }
+
+    fn encode(&self) -> [u8; 5] {
+        let mut bytes = [0; 5];
+        bytes[0] = self.kind as u8;
+        bytes[1..].copy_from_slice(&self.parent.to_be_bytes());
+        bytes
+    }
+}
+
+// The pointer-map page holding the entry of a page.
+fn ptrmap_page(usable_size: usize, page_num: u32) -> u32 {
+    let pages_per_map = usable_size as u32 / 5 + 1;
+    (page_num - 2) / pages_per_map * pages_per_map + 2
+}
+
+pub fn is_ptrmap_page(usable_size: usize, page_num: u32) -> bool {
+    page_num >= 2 && ptrmap_page(usable_size, page_num) == page_num
+}
+
+fn entry_offset(map_page: u32, page_num: u32) -> usize {
+    5 * (page_num - map_page - 1) as usize
+}
+
+pub fn read_entry(db: &mut Database, page_num: u32) -> Result<PtrmapEntry> {
+    let usable_size = db.file_header.usable_size();
+    if page_num < 2 || is_ptrmap_page(usable_size, page_num) {
+        return Err(MyError::Corrupt);
+    }
+    let map_page = ptrmap_page(usable_size, page_num);
+    let offset = entry_offset(map_page, page_num);
+    let data = db.load_page(map_page)?;
+    PtrmapEntry::decode(&data[offset..offset + 5])
+}
+
+// Update several entries, loading and storing each pointer-map page only once, and only
+// storing those where an entry actually changed.
+pub fn write_entries(db: &mut Database, entries: &[(u32, PtrmapEntry)]) -> Result<()> {
+    let usable_size = db.file_header.usable_size();
+    let mut maps: HashMap<u32, (Vec<u8>, bool)> = HashMap::new();
+    for (page_num, entry) in entries {
+        if *page_num < 2 || is_ptrmap_page(usable_size, *page_num) {
+            return Err(MyError::Corrupt);
+        }
+        let map_page = ptrmap_page(usable_size, *page_num);
+        let (data, changed) = match maps.get_mut(&map_page) {
+            Some(map) => map,
+            None => {
+                let data = db.load_page(map_page)?.into_vec();
+                maps.entry(map_page).or_insert((data, false))
+            }
+        };
+        let offset = entry_offset(map_page, *page_num);
+        let encoded = entry.encode();
+        if data[offset..offset + 5] != encoded {
+            data[offset..offset + 5].copy_from_slice(&encoded);
+            *changed = true;
+        }
+    }
+    for (map_page, (data, changed)) in maps {
+        if changed {
+            db.store_page(map_page, &data)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+    Move the content of a page into the free page `to` and repoint whatever referred to it:
+    the parent b-tree page, the schema for a root page, or the previous page of an overflow
+    chain. Storing the moved b-tree page again records it as the parent of its own children.
+*/
+fn relocate(db: &mut Database, from: u32, to: u32) -> Result<()> {
+    let entry = read_entry(db, from)?;
+    let data = db.load_page(from)?;
+    db.store_page(to, &data)?;
+    match entry.kind {
+        PtrmapType::FreePage => return Err(MyError::Corrupt),
+        PtrmapType::RootPage => {
+            write_entries(db, &[(to, entry)])?;
+            // Moving a root goes with the schema change that caused it, the cookie is bumped once.
+            let schema_cookie = db.file_header.schema_cookie;
+            db.update_schema(|e| {
+                if e.root_page == from {
+                    e.root_page = to;
+                }
+            })?;
+            db.file_header.schema_cookie = schema_cookie;
+            btree::update_ptrmap(db, to)?;
+        }
+        PtrmapType::BTree => {
+            btree::replace_reference(db, entry.parent, from, to)?;
+            btree::update_ptrmap(db, to)?;
+        }
+        PtrmapType::Overflow1 | PtrmapType::Overflow2 => {
+            if entry.kind == PtrmapType::Overflow1 {
+                btree::replace_reference(db, entry.parent, from, to)?;
+            } else {
+                let mut previous = db.load_page(entry.parent)?.into_vec();
+                previous[..4].copy_from_slice(&to.to_be_bytes());
+                db.store_page(entry.parent, &previous)?;
+                write_entries(db, &[(to, entry)])?;
+            }
+            let next = u32::from_be_bytes(data[..4].try_into()?);
+            if next != 0 {
+                write_entries(db, &[(next, PtrmapEntry::new(PtrmapType::Overflow2, to))])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// The page following the largest root, skipping pointer-map pages.
+fn next_root(db: &Database, after: u32) -> u32 {
+    let usable_size = db.file_header.usable_size();
+    let mut page_num = after + 1;
+    while is_ptrmap_page(usable_size, page_num) {
+        page_num += 1;
+    }
+    page_num
+}
+
+// Make room for a new root page right after the current largest root.
+pub fn allocate_root(db: &mut Database) -> Result<u32> {
+    let root_page = next_root(db, db.file_header.largest_root_page);
+    if root_page > db.file_header.page_count {
+        while db.file_header.page_count < root_page {
+            db.append_page()?;
+        }
+    } else if !db.take_free_page(root_page)? {
+        let moved_to = db.allocate_page()?;
+        relocate(db, root_page, moved_to)?;
+    }
+    write_entries(
+        db,
+        &[(root_page, PtrmapEntry::new(PtrmapType::RootPage, 0))],
+    )?;
+    db.file_header.largest_root_page = root_page;
+    Ok(root_page)
+}
+
+// Fill the place of a dropped root page, already freed, with the largest root.
+pub fn release_root(db: &mut Database, root_page: u32) -> Result<()> {
+    let largest_root = db.file_header.largest_root_page;
+    if root_page != largest_root && db.take_free_page(root_page)? {
+        relocate(db, largest_root, root_page)?;
+        db.free_page(largest_root)?;
+    }
+    let usable_size = db.file_header.usable_size();
+    let mut largest_root = largest_root - 1;
+    while largest_root > 1 && is_ptrmap_page(usable_size, largest_root) {
+        largest_root -= 1;
+    }
+    db.file_header.largest_root_page = largest_root;
+    Ok(())
+}
+
+/*
+    Give up to `limit` free pages (all of them without a limit) back by truncating the file.
+    Every page at the end of the file is either on the freelist already, or is moved into the
+    lowest free page. Pointer-map pages that no longer describe anything go as well.
+*/
+pub fn incremental_vacuum(db: &mut Database, limit: Option<u32>) -> Result<()> {
+    let mut free: BTreeSet<u32> = db.freelist_pages()?.into_iter().collect();
+    let count = limit.map_or(free.len(), |n| (n as usize).min(free.len()));
+    if count == 0 {
+        return Ok(());
+    }
+    db.clear_freelist();
+    let usable_size = db.file_header.usable_size();
+    let mut page_count = db.file_header.page_count;
+    for _ in 0..count {
+        while is_ptrmap_page(usable_size, page_count) {
+            page_count -= 1;
+        }
+        if !free.remove(&page_count) {
+            let Some(target) = free.pop_first() else {
+                break;
+            };
+            relocate(db, page_count, target)?;
+        }
+        page_count -= 1;
+    }
+    while is_ptrmap_page(usable_size, page_count) {
+        page_count -= 1;
+    }
+    db.file_header.page_count = page_count;
+    for page_num in free {
+        db.free_page(page_num)?;
+    }
+    db.store_header()
+}
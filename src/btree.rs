@@ -0,0 +1,1605 @@
+use std::cmp::Ordering;
+
+use crate::autovacuum::{self, PtrmapEntry, PtrmapType};
+use crate::database::Database;
+use crate::mmap::PageData;
+use crate::page::{FileHeader, MyError, PageHeader, PageType, Result};
+use crate::utils::{read_variant, write_variant};
+
+/*
+    Cell layouts for the four kinds of b-tree pages:
+
+    Table Leaf (0x0d):      varint payload size, varint rowid, local payload, [4 byte overflow page]
+    Table Interior (0x05):  4 byte left child page, varint rowid
+    Index Leaf (0x0a):      varint payload size, local payload, [4 byte overflow page]
+    Index Interior (0x02):  4 byte left child page, varint payload size, local payload, [4 byte overflow page]
+
+    When the payload is too large to be stored on the b-tree page, only the first part of it stays
+    local and the rest spills into a linked list of overflow pages. Each overflow page starts with
+    the 4 byte page number of the next overflow page (0 for the last one) followed by content.
+*/
+#[derive(Debug, Clone)]
+pub struct CellInfo {
+    pub left_child_page: Option<u32>,
+    pub rowid: Option<i64>,
+    pub payload_size: usize,
+    pub payload_offset: usize,
+    pub local_size: usize,
+    pub overflow_page: Option<u32>,
+    pub size: usize,
+}
+
+/*
+    The amount of payload stored on the b-tree page itself, where U is the usable size of a page
+    and P is the payload size:
+        X is U-35 for table leaf pages and ((U-12)*64/255)-23 for index pages.
+        M is ((U-12)*32/255)-23.
+        K is M+((P-M)%(U-4)).
+        If P<=X then all of the payload is stored on the b-tree page.
+        If P>X and K<=X then the first K bytes of P are stored on the b-tree page.
+        If P>X and K>X then the first M bytes of P are stored on the b-tree page.
+*/
+pub fn local_payload_size(page_type: PageType, payload_size: usize, usable_size: usize) -> usize {
+    let max_local = if page_type == PageType::TableLeaf {
+        usable_size - 35
+    } else {
+        ((usable_size - 12) * 64 / 255) - 23
+    };
+    if payload_size <= max_local {
+        return payload_size;
+    }
+    let min_local = ((usable_size - 12) * 32 / 255) - 23;
+    let k = min_local + ((payload_size - min_local) % (usable_size - 4));
+    if k <= max_local { k } else { min_local }
+}
+
+// A cell cut short by the end of the page parses with missing bytes read as zero, its size then
+// tells that it doesn't fit.
+pub fn parse_cell(page_type: PageType, cell: &[u8], usable_size: usize) -> CellInfo {
+    let mut offset = 0;
+    let mut left_child_page = None;
+    if !page_type.is_leaf() {
+        left_child_page = Some(read_u32(cell, 0));
+        offset += 4;
+    }
+    if page_type == PageType::TableInterior {
+        let (rowid, bytes_read) = read_variant(cell.get(offset..).unwrap_or_default());
+        return CellInfo {
+            left_child_page,
+            rowid: Some(rowid),
+            payload_size: 0,
+            payload_offset: offset + bytes_read,
+            local_size: 0,
+            overflow_page: None,
+            size: offset + bytes_read,
+        };
+    }
+    let (payload_size, bytes_read) = read_variant(cell.get(offset..).unwrap_or_default());
+    let payload_size = payload_size as usize;
+    offset += bytes_read;
+    let mut rowid = None;
+    if page_type == PageType::TableLeaf {
+        let (key, bytes_read) = read_variant(cell.get(offset..).unwrap_or_default());
+        rowid = Some(key);
+        offset += bytes_read;
+    }
+    let local_size = local_payload_size(page_type, payload_size, usable_size);
+    let mut size = offset + local_size;
+    let mut overflow_page = None;
+    if local_size < payload_size {
+        overflow_page = Some(read_u32(cell, size));
+        size += 4;
+    }
+    CellInfo {
+        left_child_page,
+        rowid,
+        payload_size,
+        payload_offset: offset,
+        local_size,
+        overflow_page,
+        size,
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes
+        .get(offset..offset + 4)
+        .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+// Read the whole payload of a cell, following the overflow chain when needed.
+pub fn read_payload(db: &mut Database, page_type: PageType, cell: &[u8]) -> Result<Vec<u8>> {
+    let usable_size = db.file_header.usable_size();
+    let info = parse_cell(page_type, cell, usable_size);
+    let mut payload = cell[info.payload_offset..info.payload_offset + info.local_size].to_vec();
+    // Neither the payload nor its overflow chain can be larger than the file.
+    if info.payload_size > db.file_header.page_count as usize * usable_size {
+        return Err(MyError::Corrupt);
+    }
+    let mut next_page = info.overflow_page.unwrap_or(0);
+    while payload.len() < info.payload_size && next_page != 0 {
+        if next_page > db.file_header.page_count {
+            return Err(MyError::CorruptPage(next_page));
+        }
+        let page = db.load_page(next_page)?;
+        let take = (info.payload_size - payload.len()).min(usable_size - 4);
+        payload.extend_from_slice(&page[4..4 + take]);
+        next_page = u32::from_be_bytes(page[..4].try_into()?);
+    }
+    Ok(payload)
+}
+
+// Build a cell for the given page type, writing any spilled payload into new overflow pages.
+pub fn build_cell(
+    db: &mut Database,
+    page_type: PageType,
+    left_child_page: Option<u32>,
+    rowid: Option<i64>,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let usable_size = db.file_header.usable_size();
+    let mut cell = Vec::new();
+    if let Some(page) = left_child_page {
+        cell.extend_from_slice(&page.to_be_bytes());
+    }
+    if page_type == PageType::TableInterior {
+        cell.extend(write_variant(rowid.unwrap_or(0)));
+        return Ok(cell);
+    }
+    cell.extend(write_variant(payload.len() as i64));
+    if let Some(rowid) = rowid {
+        cell.extend(write_variant(rowid));
+    }
+    let local_size = local_payload_size(page_type, payload.len(), usable_size);
+    cell.extend_from_slice(&payload[..local_size]);
+    if local_size < payload.len() {
+        let chunks: Vec<&[u8]> = payload[local_size..].chunks(usable_size - 4).collect();
+        let mut pages = Vec::new();
+        for _ in 0..chunks.len() {
+            pages.push(db.allocate_page()?);
+        }
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut data = vec![0; db.file_header.page_size as usize];
+            let next_page = pages.get(i + 1).copied().unwrap_or(0);
+            data[..4].copy_from_slice(&next_page.to_be_bytes());
+            data[4..4 + chunk.len()].copy_from_slice(chunk);
+            db.store_page(pages[i], &data)?;
+        }
+        // The first page is recorded when the cell is stored on its b-tree page.
+        if db.file_header.is_auto_vacuum() {
+            let entries: Vec<_> = pages
+                .windows(2)
+                .map(|w| (w[1], PtrmapEntry::new(PtrmapType::Overflow2, w[0])))
+                .collect();
+            autovacuum::write_entries(db, &entries)?;
+        }
+        cell.extend_from_slice(&pages[0].to_be_bytes());
+    }
+    Ok(cell)
+}
+
+// A chain with more pages than the file has loops back on itself.
+pub fn free_overflow_pages(db: &mut Database, page_type: PageType, cell: &[u8]) -> Result<()> {
+    let info = parse_cell(page_type, cell, db.file_header.usable_size());
+    let mut next_page = info.overflow_page.unwrap_or(0);
+    let mut remaining = db.file_header.page_count;
+    while next_page != 0 {
+        if remaining == 0 || next_page > db.file_header.page_count {
+            return Err(MyError::CorruptPage(next_page));
+        }
+        remaining -= 1;
+        let page = db.load_page(next_page)?;
+        db.free_page(next_page)?;
+        next_page = u32::from_be_bytes(page[..4].try_into()?);
+    }
+    Ok(())
+}
+
+/*
+    A b-tree page loaded for in-place modification. Removing a cell turns its space into a
+    freeblock; freeblocks form a linked list ordered by offset, where the first 2 bytes of a
+    freeblock hold the offset of the next one and the following 2 bytes its size. Free areas
+    smaller than 4 bytes cannot hold a freeblock and are counted as fragmented bytes instead.
+*/
+#[derive(Debug, Clone)]
+pub struct BTreePage {
+    pub page_num: u32,
+    // Shared with the page cache or the mapped file until the page is changed.
+    pub data: PageData,
+    pub page_header: PageHeader,
+    header_offset: usize,
+    usable_size: usize,
+}
+
+impl BTreePage {
+    pub fn load(db: &mut Database, page_num: u32) -> Result<Self> {
+        let page = Self::load_unchecked(db, page_num)?;
+        if !page.content_fits() || !page.cells_fit() || !page.freeblocks_fit() {
+            return Err(MyError::CorruptPage(page_num));
+        }
+        Ok(page)
+    }
+
+    // Load a page whose header is valid, without checking where its cells lie.
+    pub fn load_unchecked(db: &mut Database, page_num: u32) -> Result<Self> {
+        if db.file_header.is_auto_vacuum()
+            && autovacuum::is_ptrmap_page(db.file_header.usable_size(), page_num)
+        {
+            return Err(MyError::CorruptPage(page_num));
+        }
+        let data = db.load_page(page_num)?;
+        let header_offset = header_offset(page_num);
+        let page_header = PageHeader::from(&data[header_offset..]).map_err(|e| match e {
+            MyError::Corrupt => MyError::CorruptPage(page_num),
+            e => e,
+        })?;
+        Ok(Self {
+            page_num,
+            data,
+            page_header,
+            header_offset,
+            usable_size: db.file_header.usable_size(),
+        })
+    }
+
+    // The cell content area starts between the cell pointer array and the end of the usable space.
+    fn content_fits(&self) -> bool {
+        let content_offset = self.page_header.cell_content_offset as usize;
+        content_offset >= self.unallocated_start() && content_offset <= self.usable_size
+    }
+
+    // Every cell must lie between the cell pointer array and the end of the usable space.
+    fn cells_fit(&self) -> bool {
+        let pointers_end = self.cell_pointer_array_offset() + 2 * self.cell_count();
+        pointers_end <= self.usable_size
+            && (0..self.cell_count()).all(|i| {
+                let offset = self.cell_offset(i);
+                offset >= pointers_end
+                    && offset < self.usable_size
+                    && offset
+                        + parse_cell(self.page_type(), &self.data[offset..], self.usable_size).size
+                        <= self.usable_size
+            })
+    }
+
+    /*
+        The freeblock list must run in ascending order between the cell pointer array and the
+        end of the usable space, each block at least 4 bytes and clear of the next one, and
+        there can be no more than 60 fragmented bytes, like SQLite allows.
+    */
+    fn freeblocks_fit(&self) -> bool {
+        let pointers_end = self.cell_pointer_array_offset() + 2 * self.cell_count();
+        let mut next = self.page_header.first_freeblock as usize;
+        let mut end = pointers_end;
+        while next != 0 {
+            if next < end || next + 4 > self.usable_size {
+                return false;
+            }
+            let size = self.read_u16(next + 2);
+            if size < 4 || next + size > self.usable_size {
+                return false;
+            }
+            end = next + size;
+            next = self.read_u16(next);
+        }
+        self.page_header.fragmented_bytes_count <= 60
+    }
+
+    pub fn page_type(&self) -> PageType {
+        self.page_header.page_type
+    }
+
+    pub fn cell_count(&self) -> usize {
+        self.page_header.cell_count as usize
+    }
+
+    pub fn cell_pointer_array_offset(&self) -> usize {
+        self.header_offset + self.page_header.get_header_size()
+    }
+
+    pub fn cell_offset(&self, index: usize) -> usize {
+        let pointer = self.cell_pointer_array_offset() + index * 2;
+        u16::from_be_bytes([self.data[pointer], self.data[pointer + 1]]) as usize
+    }
+
+    // A page from load has every cell in bounds, one from load_unchecked gets what is there.
+    pub fn cell(&self, index: usize) -> &[u8] {
+        let offset = self.cell_offset(index);
+        let rest = self.data.get(offset..).unwrap_or_default();
+        let info = parse_cell(self.page_type(), rest, self.usable_size);
+        &rest[..info.size.min(rest.len())]
+    }
+
+    pub fn cells(&self) -> Vec<Vec<u8>> {
+        (0..self.cell_count())
+            .map(|i| self.cell(i).to_vec())
+            .collect()
+    }
+
+    // The child page followed for the index-th cell, or the right-most pointer past the last cell.
+    pub fn child_page(&self, index: usize) -> u32 {
+        if index < self.cell_count() {
+            parse_cell(self.page_type(), self.cell(index), self.usable_size)
+                .left_child_page
+                .unwrap_or(0)
+        } else {
+            self.page_header.rightmost_pointer.unwrap_or(0)
+        }
+    }
+
+    pub fn to_node(&self) -> Node {
+        Node {
+            page_num: self.page_num,
+            page_type: self.page_type(),
+            cells: self.cells(),
+            rightmost_pointer: self.page_header.rightmost_pointer,
+        }
+    }
+
+    fn read_u16(&self, offset: usize) -> usize {
+        u16::from_be_bytes([self.data[offset], self.data[offset + 1]]) as usize
+    }
+
+    // Changing a page copies it first if it is still shared.
+    fn data_mut(&mut self) -> &mut Vec<u8> {
+        self.data.to_mut()
+    }
+
+    fn write_u16(&mut self, offset: usize, value: usize) {
+        self.data_mut()[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    fn freeblocks(&self) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::new();
+        let mut next = self.page_header.first_freeblock as usize;
+        // Freeblocks are sorted by offset, anything else would be a corrupt list.
+        while next != 0
+            && next + 4 <= self.usable_size
+            && blocks.last().is_none_or(|b: &(usize, usize)| b.0 < next)
+        {
+            blocks.push((next, self.read_u16(next + 2)));
+            next = self.read_u16(next);
+        }
+        blocks
+    }
+
+    fn write_freeblocks(&mut self, blocks: &[(usize, usize)]) {
+        self.page_header.first_freeblock = blocks.first().map_or(0, |b| b.0 as u16);
+        for (i, (start, size)) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).map_or(0, |b| b.0);
+            self.write_u16(*start, next);
+            self.write_u16(start + 2, *size);
+        }
+    }
+
+    fn unallocated_start(&self) -> usize {
+        self.cell_pointer_array_offset() + self.cell_count() * 2
+    }
+
+    pub fn free_bytes(&self) -> usize {
+        let gap = self.page_header.cell_content_offset as usize - self.unallocated_start();
+        gap + self.freeblocks().iter().map(|b| b.1).sum::<usize>()
+            + self.page_header.fragmented_bytes_count as usize
+    }
+
+    // A page is rebalanced with its siblings once less than a third of it is in use.
+    pub fn is_underfull(&self) -> bool {
+        self.cell_count() == 0 || self.free_bytes() > self.usable_size * 2 / 3
+    }
+
+    // Return the space of a removed cell to the page, coalescing it with neighbouring freeblocks
+    // and reclaiming fragmented bytes that sit between them.
+    fn free_space(&mut self, start: usize, size: usize) {
+        let mut blocks = self.freeblocks();
+        let position = blocks.partition_point(|b| b.0 < start);
+        blocks.insert(position, (start, size));
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        let mut fragments_reclaimed = 0;
+        for (start, size) in blocks {
+            if let Some(last) = merged.last_mut() {
+                let last_end = last.0 + last.1;
+                if last_end + 3 >= start {
+                    fragments_reclaimed += start - last_end;
+                    last.1 = start + size - last.0;
+                    continue;
+                }
+            }
+            merged.push((start, size));
+        }
+        self.page_header.fragmented_bytes_count = self
+            .page_header
+            .fragmented_bytes_count
+            .saturating_sub(fragments_reclaimed as u8);
+        if let Some(first) = merged.first()
+            && first.0 == self.page_header.cell_content_offset as usize
+        {
+            self.page_header.cell_content_offset += first.1 as u32;
+            merged.remove(0);
+        }
+        self.write_freeblocks(&merged);
+    }
+
+    /*
+        Find room for size bytes, first in the freeblock list and then in the unallocated gap.
+        Less than 4 bytes left over of a freeblock become fragmented bytes, unless the page has
+        more than 57 already. Then, like in SQLite, the gap is used instead, so no page ever has
+        more than 60.
+    */
+    fn allocate_space(&mut self, size: usize) -> usize {
+        let mut blocks = self.freeblocks();
+        if let Some(position) = blocks.iter().position(|b| b.1 >= size) {
+            let (start, block_size) = blocks[position];
+            let remaining = block_size - size;
+            if remaining >= 4 {
+                blocks[position].1 = remaining;
+                self.write_freeblocks(&blocks);
+                return start + remaining;
+            }
+            if self.page_header.fragmented_bytes_count <= 57 {
+                self.page_header.fragmented_bytes_count += remaining as u8;
+                blocks.remove(position);
+                self.write_freeblocks(&blocks);
+                return start;
+            }
+        }
+        if self.unallocated_start() + 2 + size > self.page_header.cell_content_offset as usize {
+            self.defragment();
+        }
+        self.page_header.cell_content_offset -= size as u32;
+        self.page_header.cell_content_offset as usize
+    }
+
+    // Move all cells to the end of the page so the free space forms a single gap.
+    fn defragment(&mut self) {
+        let cells = self.cells();
+        let mut content_offset = self.usable_size;
+        let pointer_array = self.cell_pointer_array_offset();
+        for (i, cell) in cells.iter().enumerate() {
+            content_offset -= cell.len().max(4);
+            self.data_mut()[content_offset..content_offset + cell.len()].copy_from_slice(cell);
+            self.write_u16(pointer_array + i * 2, content_offset);
+        }
+        let unallocated_start = self.unallocated_start();
+        self.data_mut()[unallocated_start..content_offset].fill(0);
+        self.page_header.cell_content_offset = content_offset as u32;
+        self.page_header.first_freeblock = 0;
+        self.page_header.fragmented_bytes_count = 0;
+    }
+
+    pub fn remove_cell(&mut self, index: usize) {
+        let offset = self.cell_offset(index);
+        let size = self.cell(index).len().max(4);
+        self.free_space(offset, size);
+        let pointer = self.cell_pointer_array_offset() + index * 2;
+        let end = self.unallocated_start();
+        self.data_mut().copy_within(pointer + 2..end, pointer);
+        self.data_mut()[end - 2..end].fill(0);
+        self.page_header.cell_count -= 1;
+    }
+
+    // Insert a cell in place, or return false when the page does not have enough room left.
+    pub fn insert_cell(&mut self, index: usize, cell: &[u8]) -> bool {
+        let size = cell.len().max(4);
+        if size + 2 > self.free_bytes() {
+            return false;
+        }
+        if self.unallocated_start() + 2 > self.page_header.cell_content_offset as usize {
+            self.defragment();
+        }
+        let offset = self.allocate_space(size);
+        self.data_mut()[offset..offset + cell.len()].copy_from_slice(cell);
+        let pointer = self.cell_pointer_array_offset() + index * 2;
+        let end = self.unallocated_start();
+        self.data_mut().copy_within(pointer..end, pointer + 2);
+        self.write_u16(pointer, offset);
+        self.page_header.cell_count += 1;
+        true
+    }
+
+    pub fn store(&mut self, db: &mut Database) -> Result<()> {
+        let header_offset = self.header_offset;
+        let page_header = self.page_header.clone();
+        page_header.write_to(&mut self.data_mut()[header_offset..]);
+        db.store_page(self.page_num, &self.data)?;
+        record_pointers(
+            db,
+            self.page_num,
+            self.page_type(),
+            &self.cells(),
+            self.page_header.rightmost_pointer,
+        )
+    }
+}
+
+/*
+    In an auto-vacuum database every page a b-tree page points to, its children and the first
+    page of each overflow chain, has this page as its parent in the pointer map.
+*/
+fn record_pointers(
+    db: &mut Database,
+    page_num: u32,
+    page_type: PageType,
+    cells: &[Vec<u8>],
+    rightmost_pointer: Option<u32>,
+) -> Result<()> {
+    if !db.file_header.is_auto_vacuum() {
+        return Ok(());
+    }
+    let usable_size = db.file_header.usable_size();
+    let mut entries = Vec::new();
+    for cell in cells {
+        let info = parse_cell(page_type, cell, usable_size);
+        if let Some(child) = info.left_child_page {
+            entries.push((child, PtrmapEntry::new(PtrmapType::BTree, page_num)));
+        }
+        if let Some(overflow) = info.overflow_page {
+            entries.push((overflow, PtrmapEntry::new(PtrmapType::Overflow1, page_num)));
+        }
+    }
+    if let Some(child) = rightmost_pointer.filter(|_| !page_type.is_leaf()) {
+        entries.push((child, PtrmapEntry::new(PtrmapType::BTree, page_num)));
+    }
+    autovacuum::write_entries(db, &entries)
+}
+
+// Record a b-tree page that has just been moved as the parent of what it points to.
+pub fn update_ptrmap(db: &mut Database, page_num: u32) -> Result<()> {
+    let page = BTreePage::load(db, page_num)?;
+    record_pointers(
+        db,
+        page_num,
+        page.page_type(),
+        &page.cells(),
+        page.page_header.rightmost_pointer,
+    )
+}
+
+// Repoint a child or overflow page reference of a b-tree page to the page it was moved to.
+pub fn replace_reference(db: &mut Database, page_num: u32, old: u32, new: u32) -> Result<()> {
+    let mut page = BTreePage::load(db, page_num)?;
+    let mut found = false;
+    for i in 0..page.cell_count() {
+        let offset = page.cell_offset(i);
+        let info = parse_cell(page.page_type(), &page.data[offset..], page.usable_size);
+        if info.left_child_page == Some(old) {
+            page.data_mut()[offset..offset + 4].copy_from_slice(&new.to_be_bytes());
+            found = true;
+        }
+        if info.overflow_page == Some(old) {
+            let end = offset + info.size;
+            page.data_mut()[end - 4..end].copy_from_slice(&new.to_be_bytes());
+            found = true;
+        }
+    }
+    if !page.page_type().is_leaf() && page.page_header.rightmost_pointer == Some(old) {
+        page.page_header.rightmost_pointer = Some(new);
+        found = true;
+    }
+    if !found {
+        return Err(MyError::Corrupt);
+    }
+    page.store(db)
+}
+
+fn header_offset(page_num: u32) -> usize {
+    if page_num == 1 {
+        FileHeader::FILE_HEADER_SIZE
+    } else {
+        0
+    }
+}
+
+fn interior_type(page_type: PageType) -> PageType {
+    if page_type.is_table() {
+        PageType::TableInterior
+    } else {
+        PageType::IndexInterior
+    }
+}
+
+fn leaf_type(page_type: PageType) -> PageType {
+    if page_type.is_table() {
+        PageType::TableLeaf
+    } else {
+        PageType::IndexLeaf
+    }
+}
+
+fn left_child(cell: &[u8]) -> u32 {
+    read_u32(cell, 0)
+}
+
+/*
+    The in-memory form of a page while it is being rebalanced. Unlike BTreePage, the cells of
+    a node do not have to fit into a page, the balance step takes care of spreading them out.
+*/
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub page_num: u32,
+    pub page_type: PageType,
+    pub cells: Vec<Vec<u8>>,
+    pub rightmost_pointer: Option<u32>,
+}
+
+impl Node {
+    fn header_size(&self) -> usize {
+        if self.page_type.is_leaf() { 8 } else { 12 }
+    }
+
+    fn used_bytes(&self) -> usize {
+        header_offset(self.page_num)
+            + self.header_size()
+            + self.cells.iter().map(|c| c.len().max(4) + 2).sum::<usize>()
+    }
+
+    fn fits(&self, usable_size: usize) -> bool {
+        self.used_bytes() <= usable_size
+    }
+
+    fn is_underfull(&self, usable_size: usize) -> bool {
+        self.cells.is_empty() || usable_size - self.used_bytes() > usable_size * 2 / 3
+    }
+
+    // Rebuild the page from scratch with all cells packed at the end of the page.
+    fn store(&self, db: &mut Database) -> Result<()> {
+        let usable_size = db.file_header.usable_size();
+        let header_offset = header_offset(self.page_num);
+        let mut data = if self.page_num == 1 {
+            db.load_page(1)?.into_vec()
+        } else {
+            vec![0; db.file_header.page_size as usize]
+        };
+        data[header_offset..usable_size].fill(0);
+        let mut content_offset = usable_size;
+        let mut pointer = header_offset + self.header_size();
+        for cell in &self.cells {
+            content_offset -= cell.len().max(4);
+            data[content_offset..content_offset + cell.len()].copy_from_slice(cell);
+            data[pointer..pointer + 2].copy_from_slice(&(content_offset as u16).to_be_bytes());
+            pointer += 2;
+        }
+        let page_header = PageHeader {
+            page_type: self.page_type,
+            first_freeblock: 0,
+            cell_count: self.cells.len() as u16,
+            cell_content_offset: content_offset as u32,
+            fragmented_bytes_count: 0,
+            rightmost_pointer: if self.page_type.is_leaf() {
+                None
+            } else {
+                self.rightmost_pointer
+            },
+        };
+        page_header.write_to(&mut data[header_offset..]);
+        db.store_page(self.page_num, &data)?;
+        record_pointers(
+            db,
+            self.page_num,
+            self.page_type,
+            &self.cells,
+            self.rightmost_pointer,
+        )
+    }
+}
+
+/*
+    Rebalance a node that no longer fits into its page, or that became less than a third full.
+    The path holds each ancestor page together with the index of the child that was followed
+    to reach the node, starting from the root.
+
+    A non-root node is redistributed together with up to two of its siblings: all their cells,
+    plus the dividers pulled down from the parent, are packed into as few pages as needed and new
+    dividers are pushed back up. The parent then gets the same treatment, all the way up to the
+    root. The root page never moves: when it overflows its content is pushed down into a new
+    child, and when an interior root is left with a single child that child is pulled up into it.
+*/
+pub fn balance(db: &mut Database, mut path: Vec<(u32, usize)>, mut node: Node) -> Result<()> {
+    let usable_size = db.file_header.usable_size();
+    loop {
+        let Some((parent_num, child_index)) = path.pop() else {
+            return balance_root(db, node);
+        };
+        if node.fits(usable_size) && !node.is_underfull(usable_size) {
+            return node.store(db);
+        }
+        let parent = BTreePage::load(db, parent_num)?.to_node();
+        node = balance_siblings(db, parent, child_index, node)?;
+    }
+}
+
+fn balance_root(db: &mut Database, mut root: Node) -> Result<()> {
+    let usable_size = db.file_header.usable_size();
+    loop {
+        if !root.fits(usable_size) {
+            let child = Node {
+                page_num: db.allocate_page()?,
+                ..root.clone()
+            };
+            let new_root = Node {
+                page_num: root.page_num,
+                page_type: interior_type(root.page_type),
+                cells: Vec::new(),
+                rightmost_pointer: Some(child.page_num),
+            };
+            root = balance_siblings(db, new_root, 0, child)?;
+            continue;
+        }
+        if !root.page_type.is_leaf()
+            && root.cells.is_empty()
+            && let Some(child_num) = root.rightmost_pointer
+        {
+            let child = BTreePage::load(db, child_num)?.to_node();
+            let pulled_up = Node {
+                page_num: root.page_num,
+                ..child
+            };
+            if pulled_up.fits(usable_size) {
+                db.free_page(child_num)?;
+                root = pulled_up;
+                continue;
+            }
+        }
+        return root.store(db);
+    }
+}
+
+fn balance_siblings(
+    db: &mut Database,
+    mut parent: Node,
+    child_index: usize,
+    node: Node,
+) -> Result<Node> {
+    let usable_size = db.file_header.usable_size();
+    let child_count = parent.cells.len() + 1;
+    let first = child_index.saturating_sub(1);
+    let last = (child_index + 1).min(child_count - 1);
+    let child_page = |parent: &Node, i: usize| {
+        if i < parent.cells.len() {
+            left_child(&parent.cells[i])
+        } else {
+            parent.rightmost_pointer.unwrap_or(0)
+        }
+    };
+
+    let mut siblings = Vec::new();
+    for i in first..=last {
+        if i == child_index {
+            siblings.push(node.clone());
+        } else {
+            siblings.push(BTreePage::load(db, child_page(&parent, i))?.to_node());
+        }
+    }
+
+    // Gather every cell of the siblings in key order, with the dividers from the parent between them.
+    let page_type = node.page_type;
+    let mut entries: Vec<Vec<u8>> = Vec::new();
+    for (j, sibling) in siblings.iter().enumerate() {
+        entries.extend(sibling.cells.iter().cloned());
+        if first + j < last {
+            let divider = &parent.cells[first + j];
+            match page_type {
+                PageType::TableLeaf => {}
+                PageType::IndexLeaf => entries.push(divider[4..].to_vec()),
+                PageType::TableInterior | PageType::IndexInterior => {
+                    let mut entry = sibling
+                        .rightmost_pointer
+                        .unwrap_or(0)
+                        .to_be_bytes()
+                        .to_vec();
+                    entry.extend_from_slice(&divider[4..]);
+                    entries.push(entry);
+                }
+            }
+        }
+    }
+    let final_rightmost_pointer = siblings.last().and_then(|s| s.rightmost_pointer);
+    let header_size = if page_type.is_leaf() { 8 } else { 12 };
+    let promote = page_type != PageType::TableLeaf;
+    // When every child of the parent is empty, keep a single empty page so the parent still
+    // points somewhere. An empty root is collapsed later by balance_root.
+    let groups = if entries.is_empty() && first == 0 && last == child_count - 1 {
+        vec![(0, 0)]
+    } else {
+        pack(&entries, usable_size - header_size, promote)
+    };
+
+    let mut page_nums: Vec<u32> = siblings.iter().map(|s| s.page_num).collect();
+    while page_nums.len() < groups.len() {
+        page_nums.push(db.allocate_page()?);
+    }
+    for page_num in page_nums.drain(groups.len()..) {
+        db.free_page(page_num)?;
+    }
+
+    let mut dividers = Vec::new();
+    for (j, (start, end)) in groups.iter().enumerate() {
+        let is_last = j == groups.len() - 1;
+        let mut new_node = Node {
+            page_num: page_nums[j],
+            page_type,
+            cells: entries[*start..*end].to_vec(),
+            rightmost_pointer: None,
+        };
+        let mut divider = page_nums[j].to_be_bytes().to_vec();
+        match page_type {
+            PageType::TableLeaf => {
+                if let Some(cell) = new_node.cells.last() {
+                    let rowid = parse_cell(page_type, cell, usable_size).rowid.unwrap_or(0);
+                    divider.extend(write_variant(rowid));
+                }
+            }
+            PageType::IndexLeaf => {
+                if !is_last {
+                    divider.extend_from_slice(&entries[*end]);
+                }
+            }
+            PageType::TableInterior | PageType::IndexInterior => {
+                if is_last {
+                    new_node.rightmost_pointer = final_rightmost_pointer;
+                } else {
+                    new_node.rightmost_pointer = Some(left_child(&entries[*end]));
+                    divider.extend_from_slice(&entries[*end][4..]);
+                }
+            }
+        }
+        new_node.store(db)?;
+        if !is_last {
+            dividers.push(divider);
+        }
+    }
+
+    // Replace the old dividers and point the slot of the last sibling to the last new page.
+    parent.cells.drain(first..last);
+    let last_page = page_nums.last().copied().unwrap_or(0);
+    if first < parent.cells.len() {
+        parent.cells[first][..4].copy_from_slice(&last_page.to_be_bytes());
+    } else {
+        parent.rightmost_pointer = Some(last_page);
+    }
+    for (j, divider) in dividers.into_iter().enumerate() {
+        parent.cells.insert(first + j, divider);
+    }
+    // All the siblings were empty and have been freed, so drop the slot pointing to them.
+    if groups.is_empty() {
+        if first < parent.cells.len() {
+            parent.cells.remove(first);
+        } else if let Some(cell) = parent.cells.pop() {
+            parent.rightmost_pointer = Some(left_child(&cell));
+        }
+    }
+    Ok(parent)
+}
+
+/*
+    Split the entries into consecutive groups, each fitting into one page. When promote is set,
+    the entry following each group (except the last) moves up into the parent as the divider.
+    The number of pages is the smallest possible, with the content spread evenly among them.
+*/
+fn pack(entries: &[Vec<u8>], capacity: usize, promote: bool) -> Vec<(usize, usize)> {
+    let size = |entry: &Vec<u8>| entry.len().max(4) + 2;
+    if entries.is_empty() {
+        return if promote { vec![(0, 0)] } else { Vec::new() };
+    }
+
+    let mut greedy = Vec::new();
+    let mut start = 0;
+    let mut used = 0;
+    let mut i = 0;
+    while i < entries.len() {
+        if used + size(&entries[i]) > capacity && i > start {
+            greedy.push((start, i));
+            if promote {
+                i += 1;
+            }
+            start = i;
+            used = 0;
+            continue;
+        }
+        used += size(&entries[i]);
+        i += 1;
+    }
+    greedy.push((start, entries.len()));
+    // The last group must not be empty, borrow the promoted entry instead.
+    let count = greedy.len();
+    if count > 1 && greedy[count - 1].0 == greedy[count - 1].1 {
+        let (s, e) = greedy[count - 2];
+        greedy[count - 2] = (s, e - 1);
+        greedy[count - 1] = (e, entries.len());
+    }
+    if count == 1 {
+        return greedy;
+    }
+
+    let total: usize = entries.iter().map(size).sum();
+    let target = total / count;
+    let mut even = Vec::new();
+    let mut start = 0;
+    let mut i = 0;
+    for _ in 0..count - 1 {
+        let mut used = 0;
+        while i < entries.len() && used < target && used + size(&entries[i]) <= capacity {
+            used += size(&entries[i]);
+            i += 1;
+        }
+        if i == start || i >= entries.len() {
+            return greedy;
+        }
+        even.push((start, i));
+        if promote {
+            i += 1;
+        }
+        start = i;
+    }
+    let rest: usize = entries[start.min(entries.len())..].iter().map(size).sum();
+    if start >= entries.len() || rest > capacity {
+        return greedy;
+    }
+    even.push((start, entries.len()));
+    even
+}
+
+/*
+    Table b-trees are keyed by rowid. An interior cell holds the largest rowid found in the
+    subtree of its left child, so the search follows the first cell whose key is not smaller
+    than the wanted rowid, or the right-most pointer.
+*/
+/*
+    SQLite never builds b-trees deeper than this, a page found further down means the tree
+    loops back on itself.
+*/
+const MAX_DEPTH: usize = 20;
+
+fn load_at_depth(db: &mut Database, page_num: u32, depth: usize) -> Result<BTreePage> {
+    if depth > MAX_DEPTH {
+        return Err(MyError::CorruptPage(page_num));
+    }
+    BTreePage::load(db, page_num)
+}
+
+/*
+    Walks the rows of a table b-tree in rowid order. A payload is borrowed from its leaf page,
+    only one spilling to overflow pages is copied, into a buffer the next such row reuses. The
+    cursor keeps the pages it is on rather than a borrow of the database, which must not be
+    changed until the walk is over.
+*/
+pub struct TableCursor {
+    // The pages from the root to the current leaf, each with the next cell or child to visit.
+    path: Vec<(BTreePage, usize)>,
+    overflow: Vec<u8>,
+}
+
+impl TableCursor {
+    pub fn from(db: &mut Database, root_page: u32) -> Result<Self> {
+        Ok(Self {
+            path: vec![(load_at_depth(db, root_page, 0)?, 0)],
+            overflow: Vec::new(),
+        })
+    }
+
+    pub fn next(&mut self, db: &mut Database) -> Result<Option<(i64, &[u8])>> {
+        loop {
+            let depth = self.path.len();
+            let Some((page, index)) = self.path.last_mut() else {
+                return Ok(None);
+            };
+            if page.page_type().is_leaf() {
+                if *index < page.cell_count() {
+                    break;
+                }
+                self.path.pop();
+            } else if *index <= page.cell_count() {
+                let child = page.child_page(*index);
+                *index += 1;
+                self.path.push((load_at_depth(db, child, depth)?, 0));
+            } else {
+                self.path.pop();
+            }
+        }
+        let Some((page, index)) = self.path.last_mut() else {
+            return Ok(None);
+        };
+        let cell = page.cell(*index);
+        *index += 1;
+        let info = parse_cell(page.page_type(), cell, page.usable_size);
+        let rowid = info.rowid.unwrap_or(0);
+        if info.overflow_page.is_some() {
+            self.overflow = read_payload(db, page.page_type(), cell)?;
+            return Ok(Some((rowid, &self.overflow)));
+        }
+        let payload = cell
+            .get(info.payload_offset..info.payload_offset + info.local_size)
+            .ok_or(MyError::CorruptPage(page.page_num))?;
+        Ok(Some((rowid, payload)))
+    }
+}
+
+// Every row of a table, for the callers which change the database while going through them.
+pub fn table_rows(db: &mut Database, root_page: u32) -> Result<Vec<(i64, Vec<u8>)>> {
+    let mut cursor = TableCursor::from(db, root_page)?;
+    let mut rows = Vec::new();
+    while let Some((rowid, payload)) = cursor.next(db)? {
+        rows.push((rowid, payload.to_vec()));
+    }
+    Ok(rows)
+}
+
+struct SeekResult {
+    path: Vec<(u32, usize)>,
+    page: BTreePage,
+    index: usize,
+    found: bool,
+}
+
+fn table_seek(db: &mut Database, root_page: u32, rowid: i64) -> Result<SeekResult> {
+    let mut path = Vec::new();
+    let mut page = BTreePage::load(db, root_page)?;
+    loop {
+        let usable_size = page.usable_size;
+        let index = (0..page.cell_count())
+            .find(|i| {
+                parse_cell(page.page_type(), page.cell(*i), usable_size)
+                    .rowid
+                    .unwrap_or(0)
+                    >= rowid
+            })
+            .unwrap_or(page.cell_count());
+        if page.page_type().is_leaf() {
+            let found = index < page.cell_count()
+                && parse_cell(page.page_type(), page.cell(index), usable_size).rowid == Some(rowid);
+            return Ok(SeekResult {
+                path,
+                page,
+                index,
+                found,
+            });
+        }
+        let child = page.child_page(index);
+        path.push((page.page_num, index));
+        page = load_at_depth(db, child, path.len())?;
+    }
+}
+
+pub fn is_empty(db: &mut Database, root_page: u32) -> Result<bool> {
+    let page = BTreePage::load(db, root_page)?;
+    Ok(page.page_type().is_leaf() && page.cell_count() == 0)
+}
+
+// The largest rowid of a table, at the end of the right edge of its b-tree.
+pub fn max_rowid(db: &mut Database, root_page: u32) -> Result<Option<i64>> {
+    let mut page = BTreePage::load(db, root_page)?;
+    let mut depth = 0;
+    while !page.page_type().is_leaf() {
+        depth += 1;
+        page = load_at_depth(db, page.child_page(page.cell_count()), depth)?;
+    }
+    Ok(page.cell_count().checked_sub(1).map(|last| {
+        parse_cell(page.page_type(), page.cell(last), page.usable_size)
+            .rowid
+            .unwrap_or(0)
+    }))
+}
+
+pub fn table_contains(db: &mut Database, root_page: u32, rowid: i64) -> Result<bool> {
+    Ok(table_seek(db, root_page, rowid)?.found)
+}
+
+// The payload of the row with the given rowid.
+pub fn table_get(db: &mut Database, root_page: u32, rowid: i64) -> Result<Option<Vec<u8>>> {
+    let seek = table_seek(db, root_page, rowid)?;
+    match seek.found {
+        true => Ok(Some(read_payload(
+            db,
+            seek.page.page_type(),
+            seek.page.cell(seek.index),
+        )?)),
+        false => Ok(None),
+    }
+}
+
+// Insert a row, replacing the existing row with the same rowid if there is one.
+pub fn table_insert(db: &mut Database, root_page: u32, rowid: i64, payload: &[u8]) -> Result<()> {
+    let SeekResult {
+        path,
+        mut page,
+        index,
+        found,
+    } = table_seek(db, root_page, rowid)?;
+    if found {
+        let old_cell = page.cell(index).to_vec();
+        free_overflow_pages(db, page.page_type(), &old_cell)?;
+        page.remove_cell(index);
+    }
+    let cell = build_cell(db, PageType::TableLeaf, None, Some(rowid), payload)?;
+    insert_into_leaf(db, path, page, index, cell)
+}
+
+pub fn table_delete(db: &mut Database, root_page: u32, rowid: i64) -> Result<bool> {
+    let SeekResult {
+        path,
+        mut page,
+        index,
+        found,
+    } = table_seek(db, root_page, rowid)?;
+    if !found {
+        return Ok(false);
+    }
+    let cell = page.cell(index).to_vec();
+    free_overflow_pages(db, page.page_type(), &cell)?;
+    page.remove_cell(index);
+    store_after_removal(db, path, page)?;
+    Ok(true)
+}
+
+fn insert_into_leaf(
+    db: &mut Database,
+    path: Vec<(u32, usize)>,
+    mut page: BTreePage,
+    index: usize,
+    cell: Vec<u8>,
+) -> Result<()> {
+    if page.insert_cell(index, &cell) {
+        return page.store(db);
+    }
+    let mut node = page.to_node();
+    node.cells.insert(index, cell);
+    balance(db, path, node)
+}
+
+fn store_after_removal(
+    db: &mut Database,
+    path: Vec<(u32, usize)>,
+    mut page: BTreePage,
+) -> Result<()> {
+    if path.is_empty() || !page.is_underfull() {
+        return page.store(db);
+    }
+    balance(db, path, page.to_node())
+}
+
+// Allocate the root page of a new, empty b-tree.
+pub fn create_tree(db: &mut Database, page_type: PageType) -> Result<u32> {
+    let root_page = if db.file_header.is_auto_vacuum() {
+        autovacuum::allocate_root(db)?
+    } else {
+        db.allocate_page()?
+    };
+    Node {
+        page_num: root_page,
+        page_type: leaf_type(page_type),
+        cells: Vec::new(),
+        rightmost_pointer: None,
+    }
+    .store(db)?;
+    Ok(root_page)
+}
+
+/*
+    Fill an empty index b-tree with keys that are already sorted. All the cells are put into
+    the root at once and balancing spreads them over as many leaves and levels as needed,
+    which is much cheaper than inserting the keys one by one.
+*/
+pub fn index_build(db: &mut Database, root_page: u32, keys: &[Vec<u8>]) -> Result<()> {
+    let mut cells = Vec::with_capacity(keys.len());
+    for key in keys {
+        cells.push(build_cell(db, PageType::IndexLeaf, None, None, key)?);
+    }
+    build_tree(db, root_page, PageType::IndexLeaf, cells)
+}
+
+// The same for a table b-tree, from rows sorted by rowid.
+pub fn table_build(db: &mut Database, root_page: u32, rows: &[(i64, Vec<u8>)]) -> Result<()> {
+    let mut cells = Vec::with_capacity(rows.len());
+    for (rowid, payload) in rows {
+        cells.push(build_cell(
+            db,
+            PageType::TableLeaf,
+            None,
+            Some(*rowid),
+            payload,
+        )?);
+    }
+    build_tree(db, root_page, PageType::TableLeaf, cells)
+}
+
+fn build_tree(
+    db: &mut Database,
+    root_page: u32,
+    page_type: PageType,
+    cells: Vec<Vec<u8>>,
+) -> Result<()> {
+    balance_root(
+        db,
+        Node {
+            page_num: root_page,
+            page_type,
+            cells,
+            rightmost_pointer: None,
+        },
+    )
+}
+
+// Interior cells of an index b-tree hold keys as well, between those of their children.
+pub fn index_scan(db: &mut Database, root_page: u32) -> Result<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    collect_index_keys(db, root_page, 0, &mut keys)?;
+    Ok(keys)
+}
+
+fn collect_index_keys(
+    db: &mut Database,
+    page_num: u32,
+    depth: usize,
+    keys: &mut Vec<Vec<u8>>,
+) -> Result<()> {
+    let page = load_at_depth(db, page_num, depth)?;
+    for i in 0..page.cell_count() {
+        if !page.page_type().is_leaf() {
+            collect_index_keys(db, page.child_page(i), depth + 1, keys)?;
+        }
+        keys.push(read_payload(db, page.page_type(), page.cell(i))?);
+    }
+    if !page.page_type().is_leaf() {
+        collect_index_keys(db, page.child_page(page.cell_count()), depth + 1, keys)?;
+    }
+    Ok(())
+}
+
+pub fn root_type(db: &mut Database, root_page: u32) -> Result<PageType> {
+    Ok(BTreePage::load(db, root_page)?.page_type())
+}
+
+// Free every page of a b-tree except the root, which is left as an empty leaf.
+pub fn clear_tree(db: &mut Database, root_page: u32) -> Result<()> {
+    let page_type = BTreePage::load(db, root_page)?.page_type();
+    free_subtree(db, root_page, false)?;
+    Node {
+        page_num: root_page,
+        page_type: leaf_type(page_type),
+        cells: Vec::new(),
+        rightmost_pointer: None,
+    }
+    .store(db)
+}
+
+pub fn free_subtree(db: &mut Database, page_num: u32, free_self: bool) -> Result<()> {
+    free_pages_below(db, page_num, 0, free_self)
+}
+
+fn free_pages_below(db: &mut Database, page_num: u32, depth: usize, free_self: bool) -> Result<()> {
+    let page = load_at_depth(db, page_num, depth)?;
+    for cell in page.cells() {
+        free_overflow_pages(db, page.page_type(), &cell)?;
+    }
+    if !page.page_type().is_leaf() {
+        for i in 0..=page.cell_count() {
+            free_pages_below(db, page.child_page(i), depth + 1, true)?;
+        }
+    }
+    if free_self {
+        db.free_page(page_num)?;
+    }
+    Ok(())
+}
+
+/*
+    Index b-trees are keyed by the whole record (the indexed columns followed by the rowid). Unlike
+    table b-trees, interior cells carry real entries: every entry in the subtree of a left child
+    sorts before the cell, and every entry after it sorts after the cell.
+*/
+pub type KeyComparator<'a> = &'a dyn Fn(&[u8], &[u8]) -> Result<Ordering>;
+
+// Descend to the entry equal to key, or to the leaf position where it would be inserted.
+// With left_on_equal set the search passes equal interior entries on their left side, which
+// leads to the leaf holding the in-order predecessor of that entry.
+fn index_seek(
+    db: &mut Database,
+    root_page: u32,
+    key: &[u8],
+    compare: KeyComparator,
+    left_on_equal: bool,
+) -> Result<SeekResult> {
+    let mut path = Vec::new();
+    let mut page = BTreePage::load(db, root_page)?;
+    loop {
+        let mut index = page.cell_count();
+        let mut found = false;
+        for i in 0..page.cell_count() {
+            let payload = read_payload(db, page.page_type(), page.cell(i))?;
+            match compare(key, &payload)? {
+                Ordering::Less => {
+                    index = i;
+                    break;
+                }
+                Ordering::Equal => {
+                    index = i;
+                    found = !left_on_equal || page.page_type().is_leaf();
+                    break;
+                }
+                Ordering::Greater => {}
+            }
+        }
+        if found || page.page_type().is_leaf() {
+            return Ok(SeekResult {
+                path,
+                page,
+                index,
+                found,
+            });
+        }
+        let child = page.child_page(index);
+        path.push((page.page_num, index));
+        page = load_at_depth(db, child, path.len())?;
+    }
+}
+
+/*
+    An entry equal to the key. With a comparator ignoring the rowid, the key can be just the
+    indexed columns, to find the entry holding them whatever its rowid.
+*/
+pub fn index_find(
+    db: &mut Database,
+    root_page: u32,
+    key: &[u8],
+    compare: KeyComparator,
+) -> Result<Option<Vec<u8>>> {
+    let seek = index_seek(db, root_page, key, compare, false)?;
+    match seek.found {
+        true => Ok(Some(read_payload(
+            db,
+            seek.page.page_type(),
+            seek.page.cell(seek.index),
+        )?)),
+        false => Ok(None),
+    }
+}
+
+pub fn index_insert(
+    db: &mut Database,
+    root_page: u32,
+    key: &[u8],
+    compare: KeyComparator,
+) -> Result<()> {
+    let SeekResult {
+        path,
+        page,
+        index,
+        found,
+    } = index_seek(db, root_page, key, compare, false)?;
+    if found {
+        return Ok(());
+    }
+    let cell = build_cell(db, PageType::IndexLeaf, None, None, key)?;
+    insert_into_leaf(db, path, page, index, cell)
+}
+
+pub fn index_delete(
+    db: &mut Database,
+    root_page: u32,
+    key: &[u8],
+    compare: KeyComparator,
+) -> Result<bool> {
+    let SeekResult {
+        path,
+        mut page,
+        index,
+        found,
+    } = index_seek(db, root_page, key, compare, false)?;
+    if !found {
+        return Ok(false);
+    }
+    let cell = page.cell(index).to_vec();
+    free_overflow_pages(db, page.page_type(), &cell)?;
+    if page.page_type().is_leaf() {
+        page.remove_cell(index);
+        store_after_removal(db, path, page)?;
+        return Ok(true);
+    }
+
+    // The entry lives in an interior page: replace it by its in-order predecessor, which is the
+    // last entry of the right-most leaf in the left subtree.
+    let mut leaf = BTreePage::load(db, left_child(&cell))?;
+    let mut depth = 1;
+    while !leaf.page_type().is_leaf() {
+        depth += 1;
+        leaf = load_at_depth(db, leaf.child_page(leaf.cell_count()), depth)?;
+    }
+    // Only the root can be an empty leaf.
+    let Some(last) = leaf.cell_count().checked_sub(1) else {
+        return Err(MyError::CorruptPage(leaf.page_num));
+    };
+    let predecessor = leaf.cell(last).to_vec();
+    let predecessor_key = read_payload(db, PageType::IndexLeaf, &predecessor)?;
+    leaf.remove_cell(last);
+    let leaf_underfull = leaf.is_underfull();
+    leaf.store(db)?;
+
+    let mut replacement = cell[..4].to_vec();
+    replacement.extend_from_slice(&predecessor);
+    page.remove_cell(index);
+    if page.insert_cell(index, &replacement) {
+        page.store(db)?;
+    } else {
+        let mut node = page.to_node();
+        node.cells.insert(index, replacement);
+        balance(db, path, node)?;
+    }
+
+    if leaf_underfull {
+        let SeekResult { path, page, .. } =
+            index_seek(db, root_page, &predecessor_key, compare, true)?;
+        if !path.is_empty() {
+            balance(db, path, page.to_node())?;
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::TextEncoding;
+    use crate::parser::TransactionMode;
+
+    // A table b-tree in a new database in memory, left in a transaction that is never committed.
+    fn table(rows: &[(i64, Vec<u8>)]) -> (Database, u32) {
+        let mut db = Database::memory(TextEncoding::Utf8).unwrap();
+        db.begin(TransactionMode::Immediate).unwrap();
+        let root = create_tree(&mut db, PageType::TableLeaf).unwrap();
+        for (rowid, payload) in rows {
+            table_insert(&mut db, root, *rowid, payload).unwrap();
+        }
+        (db, root)
+    }
+
+    // Overwrite bytes of a page, then load it again.
+    fn patch(db: &mut Database, page_num: u32, offset: usize, bytes: &[u8]) -> Result<BTreePage> {
+        let mut data = db.load_page(page_num).unwrap().into_vec();
+        data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        db.store_page(page_num, &data).unwrap();
+        BTreePage::load(db, page_num)
+    }
+
+    fn rows(db: &mut Database, root: u32) -> Vec<(i64, Vec<u8>)> {
+        let mut cursor = TableCursor::from(db, root).unwrap();
+        let mut rows = Vec::new();
+        while let Some((rowid, payload)) = cursor.next(db).unwrap() {
+            rows.push((rowid, payload.to_vec()));
+        }
+        rows
+    }
+
+    #[test]
+    fn local_payload_size_spills_like_sqlite() {
+        // 4096 usable bytes: a table leaf keeps up to 4061 bytes, 489 at least once it spills.
+        assert_eq!(local_payload_size(PageType::TableLeaf, 4061, 4096), 4061);
+        assert_eq!(local_payload_size(PageType::TableLeaf, 4062, 4096), 489);
+        assert_eq!(local_payload_size(PageType::TableLeaf, 10000, 4096), 1816);
+        assert_eq!(local_payload_size(PageType::IndexLeaf, 1002, 4096), 1002);
+        assert_eq!(local_payload_size(PageType::IndexLeaf, 1003, 4096), 489);
+        // 32 reserved bytes leave 4064 usable ones.
+        assert_eq!(local_payload_size(PageType::TableLeaf, 4029, 4064), 4029);
+        assert_eq!(local_payload_size(PageType::TableLeaf, 4030, 4064), 485);
+    }
+
+    #[test]
+    fn cursor_reads_overflow_chains() {
+        let long: Vec<u8> = (0..10000).map(|i| (i % 251) as u8).collect();
+        let expected = vec![(1, b"short".to_vec()), (2, long.clone()), (3, long)];
+        let (mut db, root) = table(&expected);
+        assert_eq!(rows(&mut db, root), expected);
+    }
+
+    #[test]
+    fn cursor_walks_interior_pages_in_rowid_order() {
+        let expected: Vec<(i64, Vec<u8>)> = (1..=500).map(|i| (i, vec![i as u8; 100])).collect();
+        let (mut db, root) = table(&expected);
+        assert!(
+            !BTreePage::load(&mut db, root)
+                .unwrap()
+                .page_type()
+                .is_leaf()
+        );
+        assert_eq!(rows(&mut db, root), expected);
+    }
+
+    // Three rows with the middle one deleted, which leaves a freeblock between the others.
+    fn with_freeblock() -> (Database, u32, usize) {
+        let (mut db, root) = table(&[(1, vec![1; 20]), (2, vec![2; 20]), (3, vec![3; 20])]);
+        table_delete(&mut db, root, 2).unwrap();
+        let page = BTreePage::load(&mut db, root).unwrap();
+        (db, root, page.page_header.first_freeblock as usize)
+    }
+
+    #[test]
+    fn corrupt_freeblocks_are_rejected() {
+        let (_, _, first) = with_freeblock();
+        assert_ne!(first, 0);
+        let at = |offset: usize| (offset as u16).to_be_bytes().to_vec();
+        let corruptions = [
+            // Over the cell pointer array.
+            (1, at(8)),
+            // Too small to hold a freeblock, or running past the end of the page.
+            (first + 2, at(2)),
+            (first + 2, at(4096)),
+            // The next block inside this one, or before it.
+            (first, at(first + 1)),
+            (first, at(first - 4)),
+            // More fragmented bytes than SQLite ever leaves.
+            (7, vec![61]),
+        ];
+        for (offset, bytes) in corruptions {
+            let (mut db, root, _) = with_freeblock();
+            let loaded = patch(&mut db, root, offset, &bytes);
+            assert!(
+                matches!(loaded, Err(MyError::CorruptPage(page)) if page == root),
+                "{offset}: {bytes:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn allocation_leaves_no_more_than_60_fragmented_bytes() {
+        // The deleted row left a 22 byte freeblock, a 20 byte cell has 2 bytes to spare in it.
+        for (fragmented, expected, block_used) in [(57, 59, true), (58, 58, false)] {
+            let (mut db, root, first) = with_freeblock();
+            let mut page = patch(&mut db, root, 7, &[fragmented]).unwrap();
+            assert!(page.insert_cell(1, &[0; 20]));
+            assert_eq!(page.page_header.fragmented_bytes_count, expected);
+            assert_eq!(page.cell_offset(1) == first, block_used);
+        }
+    }
+
+    #[test]
+    fn looping_overflow_chains_and_empty_leaves_are_corrupt() {
+        // An overflow chain whose first page points back to itself.
+        let (mut db, root) = table(&[(1, vec![7; 10000])]);
+        let cell = BTreePage::load(&mut db, root).unwrap().cell(0).to_vec();
+        let first = parse_cell(PageType::TableLeaf, &cell, 4096)
+            .overflow_page
+            .unwrap();
+        let mut data = db.load_page(first).unwrap().into_vec();
+        data[..4].copy_from_slice(&first.to_be_bytes());
+        db.store_page(first, &data).unwrap();
+        // With a trunk on the freelist the freed page joins it as a leaf and keeps its content.
+        let spare = db.allocate_page().unwrap();
+        db.free_page(spare).unwrap();
+        assert!(matches!(
+            free_overflow_pages(&mut db, PageType::TableLeaf, &cell),
+            Err(MyError::CorruptPage(_))
+        ));
+
+        // The leaf holding the predecessor of an interior entry has lost its cells.
+        let compare = |a: &[u8], b: &[u8]| Ok(a.cmp(b));
+        let (mut db, _) = table(&[]);
+        let root = create_tree(&mut db, PageType::IndexLeaf).unwrap();
+        for i in 0..200u32 {
+            let mut key = i.to_be_bytes().to_vec();
+            key.resize(100, 0);
+            index_insert(&mut db, root, &key, &compare).unwrap();
+        }
+        let page = BTreePage::load(&mut db, root).unwrap();
+        assert!(!page.page_type().is_leaf());
+        let key = read_payload(&mut db, PageType::IndexInterior, page.cell(0)).unwrap();
+        let leaf = page.child_page(0);
+        assert!(
+            BTreePage::load(&mut db, leaf)
+                .unwrap()
+                .page_type()
+                .is_leaf()
+        );
+        patch(&mut db, leaf, 3, &[0, 0]).unwrap();
+        assert!(matches!(
+            index_delete(&mut db, root, &key, &compare),
+            Err(MyError::CorruptPage(page)) if page == leaf
+        ));
+    }
+
+    #[test]
+    fn unchecked_cells_stay_in_the_page() {
+        let (mut db, root) = table(&[(1, vec![1; 20])]);
+        // The only cell pointer, moved to the last byte of the page.
+        assert!(patch(&mut db, root, 8, &4095u16.to_be_bytes()).is_err());
+        let page = BTreePage::load_unchecked(&mut db, root).unwrap();
+        assert_eq!(page.cell(0).len(), 1);
+    }
+}
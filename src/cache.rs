@@ -0,0 +1,139 @@
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+/*
+    Pages read from the file or the log are kept in memory, so that walking a b-tree again does
+    not go back to the disk. A cached page is shared, whoever loads it gets another reference
+    to the same buffer and only copies it to change it.
+
+    When the cache is full, the least recently used page nobody else holds a reference to is
+    evicted. Pages still in use stay, the cache may then hold more pages than its capacity
+    until they are released.
+
+    The size is set with PRAGMA cache_size: a positive value is a number of pages, a negative
+    one a number of KiB. It starts out as the default cache size of the header (offset 48),
+    or -2000 when that is zero, like in SQLite.
+*/
+#[derive(Debug)]
+pub struct PageCache {
+    capacity: usize,
+    pages: HashMap<u32, (Rc<Vec<u8>>, u64)>,
+    // Page numbers by the time of their last use, the least recently used first.
+    recency: BTreeMap<u64, u32>,
+    clock: u64,
+    version: Option<Version>,
+}
+
+/*
+    The state of the database the cached pages were read from: the file change counter in
+    rollback mode, the snapshot of the log in WAL mode. Once another connection commits, the
+    version differs and every cached page is dropped.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Version {
+    File(u32),
+    Log { salt: (u32, u32), max_frame: u32 },
+}
+
+impl PageCache {
+    pub const DEFAULT_SIZE: i64 = -2000;
+
+    pub fn from(capacity: usize) -> Self {
+        Self {
+            capacity,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+            version: None,
+        }
+    }
+
+    // The number of pages a cache_size value allows for.
+    pub fn capacity_for(cache_size: i64, page_size: usize) -> usize {
+        if cache_size >= 0 {
+            cache_size as usize
+        } else {
+            (cache_size.unsigned_abs() as usize * 1024) / page_size
+        }
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn get(&mut self, page_num: u32) -> Option<Rc<Vec<u8>>> {
+        self.clock += 1;
+        let (data, used) = self.pages.get_mut(&page_num)?;
+        self.recency.remove(used);
+        *used = self.clock;
+        self.recency.insert(self.clock, page_num);
+        Some(Rc::clone(data))
+    }
+
+    pub fn insert(&mut self, page_num: u32, data: Rc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.pages.insert(page_num, (data, self.clock)) {
+            self.recency.remove(&used);
+        }
+        self.recency.insert(self.clock, page_num);
+        self.evict();
+    }
+
+    pub fn remove(&mut self, page_num: u32) {
+        if let Some((_, used)) = self.pages.remove(&page_num) {
+            self.recency.remove(&used);
+        }
+    }
+
+    // Drop the pages beyond the end of a database that shrank.
+    pub fn truncate(&mut self, page_count: u32) {
+        let beyond: Vec<u32> = self
+            .pages
+            .keys()
+            .copied()
+            .filter(|page_num| *page_num > page_count)
+            .collect();
+        for page_num in beyond {
+            self.remove(page_num);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.recency.clear();
+        self.version = None;
+    }
+
+    // Keep the cached pages only if they were read from the same version of the database.
+    pub fn validate(&mut self, version: Version) {
+        if self.version != Some(version) {
+            self.clear();
+            self.version = Some(version);
+        }
+    }
+
+    // A commit of this connection moves the cache on to the version it created, the cached
+    // pages it did not change are still up to date.
+    pub fn advance(&mut self, version: Version) {
+        self.version = Some(version);
+    }
+
+    fn evict(&mut self) {
+        while self.pages.len() > self.capacity {
+            let unused = self
+                .recency
+                .iter()
+                .find(|(_, page_num)| Rc::strong_count(&self.pages[*page_num].0) == 1)
+                .map(|(used, page_num)| (*used, *page_num));
+            let Some((used, page_num)) = unused else {
+                return;
+            };
+            self.recency.remove(&used);
+            self.pages.remove(&page_num);
+        }
+    }
+}
@@ -1,23 +1,216 @@
-use crate::page::FileHeader;
-use crate::page_scanner::PageScanner;
-use std::fs::File;
+use crate::autovacuum::{self, AutoVacuum, PtrmapEntry, PtrmapType};
+use crate::btree;
+use crate::cache::{PageCache, Version};
+use crate::journal::Journal;
+use crate::lock::{self, DatabaseLock, LockLevel};
+use crate::mmap::{MappedFile, PageData};
+use crate::page::{FileHeader, MyError, Result, TextEncoding};
+use crate::parser::TransactionMode;
+use crate::record::RecordView;
+use crate::table::{IndexSchema, SchemaEntry, TableSchema};
+use crate::vfs::{MemoryVfs, OpenMode, OsVfs, Vfs, VfsFile};
+use crate::wal::{CheckpointMode, Wal};
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
 
 #[derive(Debug)]
 pub struct Database {
     pub file_header: FileHeader,
-    // This member actually need to be thread safe mutable
-    pub page_scanner: PageScanner,
+    // Every file of the database is opened through the VFS.
+    vfs: Rc<dyn Vfs>,
+    db_file: Box<dyn VfsFile>,
+    db_path: String,
+    read_only: bool,
+    // Modified pages stay in memory until the transaction commits.
+    dirty_pages: BTreeMap<u32, Vec<u8>>,
+    // Committed pages read before, shared with whoever loaded them.
+    cache: PageCache,
+    // The value of PRAGMA cache_size the cache was sized for.
+    cache_size: i64,
+    // How much of the file PRAGMA mmap_size allows to map, and the current map.
+    mmap_size: u64,
+    map: Option<Rc<MappedFile>>,
+    transaction: Option<Transaction>,
+    // Present when the file header says the database is in WAL mode.
+    wal: Option<Wal>,
+    // The lock held on the database file, always at least SHARED in WAL mode.
+    lock: DatabaseLock,
+    // An auto_vacuum setting that only takes effect with the next VACUUM.
+    pub pending_auto_vacuum: Option<AutoVacuum>,
+    // The extension owning the reserved bytes at the end of each page, if any.
+    reserved_space: Option<Box<dyn ReservedSpace>>,
+    schema: Option<Schema>,
+}
+
+/*
+    The schema as read at the schema cookie, and the tables parsed from it so far. Statements
+    look up their tables here rather than in sqlite_schema until the schema changes: a change
+    of the cookie, by this connection or another one, or a rollback, parses it again.
+*/
+#[derive(Debug)]
+struct Schema {
+    cookie: u32,
+    entries: Vec<SchemaEntry>,
+    tables: HashMap<String, TableSchema>,
+}
+
+/*
+    The reserved bytes at the end of every page (header offset 20) are not used by the database
+    itself, extensions keep per-page data there, such as a checksum or an encryption nonce.
+    An extension fills them in for every page a transaction writes, once the page content is
+    final, and checks them for every page read from the file or the log. A page failing the
+    check reads as corrupt.
+*/
+pub trait ReservedSpace: std::fmt::Debug {
+    fn fill(&self, page_num: u32, content: &[u8], reserved: &mut [u8]);
+    fn verify(&self, page_num: u32, content: &[u8], reserved: &[u8]) -> bool;
+}
+
+#[derive(Debug)]
+struct Transaction {
+    // The header as it was when the transaction started, restored by ROLLBACK.
+    original_header: FileHeader,
+    savepoints: Vec<Savepoint>,
+    // A transaction started by SAVEPOINT rather than BEGIN commits when that savepoint is released.
+    opened_by_savepoint: bool,
+}
+
+/*
+    A savepoint keeps the content every page had before its first change after the savepoint,
+    None for pages which did not exist yet. Savepoints without a name wrap single statements
+    so that a failing statement can be undone without aborting the whole transaction.
+*/
+#[derive(Debug)]
+struct Savepoint {
+    name: Option<String>,
+    file_header: FileHeader,
+    pages: HashMap<u32, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    fn find_savepoint(&self, name: Option<&str>) -> Result<usize> {
+        self.savepoints
+            .iter()
+            .rposition(|sp| match (sp.name.as_deref(), name) {
+                (Some(a), Some(b)) => a.eq_ignore_ascii_case(b),
+                (a, b) => a == b,
+            })
+            .ok_or_else(|| MyError::NoSuchSavepoint(name.unwrap_or_default().to_string()))
+    }
 }
 
 impl Database {
-    pub fn from(db_path: String) -> Self {
-        let mut db_file = File::open(db_path).unwrap();
-        let file_header = FileHeader::from(&mut db_file).unwrap();
-        let page_size = file_header.page_size as u64;
-        Self {
+    const WAL_AUTOCHECKPOINT: u32 = 1000;
+
+    // The name that stands for a database in memory rather than a file.
+    pub const MEMORY: &str = ":memory:";
+
+    pub fn from(db_path: String) -> Result<Self> {
+        Self::from_vfs(Rc::new(OsVfs), db_path)
+    }
+
+    pub fn from_vfs(vfs: Rc<dyn Vfs>, db_path: String) -> Result<Self> {
+        let (db_file, read_only) = match vfs.open(&db_path, OpenMode::ReadWrite) {
+            Ok(file) => (file, false),
+            Err(_) => (vfs.open(&db_path, OpenMode::ReadOnly)?, true),
+        };
+        let file_header = FileHeader::from(db_file.as_ref())?;
+        let cache_size = match file_header.default_cache_size {
+            0 => PageCache::DEFAULT_SIZE,
+            size => size as i64,
+        };
+        let capacity = PageCache::capacity_for(cache_size, file_header.page_size as usize);
+        let mut database = Self {
             file_header,
-            page_scanner: PageScanner::from(db_file, 1, page_size),
+            vfs,
+            db_file,
+            db_path,
+            read_only,
+            dirty_pages: BTreeMap::new(),
+            cache: PageCache::from(capacity),
+            cache_size,
+            mmap_size: 0,
+            map: None,
+            transaction: None,
+            wal: None,
+            lock: DatabaseLock::default(),
+            pending_auto_vacuum: None,
+            reserved_space: None,
+            schema: None,
+        };
+        database.begin_read()?;
+        database.end_read()?;
+        Ok(database)
+    }
+
+    /*
+        Open a database, creating it when the file does not exist or is empty. A new database
+        starts out with an empty schema in the given text encoding.
+    */
+    pub fn create(vfs: Rc<dyn Vfs>, db_path: String, encoding: TextEncoding) -> Result<Self> {
+        let file = vfs.open(&db_path, OpenMode::Create)?;
+        if file.file_size()? == 0 {
+            file.write_at(&FileHeader::empty_database(encoding), 0)?;
+            file.sync()?;
+        }
+        drop(file);
+        Self::from_vfs(vfs, db_path)
+    }
+
+    // A new database which only lives in memory, and is gone once the connection closes.
+    pub fn memory(encoding: TextEncoding) -> Result<Self> {
+        Self::create(
+            Rc::new(MemoryVfs::new()),
+            Self::MEMORY.to_string(),
+            encoding,
+        )
+    }
+
+    pub fn path(&self) -> &str {
+        &self.db_path
+    }
+
+    pub fn file_id(&self) -> Option<(u64, u64)> {
+        self.vfs.file_id(&self.db_path)
+    }
+
+    pub fn is_memory(&self) -> bool {
+        self.db_path == Self::MEMORY
+    }
+
+    // The binary ships no extension, only the tests install one.
+    #[cfg(test)]
+    pub fn set_reserved_space(&mut self, extension: Box<dyn ReservedSpace>) {
+        self.reserved_space = Some(extension);
+    }
+
+    pub fn cache_size(&self) -> i64 {
+        self.cache_size
+    }
+
+    pub fn set_cache_size(&mut self, cache_size: i64) {
+        self.cache_size = cache_size;
+        let page_size = self.file_header.page_size as usize;
+        self.cache
+            .set_capacity(PageCache::capacity_for(cache_size, page_size));
+    }
+
+    pub fn mmap_size(&self) -> u64 {
+        self.mmap_size
+    }
+
+    pub fn set_mmap_size(&mut self, mmap_size: u64) -> Result<()> {
+        self.mmap_size = mmap_size;
+        self.remap()
+    }
+
+    // Map as much of the file as mmap_size allows, again whenever the size of the file changed.
+    fn remap(&mut self) -> Result<()> {
+        let len = self.db_file.file_size()?.min(self.mmap_size) as usize;
+        if self.map.as_ref().map_or(0, |map| map.len()) != len {
+            self.map = self.db_file.map(len)?.map(Rc::new);
         }
+        Ok(())
     }
 
     pub fn get_page_size(&self) -> u16 {
@@ -28,6 +221,17 @@ impl Database {
         self.file_header.page_count
     }
 
+    pub fn auto_vacuum(&self) -> AutoVacuum {
+        match (
+            self.file_header.is_auto_vacuum(),
+            self.file_header.incremental_vacuum,
+        ) {
+            (false, _) => AutoVacuum::None,
+            (true, false) => AutoVacuum::Full,
+            (true, true) => AutoVacuum::Incremental,
+        }
+    }
+
     /*
         Every SQLite database contains a single "schema table" that stores the schema for that database.
         The schema for a database is a description of all of the other tables, indexes, triggers, and
@@ -44,11 +248,980 @@ impl Database {
         we can parse this table to get all the table name and schema. This table is in the first page of
         every Sqlite database file.
     */
-    pub fn get_table_names(&mut self) -> Vec<String> {
-        let mut result: Vec<String> = Vec::new();
-        while let Some(record) = self.page_scanner.get_next_record() {
-            result.push(record.columns[1].value());
+    pub fn get_table_names(&mut self) -> Result<Vec<String>> {
+        Ok(self.get_schema()?.into_iter().map(|e| e.name).collect())
+    }
+
+    // The schema b-tree may span several pages, which are read through the WAL when there is one.
+    pub fn get_schema(&mut self) -> Result<Vec<SchemaEntry>> {
+        Ok(self.schema()?.entries.clone())
+    }
+
+    fn schema(&mut self) -> Result<&mut Schema> {
+        self.begin_read()?;
+        let cookie = self.file_header.schema_cookie;
+        match self.schema.take() {
+            Some(schema) if schema.cookie == cookie => Ok(self.schema.insert(schema)),
+            _ => {
+                let entries = self.schema_rows()?.into_iter().map(|(_, e)| e).collect();
+                Ok(self.schema.insert(Schema {
+                    cookie,
+                    entries,
+                    tables: HashMap::new(),
+                }))
+            }
+        }
+    }
+
+    fn schema_rows(&mut self) -> Result<Vec<(i64, SchemaEntry)>> {
+        let mut entries = Vec::new();
+        let encoding = self.file_header.text_encoding;
+        let mut cursor = btree::TableCursor::from(self, 1)?;
+        while let Some((rowid, payload)) = cursor.next(self)? {
+            entries.push((
+                rowid,
+                SchemaEntry::from(&RecordView::from(payload, encoding)?.values()?),
+            ));
+        }
+        Ok(entries)
+    }
+
+    /*
+        Every change to sqlite_schema increments the schema cookie, which tells other
+        connections that the schema they have parsed is out of date.
+    */
+    pub fn add_schema_entry(&mut self, entry: &SchemaEntry) -> Result<()> {
+        let rowid = self
+            .schema_rows()?
+            .iter()
+            .map(|(rowid, _)| *rowid)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        let payload = entry.encode(self.file_header.text_encoding);
+        btree::table_insert(self, 1, rowid, &payload)?;
+        self.schema = None;
+        self.file_header.schema_cookie = self.file_header.schema_cookie.wrapping_add(1);
+        Ok(())
+    }
+
+    // Remove an object from sqlite_schema and free every page of its b-tree.
+    pub fn drop_schema_entry(&mut self, dropped: &SchemaEntry) -> Result<()> {
+        for (rowid, entry) in self.schema_rows()? {
+            if entry.entry_type == dropped.entry_type
+                && entry.name.eq_ignore_ascii_case(&dropped.name)
+            {
+                if entry.root_page != 0 {
+                    btree::free_subtree(self, entry.root_page, true)?;
+                    if self.file_header.is_auto_vacuum() {
+                        autovacuum::release_root(self, entry.root_page)?;
+                    }
+                }
+                btree::table_delete(self, 1, rowid)?;
+            }
+        }
+        self.schema = None;
+        self.file_header.schema_cookie = self.file_header.schema_cookie.wrapping_add(1);
+        Ok(())
+    }
+
+    // Rewrite the sqlite_schema rows changed by `edit`, which counts as one schema change.
+    pub fn update_schema<F>(&mut self, mut edit: F) -> Result<()>
+    where
+        F: FnMut(&mut SchemaEntry),
+    {
+        let mut changed = false;
+        for (rowid, entry) in self.schema_rows()? {
+            let mut updated = entry.clone();
+            edit(&mut updated);
+            if updated != entry {
+                let payload = updated.encode(self.file_header.text_encoding);
+                btree::table_insert(self, 1, rowid, &payload)?;
+                changed = true;
+            }
+        }
+        if changed {
+            // Auto-vacuum keeps the cookie when it only moves root pages, so it is not enough.
+            self.schema = None;
+            self.file_header.schema_cookie = self.file_header.schema_cookie.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    pub fn get_table(&mut self, table_name: &str) -> Result<TableSchema> {
+        if ["sqlite_schema", "sqlite_master"]
+            .iter()
+            .chain(&TableSchema::TEMP_SCHEMA_NAMES)
+            .any(|name| name.eq_ignore_ascii_case(table_name))
+        {
+            return TableSchema::from(
+                TableSchema::SCHEMA_TABLE_SQL,
+                1,
+                self.file_header.text_encoding,
+            )
+            .ok_or_else(|| MyError::NoSuchTable(table_name.to_string()));
+        }
+        let encoding = self.file_header.text_encoding;
+        let schema = self.schema()?;
+        let key = table_name.to_lowercase();
+        if let Some(table) = schema.tables.get(&key) {
+            return Ok(table.clone());
+        }
+        let entry = schema
+            .entries
+            .iter()
+            .find(|e| e.entry_type == "table" && e.name.eq_ignore_ascii_case(table_name))
+            .ok_or_else(|| MyError::NoSuchTable(table_name.to_string()))?;
+        let table = TableSchema::from_entry(entry, encoding).ok_or_else(|| {
+            MyError::Schema(format!("malformed database schema ({})", entry.name))
+        })?;
+        schema.tables.insert(key, table.clone());
+        Ok(table)
+    }
+
+    pub fn get_indexes(&mut self, table: &TableSchema) -> Result<Vec<IndexSchema>> {
+        Ok(self
+            .get_schema()?
+            .iter()
+            .filter(|e| {
+                e.entry_type == "index" && e.table_name.eq_ignore_ascii_case(&table.table_name)
+            })
+            .filter_map(|e| IndexSchema::from_entry(e, table))
+            .collect())
+    }
+
+    /*
+        Pages are numbered from 1, the first page also holds the 100 bytes file header. A page
+        changed by the current transaction comes from memory, any other from the cache, the log,
+        the mapped part of the file or the file, in that order.
+    */
+    pub fn load_page(&mut self, page_num: u32) -> Result<PageData> {
+        self.begin_read()?;
+        if let Some(data) = self.dirty_pages.get(&page_num) {
+            return Ok(PageData::Owned(Rc::new(data.clone())));
+        }
+        if let Some(data) = self.cache.get(page_num) {
+            return Ok(PageData::Owned(data));
+        }
+        let data = match self.wal.as_mut() {
+            Some(wal) => wal
+                .read_page(page_num)?
+                .map(|data| PageData::Owned(Rc::new(data))),
+            None => None,
+        };
+        let data = match data {
+            Some(data) => data,
+            None => match self.mapped_page(page_num) {
+                Some(data) => data,
+                None => PageData::Owned(Rc::new(self.read_page(page_num)?)),
+            },
+        };
+        if let Some(extension) = &self.reserved_space {
+            let (content, reserved) = data.split_at(self.file_header.usable_size());
+            if !extension.verify(page_num, content, reserved) {
+                return Err(MyError::CorruptPage(page_num));
+            }
+        }
+        // The map needs no cache, the operating system already keeps those pages in memory.
+        if let PageData::Owned(data) = &data {
+            self.cache.insert(page_num, Rc::clone(data));
+        }
+        Ok(data)
+    }
+
+    fn mapped_page(&self, page_num: u32) -> Option<PageData> {
+        let map = self.map.as_ref()?;
+        let len = self.file_header.page_size as usize;
+        let offset = (page_num as usize).checked_sub(1)? * len;
+        (page_num != 0 && offset + len <= map.len()).then(|| PageData::Mapped {
+            map: Rc::clone(map),
+            offset,
+            len,
+        })
+    }
+
+    fn read_page(&mut self, page_num: u32) -> Result<Vec<u8>> {
+        if page_num == 0 {
+            return Err(MyError::CorruptPage(page_num));
+        }
+        let page_size = self.file_header.page_size as u64;
+        let mut data = vec![0; page_size as usize];
+        // A page the file is too short to hold was never written.
+        self.db_file
+            .read_exact_at(&mut data, (page_num as u64 - 1) * page_size)
+            .map_err(|e| match e {
+                MyError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    MyError::CorruptPage(page_num)
+                }
+                e => e,
+            })?;
+        Ok(data)
+    }
+
+    pub fn store_page(&mut self, page_num: u32, data: &[u8]) -> Result<()> {
+        self.begin_write()?;
+        let needs_image = self
+            .transaction
+            .as_ref()
+            .and_then(|t| t.savepoints.last())
+            .is_some_and(|sp| !sp.pages.contains_key(&page_num));
+        if needs_image {
+            let image = if page_num <= self.file_header.page_count {
+                Some(self.load_page(page_num)?.into_vec())
+            } else {
+                None
+            };
+            if let Some(sp) = self
+                .transaction
+                .as_mut()
+                .and_then(|t| t.savepoints.last_mut())
+            {
+                sp.pages.insert(page_num, image);
+            }
+        }
+        self.dirty_pages.insert(page_num, data.to_vec());
+        Ok(())
+    }
+
+    /*
+        Free pages are reused before the file grows: the last leaf of the first freelist trunk,
+        or the trunk itself once it has no leaves left, its successor becoming the first trunk.
+        New pages are appended at the end of the file.
+    */
+    pub fn allocate_page(&mut self) -> Result<u32> {
+        let empty_page = vec![0; self.file_header.page_size as usize];
+        let trunk_num = self.file_header.first_freelist_trunk_page;
+        if trunk_num == 0 {
+            return self.append_page();
+        }
+        if trunk_num > self.file_header.page_count {
+            return Err(MyError::Corrupt);
+        }
+        let mut trunk = self.load_page(trunk_num)?.into_vec();
+        let leaf_count = u32::from_be_bytes(trunk[4..8].try_into()?) as usize;
+        let page_num = if leaf_count == 0 {
+            self.file_header.first_freelist_trunk_page = u32::from_be_bytes(trunk[..4].try_into()?);
+            trunk_num
+        } else {
+            let offset = 4 + leaf_count * 4;
+            if offset + 4 > trunk.len() {
+                return Err(MyError::Corrupt);
+            }
+            let leaf = u32::from_be_bytes(trunk[offset..offset + 4].try_into()?);
+            if leaf == 0 || leaf > self.file_header.page_count {
+                return Err(MyError::Corrupt);
+            }
+            trunk[4..8].copy_from_slice(&(leaf_count as u32 - 1).to_be_bytes());
+            self.store_page(trunk_num, &trunk)?;
+            leaf
+        };
+        self.file_header.freelist_page_count =
+            self.file_header.freelist_page_count.saturating_sub(1);
+        self.store_page(page_num, &empty_page)?;
+        Ok(page_num)
+    }
+
+    /*
+        Unused pages are kept in the freelist, a linked list of trunk pages:
+            Offset  Size    Description
+            0       4       The page number of the next trunk page, or zero for the last one.
+            4       4       The number of leaf page numbers stored on this trunk.
+            8       4*N     The page numbers of the leaf pages.
+        A freed page becomes a leaf of the first trunk while there is room, and a new trunk otherwise.
+    */
+    pub fn free_page(&mut self, page_num: u32) -> Result<()> {
+        if self.file_header.is_auto_vacuum() {
+            autovacuum::write_entries(
+                self,
+                &[(page_num, PtrmapEntry::new(PtrmapType::FreePage, 0))],
+            )?;
+        }
+        let trunk_num = self.file_header.first_freelist_trunk_page;
+        // Older SQLite versions reject trunks filled beyond usable_size/4 - 8 leaves.
+        let max_leaves = self.file_header.usable_size() / 4 - 8;
+        if trunk_num != 0 {
+            let mut trunk = self.load_page(trunk_num)?.into_vec();
+            let leaf_count = u32::from_be_bytes(trunk[4..8].try_into()?) as usize;
+            if leaf_count < max_leaves {
+                let offset = 8 + leaf_count * 4;
+                trunk[offset..offset + 4].copy_from_slice(&page_num.to_be_bytes());
+                trunk[4..8].copy_from_slice(&(leaf_count as u32 + 1).to_be_bytes());
+                self.store_page(trunk_num, &trunk)?;
+                self.file_header.freelist_page_count += 1;
+                return Ok(());
+            }
+        }
+        let mut trunk = vec![0; self.file_header.page_size as usize];
+        trunk[..4].copy_from_slice(&trunk_num.to_be_bytes());
+        self.store_page(page_num, &trunk)?;
+        self.file_header.first_freelist_trunk_page = page_num;
+        self.file_header.freelist_page_count += 1;
+        Ok(())
+    }
+
+    // Header changes are written with page 1 at commit, which has to be among the dirty pages.
+    pub fn store_header(&mut self) -> Result<()> {
+        let first_page = self.load_page(1)?;
+        self.store_page(1, &first_page)
+    }
+
+    // Pointer-map pages are never handed out, the one due next is added as an empty map.
+    pub fn append_page(&mut self) -> Result<u32> {
+        let empty_page = vec![0; self.file_header.page_size as usize];
+        let mut page_num = self
+            .file_header
+            .page_count
+            .checked_add(1)
+            .ok_or(MyError::Corrupt)?;
+        if self.file_header.is_auto_vacuum()
+            && autovacuum::is_ptrmap_page(self.file_header.usable_size(), page_num)
+        {
+            self.store_page(page_num, &empty_page)?;
+            page_num = page_num.checked_add(1).ok_or(MyError::Corrupt)?;
+        }
+        self.store_page(page_num, &empty_page)?;
+        self.file_header.page_count = page_num;
+        Ok(page_num)
+    }
+
+    // Every page on the freelist, trunks included, in the order they appear.
+    pub fn freelist_pages(&mut self) -> Result<Vec<u32>> {
+        let mut pages = Vec::new();
+        let mut trunk_num = self.file_header.first_freelist_trunk_page;
+        while trunk_num != 0 {
+            if trunk_num > self.file_header.page_count
+                || pages.len() > self.file_header.page_count as usize
+            {
+                return Err(MyError::Corrupt);
+            }
+            let trunk = self.load_page(trunk_num)?;
+            let leaf_count = u32::from_be_bytes(trunk[4..8].try_into()?) as usize;
+            if 8 + leaf_count * 4 > trunk.len() {
+                return Err(MyError::Corrupt);
+            }
+            pages.push(trunk_num);
+            for i in 0..leaf_count {
+                let offset = 8 + i * 4;
+                let leaf = u32::from_be_bytes(trunk[offset..offset + 4].try_into()?);
+                if leaf == 0 || leaf > self.file_header.page_count {
+                    return Err(MyError::Corrupt);
+                }
+                pages.push(leaf);
+            }
+            trunk_num = u32::from_be_bytes(trunk[..4].try_into()?);
+        }
+        Ok(pages)
+    }
+
+    pub fn clear_freelist(&mut self) {
+        self.file_header.first_freelist_trunk_page = 0;
+        self.file_header.freelist_page_count = 0;
+    }
+
+    // Take a particular page off the freelist, false when it is not free.
+    pub fn take_free_page(&mut self, page_num: u32) -> Result<bool> {
+        let pages = self.freelist_pages()?;
+        if !pages.contains(&page_num) {
+            return Ok(false);
+        }
+        self.clear_freelist();
+        for free in pages.into_iter().filter(|p| *p != page_num) {
+            self.free_page(free)?;
+        }
+        self.store_page(page_num, &vec![0; self.file_header.page_size as usize])?;
+        Ok(true)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        self.transaction.is_some()
+    }
+
+    fn is_reading(&self) -> bool {
+        match self.wal.as_ref() {
+            Some(wal) => wal.is_reading(),
+            None => self.lock.level() != LockLevel::None,
+        }
+    }
+
+    /*
+        Start a read transaction unless one is open. In rollback mode this takes a SHARED lock,
+        rolls back a hot journal left by a crashed writer and reads the header again, as another
+        process may have changed the database, or switched it to WAL mode, since the last read.
+        A journal is only hot if no other process holds RESERVED, i.e. is still writing it.
+    */
+    pub fn begin_read(&mut self) -> Result<()> {
+        if self.lock.level() == LockLevel::None {
+            lock::retry(|| self.lock.lock(self.db_file.as_ref(), LockLevel::Shared))?;
+            if !self.read_only
+                && Journal::exists(self.vfs.as_ref(), &self.db_path)
+                && self.lock.lock(self.db_file.as_ref(), LockLevel::Reserved)?
+            {
+                let recovered =
+                    lock::retry(|| self.lock.lock(self.db_file.as_ref(), LockLevel::Exclusive))
+                        .and_then(|_| {
+                            Journal::recover(
+                                self.vfs.as_ref(),
+                                &self.db_path,
+                                self.db_file.as_ref(),
+                            )
+                        });
+                self.lock.unlock(self.db_file.as_ref(), LockLevel::Shared)?;
+                recovered?;
+            }
+            self.file_header = self.read_header()?;
+            if self.file_header.is_wal() && self.wal.is_none() {
+                let page_size = self.file_header.page_size as usize;
+                self.wal = Some(Wal::open(
+                    self.vfs.as_ref(),
+                    &self.db_path,
+                    page_size,
+                    self.read_only,
+                )?);
+            }
+            if self.wal.is_none() {
+                self.cache
+                    .validate(Version::File(self.file_header.file_change_counter));
+            }
+            self.remap()?;
+        }
+        if let Some(wal) = self.wal.as_mut()
+            && !wal.is_reading()
+        {
+            wal.begin_read()?;
+            // Committed frames supersede the header and the size stored in the database file.
+            let mut file_header = match wal.read_page(1)? {
+                Some(first_page) => FileHeader::parse(&first_page),
+                None => self.read_header()?,
+            };
+            if let Some(wal) = self.wal.as_ref()
+                && let Some(db_size) = wal.db_size()
+            {
+                file_header.page_count = db_size;
+            }
+            self.file_header = file_header;
+            self.cache.validate(self.log_version());
+            self.remap()?;
+        }
+        Ok(())
+    }
+
+    // The header as the database file has it, with the page count it stands for.
+    fn read_header(&mut self) -> Result<FileHeader> {
+        let first_page = self.read_page(1)?;
+        FileHeader::parse_sized(&first_page, self.db_file.file_size()?)
+    }
+
+    fn log_version(&self) -> Version {
+        match self.wal.as_ref() {
+            Some(wal) => Version::Log {
+                salt: wal.salt(),
+                max_frame: wal.frame_count(),
+            },
+            None => Version::File(self.file_header.file_change_counter),
+        }
+    }
+
+    // The pages a transaction wrote are what the database holds now, they stay cached.
+    fn cache_committed(&mut self, pages: BTreeMap<u32, Vec<u8>>) {
+        let version = self.log_version();
+        self.cache.advance(version);
+        self.cache.truncate(self.file_header.page_count);
+        for (page_num, data) in pages {
+            self.cache.insert(page_num, Rc::new(data));
+        }
+    }
+
+    // Locks are kept until the end of the transaction. In WAL mode SHARED is never released.
+    pub fn end_read(&mut self) -> Result<()> {
+        if self.transaction.is_some() {
+            return Ok(());
+        }
+        match self.wal.as_mut() {
+            Some(wal) => wal.end_read(),
+            None => self.lock.unlock(self.db_file.as_ref(), LockLevel::None),
+        }
+    }
+
+    /*
+        Announce a write: RESERVED in rollback mode, the WAL write lock otherwise. Like SQLite,
+        a busy lock is only waited for if nothing was read yet. A connection which already reads
+        an older snapshot could never write it back without losing the other commit.
+    */
+    pub fn begin_write(&mut self) -> Result<()> {
+        if self.read_only {
+            return Err(MyError::ReadOnly);
+        }
+        let wait = !self.is_reading();
+        lock::retry(|| {
+            self.begin_read()?;
+            let locked = match self.wal.as_mut() {
+                Some(wal) => wal.begin_write()?,
+                None => self.lock.lock(self.db_file.as_ref(), LockLevel::Reserved)?,
+            };
+            if !locked {
+                if !wait {
+                    return Err(MyError::Busy);
+                }
+                self.end_read()?;
+            }
+            Ok(locked)
+        })
+    }
+
+    // IMMEDIATE and EXCLUSIVE transactions take their locks at once, which fails on a read only file.
+    pub fn begin(&mut self, mode: TransactionMode) -> Result<()> {
+        if self.transaction.is_some() {
+            return Err(MyError::Transaction(
+                "cannot start a transaction within a transaction".to_string(),
+            ));
+        }
+        match mode {
+            TransactionMode::Deferred => self.begin_read()?,
+            TransactionMode::Immediate => self.begin_write()?,
+            TransactionMode::Exclusive => {
+                self.begin_write()?;
+                if self.wal.is_none() {
+                    lock::retry(|| self.lock.lock(self.db_file.as_ref(), LockLevel::Exclusive))?;
+                }
+            }
+        }
+        self.transaction = Some(Transaction {
+            original_header: self.file_header.clone(),
+            savepoints: Vec::new(),
+            opened_by_savepoint: false,
+        });
+        Ok(())
+    }
+
+    pub fn savepoint(&mut self, name: Option<String>) -> Result<()> {
+        if self.transaction.is_none() {
+            self.begin(TransactionMode::Deferred)?;
+            if let Some(transaction) = self.transaction.as_mut() {
+                transaction.opened_by_savepoint = true;
+            }
+        }
+        let file_header = self.file_header.clone();
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.savepoints.push(Savepoint {
+                name,
+                file_header,
+                pages: HashMap::new(),
+            });
+        }
+        Ok(())
+    }
+
+    // Releasing a savepoint hands its page images to the enclosing one, so they stay undoable.
+    pub fn release(&mut self, name: Option<&str>) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_mut()
+            .ok_or_else(|| MyError::NoSuchSavepoint(name.unwrap_or_default().to_string()))?;
+        let index = transaction.find_savepoint(name)?;
+        let released: Vec<Savepoint> = transaction.savepoints.drain(index..).collect();
+        match transaction.savepoints.last_mut() {
+            Some(parent) => {
+                for savepoint in released {
+                    for (page_num, image) in savepoint.pages {
+                        parent.pages.entry(page_num).or_insert(image);
+                    }
+                }
+                Ok(())
+            }
+            None if transaction.opened_by_savepoint => self.commit(),
+            None => Ok(()),
+        }
+    }
+
+    // The savepoint itself stays open after ROLLBACK TO, only the changes made since are undone.
+    pub fn rollback_to(&mut self, name: Option<&str>) -> Result<()> {
+        let transaction = self
+            .transaction
+            .as_mut()
+            .ok_or_else(|| MyError::NoSuchSavepoint(name.unwrap_or_default().to_string()))?;
+        let index = transaction.find_savepoint(name)?;
+        for savepoint in transaction.savepoints[index..].iter_mut().rev() {
+            for (page_num, image) in savepoint.pages.drain() {
+                match image {
+                    Some(data) => self.dirty_pages.insert(page_num, data),
+                    None => self.dirty_pages.remove(&page_num),
+                };
+            }
+        }
+        transaction.savepoints.truncate(index + 1);
+        self.file_header = transaction.savepoints[index].file_header.clone();
+        self.schema = None;
+        Ok(())
+    }
+
+    // Nothing reaches the file before commit, so rolling back only drops the modified pages.
+    pub fn rollback(&mut self) -> Result<()> {
+        let transaction = self.transaction.take().ok_or_else(|| {
+            MyError::Transaction("cannot rollback - no transaction is active".to_string())
+        })?;
+        self.dirty_pages.clear();
+        self.file_header = transaction.original_header;
+        self.schema = None;
+        self.end_read()
+    }
+
+    /*
+        Committing follows the rollback journal protocol:
+            1. The original content of every modified page is written to the journal.
+            2. The journal is synced, then its page count is set and it is synced again.
+            3. The modified pages are written to the database file, which is then synced.
+            4. Deleting the journal commits the transaction.
+        A crash before step 4 leaves a hot journal, which the next reader copies back.
+        The database file is only written under an EXCLUSIVE lock, once every reader is gone.
+    */
+    pub fn commit(&mut self) -> Result<()> {
+        if self.transaction.is_none() {
+            return Err(MyError::Transaction(
+                "cannot commit - no transaction is active".to_string(),
+            ));
+        }
+        if !self.dirty_pages.is_empty() && self.wal.is_none() {
+            lock::retry(|| self.lock.lock(self.db_file.as_ref(), LockLevel::Exclusive))?;
+        }
+        let result = self.write_transaction();
+        let transaction = self.transaction.take();
+        // A commit that fails half way is rolled back, a hot journal undoes what reached the file.
+        if result.is_err()
+            && let Some(transaction) = transaction
+        {
+            self.dirty_pages.clear();
+            self.file_header = transaction.original_header;
+            self.schema = None;
+        }
+        self.end_read()?;
+        result?;
+        if let Some(wal) = self.wal.as_mut()
+            && wal.frame_count() >= Self::WAL_AUTOCHECKPOINT
+        {
+            wal.checkpoint(self.db_file.as_ref(), CheckpointMode::Passive)?;
+        }
+        // The file may have grown or shrunk, a map past its end must not be read.
+        self.remap()
+    }
+
+    fn write_transaction(&mut self) -> Result<()> {
+        let Some(transaction) = self.transaction.as_ref() else {
+            return Ok(());
+        };
+        let original_page_count = transaction.original_header.page_count;
+        if self.dirty_pages.is_empty() {
+            return Ok(());
+        }
+        // Without incremental vacuum, an auto-vacuum database gives free pages back at commit.
+        if self.file_header.is_auto_vacuum()
+            && !self.file_header.incremental_vacuum
+            && self.file_header.freelist_page_count > 0
+        {
+            autovacuum::incremental_vacuum(self, None)?;
+        }
+        let page_count = self.file_header.page_count;
+        self.dirty_pages
+            .retain(|page_num, _| *page_num <= page_count);
+        self.file_header.file_change_counter = self.file_header.file_change_counter.wrapping_add(1);
+        let mut first_page = self.load_page(1)?.into_vec();
+        self.file_header.write_to(&mut first_page);
+        self.dirty_pages.insert(1, first_page);
+        if let Some(extension) = &self.reserved_space {
+            let usable_size = self.file_header.usable_size();
+            for (page_num, data) in self.dirty_pages.iter_mut() {
+                let (content, reserved) = data.split_at_mut(usable_size);
+                extension.fill(*page_num, content, reserved);
+            }
+        }
+        if self.wal.is_some() {
+            return self.commit_wal();
         }
-        result
+
+        let page_size = self.file_header.page_size as usize;
+        let mut journal = Journal::create(
+            self.vfs.as_ref(),
+            &self.db_path,
+            original_page_count,
+            page_size,
+        )?;
+        // Pages cut off when the file shrinks are journaled too, a rollback puts them back.
+        let truncated = (self.file_header.page_count..original_page_count)
+            .map(|page_num| page_num + 1)
+            .filter(|page_num| !self.dirty_pages.contains_key(page_num));
+        let journaled: Vec<u32> = self
+            .dirty_pages
+            .keys()
+            .copied()
+            .filter(|page_num| *page_num <= original_page_count)
+            .chain(truncated)
+            .collect();
+        for page_num in journaled {
+            let original = self.read_page(page_num)?;
+            journal.append(page_num, &original)?;
+        }
+        journal.seal()?;
+
+        for (page_num, data) in &self.dirty_pages {
+            self.db_file
+                .write_at(data, (*page_num as u64 - 1) * page_size as u64)?;
+        }
+        self.db_file
+            .truncate(self.file_header.page_count as u64 * page_size as u64)?;
+        self.db_file.sync()?;
+        let pages = std::mem::take(&mut self.dirty_pages);
+        journal.delete(self.vfs.as_ref())?;
+        self.cache_committed(pages);
+        Ok(())
+    }
+
+    /*
+        In WAL mode a commit only appends the modified pages to the log, the database file is
+        updated by checkpoints. Like SQLite, a checkpoint runs once the log exceeds 1000 frames.
+    */
+    fn commit_wal(&mut self) -> Result<()> {
+        let dirty_pages = std::mem::take(&mut self.dirty_pages);
+        let page_count = self.file_header.page_count;
+        if let Some(wal) = self.wal.as_mut() {
+            wal.commit(&dirty_pages, page_count)?;
+            self.cache_committed(dirty_pages);
+        }
+        Ok(())
+    }
+
+    /*
+        Switching to WAL mode is an ordinary commit setting both file format versions to 2, the
+        log is opened by the next read. Leaving it needs the database to itself: every frame is
+        checkpointed, the versions go back to 1 and the log is deleted.
+    */
+    pub fn set_wal_mode(&mut self, wal: bool) -> Result<()> {
+        let direction = if wal { "into" } else { "out of" };
+        if self.in_transaction() {
+            return Err(MyError::Transaction(format!(
+                "cannot change {direction} wal mode from within a transaction"
+            )));
+        }
+        self.begin_read()?;
+        self.end_read()?;
+        if wal == self.file_header.is_wal() {
+            return Ok(());
+        }
+        if wal {
+            self.begin(TransactionMode::Exclusive)?;
+            let mut first_page = self.load_page(1)?.into_vec();
+            first_page[18..20].copy_from_slice(&[2, 2]);
+            self.store_page(1, &first_page)?;
+            self.file_header.read_version = 2;
+            return self.commit();
+        }
+        if let Some(wal) = self.wal.as_mut() {
+            let (busy, _, _) = wal.checkpoint(self.db_file.as_ref(), CheckpointMode::Truncate)?;
+            if busy {
+                return Err(MyError::Busy);
+            }
+        }
+        lock::retry(|| self.lock.lock(self.db_file.as_ref(), LockLevel::Exclusive))?;
+        let mut first_page = self.read_page(1)?;
+        first_page[18..20].copy_from_slice(&[1, 1]);
+        self.db_file.write_at(&first_page, 0)?;
+        self.db_file.sync()?;
+        self.wal = None;
+        self.cache.clear();
+        self.file_header = FileHeader::parse(&first_page);
+        let _ = self.vfs.delete(&Wal::path_for(&self.db_path));
+        self.lock.unlock(self.db_file.as_ref(), LockLevel::None)
+    }
+
+    /*
+        Returns whether the checkpoint was blocked by another connection, the size of the log
+        and the number of frames checkpointed. Databases not in WAL mode report 0|-1|-1.
+    */
+    pub fn checkpoint(&mut self, mode: CheckpointMode) -> Result<(i64, i64, i64)> {
+        if !self.in_transaction() {
+            // Another process may have switched the database to WAL mode.
+            self.begin_read()?;
+            self.end_read()?;
+        }
+        match self.wal.as_mut() {
+            Some(wal) if wal.is_reading() => {
+                Err(MyError::Transaction("database table is locked".to_string()))
+            }
+            Some(wal) => {
+                let (busy, log, checkpointed) = wal.checkpoint(self.db_file.as_ref(), mode)?;
+                self.remap()?;
+                Ok((busy as i64, log, checkpointed))
+            }
+            None => Ok((0, -1, -1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::PageType;
+
+    fn database() -> Database {
+        let mut db = Database::memory(TextEncoding::Utf8).unwrap();
+        db.begin(TransactionMode::Immediate).unwrap();
+        db
+    }
+
+    fn table(name: &str, root_page: u32) -> SchemaEntry {
+        SchemaEntry {
+            entry_type: "table".to_string(),
+            name: name.to_string(),
+            table_name: name.to_string(),
+            root_page,
+            sql: Some(format!("CREATE TABLE {name}(a, b)")),
+        }
+    }
+
+    #[test]
+    fn freed_pages_are_reused_before_the_file_grows() {
+        let mut db = database();
+        let pages: Vec<u32> = (0..3).map(|_| db.allocate_page().unwrap()).collect();
+        let page_count = db.get_page_count();
+        db.free_page(pages[0]).unwrap();
+        db.free_page(pages[2]).unwrap();
+        assert_eq!(db.file_header.freelist_page_count, 2);
+        assert_eq!(db.file_header.first_freelist_trunk_page, pages[0]);
+        // The leaves go first, the trunk last.
+        assert_eq!(db.allocate_page().unwrap(), pages[2]);
+        assert_eq!(db.allocate_page().unwrap(), pages[0]);
+        assert_eq!(db.file_header.freelist_page_count, 0);
+        assert_eq!(db.file_header.first_freelist_trunk_page, 0);
+        assert_eq!(db.get_page_count(), page_count);
+    }
+
+    #[test]
+    fn full_trunks_chain_to_a_new_one() {
+        let mut db = database();
+        // One trunk holds 1016 leaves of a 4096 byte page.
+        let mut pages: Vec<u32> = (0..1020).map(|_| db.allocate_page().unwrap()).collect();
+        for page in &pages {
+            db.free_page(*page).unwrap();
+        }
+        let first_trunk = db.file_header.first_freelist_trunk_page;
+        assert_eq!(first_trunk, pages[1017]);
+        let trunk = db.load_page(first_trunk).unwrap();
+        assert_eq!(u32::from_be_bytes(trunk[..4].try_into().unwrap()), pages[0]);
+
+        let page_count = db.get_page_count();
+        let mut reused: Vec<u32> = (0..1020).map(|_| db.allocate_page().unwrap()).collect();
+        reused.sort();
+        pages.sort();
+        assert_eq!(reused, pages);
+        assert_eq!(db.file_header.freelist_page_count, 0);
+        assert_eq!(db.get_page_count(), page_count);
+    }
+
+    // Files in memory, except for journals, which cannot be created.
+    #[derive(Debug, Default)]
+    struct NoJournalVfs(MemoryVfs);
+
+    impl Vfs for NoJournalVfs {
+        fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+            match path == Journal::path_for("test.db") {
+                true => Err(std::io::Error::other("no journal").into()),
+                false => self.0.open(path, mode),
+            }
+        }
+
+        fn delete(&self, path: &str) -> Result<()> {
+            self.0.delete(path)
+        }
+    }
+
+    #[test]
+    fn failed_commit_leaves_nothing_behind() {
+        let vfs = Rc::new(NoJournalVfs::default());
+        let mut db = Database::create(vfs, "test.db".to_string(), TextEncoding::Utf8).unwrap();
+        let first_page = db.load_page(1).unwrap().into_vec();
+        db.begin(TransactionMode::Immediate).unwrap();
+        db.add_schema_entry(&table("t", 2)).unwrap();
+        assert!(db.get_table("t").is_ok());
+        assert!(matches!(db.commit(), Err(MyError::Io(_))));
+        assert!(!db.in_transaction());
+        assert!(matches!(db.get_table("t"), Err(MyError::NoSuchTable(_))));
+        assert_eq!(db.load_page(1).unwrap().into_vec(), first_page);
+    }
+
+    #[test]
+    fn schema_is_read_again_after_a_change_or_rollback() {
+        let mut db = database();
+        db.add_schema_entry(&table("t", 2)).unwrap();
+        assert_eq!(db.get_table("T").unwrap().root_page, 2);
+        db.savepoint(Some("s".to_string())).unwrap();
+        // Root pages move without a new cookie under auto-vacuum.
+        db.update_schema(|e| e.root_page = 3).unwrap();
+        assert_eq!(db.get_table("t").unwrap().root_page, 3);
+        db.rollback_to(Some("s")).unwrap();
+        assert_eq!(db.get_table("t").unwrap().root_page, 2);
+        db.rollback().unwrap();
+        assert!(matches!(db.get_table("t"), Err(MyError::NoSuchTable(_))));
+    }
+
+    // Keeps the page number and a sum of the page content in 8 reserved bytes.
+    #[derive(Debug)]
+    struct PageSum;
+
+    impl PageSum {
+        fn compute(page_num: u32, content: &[u8]) -> [u8; 8] {
+            let sum = content
+                .iter()
+                .fold(0u32, |sum, b| sum.wrapping_mul(31).wrapping_add(*b as u32));
+            let mut reserved = [0; 8];
+            reserved[..4].copy_from_slice(&page_num.to_be_bytes());
+            reserved[4..].copy_from_slice(&sum.to_be_bytes());
+            reserved
+        }
+    }
+
+    impl ReservedSpace for PageSum {
+        fn fill(&self, page_num: u32, content: &[u8], reserved: &mut [u8]) {
+            reserved.copy_from_slice(&Self::compute(page_num, content));
+        }
+
+        fn verify(&self, page_num: u32, content: &[u8], reserved: &[u8]) -> bool {
+            reserved == Self::compute(page_num, content)
+        }
+    }
+
+    #[test]
+    fn reserved_space_is_filled_on_commit_and_checked_on_read() {
+        const PAGE_SIZE: usize = FileHeader::DEFAULT_PAGE_SIZE as usize;
+        let usable = PAGE_SIZE - 8;
+        let mut page = FileHeader::empty_database(TextEncoding::Utf8);
+        page[20] = 8;
+        page[105..107].copy_from_slice(&(usable as u16).to_be_bytes());
+        let (content, reserved) = page.split_at_mut(usable);
+        PageSum.fill(1, content, reserved);
+        let vfs = Rc::new(MemoryVfs::new());
+        vfs.insert("test.db", page);
+        let open = || {
+            let mut db = Database::from_vfs(vfs.clone(), "test.db".to_string()).unwrap();
+            db.set_reserved_space(Box::new(PageSum));
+            db
+        };
+
+        let mut db = open();
+        db.begin(TransactionMode::Immediate).unwrap();
+        let root = btree::create_tree(&mut db, PageType::TableLeaf).unwrap();
+        btree::table_insert(&mut db, root, 1, &[2, 1, 42]).unwrap();
+        db.commit().unwrap();
+        let file = vfs.contents("test.db").unwrap();
+        assert_eq!(file.len(), 2 * PAGE_SIZE);
+        for (i, page) in file.chunks(PAGE_SIZE).enumerate() {
+            let (content, reserved) = page.split_at(usable);
+            assert!(PageSum.verify(i as u32 + 1, content, reserved));
+        }
+
+        // A changed byte of the second page makes only that page corrupt.
+        let mut corrupt = file.clone();
+        corrupt[PAGE_SIZE + 100] ^= 1;
+        vfs.insert("test.db", corrupt);
+        let mut db = open();
+        assert!(db.load_page(1).is_ok());
+        assert!(matches!(db.load_page(2), Err(MyError::CorruptPage(2))));
     }
 }
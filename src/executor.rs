@@ -1,43 +1,2836 @@
+use crate::alter;
+use crate::autovacuum::{self, AutoVacuum};
+use crate::btree;
+use crate::cache::PageCache;
 use crate::database::Database;
-use crate::parser::SqlStatement;
+use crate::foreign_key;
+use crate::integrity::Checker;
+use crate::page::{FileHeader, MyError, PageType, Result, TextEncoding};
+use crate::parser::{
+    AlterAction, AlterTableStatement, AttachStatement, BinaryOperator, ColumnConstraint,
+    ColumnDefinition, ConflictResolution, CreateIndexStatement, CreateStatement,
+    CreateTriggerStatement, CreateViewStatement, DeleteStatement, DropStatement, Expression,
+    ForeignKey, ForeignKeyAction, InsertStatement, ObjectType, Parameter, PragmaStatement,
+    RaiseAction, ResultColumns, SelectStatement, SqlStatement, TableConstraint, TransactionMode,
+    TriggerEvent, TriggerTiming, UnaryOperator, UpdateStatement, Upsert, UpsertAction,
+    VacuumStatement, sql_query,
+};
+use crate::pragma;
+use crate::record::{Record, RecordView};
+use crate::table::{IndexSchema, SchemaEntry, TableSchema};
+use crate::trigger::{self, RowReferences};
+use crate::vacuum;
+use crate::value::Value;
+use crate::vfs::{OsVfs, Vfs};
+use crate::wal::CheckpointMode;
+use std::rc::Rc;
 
+/*
+    The databases of a connection: main, temp and the attached ones in the order they were
+    attached. The temp database holds the TEMP tables and views, it lives in memory and is
+    gone with the connection.
+*/
 pub struct Executor {
-    //pub head_page_reader: PageReader,
-    pub database: Database,
+    databases: Vec<AttachedDatabase>,
+    // The database the running statement works on.
+    current: usize,
+    // The views being expanded, to catch views defined in terms of themselves.
+    expanding: Vec<String>,
+    // The triggers running, which don't fire again until they are done.
+    firing: Vec<String>,
+    // PRAGMA foreign_keys, off by default like in SQLite.
+    foreign_keys: bool,
+    // The foreign keys to check once the statement, or for deferred ones the transaction, ends.
+    key_checks: Vec<KeyCheck>,
+    /*
+        The databases the write statement running has its savepoint on, with whether it was
+        outside a transaction there, None between statements.
+    */
+    statement_databases: Option<Vec<(usize, bool)>>,
+    // The values bound to the parameters of the statement running, by number.
+    parameters: Vec<Value>,
+}
+
+struct AttachedDatabase {
+    name: String,
+    database: Database,
+}
+
+/*
+    A key that may have lost its parent row, or been given one that doesn't exist. It is a
+    violation when rows of the child table still hold it and no row of the parent does, so
+    that rows deleted or inserted later in the same statement are accounted for.
+*/
+struct KeyCheck {
+    database: usize,
+    child: String,
+    foreign_key: ForeignKey,
+    key: Vec<Value>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub rowid: i64,
+    pub values: Vec<Value>,
+}
+
+// What becomes of a row once its constraints are checked.
+enum Checked {
+    Write,
+    Skip,
+    // The upsert at that position takes over, the row conflicting with the one of the rowid.
+    Upsert(usize, i64),
 }
 
 impl Executor {
-    pub fn from(database: Database) -> Self {
-        Self { database }
+    // Like SQLite's default SQLITE_MAX_ATTACHED.
+    const MAX_ATTACHED: usize = 10;
+    const MAIN: usize = 0;
+    const TEMP: usize = 1;
+
+    pub fn from(database: Database) -> Result<Self> {
+        let temp = Database::memory(database.file_header.text_encoding)?;
+        Ok(Self {
+            databases: vec![
+                AttachedDatabase {
+                    name: "main".to_string(),
+                    database,
+                },
+                AttachedDatabase {
+                    name: "temp".to_string(),
+                    database: temp,
+                },
+            ],
+            current: Self::MAIN,
+            expanding: Vec::new(),
+            firing: Vec::new(),
+            foreign_keys: false,
+            key_checks: Vec::new(),
+            statement_databases: None,
+            parameters: Vec::new(),
+        })
+    }
+
+    fn database(&mut self) -> &mut Database {
+        &mut self.databases[self.current].database
     }
 
-    pub fn execute(&self, sql_statement: SqlStatement) {
-        println!("{:?}", sql_statement);
+    /*
+        Run a statement, giving back the rows of a query, a pragma or a RETURNING clause. The
+        parameters are read from `parameters` by number as the statement runs. Outside of a
+        transaction the locks taken by a statement are released once it is done.
+    */
+    pub fn run(
+        &mut self,
+        sql_statement: &SqlStatement,
+        parameters: &[Value],
+    ) -> Result<Vec<Vec<Value>>> {
+        self.parameters.clear();
+        self.parameters.extend_from_slice(parameters);
+        let result = self.dispatch(sql_statement).map_err(|e| match e {
+            MyError::Conflict(_, e) => *e,
+            e => e,
+        });
+        self.current = Self::MAIN;
+        for attached in &mut self.databases {
+            attached.database.end_read()?;
+        }
+        result
+    }
+
+    fn dispatch(&mut self, sql_statement: &SqlStatement) -> Result<Vec<Vec<Value>>> {
         match sql_statement {
-            SqlStatement::SELECT(select_cmd) => {
-                let _table_name = select_cmd.table;
-                //self.database.print_table_columns(table_name.as_str());
-                let fields = select_cmd.fields;
-                if fields.len() == 1 && fields[0] == "COUNT" {
-                    println!("Not Implemented");
-                    //println!("{}", self.database.count_rows(table_name.as_str()));
+            SqlStatement::SELECT(select_cmd) => self.on(
+                select_cmd.schema.clone(),
+                select_cmd.table.clone(),
+                "table",
+                |e| e.query(select_cmd).map(|(_, rows)| rows),
+            ),
+            SqlStatement::INSERT(insert_cmd) => self.on(
+                insert_cmd.schema.clone(),
+                insert_cmd.table.clone(),
+                "table",
+                |e| e.modify_returning(|e| e.insert(insert_cmd)),
+            ),
+            SqlStatement::UPDATE(update_cmd) => self.on(
+                update_cmd.schema.clone(),
+                update_cmd.table.clone(),
+                "table",
+                |e| e.modify_returning(|e| e.update(update_cmd)),
+            ),
+            SqlStatement::DELETE(delete_cmd) => self.on(
+                delete_cmd.schema.clone(),
+                delete_cmd.table.clone(),
+                "table",
+                |e| e.modify_returning(|e| e.delete(delete_cmd)),
+            ),
+            SqlStatement::PRAGMA(pragma_cmd) => {
+                // The schema pragmas look for their table or index like any other statement.
+                let entry_type = match pragma_cmd.name.to_ascii_lowercase().as_str() {
+                    "table_info" | "table_xinfo" | "index_list" | "foreign_key_list"
+                    | "foreign_key_check" => "table",
+                    "index_info" => "index",
+                    _ => "",
+                };
+                let argument = pragma_cmd.value.as_ref().map(|v| v.to_string());
+                let name = argument.unwrap_or_default();
+                self.on(pragma_cmd.schema.clone(), name, entry_type, |e| {
+                    e.pragma(pragma_cmd)
+                })
+            }
+            // The others have no parameters, they run from a copy of their own.
+            statement => self.command(statement.clone()).map(|_| Vec::new()),
+        }
+    }
+
+    // The statements which give back no rows.
+    fn command(&mut self, sql_statement: SqlStatement) -> Result<()> {
+        match sql_statement {
+            SqlStatement::CREATE(creation_cmd) => {
+                let schema = creation_schema(creation_cmd.temp, &creation_cmd.schema)?;
+                self.on(schema, creation_cmd.table_name.clone(), "", |e| {
+                    e.write(|e| e.create_table(creation_cmd))
+                })
+            }
+            SqlStatement::VIEW(view_cmd) => {
+                let schema = creation_schema(view_cmd.temp, &view_cmd.schema)?;
+                self.on(schema, view_cmd.view_name.clone(), "", |e| {
+                    e.write(|e| e.create_view(view_cmd))
+                })
+            }
+            SqlStatement::TRIGGER(trigger_cmd) => {
+                let schema = creation_schema(trigger_cmd.temp, &trigger_cmd.schema)?;
+                self.on(schema, trigger_cmd.trigger_name.clone(), "", |e| {
+                    e.write(|e| e.create_trigger(trigger_cmd))
+                })
+            }
+            SqlStatement::INDEX(index_cmd) => {
+                let schema = index_cmd.schema.clone();
+                let name = index_cmd.index_name.clone();
+                self.on(schema, name, "index", |e| {
+                    e.write(|e| e.create_index(index_cmd))
+                })
+            }
+            SqlStatement::DROP(drop_cmd) => {
+                let entry_type = drop_cmd.object_type.name();
+                let (schema, name) = (drop_cmd.schema.clone(), drop_cmd.name.clone());
+                self.on(schema, name, entry_type, |e| e.modify(|e| e.drop(drop_cmd)))
+            }
+            SqlStatement::ALTER(alter_cmd) => self.on(
+                alter_cmd.schema.clone(),
+                alter_cmd.table_name.clone(),
+                "table",
+                |e| e.write(|e| e.alter(alter_cmd)),
+            ),
+            SqlStatement::BEGIN(mode) => self.begin(mode),
+            SqlStatement::COMMIT => {
+                self.check_keys()?;
+                self.each(|db| db.commit())
+            }
+            SqlStatement::ROLLBACK(None) => {
+                self.key_checks.clear();
+                self.each(|db| db.rollback())
+            }
+            SqlStatement::ROLLBACK(Some(name)) => self.each(|db| db.rollback_to(Some(&name))),
+            SqlStatement::SAVEPOINT(name) => self.each(|db| db.savepoint(Some(name.clone()))),
+            SqlStatement::RELEASE(name) => self.each(|db| db.release(Some(&name))),
+            SqlStatement::VACUUM(vacuum_cmd) => {
+                self.on(vacuum_cmd.schema.clone(), String::new(), "", |e| {
+                    e.vacuum(vacuum_cmd)
+                })
+            }
+            SqlStatement::ATTACH(attach_cmd) => self.attach(attach_cmd),
+            SqlStatement::DETACH(name) => self.detach(&name),
+            // Those giving back rows are run by dispatch.
+            _ => Ok(()),
+        }
+    }
+
+    fn find_database(&self, schema: &str) -> Option<usize> {
+        self.databases
+            .iter()
+            .position(|d| d.name.eq_ignore_ascii_case(schema))
+    }
+
+    /*
+        Run a statement on the database it names. Without a schema name that is the first
+        database holding an object of the given type and name, searching temp, main and then
+        the attached ones, or main when none does. Tables and views share their names, looking
+        for a table finds views too. A table missing from a named database is reported with
+        the schema name, as SQLite does.
+    */
+    fn on<T, F>(
+        &mut self,
+        schema: Option<String>,
+        name: String,
+        entry_type: &str,
+        statement: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let previous = self.current;
+        self.current = match &schema {
+            Some(schema) => self.find_database(schema).ok_or_else(|| match entry_type {
+                "table" => MyError::NoSuchTable(format!("{schema}.{name}")),
+                _ => MyError::Schema(format!("unknown database {schema}")),
+            })?,
+            None if TableSchema::TEMP_SCHEMA_NAMES
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(&name)) =>
+            {
+                Self::TEMP
+            }
+            None => self.locate(&name, entry_type)?,
+        };
+        let result = statement(self);
+        self.current = previous;
+        match (result, schema) {
+            (Err(MyError::NoSuchTable(table)), Some(schema)) if !table.contains('.') => {
+                Err(MyError::NoSuchTable(format!("{schema}.{table}")))
+            }
+            (result, _) => result,
+        }
+    }
+
+    // The first database holding the object, or main when none does.
+    fn locate(&mut self, name: &str, entry_type: &str) -> Result<usize> {
+        if entry_type.is_empty() {
+            return Ok(Self::MAIN);
+        }
+        let search = [Self::TEMP, Self::MAIN]
+            .into_iter()
+            .chain(Self::TEMP + 1..self.databases.len());
+        for i in search {
+            if self.databases[i].database.get_schema()?.iter().any(|e| {
+                (e.entry_type == entry_type
+                    || (is_relation(entry_type) && is_relation(&e.entry_type)))
+                    && e.name.eq_ignore_ascii_case(name)
+            }) {
+                return Ok(i);
+            }
+        }
+        Ok(Self::MAIN)
+    }
+
+    /*
+        A transaction spans every database of the connection. Each one commits on its own:
+        unlike SQLite, which ties the journals together with a super-journal, a crash while
+        committing may leave some of them committed and others not.
+    */
+    fn begin(&mut self, mode: TransactionMode) -> Result<()> {
+        for i in 0..self.databases.len() {
+            if let Err(e) = self.databases[i].database.begin(mode) {
+                for attached in &mut self.databases[..i] {
+                    attached.database.rollback()?;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn each<F>(&mut self, mut action: F) -> Result<()>
+    where
+        F: FnMut(&mut Database) -> Result<()>,
+    {
+        for attached in &mut self.databases {
+            action(&mut attached.database)?;
+        }
+        Ok(())
+    }
+
+    /*
+        The attached file is created when it does not exist. ':memory:' and the empty name
+        attach a new database in memory. Every database of a connection has to use the text
+        encoding of the main one.
+
+        A file can only be open once. POSIX locks belong to the process, so a second connection
+        to the same file would be granted every lock the first holds and both could write it at
+        once. Closing either file would also release the locks of the other.
+    */
+    fn attach(&mut self, attach_cmd: AttachStatement) -> Result<()> {
+        let name = attach_cmd.schema;
+        if self.find_database(&name).is_some() || name.eq_ignore_ascii_case("temp") {
+            return Err(MyError::Schema(format!(
+                "database {name} is already in use"
+            )));
+        }
+        if self.databases.len() - 2 >= Self::MAX_ATTACHED {
+            return Err(MyError::Schema(format!(
+                "too many attached databases - max {}",
+                Self::MAX_ATTACHED
+            )));
+        }
+        if self.databases[Self::MAIN].database.in_transaction() {
+            return Err(MyError::Transaction(
+                "cannot ATTACH database within transaction".to_string(),
+            ));
+        }
+        let encoding = self.databases[Self::MAIN]
+            .database
+            .file_header
+            .text_encoding;
+        let database = match attach_cmd.path.as_str() {
+            "" | Database::MEMORY => Database::memory(encoding)?,
+            path => {
+                if let Some(id) = OsVfs.file_id(path)
+                    && let Some(open) = self
+                        .databases
+                        .iter()
+                        .find(|d| d.database.file_id() == Some(id))
+                {
+                    return Err(MyError::Schema(format!(
+                        "database {path} is already in use as {}",
+                        open.name
+                    )));
+                }
+                Database::create(Rc::new(OsVfs), path.to_string(), encoding)?
+            }
+        };
+        if database.file_header.text_encoding != encoding {
+            return Err(MyError::Schema(
+                "attached databases must use the same text encoding as main database".to_string(),
+            ));
+        }
+        self.databases.push(AttachedDatabase { name, database });
+        Ok(())
+    }
+
+    fn detach(&mut self, name: &str) -> Result<()> {
+        let index = self
+            .find_database(name)
+            .ok_or_else(|| MyError::NoSuchDatabase(name.to_string()))?;
+        if index <= Self::TEMP {
+            return Err(MyError::Schema(format!("cannot detach database {name}")));
+        }
+        if self.databases[index].database.in_transaction() {
+            return Err(MyError::Transaction(format!("database {name} is locked")));
+        }
+        self.databases.remove(index);
+        Ok(())
+    }
+
+    /*
+        Unknown pragmas are ignored, as SQLite does. Header fields are shown as queries and
+        changed through a write transaction, the schema pragmas take a table or index name.
+    */
+    fn pragma(&mut self, pragma_cmd: &PragmaStatement) -> Result<Vec<Vec<Value>>> {
+        let argument = pragma_cmd.value.as_ref().map(|v| v.to_string());
+        let name = pragma_cmd.name.to_ascii_lowercase();
+        // The header as of now, the read ends so that a checkpoint or a mode switch can run.
+        self.database().begin_read()?;
+        let header = self.database().file_header.clone();
+        self.database().end_read()?;
+        let rows = match (name.as_str(), argument.as_deref()) {
+            ("wal_checkpoint", mode) => {
+                let (busy, log, checkpointed) = self
+                    .database()
+                    .checkpoint(CheckpointMode::from(mode.unwrap_or_default()))?;
+                vec![vec![
+                    Value::Integer(busy),
+                    Value::Integer(log),
+                    Value::Integer(checkpointed),
+                ]]
+            }
+            ("auto_vacuum", None) => {
+                vec![vec![Value::Integer(self.database().auto_vacuum().value())]]
+            }
+            ("auto_vacuum", Some(mode)) => {
+                if let Some(mode) = AutoVacuum::from(mode) {
+                    self.set_auto_vacuum(mode)?;
+                }
+                Vec::new()
+            }
+            ("incremental_vacuum", limit) => {
+                // A missing, zero or negative count gives back every free page.
+                let limit = limit.map(pragma_integer).filter(|n| *n > 0);
+                if self.database().auto_vacuum() == AutoVacuum::Incremental {
+                    self.write(|e| {
+                        autovacuum::incremental_vacuum(e.database(), limit.map(|n| n as u32))
+                    })?;
+                }
+                Vec::new()
+            }
+            ("page_size", None) => vec![vec![Value::Integer(header.page_size as i64)]],
+            ("page_count", None) => vec![vec![Value::Integer(header.page_count as i64)]],
+            ("freelist_count", None) => {
+                vec![vec![Value::Integer(header.freelist_page_count as i64)]]
+            }
+            ("cache_size", None) => vec![vec![Value::Integer(self.database().cache_size())]],
+            ("cache_size", Some(value)) => {
+                self.database().set_cache_size(pragma_integer(value));
+                Vec::new()
+            }
+            // The deprecated persistent default, stored in the header and applied at once.
+            ("default_cache_size", None) => {
+                let size = match header.default_cache_size {
+                    0 => PageCache::DEFAULT_SIZE,
+                    size => size as i64,
+                };
+                vec![vec![Value::Integer(size)]]
+            }
+            ("default_cache_size", Some(value)) => {
+                let size = (pragma_integer(value) as i32).saturating_abs();
+                self.write_header(|h| h.default_cache_size = size)?;
+                self.database().set_cache_size(size as i64);
+                Vec::new()
+            }
+            ("mmap_size", None) => {
+                vec![vec![Value::Integer(self.database().mmap_size() as i64)]]
+            }
+            ("mmap_size", Some(value)) => {
+                let size = pragma_integer(value).max(0) as u64;
+                self.database().set_mmap_size(size)?;
+                vec![vec![Value::Integer(size as i64)]]
+            }
+            // The temp database is only listed once it holds something.
+            ("database_list", None) => {
+                let temp_used = !self.databases[Self::TEMP].database.get_schema()?.is_empty();
+                self.databases
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| *i != Self::TEMP || temp_used)
+                    .map(|(i, attached)| {
+                        let file = match attached.database.is_memory() {
+                            true => String::new(),
+                            false => std::fs::canonicalize(attached.database.path()).map_or_else(
+                                |_| attached.database.path().to_string(),
+                                |path| path.to_string_lossy().to_string(),
+                            ),
+                        };
+                        vec![
+                            Value::Integer(i as i64),
+                            Value::Text(attached.name.clone()),
+                            Value::Text(file),
+                        ]
+                    })
+                    .collect()
+            }
+            ("encoding", None) => vec![vec![Value::Text(header.text_encoding.name().to_string())]],
+            ("user_version", None) => vec![vec![Value::Integer(header.user_version as i64)]],
+            ("user_version", Some(value)) => {
+                let value = pragma_integer(value) as i32;
+                self.write_header(|h| h.user_version = value)?;
+                Vec::new()
+            }
+            ("application_id", None) => vec![vec![Value::Integer(header.application_id as i64)]],
+            ("application_id", Some(value)) => {
+                let value = pragma_integer(value) as i32;
+                self.write_header(|h| h.application_id = value)?;
+                Vec::new()
+            }
+            ("schema_version", None) => vec![vec![Value::Integer(header.schema_cookie as i64)]],
+            ("schema_version", Some(value)) => {
+                let value = pragma_integer(value) as u32;
+                self.write_header(|h| h.schema_cookie = value)?;
+                Vec::new()
+            }
+            ("journal_mode", mode) => {
+                // Only the default DELETE mode and WAL are supported, others leave it as it is.
+                match mode.map(|m| m.to_ascii_lowercase()).as_deref() {
+                    Some("wal") => self.database().set_wal_mode(true)?,
+                    Some("delete") => self.database().set_wal_mode(false)?,
+                    _ => {}
+                }
+                let mode = if self.database().file_header.is_wal() {
+                    "wal"
                 } else {
-                    for _col in fields {
-                        println!("Not Implemented");
-                        /*println!(
-                            "{}: {:?}",
-                            col,
-                            self.database.get_column(table_name.as_str(), col.as_str())
-                        );*/
+                    "delete"
+                };
+                vec![vec![Value::Text(mode.to_string())]]
+            }
+            (check @ ("integrity_check" | "quick_check"), limit) => {
+                let max_errors = limit
+                    .and_then(|l| l.parse::<i64>().ok())
+                    .filter(|n| *n > 0)
+                    .unwrap_or(100);
+                Checker::from(self.database(), max_errors as usize, check == "quick_check")
+                    .run()?
+                    .into_iter()
+                    .map(|line| vec![Value::Text(line)])
+                    .collect()
+            }
+            ("table_info", Some(table)) => pragma::table_info(self.database(), table, false)?,
+            ("table_xinfo", Some(table)) => pragma::table_info(self.database(), table, true)?,
+            ("index_list", Some(table)) => pragma::index_list(self.database(), table)?,
+            ("index_info", Some(index)) => pragma::index_info(self.database(), index)?,
+            ("foreign_key_list", Some(table)) => pragma::foreign_key_list(self.database(), table)?,
+            ("foreign_keys", None) => vec![vec![Value::Integer(self.foreign_keys as i64)]],
+            // Like in SQLite, the setting can't change within a transaction.
+            ("foreign_keys", Some(value)) => {
+                if !self.databases.iter().any(|d| d.database.in_transaction()) {
+                    self.foreign_keys = pragma_boolean(value);
+                }
+                Vec::new()
+            }
+            ("foreign_key_check", table) => {
+                let tables = match table {
+                    Some(table) => vec![self.database().get_table(table)?],
+                    None => {
+                        let mut tables = Vec::new();
+                        for entry in self.database().get_schema()? {
+                            if entry.entry_type == "table" {
+                                tables.push(self.database().get_table(&entry.name)?);
+                            }
+                        }
+                        tables
+                    }
+                };
+                let mut rows = Vec::new();
+                for table in tables {
+                    rows.extend(foreign_key::check_table(self.database(), &table)?);
+                }
+                rows
+            }
+            _ => Vec::new(),
+        };
+        Ok(rows)
+    }
+
+    // Change a field of the file header, which is written with page 1 at commit.
+    fn write_header<F>(&mut self, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut FileHeader),
+    {
+        self.write(|e| {
+            change(&mut e.database().file_header);
+            e.database().store_header()
+        })
+    }
+
+    /*
+        Pointer-map pages cannot be added to or removed from a database that already has pages,
+        so turning auto-vacuum on or off only takes effect with the next VACUUM. Switching
+        between full and incremental mode is a change to the header alone.
+    */
+    fn set_auto_vacuum(&mut self, mode: AutoVacuum) -> Result<()> {
+        let current = self.database().auto_vacuum();
+        if current == mode {
+            self.database().pending_auto_vacuum = None;
+        } else if current != AutoVacuum::None && mode != AutoVacuum::None {
+            self.write_header(|h| h.incremental_vacuum = mode == AutoVacuum::Incremental)?;
+        } else {
+            self.database().pending_auto_vacuum = Some(mode);
+        }
+        Ok(())
+    }
+
+    // VACUUM INTO only reads the database, VACUUM rewrites all of it in one transaction.
+    fn vacuum(&mut self, vacuum_cmd: VacuumStatement) -> Result<()> {
+        if self.database().in_transaction() {
+            return Err(MyError::Transaction(
+                "cannot VACUUM from within a transaction".to_string(),
+            ));
+        }
+        match vacuum_cmd.into {
+            Some(path) => vacuum::vacuum_into(self.database(), &path),
+            None => self.write(|e| vacuum::vacuum(e.database())),
+        }
+    }
+
+    /*
+        Outside of an explicit transaction every write statement commits on its own. Inside one,
+        a failing statement is undone through its own savepoint and the transaction carries on.
+        Only the schema is read before the write lock is taken, and outside of a transaction
+        that read ends first, so a busy database can be waited for.
+        A trigger writing to another database, temp or an attached one, opens the savepoint of
+        the statement there too, and all of them end together with the statement.
+    */
+    fn write<F>(&mut self, statement: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        if self.statement_databases.is_some() {
+            self.join_statement()?;
+            return statement(self);
+        }
+        self.statement_databases = Some(Vec::new());
+        let result = self.join_statement().and_then(|_| statement(self));
+        let joined = self.statement_databases.take().unwrap_or_default();
+        self.end_statement(joined, result)
+    }
+
+    // Take the write lock of the current database and open the statement's savepoint on it.
+    fn join_statement(&mut self) -> Result<()> {
+        let current = self.current;
+        if let Some(joined) = &self.statement_databases
+            && joined.iter().any(|(database, _)| *database == current)
+        {
+            return Ok(());
+        }
+        let autocommit = !self.database().in_transaction();
+        // Finding the table only read the schema. Outside of a transaction that read ends here,
+        // so begin_write waits for a busy database instead of failing at once.
+        if self
+            .statement_databases
+            .as_ref()
+            .is_some_and(|j| j.is_empty())
+        {
+            self.database().end_read()?;
+        }
+        self.database().begin_write()?;
+        self.database().savepoint(None)?;
+        if let Some(joined) = self.statement_databases.as_mut() {
+            joined.push((current, autocommit));
+        }
+        Ok(())
+    }
+
+    fn end_statement(&mut self, joined: Vec<(usize, bool)>, result: Result<()>) -> Result<()> {
+        // A failed release, a commit that could not get its lock, is undone like the statement.
+        let mut released = 0;
+        let error = match result.and_then(|_| {
+            joined.iter().try_for_each(|(database, _)| {
+                self.databases[*database].database.release(None)?;
+                released += 1;
+                Ok(())
+            })
+        }) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        let joined = joined.into_iter().skip(released);
+        match &error {
+            MyError::Raised(RaiseAction::Rollback, _)
+            | MyError::Conflict(ConflictResolution::Rollback, _) => {
+                for attached in &mut self.databases {
+                    if attached.database.in_transaction() {
+                        attached.database.rollback()?;
                     }
                 }
             }
-            SqlStatement::CREATE(_creation_cmd) => {
-                println!("This is a create cmd, doing nothing for now!");
+            // The changes made before RAISE(FAIL), or a constraint failing under FAIL, are kept.
+            MyError::Raised(RaiseAction::Fail, _)
+            | MyError::Conflict(ConflictResolution::Fail, _) => {
+                for (database, _) in joined {
+                    let database = &mut self.databases[database].database;
+                    if database.in_transaction() {
+                        database.release(None)?;
+                    }
+                }
             }
             _ => {
-                println!("Not implement yet");
+                for (database, autocommit) in joined {
+                    let database = &mut self.databases[database].database;
+                    // A statement within a trigger can have ended the whole transaction already.
+                    if !database.in_transaction() {
+                        continue;
+                    }
+                    if autocommit {
+                        database.rollback()?;
+                    } else {
+                        database.rollback_to(None)?;
+                        database.release(None)?;
+                    }
+                }
             }
         }
+        Err(error)
+    }
+
+    /*
+        A statement changing rows, checked against the foreign keys once it is done. Outside
+        of an explicit transaction that includes the deferred foreign keys.
+    */
+    fn modify<F>(&mut self, statement: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        let autocommit = !self.database().in_transaction();
+        let pending = self.key_checks.len();
+        let result = self.write(|e| {
+            statement(e)?;
+            match autocommit {
+                true => e.check_keys(),
+                false => e.check_immediate_keys(pending),
+            }
+        });
+        if result.is_err() {
+            self.key_checks.truncate(pending);
+        }
+        result
+    }
+
+    // The rows of a RETURNING clause come out once the statement is done, like those of SELECT.
+    fn modify_returning<F>(&mut self, statement: F) -> Result<Vec<Vec<Value>>>
+    where
+        F: FnOnce(&mut Self) -> Result<Vec<Vec<Value>>>,
+    {
+        let mut returned = Vec::new();
+        self.modify(|e| {
+            returned = statement(e)?;
+            Ok(())
+        })?;
+        Ok(returned)
+    }
+
+    // The checks the statement added for keys which are not deferred.
+    fn check_immediate_keys(&mut self, pending: usize) -> Result<()> {
+        let mut checks = self.key_checks.split_off(pending);
+        let result = self.check(checks.iter().filter(|c| !c.foreign_key.deferred));
+        checks.retain(|c| c.foreign_key.deferred);
+        self.key_checks.extend(checks);
+        result
+    }
+
+    // Every pending check, when the transaction commits. A violation keeps it open.
+    fn check_keys(&mut self) -> Result<()> {
+        let checks = std::mem::take(&mut self.key_checks);
+        let result = self.check(checks.iter());
+        if result.is_err() {
+            self.key_checks = checks;
+        }
+        result
+    }
+
+    fn check<'a>(&mut self, checks: impl Iterator<Item = &'a KeyCheck>) -> Result<()> {
+        for check in checks {
+            let database = &mut self.databases[check.database].database;
+            let Ok(child) = database.get_table(&check.child) else {
+                continue;
+            };
+            let columns = foreign_key::child_columns(&child, &check.foreign_key);
+            if !foreign_key::contains_key(database, &child, &columns, &check.key)? {
+                continue;
+            }
+            let found = match foreign_key::parent_key(database, &child, &check.foreign_key)? {
+                Some(parent) => {
+                    foreign_key::contains_key(database, &parent.table, &parent.columns, &check.key)?
+                }
+                None => false,
+            };
+            if !found {
+                return Err(MyError::ForeignKey);
+            }
+        }
+        Ok(())
+    }
+
+    // The parent key a row of a child table must find, checked when the statement ends.
+    fn check_parents(&mut self, child: &TableSchema, old: Option<&Row>, new: &Row) -> Result<()> {
+        if !self.foreign_keys {
+            return Ok(());
+        }
+        for foreign_key in child.foreign_keys() {
+            let columns = foreign_key::child_columns(child, &foreign_key);
+            let Some(key) = foreign_key::key_of(&new.values, &columns) else {
+                continue;
+            };
+            let old_key = old.and_then(|old| foreign_key::key_of(&old.values, &columns));
+            if old_key.is_some_and(|old_key| foreign_key::same_key(&old_key, &key, child.encoding))
+            {
+                continue;
+            }
+            if foreign_key::parent_key(self.database(), child, &foreign_key)?.is_none() {
+                return Err(MyError::NoSuchTable(format!(
+                    "{}.{}",
+                    self.databases[self.current].name, foreign_key.foreign_table
+                )));
+            }
+            self.key_checks.push(KeyCheck {
+                database: self.current,
+                child: child.table_name.clone(),
+                foreign_key,
+                key,
+            });
+        }
+        Ok(())
+    }
+
+    // The foreign keys of the database referencing a table, with the table holding each.
+    fn children(&mut self, parent: &TableSchema) -> Result<Vec<(TableSchema, ForeignKey)>> {
+        if !self.foreign_keys {
+            return Ok(Vec::new());
+        }
+        let mut children = Vec::new();
+        for entry in self.database().get_schema()? {
+            if entry.entry_type != "table" {
+                continue;
+            }
+            let child = self.database().get_table(&entry.name)?;
+            for foreign_key in child.foreign_keys() {
+                if foreign_key
+                    .foreign_table
+                    .eq_ignore_ascii_case(&parent.table_name)
+                {
+                    // Fails on a mismatch, as every statement on the parent does.
+                    foreign_key::parent_key(self.database(), &child, &foreign_key)?;
+                    children.push((child.clone(), foreign_key));
+                }
+            }
+        }
+        Ok(children)
+    }
+
+    /*
+        What happens to the rows of child tables when a parent row is deleted, or its key is
+        updated: the ON DELETE or ON UPDATE action of each foreign key. Rows changed by an
+        action are checked and acted upon like those of any other statement.
+    */
+    fn act_on_children(
+        &mut self,
+        parent: &TableSchema,
+        children: &[(TableSchema, ForeignKey)],
+        old: &Row,
+        new: Option<&Row>,
+    ) -> Result<()> {
+        for (child, foreign_key) in children {
+            let Some(parent_key) = foreign_key::parent_key(self.database(), child, foreign_key)?
+            else {
+                continue;
+            };
+            let Some(old_key) = foreign_key::key_of(&old.values, &parent_key.columns) else {
+                continue;
+            };
+            let new_key = new.and_then(|new| foreign_key::key_of(&new.values, &parent_key.columns));
+            if new_key
+                .is_some_and(|new_key| foreign_key::same_key(&new_key, &old_key, parent.encoding))
+            {
+                continue;
+            }
+            let action = match new {
+                Some(_) => foreign_key.on_update,
+                None => foreign_key.on_delete,
+            };
+            let columns = foreign_key::child_columns(child, foreign_key);
+            let condition = columns
+                .iter()
+                .zip(&old_key)
+                .map(|(i, value)| {
+                    Expression::Binary(
+                        Box::new(Expression::Column(child.cols[*i].name.clone())),
+                        BinaryOperator::Equal,
+                        Box::new(Expression::Literal(value.clone())),
+                    )
+                })
+                .reduce(|a, b| Expression::Binary(Box::new(a), BinaryOperator::And, Box::new(b)));
+            let set = |values: Vec<Value>| UpdateStatement {
+                schema: None,
+                table: child.table_name.clone(),
+                conflict: None,
+                assignments: columns
+                    .iter()
+                    .zip(values)
+                    .map(|(i, v)| (child.cols[*i].name.clone(), Expression::Literal(v)))
+                    .collect(),
+                condition: condition.clone(),
+                returning: None,
+            };
+            match (action, new) {
+                (ForeignKeyAction::NoAction, _) => self.key_checks.push(KeyCheck {
+                    database: self.current,
+                    child: child.table_name.clone(),
+                    foreign_key: foreign_key.clone(),
+                    key: old_key,
+                }),
+                (ForeignKeyAction::Restrict, _) => {
+                    if foreign_key::contains_key(self.database(), child, &columns, &old_key)? {
+                        return Err(MyError::ForeignKey);
+                    }
+                }
+                (ForeignKeyAction::Cascade, None) => {
+                    self.delete(&DeleteStatement {
+                        schema: None,
+                        table: child.table_name.clone(),
+                        condition,
+                        returning: None,
+                    })?;
+                }
+                (ForeignKeyAction::Cascade, Some(new)) => {
+                    let values = parent_key.columns.iter().map(|i| new.values[*i].clone());
+                    self.update(&set(values.collect()))?;
+                }
+                (ForeignKeyAction::SetNull, _) => {
+                    self.update(&set(vec![Value::Null; columns.len()]))?;
+                }
+                (ForeignKeyAction::SetDefault, _) => {
+                    let values: Vec<Value> =
+                        columns.iter().map(|i| child.defaults[*i].clone()).collect();
+                    self.update(&set(values.clone()))?;
+                    // A default equal to the old key leaves the rows as they were, so the update
+                    // checks nothing. The parent row is gone once the statement ends.
+                    if values.iter().all(|v| !v.is_null()) {
+                        self.key_checks.push(KeyCheck {
+                            database: self.current,
+                            child: child.table_name.clone(),
+                            foreign_key: foreign_key.clone(),
+                            key: values,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        The names of the columns of a query and the values of its rows. A view is replaced by
+        the rows of its own query, which are then filtered and projected like those of a table.
+    */
+    fn query(&mut self, select_cmd: &SelectStatement) -> Result<(Vec<String>, Vec<Vec<Value>>)> {
+        let condition = select_cmd.condition.as_ref();
+        let (table, view_rows) = if select_cmd.table.is_empty() {
+            if matches!(select_cmd.columns, ResultColumns::All) {
+                return Err(MyError::Schema("no tables specified".to_string()));
+            }
+            // Without FROM there is a single row, without columns.
+            let encoding = self.database().file_header.text_encoding;
+            let table = TableSchema::from_columns("", Vec::new(), encoding);
+            let row = Row {
+                rowid: 0,
+                values: Vec::new(),
+            };
+            let rows = filter(vec![row], &table, condition, &self.parameters)?;
+            (table, Some(rows))
+        } else {
+            match self.find_view(&select_cmd.table)? {
+                Some(view) => {
+                    let (table, rows) = self.view_rows(&view, condition)?;
+                    (table, Some(rows))
+                }
+                None => (self.database().get_table(&select_cmd.table)?, None),
+            }
+        };
+        let count = matches!(select_cmd.columns, ResultColumns::Count);
+        let (names, projection): (Vec<String>, Vec<Expression>) = match &select_cmd.columns {
+            ResultColumns::Count => (vec!["COUNT(*)".to_string()], Vec::new()),
+            ResultColumns::All => table
+                .cols
+                .iter()
+                .map(|c| (c.name.clone(), Expression::Column(c.name.clone())))
+                .unzip(),
+            ResultColumns::Expressions(columns) => columns.iter().cloned().unzip(),
+        };
+        let rows = match view_rows {
+            Some(rows) => rows,
+            None => {
+                // Only the columns the query reads are decoded.
+                let mut wanted = vec![false; table.cols.len()];
+                for expr in projection.iter().chain(condition) {
+                    mark_columns(expr, &table, &mut wanted);
+                }
+                self.scan_columns(&table, condition, &wanted)?
+            }
+        };
+        if count {
+            return Ok((names, vec![vec![Value::Integer(rows.len() as i64)]]));
+        }
+        let mut values = Vec::new();
+        for row in rows {
+            values.push(
+                projection
+                    .iter()
+                    .map(|e| evaluate(e, &table, &row, &self.parameters))
+                    .collect::<Result<Vec<Value>>>()?,
+            );
+        }
+        Ok((names, values))
+    }
+
+    fn find_view(&mut self, name: &str) -> Result<Option<SchemaEntry>> {
+        Ok(self
+            .database()
+            .get_schema()?
+            .into_iter()
+            .find(|e| e.entry_type == "view" && e.name.eq_ignore_ascii_case(name)))
+    }
+
+    /*
+        A view is a stored query, run again whenever the view is read. Its rows are given to the
+        outer query as those of a table named after the view, with the columns the view lists
+        or those of its query. Views outside of temp only see the objects of their own database.
+    */
+    fn expand_view(&mut self, view: &SchemaEntry) -> Result<(TableSchema, Vec<Row>)> {
+        let malformed = || MyError::Schema(format!("malformed database schema ({})", view.name));
+        let definition = match view.sql.as_deref().map(sql_query) {
+            Some(Ok((_, SqlStatement::VIEW(definition)))) => definition,
+            _ => return Err(malformed()),
+        };
+        let database = self.current;
+        let key = format!("{}.{}", self.databases[database].name, view.name).to_ascii_lowercase();
+        if self.expanding.contains(&key) {
+            return Err(MyError::Schema(format!(
+                "view {} is circularly defined",
+                view.name
+            )));
+        }
+        let schema =
+            definition.select.schema.clone().or_else(|| {
+                (database != Self::TEMP).then(|| self.databases[database].name.clone())
+            });
+        self.expanding.push(key);
+        let result = self.on(schema, definition.select.table.clone(), "table", |e| {
+            e.query(&definition.select)
+        });
+        self.expanding.pop();
+        let (names, rows) = result?;
+
+        let names = match definition.columns.len() {
+            0 => names,
+            n if n == names.len() => definition.columns,
+            n => {
+                return Err(MyError::Schema(format!(
+                    "expected {n} columns for '{}' but got {}",
+                    view.name,
+                    names.len()
+                )));
+            }
+        };
+        let encoding = self.database().file_header.text_encoding;
+        let table = TableSchema::from_columns(&view.name, names, encoding);
+        let rows = rows
+            .into_iter()
+            .enumerate()
+            .map(|(i, values)| Row {
+                rowid: i as i64 + 1,
+                values,
+            })
+            .collect();
+        Ok((table, rows))
+    }
+
+    // The rows of a view a condition holds for.
+    fn view_rows(
+        &mut self,
+        view: &SchemaEntry,
+        condition: Option<&Expression>,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let (table, rows) = self.expand_view(view)?;
+        let rows = filter(rows, &table, condition, &self.parameters)?;
+        Ok((table, rows))
+    }
+
+    // The triggers which fire on an event on a table: those of its database, then the TEMP ones.
+    fn triggers(
+        &mut self,
+        table: &str,
+        event: &TriggerEvent,
+    ) -> Result<Vec<(usize, CreateTriggerStatement)>> {
+        let mut databases = vec![self.current];
+        if self.current != Self::TEMP {
+            databases.push(Self::TEMP);
+        }
+        let mut triggers = Vec::new();
+        for i in databases {
+            for entry in self.databases[i].database.get_schema()? {
+                if entry.entry_type == "trigger" && entry.table_name.eq_ignore_ascii_case(table) {
+                    let definition = trigger::parse(&entry)?;
+                    if trigger::fires_on(&definition.event, event) {
+                        triggers.push((i, definition));
+                    }
+                }
+            }
+        }
+        Ok(triggers)
+    }
+
+    // A view can only be written to through its INSTEAD OF triggers.
+    fn instead_of_triggers(
+        &mut self,
+        view: &SchemaEntry,
+        event: &TriggerEvent,
+    ) -> Result<Vec<(usize, CreateTriggerStatement)>> {
+        let mut triggers = self.triggers(&view.name, event)?;
+        triggers.retain(|(_, t)| t.timing == TriggerTiming::InsteadOf);
+        if triggers.is_empty() {
+            return Err(MyError::Schema(format!(
+                "cannot modify {} because it is a view",
+                view.name
+            )));
+        }
+        Ok(triggers)
+    }
+
+    /*
+        Run the triggers of one timing for a row. False when one of them raised IGNORE, which
+        leaves the row alone. Like in SQLite without recursive_triggers, a trigger running does
+        not fire again.
+    */
+    fn fire(
+        &mut self,
+        triggers: &[(usize, CreateTriggerStatement)],
+        timing: TriggerTiming,
+        references: &RowReferences,
+    ) -> Result<bool> {
+        for (database, trigger) in triggers.iter().filter(|(_, t)| t.timing == timing) {
+            let key = format!(
+                "{}.{}",
+                self.databases[*database].name, trigger.trigger_name
+            )
+            .to_ascii_lowercase();
+            if self.firing.contains(&key) {
+                continue;
+            }
+            self.firing.push(key);
+            let result = self.run_trigger(*database, trigger, references);
+            self.firing.pop();
+            match result {
+                Ok(()) => {}
+                Err(MyError::Raised(RaiseAction::Ignore, _)) => return Ok(false),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    // The body of a trigger outside temp only sees the tables of its own database.
+    fn run_trigger(
+        &mut self,
+        database: usize,
+        trigger: &CreateTriggerStatement,
+        references: &RowReferences,
+    ) -> Result<()> {
+        if let Some(condition) = &trigger.condition {
+            let mut condition = condition.clone();
+            references.bind(&mut condition)?;
+            let encoding = self.database().file_header.text_encoding;
+            if evaluate_constant(&condition, encoding, &self.parameters)?.as_bool() != Some(true) {
+                return Ok(());
+            }
+        }
+        let schema = (database != Self::TEMP).then(|| self.databases[database].name.clone());
+        for statement in &trigger.body {
+            let mut statement = statement.clone();
+            references.bind_statement(&mut statement)?;
+            match statement {
+                SqlStatement::INSERT(cmd) => {
+                    self.on(schema.clone(), cmd.table.clone(), "table", |e| {
+                        e.write(|e| e.insert(&cmd).map(|_| ()))
+                    })
+                }
+                SqlStatement::UPDATE(cmd) => {
+                    self.on(schema.clone(), cmd.table.clone(), "table", |e| {
+                        e.write(|e| e.update(&cmd).map(|_| ()))
+                    })
+                }
+                SqlStatement::DELETE(cmd) => {
+                    self.on(schema.clone(), cmd.table.clone(), "table", |e| {
+                        e.write(|e| e.delete(&cmd).map(|_| ()))
+                    })
+                }
+                SqlStatement::SELECT(cmd) => {
+                    self.on(schema.clone(), cmd.table.clone(), "table", |e| {
+                        e.query(&cmd).map(|_| ())
+                    })
+                }
+                _ => Ok(()),
+            }?;
+        }
+        Ok(())
+    }
+
+    /*
+        Columns missing from the column list get their default value. The row gets the rowid
+        past the largest one of the table when it has no value, or NULL, for the INTEGER PRIMARY
+        KEY. For an AUTOINCREMENT table that is past the largest rowid the table ever had.
+    */
+    fn insert(&mut self, insert_cmd: &InsertStatement) -> Result<Vec<Vec<Value>>> {
+        let mut written = Vec::new();
+        if let Some(view) = self.find_view(&insert_cmd.table)? {
+            let triggers = self.instead_of_triggers(&view, &TriggerEvent::Insert)?;
+            let (table, _) = self.expand_view(&view)?;
+            for values in insert_values(insert_cmd, &table, &self.parameters)? {
+                let row = Row { rowid: 0, values };
+                let references = RowReferences {
+                    table: &table,
+                    old: None,
+                    new: Some(&row),
+                };
+                if self.fire(&triggers, TriggerTiming::InsteadOf, &references)? {
+                    written.push(row);
+                }
+            }
+            return returning(
+                insert_cmd.returning.as_ref(),
+                &table,
+                written,
+                &self.parameters,
+            );
+        }
+        let table = self.database().get_table(&insert_cmd.table)?;
+        if table.root_page == 1 {
+            return Err(MyError::Schema(
+                "table sqlite_master may not be modified".to_string(),
+            ));
+        }
+        let indexes = self.database().get_indexes(&table)?;
+        check_upserts(&insert_cmd.upserts, &table, &indexes)?;
+        let triggers = self.triggers(&table.table_name, &TriggerEvent::Insert)?;
+        for mut values in insert_values(insert_cmd, &table, &self.parameters)? {
+            let rowid = match table.rowid_column().map(|i| values[i].clone()) {
+                None | Some(Value::Null) => self.next_rowid(&table)?,
+                Some(Value::Integer(rowid)) => rowid,
+                Some(_) => return Err(MyError::Constraint("datatype mismatch".to_string())),
+            };
+            if let Some(i) = table.rowid_column() {
+                values[i] = Value::Integer(rowid);
+            }
+            let mut row = Row { rowid, values };
+            let references = RowReferences {
+                table: &table,
+                old: None,
+                new: Some(&row),
+            };
+            if !self.fire(&triggers, TriggerTiming::Before, &references)? {
+                continue;
+            }
+            let upserts = &insert_cmd.upserts;
+            match self.check_row(
+                &table,
+                &indexes,
+                &mut row,
+                None,
+                insert_cmd.conflict,
+                upserts,
+            )? {
+                Checked::Write => {}
+                Checked::Skip => continue,
+                Checked::Upsert(i, rowid) => {
+                    written.extend(self.upsert(&table, &indexes, &upserts[i], &row, rowid)?);
+                    continue;
+                }
+            }
+            let payload = table.encode_row(&row.values);
+            btree::table_insert(self.database(), table.root_page, rowid, &payload)?;
+            for index in &indexes {
+                let compare = |a: &[u8], b: &[u8]| index.compare(a, b);
+                let key = index.key(rowid, &row.values);
+                btree::index_insert(self.database(), index.root_page, &key, &compare)?;
+            }
+            if table.is_autoincrement() {
+                self.update_sequence(&table.table_name, rowid)?;
+            }
+            self.check_parents(&table, None, &row)?;
+            let references = RowReferences {
+                table: &table,
+                old: None,
+                new: Some(&row),
+            };
+            self.fire(&triggers, TriggerTiming::After, &references)?;
+            written.push(row);
+        }
+        returning(
+            insert_cmd.returning.as_ref(),
+            &table,
+            written,
+            &self.parameters,
+        )
+    }
+
+    /*
+        DO NOTHING leaves the row in the way as it is. DO UPDATE updates it instead, with
+        excluded.column naming the values of the row that could not be inserted, unless its
+        WHERE clause is not true for the row.
+    */
+    fn upsert(
+        &mut self,
+        table: &TableSchema,
+        indexes: &[IndexSchema],
+        upsert: &Upsert,
+        excluded: &Row,
+        rowid: i64,
+    ) -> Result<Option<Row>> {
+        let UpsertAction::Update(assignments, condition) = &upsert.action else {
+            return Ok(None);
+        };
+        let Some(payload) = btree::table_get(self.database(), table.root_page, rowid)? else {
+            return Ok(None);
+        };
+        let row = Row {
+            rowid,
+            values: table.project(rowid, &payload, |_| true)?,
+        };
+        if let Some(condition) = condition {
+            let mut condition = condition.clone();
+            trigger::bind_excluded(&mut condition, table, excluded)?;
+            if evaluate(&condition, table, &row, &self.parameters)?.as_bool() != Some(true) {
+                return Ok(None);
+            }
+        }
+        let mut values = row.values.clone();
+        for (col, expr) in assignments {
+            let index = table
+                .column_index(col)
+                .ok_or_else(|| MyError::NoSuchColumn(col.clone()))?;
+            let mut expr = expr.clone();
+            trigger::bind_excluded(&mut expr, table, excluded)?;
+            values[index] = evaluate(&expr, table, &row, &self.parameters)?
+                .apply_affinity(table.affinity(index));
+        }
+        let assigned = assignments.iter().map(|(c, _)| c.clone());
+        let triggers =
+            self.triggers(&table.table_name, &TriggerEvent::Update(assigned.collect()))?;
+        let children = self.children(table)?;
+        self.update_row(table, indexes, &triggers, &children, &row, values, None)
+    }
+
+    fn next_rowid(&mut self, table: &TableSchema) -> Result<i64> {
+        let mut largest = btree::max_rowid(self.database(), table.root_page)?.unwrap_or(0);
+        if table.is_autoincrement()
+            && let Some((_, used)) = self.sequence(&table.table_name)?
+        {
+            largest = largest.max(used);
+        }
+        largest
+            .checked_add(1)
+            .ok_or_else(|| MyError::Schema("database or disk is full".to_string()))
+    }
+
+    // The row of sqlite_sequence for a table: its rowid and the largest rowid the table used.
+    fn sequence(&mut self, table_name: &str) -> Result<Option<(i64, i64)>> {
+        let Ok(sequence) = self.database().get_table("sqlite_sequence") else {
+            return Ok(None);
+        };
+        let database = self.database();
+        let mut cursor = btree::TableCursor::from(database, sequence.root_page)?;
+        while let Some((rowid, payload)) = cursor.next(database)? {
+            let values = sequence.row_values(rowid, payload)?;
+            if values
+                .first()
+                .is_some_and(|v| v.to_string().eq_ignore_ascii_case(table_name))
+            {
+                let used = values.get(1).and_then(|v| v.as_i64()).unwrap_or(0);
+                return Ok(Some((rowid, used)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn update_sequence(&mut self, table_name: &str, rowid: i64) -> Result<()> {
+        let sequence = self.database().get_table("sqlite_sequence")?;
+        let sequence_rowid = match self.sequence(table_name)? {
+            Some((_, used)) if used >= rowid => return Ok(()),
+            Some((sequence_rowid, _)) => sequence_rowid,
+            None => btree::max_rowid(self.database(), sequence.root_page)?.unwrap_or(0) + 1,
+        };
+        let values = [Value::Text(table_name.to_string()), Value::Integer(rowid)];
+        let payload = sequence.encode_row(&values);
+        btree::table_insert(
+            self.database(),
+            sequence.root_page,
+            sequence_rowid,
+            &payload,
+        )
+    }
+
+    /*
+        Rows are collected before being modified, so the b-tree is never changed while it is
+        being walked. Index entries are removed with the old values and added back with the new
+        ones. A row whose rowid changes is moved, which fails if the new rowid is already taken.
+    */
+    fn update(&mut self, update_cmd: &UpdateStatement) -> Result<Vec<Vec<Value>>> {
+        let mut written = Vec::new();
+        let assigned = update_cmd.assignments.iter().map(|(c, _)| c.clone());
+        let event = TriggerEvent::Update(assigned.collect());
+        if let Some(view) = self.find_view(&update_cmd.table)? {
+            let triggers = self.instead_of_triggers(&view, &event)?;
+            let (table, rows) = self.view_rows(&view, update_cmd.condition.as_ref())?;
+            for row in rows {
+                let mut new = row.clone();
+                for (col, expr) in &update_cmd.assignments {
+                    let index = table
+                        .column_index(col)
+                        .ok_or_else(|| MyError::NoSuchColumn(col.clone()))?;
+                    new.values[index] = evaluate(expr, &table, &row, &self.parameters)?;
+                }
+                let references = RowReferences {
+                    table: &table,
+                    old: Some(&row),
+                    new: Some(&new),
+                };
+                if self.fire(&triggers, TriggerTiming::InsteadOf, &references)? {
+                    written.push(new);
+                }
+            }
+            return returning(
+                update_cmd.returning.as_ref(),
+                &table,
+                written,
+                &self.parameters,
+            );
+        }
+        let table = self.database().get_table(&update_cmd.table)?;
+        let indexes = self.database().get_indexes(&table)?;
+        let triggers = self.triggers(&table.table_name, &event)?;
+        let children = self.children(&table)?;
+        let mut assignments = Vec::new();
+        for (col, expr) in &update_cmd.assignments {
+            let index = table
+                .column_index(col)
+                .ok_or_else(|| MyError::NoSuchColumn(col.clone()))?;
+            assignments.push((index, expr));
+        }
+
+        for row in self.scan(&table, update_cmd.condition.as_ref())? {
+            let mut values = row.values.clone();
+            for (index, expr) in &assignments {
+                values[*index] = evaluate(expr, &table, &row, &self.parameters)?
+                    .apply_affinity(table.affinity(*index));
+            }
+            let conflict = update_cmd.conflict;
+            written.extend(self.update_row(
+                &table, &indexes, &triggers, &children, &row, values, conflict,
+            )?);
+        }
+        returning(
+            update_cmd.returning.as_ref(),
+            &table,
+            written,
+            &self.parameters,
+        )
+    }
+
+    /*
+        Write the new values of a row, firing the UPDATE triggers around it. Index entries are
+        removed with the old values and added back with the new ones. None when the row is left
+        as it is.
+    */
+    #[allow(clippy::too_many_arguments)]
+    fn update_row(
+        &mut self,
+        table: &TableSchema,
+        indexes: &[IndexSchema],
+        triggers: &[(usize, CreateTriggerStatement)],
+        children: &[(TableSchema, ForeignKey)],
+        row: &Row,
+        values: Vec<Value>,
+        conflict: Option<ConflictResolution>,
+    ) -> Result<Option<Row>> {
+        let rowid = match table.rowid_column() {
+            Some(i) => match values[i] {
+                Value::Integer(rowid) => rowid,
+                _ => return Err(MyError::Constraint("datatype mismatch".to_string())),
+            },
+            None => row.rowid,
+        };
+        let mut new = Row { rowid, values };
+        let references = RowReferences {
+            table,
+            old: Some(row),
+            new: Some(&new),
+        };
+        if !self.fire(triggers, TriggerTiming::Before, &references)? {
+            return Ok(None);
+        }
+        match self.check_row(table, indexes, &mut new, Some(row.rowid), conflict, &[])? {
+            Checked::Write => {}
+            Checked::Skip | Checked::Upsert(..) => return Ok(None),
+        }
+
+        for index in indexes {
+            let compare = |a: &[u8], b: &[u8]| index.compare(a, b);
+            let old_key = index.key(row.rowid, &row.values);
+            btree::index_delete(self.database(), index.root_page, &old_key, &compare)?;
+        }
+        if rowid != row.rowid {
+            btree::table_delete(self.database(), table.root_page, row.rowid)?;
+        }
+        let payload = table.encode_row(&new.values);
+        btree::table_insert(self.database(), table.root_page, rowid, &payload)?;
+        for index in indexes {
+            let compare = |a: &[u8], b: &[u8]| index.compare(a, b);
+            let new_key = index.key(rowid, &new.values);
+            btree::index_insert(self.database(), index.root_page, &new_key, &compare)?;
+        }
+        self.check_parents(table, Some(row), &new)?;
+        self.act_on_children(table, children, row, Some(&new))?;
+        let references = RowReferences {
+            table,
+            old: Some(row),
+            new: Some(&new),
+        };
+        self.fire(triggers, TriggerTiming::After, &references)?;
+        Ok(Some(new))
+    }
+
+    /*
+        The constraints of a row about to be written: NOT NULL, CHECK, then the rowid and each
+        UNIQUE index. The conflict resolution of the statement wins over the one of the
+        constraint, ABORT being the default. REPLACE deletes the rows in the way, or gives a
+        NOT NULL column its default value, IGNORE skips the row. A row being updated does not
+        conflict with itself.
+    */
+    fn check_row(
+        &mut self,
+        table: &TableSchema,
+        indexes: &[IndexSchema],
+        row: &mut Row,
+        old_rowid: Option<i64>,
+        conflict: Option<ConflictResolution>,
+        upserts: &[Upsert],
+    ) -> Result<Checked> {
+        let resolve = |on_conflict: Option<ConflictResolution>| {
+            conflict
+                .or(on_conflict)
+                .unwrap_or(ConflictResolution::Abort)
+        };
+        for i in 0..table.cols.len() {
+            let Some(on_conflict) = table.not_null(i) else {
+                continue;
+            };
+            if !row.values[i].is_null() {
+                continue;
+            }
+            let error = MyError::NotNull {
+                table: table.table_name.clone(),
+                column: table.cols[i].name.clone(),
+            };
+            match resolve(on_conflict) {
+                ConflictResolution::Ignore => return Ok(Checked::Skip),
+                ConflictResolution::Replace if !table.defaults[i].is_null() => {
+                    row.values[i] = table.defaults[i].clone()
+                }
+                resolution => return Err(conflict_error(resolution, error)),
+            }
+        }
+        for check in table.checks() {
+            if evaluate(&check.expr, table, row, &self.parameters)?.as_bool() != Some(false) {
+                continue;
+            }
+            let error = MyError::Check {
+                table: table.table_name.clone(),
+                constraint: check.name.unwrap_or(check.text),
+            };
+            match resolve(None) {
+                ConflictResolution::Ignore => return Ok(Checked::Skip),
+                resolution => return Err(conflict_error(resolution, error)),
+            }
+        }
+
+        let mut conflicts = Vec::new();
+        if old_rowid != Some(row.rowid)
+            && btree::table_contains(self.database(), table.root_page, row.rowid)?
+        {
+            let columns = table
+                .rowid_column()
+                .map(|i| vec![table.cols[i].name.clone()]);
+            let on_conflict = table.primary_key_on_conflict();
+            conflicts.push((row.rowid, columns.unwrap_or_default(), on_conflict));
+        }
+        for index in indexes.iter().filter(|index| index.unique) {
+            let key: Vec<Value> = index
+                .column_indices
+                .iter()
+                .map(|i| row.values[*i].clone())
+                .collect();
+            // NULLs are distinct from each other.
+            if key.iter().any(|v| v.is_null()) {
+                continue;
+            }
+            let key = Record::encode(&key, index.encoding);
+            let compare = |a: &[u8], b: &[u8]| index.compare_columns(a, b);
+            let Some(entry) = btree::index_find(self.database(), index.root_page, &key, &compare)?
+            else {
+                continue;
+            };
+            let rowid = match RecordView::from(&entry, index.encoding)?.values()?.last() {
+                Some(Value::Integer(rowid)) => *rowid,
+                _ => continue,
+            };
+            if old_rowid != Some(rowid) {
+                let columns = index.cols.iter().map(|c| c.name.clone()).collect();
+                conflicts.push((rowid, columns, index.on_conflict));
+            }
+        }
+        for (rowid, columns, on_conflict) in conflicts {
+            if let Some(i) = upserts
+                .iter()
+                .position(|u| u.target.is_empty() || same_columns(&u.target, &columns))
+            {
+                return Ok(Checked::Upsert(i, rowid));
+            }
+            match resolve(on_conflict) {
+                ConflictResolution::Replace => self.delete_row(table, indexes, rowid)?,
+                ConflictResolution::Ignore => return Ok(Checked::Skip),
+                resolution => {
+                    let error = MyError::Unique {
+                        table: table.table_name.clone(),
+                        columns,
+                    };
+                    return Err(conflict_error(resolution, error));
+                }
+            }
+        }
+        Ok(Checked::Write)
+    }
+
+    // A row REPLACE deletes. Like in SQLite without recursive_triggers no trigger fires.
+    fn delete_row(
+        &mut self,
+        table: &TableSchema,
+        indexes: &[IndexSchema],
+        rowid: i64,
+    ) -> Result<()> {
+        let Some(payload) = btree::table_get(self.database(), table.root_page, rowid)? else {
+            return Ok(());
+        };
+        let row = Row {
+            rowid,
+            values: table.project(rowid, &payload, |_| true)?,
+        };
+        for index in indexes {
+            let compare = |a: &[u8], b: &[u8]| index.compare(a, b);
+            let key = index.key(rowid, &row.values);
+            btree::index_delete(self.database(), index.root_page, &key, &compare)?;
+        }
+        btree::table_delete(self.database(), table.root_page, rowid)?;
+        let children = self.children(table)?;
+        self.act_on_children(table, &children, &row, None)
+    }
+
+    /*
+        Without a WHERE clause, and without triggers to fire, foreign keys to act on or rows to
+        return for each row, the whole table and its indexes are emptied at once.
+    */
+    fn delete(&mut self, delete_cmd: &DeleteStatement) -> Result<Vec<Vec<Value>>> {
+        let mut deleted = Vec::new();
+        if let Some(view) = self.find_view(&delete_cmd.table)? {
+            let triggers = self.instead_of_triggers(&view, &TriggerEvent::Delete)?;
+            let (table, rows) = self.view_rows(&view, delete_cmd.condition.as_ref())?;
+            for row in rows {
+                let references = RowReferences {
+                    table: &table,
+                    old: Some(&row),
+                    new: None,
+                };
+                if self.fire(&triggers, TriggerTiming::InsteadOf, &references)? {
+                    deleted.push(row);
+                }
+            }
+            return returning(
+                delete_cmd.returning.as_ref(),
+                &table,
+                deleted,
+                &self.parameters,
+            );
+        }
+        let table = self.database().get_table(&delete_cmd.table)?;
+        let indexes = self.database().get_indexes(&table)?;
+        let triggers = self.triggers(&table.table_name, &TriggerEvent::Delete)?;
+        let children = self.children(&table)?;
+        if delete_cmd.condition.is_none()
+            && triggers.is_empty()
+            && children.is_empty()
+            && delete_cmd.returning.is_none()
+        {
+            btree::clear_tree(self.database(), table.root_page)?;
+            for index in &indexes {
+                btree::clear_tree(self.database(), index.root_page)?;
+            }
+            return Ok(Vec::new());
+        }
+
+        for row in self.scan(&table, delete_cmd.condition.as_ref())? {
+            // A trigger or a foreign key action may have deleted the row already.
+            if !btree::table_contains(self.database(), table.root_page, row.rowid)? {
+                continue;
+            }
+            let references = RowReferences {
+                table: &table,
+                old: Some(&row),
+                new: None,
+            };
+            if !self.fire(&triggers, TriggerTiming::Before, &references)? {
+                continue;
+            }
+            for index in &indexes {
+                let compare = |a: &[u8], b: &[u8]| index.compare(a, b);
+                let key = index.key(row.rowid, &row.values);
+                btree::index_delete(self.database(), index.root_page, &key, &compare)?;
+            }
+            btree::table_delete(self.database(), table.root_page, row.rowid)?;
+            self.act_on_children(&table, &children, &row, None)?;
+            self.fire(&triggers, TriggerTiming::After, &references)?;
+            deleted.push(row);
+        }
+        returning(
+            delete_cmd.returning.as_ref(),
+            &table,
+            deleted,
+            &self.parameters,
+        )
+    }
+
+    /*
+        A new table or view needs a name no other object of its database has, and none of those
+        reserved for SQLite. False when the object already exists and IF NOT EXISTS allows it.
+    */
+    fn check_new_name(&mut self, name: &str, if_not_exists: bool) -> Result<bool> {
+        if name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(format!(
+                "object name reserved for internal use: {name}"
+            )));
+        }
+        match self
+            .database()
+            .get_schema()?
+            .into_iter()
+            .find(|e| e.entry_type != "trigger" && e.name.eq_ignore_ascii_case(name))
+        {
+            None => Ok(true),
+            Some(existing) if existing.entry_type == "index" => Err(MyError::Schema(format!(
+                "there is already an index named {name}"
+            ))),
+            Some(_) if if_not_exists => Ok(false),
+            Some(existing) => Err(MyError::Schema(format!(
+                "{} {name} already exists",
+                existing.entry_type
+            ))),
+        }
+    }
+
+    /*
+        Besides its own b-tree a table gets an index for each of its UNIQUE and PRIMARY KEY
+        constraints, and the first AUTOINCREMENT table of a database creates sqlite_sequence.
+    */
+    fn create_table(&mut self, creation_cmd: CreateStatement) -> Result<()> {
+        let name = creation_cmd.table_name;
+        if !self.check_new_name(&name, creation_cmd.if_not_exists)? {
+            return Ok(());
+        }
+        let encoding = self.database().file_header.text_encoding;
+        let table = TableSchema::from(&creation_cmd.sql, 0, encoding)
+            .ok_or_else(|| MyError::Schema(format!("malformed table definition: {name}")))?;
+        for (i, col) in table.cols.iter().enumerate() {
+            if table.cols[..i]
+                .iter()
+                .any(|c| c.name.eq_ignore_ascii_case(&col.name))
+            {
+                return Err(MyError::Schema(format!(
+                    "duplicate column name: {}",
+                    col.name
+                )));
+            }
+        }
+        let autoincrement = table.cols.iter().position(|c| {
+            c.constraints.iter().any(|constraint| {
+                matches!(
+                    constraint,
+                    ColumnConstraint::PrimaryKey {
+                        autoincrement: true,
+                        ..
+                    }
+                )
+            })
+        });
+        if autoincrement.is_some() && autoincrement != table.rowid_column() {
+            return Err(MyError::Schema(
+                "AUTOINCREMENT is only allowed on an INTEGER PRIMARY KEY".to_string(),
+            ));
+        }
+
+        let root_page = btree::create_tree(self.database(), PageType::TableLeaf)?;
+        self.database().add_schema_entry(&SchemaEntry {
+            entry_type: "table".to_string(),
+            name: name.clone(),
+            table_name: name.clone(),
+            root_page,
+            sql: Some(creation_cmd.sql),
+        })?;
+        for n in 1..=table.unique_column_sets().len() {
+            let root_page = btree::create_tree(self.database(), PageType::IndexLeaf)?;
+            self.database().add_schema_entry(&SchemaEntry {
+                entry_type: "index".to_string(),
+                name: format!("sqlite_autoindex_{name}_{n}"),
+                table_name: name.clone(),
+                root_page,
+                sql: None,
+            })?;
+        }
+        if autoincrement.is_some() && self.database().get_table("sqlite_sequence").is_err() {
+            let root_page = btree::create_tree(self.database(), PageType::TableLeaf)?;
+            self.database().add_schema_entry(&SchemaEntry {
+                entry_type: "table".to_string(),
+                name: "sqlite_sequence".to_string(),
+                table_name: "sqlite_sequence".to_string(),
+                root_page,
+                sql: Some(TableSchema::SEQUENCE_TABLE_SQL.to_string()),
+            })?;
+        }
+        Ok(())
+    }
+
+    // Only the definition of a view is stored, the query it names is not checked until it runs.
+    fn create_view(&mut self, view_cmd: CreateViewStatement) -> Result<()> {
+        let name = view_cmd.view_name;
+        if !self.check_new_name(&name, view_cmd.if_not_exists)? {
+            return Ok(());
+        }
+        self.database().add_schema_entry(&SchemaEntry {
+            entry_type: "view".to_string(),
+            name: name.clone(),
+            table_name: name,
+            root_page: 0,
+            sql: Some(view_cmd.sql),
+        })
+    }
+
+    /*
+        Triggers have names of their own, a trigger may be named like a table. A TEMP trigger
+        can be on a table of any database, any other one only on a table of its own database.
+    */
+    fn create_trigger(&mut self, trigger_cmd: CreateTriggerStatement) -> Result<()> {
+        let name = &trigger_cmd.trigger_name;
+        if name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(format!(
+                "object name reserved for internal use: {name}"
+            )));
+        }
+        if self
+            .database()
+            .get_schema()?
+            .iter()
+            .any(|e| e.entry_type == "trigger" && e.name.eq_ignore_ascii_case(name))
+        {
+            return match trigger_cmd.if_not_exists {
+                true => Ok(()),
+                false => Err(MyError::Schema(format!("trigger {name} already exists"))),
+            };
+        }
+        trigger::check_body(&trigger_cmd)?;
+        let table_name = &trigger_cmd.table_name;
+        if table_name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(
+                "cannot create trigger on system table".to_string(),
+            ));
+        }
+        let database = match self.current {
+            Self::TEMP => self.locate(table_name, "table")?,
+            current => current,
+        };
+        let Some(target) = self.databases[database]
+            .database
+            .get_schema()?
+            .into_iter()
+            .find(|e| is_relation(&e.entry_type) && e.name.eq_ignore_ascii_case(table_name))
+        else {
+            return Err(MyError::NoSuchTable(format!(
+                "{}.{table_name}",
+                self.databases[database].name
+            )));
+        };
+        let is_view = target.entry_type == "view";
+        if is_view != (trigger_cmd.timing == TriggerTiming::InsteadOf) {
+            return Err(MyError::Schema(format!(
+                "cannot create {} trigger on {}: {}",
+                trigger_cmd.timing.name(),
+                target.entry_type,
+                target.name
+            )));
+        }
+        self.database().add_schema_entry(&SchemaEntry {
+            entry_type: "trigger".to_string(),
+            name: name.clone(),
+            table_name: target.name,
+            root_page: 0,
+            sql: Some(trigger_cmd.sql),
+        })
+    }
+
+    /*
+        The index is built by sorting the keys of every existing row, a UNIQUE index fails on
+        the first pair of equal keys. Names starting with "sqlite_" are reserved for SQLite.
+    */
+    fn create_index(&mut self, index_cmd: CreateIndexStatement) -> Result<()> {
+        let name = &index_cmd.index_name;
+        if let Some(existing) = self
+            .database()
+            .get_schema()?
+            .into_iter()
+            .find(|e| e.entry_type != "trigger" && e.name.eq_ignore_ascii_case(name))
+        {
+            return match existing.entry_type.as_str() {
+                "index" if index_cmd.if_not_exists => Ok(()),
+                "index" => Err(MyError::Schema(format!("index {name} already exists"))),
+                _ => Err(MyError::Schema(format!(
+                    "there is already a table named {name}"
+                ))),
+            };
+        }
+        if name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(format!(
+                "object name reserved for internal use: {name}"
+            )));
+        }
+        if self.find_view(&index_cmd.table_name)?.is_some() {
+            return Err(MyError::Schema("views may not be indexed".to_string()));
+        }
+        let table = self.database().get_table(&index_cmd.table_name)?;
+        if table.root_page == 1 {
+            return Err(MyError::Schema(
+                "table sqlite_master may not be indexed".to_string(),
+            ));
+        }
+        if let Some(col) = index_cmd
+            .cols
+            .iter()
+            .find(|c| table.column_index(&c.name).is_none())
+        {
+            return Err(MyError::NoSuchColumn(col.name.clone()));
+        }
+
+        let root_page = btree::create_tree(self.database(), PageType::IndexLeaf)?;
+        let entry = SchemaEntry {
+            entry_type: "index".to_string(),
+            name: name.clone(),
+            table_name: table.table_name.clone(),
+            root_page,
+            sql: Some(index_cmd.sql),
+        };
+        let index = IndexSchema::from_entry(&entry, &table)
+            .ok_or_else(|| MyError::Schema(format!("malformed index: {name}")))?;
+        let mut keys = Vec::new();
+        let database = self.database();
+        let mut cursor = btree::TableCursor::from(database, table.root_page)?;
+        while let Some((rowid, payload)) = cursor.next(database)? {
+            keys.push(index.key(rowid, &table.row_values(rowid, payload)?));
+        }
+        let mut failed = None;
+        keys.sort_by(|a, b| {
+            index.compare(a, b).unwrap_or_else(|e| {
+                failed.get_or_insert(e);
+                std::cmp::Ordering::Equal
+            })
+        });
+        if let Some(e) = failed {
+            return Err(e);
+        }
+        if index.unique && keys.windows(2).any(|w| index.conflicts(&w[0], &w[1])) {
+            return Err(index.unique_error());
+        }
+        btree::index_build(self.database(), root_page, &keys)?;
+        self.database().add_schema_entry(&entry)
+    }
+
+    /*
+        Dropping a table also drops its indexes, its triggers and its row in sqlite_sequence.
+        With foreign keys on, its rows are deleted first, without firing triggers.
+        Indexes created for UNIQUE and PRIMARY KEY constraints only go away with their table. A
+        view or a trigger has no b-tree, dropping it only removes its row from sqlite_schema.
+    */
+    fn drop(&mut self, drop_cmd: DropStatement) -> Result<()> {
+        let name = &drop_cmd.name;
+        if drop_cmd.object_type == ObjectType::Table
+            && ["sqlite_schema", "sqlite_master"]
+                .iter()
+                .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            return Err(MyError::Schema(
+                "table sqlite_master may not be dropped".to_string(),
+            ));
+        }
+        let schema = self.database().get_schema()?;
+        let entry_type = drop_cmd.object_type.name();
+        if let Some(other) = schema.iter().find(|e| {
+            is_relation(entry_type)
+                && is_relation(&e.entry_type)
+                && e.entry_type != entry_type
+                && e.name.eq_ignore_ascii_case(name)
+        }) {
+            return Err(MyError::Schema(format!(
+                "use DROP {} to delete {} {}",
+                other.entry_type.to_ascii_uppercase(),
+                other.entry_type,
+                other.name
+            )));
+        }
+        let Some(entry) = schema
+            .iter()
+            .find(|e| e.entry_type == entry_type && e.name.eq_ignore_ascii_case(name))
+        else {
+            return match (drop_cmd.if_exists, drop_cmd.object_type) {
+                (true, _) => Ok(()),
+                (false, ObjectType::Table) => Err(MyError::NoSuchTable(name.clone())),
+                (false, ObjectType::Index) => Err(MyError::NoSuchIndex(name.clone())),
+                (false, ObjectType::View) => Err(MyError::Schema(format!("no such view: {name}"))),
+                (false, ObjectType::Trigger) => {
+                    Err(MyError::Schema(format!("no such trigger: {name}")))
+                }
+            };
+        };
+        match drop_cmd.object_type {
+            ObjectType::Index if entry.sql.is_none() => Err(MyError::Schema(
+                "index associated with UNIQUE or PRIMARY KEY constraint cannot be dropped"
+                    .to_string(),
+            )),
+            ObjectType::Index | ObjectType::Trigger => self.database().drop_schema_entry(entry),
+            ObjectType::View => {
+                for dependent in schema.iter().filter(|e| {
+                    e.entry_type == "trigger" && e.table_name.eq_ignore_ascii_case(&entry.name)
+                }) {
+                    self.database().drop_schema_entry(dependent)?;
+                }
+                self.database().drop_schema_entry(entry)
+            }
+            ObjectType::Table if name.to_ascii_lowercase().starts_with("sqlite_") => Err(
+                MyError::Schema(format!("table {} may not be dropped", entry.name)),
+            ),
+            ObjectType::Table => {
+                for dependent in schema.iter().filter(|e| {
+                    e.entry_type != "table" && e.table_name.eq_ignore_ascii_case(&entry.name)
+                }) {
+                    self.database().drop_schema_entry(dependent)?;
+                }
+                // Rows of other tables referencing the table are dealt with like for DELETE.
+                let table = self.database().get_table(&entry.name)?;
+                if !self.children(&table)?.is_empty() {
+                    self.delete(&DeleteStatement {
+                        schema: None,
+                        table: entry.name.clone(),
+                        condition: None,
+                        returning: None,
+                    })?;
+                }
+                // Looked up again, dropping the dependents can have moved it with auto-vacuum.
+                let sequence = self
+                    .database()
+                    .get_schema()?
+                    .into_iter()
+                    .find(|e| e.name == "sqlite_sequence");
+                if let Some(sequence) = sequence {
+                    for (rowid, payload) in btree::table_rows(self.database(), sequence.root_page)?
+                    {
+                        let encoding = self.database().file_header.text_encoding;
+                        let values = RecordView::from(&payload, encoding)?.values()?;
+                        if values
+                            .first()
+                            .is_some_and(|v| v.to_string().eq_ignore_ascii_case(&entry.name))
+                        {
+                            btree::table_delete(self.database(), sequence.root_page, rowid)?;
+                        }
+                    }
+                }
+                self.database().drop_schema_entry(entry)
+            }
+        }
+    }
+
+    /*
+        ALTER TABLE only edits sqlite_schema, except DROP COLUMN which rewrites every row of
+        the table. Rows stored before ADD COLUMN are left as they are, the new column reads as
+        its default value.
+    */
+    fn alter(&mut self, alter_cmd: AlterTableStatement) -> Result<()> {
+        let name = &alter_cmd.table_name;
+        if ["sqlite_schema", "sqlite_master"]
+            .iter()
+            .any(|n| n.eq_ignore_ascii_case(name))
+        {
+            return Err(MyError::Schema(
+                "table sqlite_master may not be altered".to_string(),
+            ));
+        }
+        if let Some(view) = self.find_view(name)? {
+            return Err(MyError::Schema(format!(
+                "view {} may not be altered",
+                view.name
+            )));
+        }
+        let table = self.database().get_table(name)?;
+        if table.table_name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(format!(
+                "table {} may not be altered",
+                table.table_name
+            )));
+        }
+        match alter_cmd.action {
+            AlterAction::RenameTable(new_name) => self.rename_table(&table, &new_name),
+            AlterAction::RenameColumn(old, new) => self.rename_column(&table, &old, &new),
+            AlterAction::AddColumn(column, sql) => self.add_column(&table, &column, &sql),
+            AlterAction::DropColumn(column) => self.drop_column(&table, &column),
+        }
+    }
+
+    // The table's indexes, including the automatic ones named after it, follow the table.
+    fn rename_table(&mut self, table: &TableSchema, new_name: &str) -> Result<()> {
+        if self
+            .database()
+            .get_schema()?
+            .iter()
+            .any(|e| e.entry_type != "trigger" && e.name.eq_ignore_ascii_case(new_name))
+        {
+            return Err(MyError::Schema(format!(
+                "there is already another table or index with this name: {new_name}"
+            )));
+        }
+        if new_name.to_ascii_lowercase().starts_with("sqlite_") {
+            return Err(MyError::Schema(format!(
+                "object name reserved for internal use: {new_name}"
+            )));
+        }
+        let old_name = &table.table_name;
+        let autoindex_prefix = format!("sqlite_autoindex_{old_name}_");
+        self.database().update_schema(|entry| {
+            if !entry.table_name.eq_ignore_ascii_case(old_name) {
+                return;
+            }
+            if entry.entry_type == "table" {
+                entry.name = new_name.to_string();
+            } else if let Some(number) = entry.name.strip_prefix(&autoindex_prefix) {
+                entry.name = format!("sqlite_autoindex_{new_name}_{number}");
+            }
+            entry.table_name = new_name.to_string();
+            entry.sql = entry
+                .sql
+                .as_deref()
+                .map(|sql| alter::rename_table(sql, new_name));
+        })?;
+
+        let sequence = self.database().get_table("sqlite_sequence");
+        if let Ok(sequence) = sequence {
+            for (rowid, payload) in btree::table_rows(self.database(), sequence.root_page)? {
+                let mut values = RecordView::from(&payload, sequence.encoding)?.values()?;
+                if values
+                    .first()
+                    .is_some_and(|v| v.to_string().eq_ignore_ascii_case(old_name))
+                {
+                    values[0] = Value::Text(new_name.to_string());
+                    let payload = Record::encode(&values, sequence.encoding);
+                    btree::table_insert(self.database(), sequence.root_page, rowid, &payload)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_column(&mut self, table: &TableSchema, old: &str, new: &str) -> Result<()> {
+        let index = table
+            .column_index(old)
+            .ok_or_else(|| MyError::NoSuchColumn(format!("\"{old}\"")))?;
+        if table.column_index(new).is_some_and(|i| i != index) {
+            return Err(MyError::Schema(format!(
+                "error in table {} after rename: duplicate column name: {new}",
+                table.table_name
+            )));
+        }
+        let old = &table.cols[index].name;
+        self.database().update_schema(|entry| {
+            if entry.entry_type != "trigger"
+                && entry.table_name.eq_ignore_ascii_case(&table.table_name)
+            {
+                entry.sql = entry
+                    .sql
+                    .as_deref()
+                    .map(|sql| alter::rename_column(sql, old, new));
+            }
+        })
+    }
+
+    /*
+        The added column may not need a value that existing rows lack: it can't be part of a
+        key, and its default must be a constant, which can't be NULL for a NOT NULL column
+        unless the table is empty.
+    */
+    fn add_column(
+        &mut self,
+        table: &TableSchema,
+        column: &ColumnDefinition,
+        definition: &str,
+    ) -> Result<()> {
+        let name = &column.name;
+        if table.column_index(name).is_some() {
+            return Err(MyError::Schema(format!("duplicate column name: {name}")));
+        }
+        let mut default = None;
+        for constraint in &column.constraints {
+            match constraint {
+                ColumnConstraint::PrimaryKey { .. } => {
+                    return Err(MyError::Schema(
+                        "Cannot add a PRIMARY KEY column".to_string(),
+                    ));
+                }
+                ColumnConstraint::Unique(_) => {
+                    return Err(MyError::Schema("Cannot add a UNIQUE column".to_string()));
+                }
+                ColumnConstraint::Default(expr, _) => default = Some(expr),
+                _ => {}
+            }
+        }
+        if default.is_some_and(references_column) {
+            return Err(MyError::Schema(format!(
+                "default value of column [{name}] is not constant"
+            )));
+        }
+        let has_rows = !btree::is_empty(self.database(), table.root_page)?;
+        let constant = match default {
+            None | Some(Expression::Literal(_)) => true,
+            Some(Expression::Unary(UnaryOperator::Negate, operand)) => {
+                matches!(operand.as_ref(), Expression::Literal(_))
+            }
+            Some(_) => false,
+        };
+        if has_rows && !constant {
+            return Err(MyError::Schema(
+                "Cannot add a column with non-constant default".to_string(),
+            ));
+        }
+        let not_null = column
+            .constraints
+            .iter()
+            .any(|c| matches!(c, ColumnConstraint::NotNull(_)));
+        if has_rows
+            && not_null
+            && default.is_none_or(|e| matches!(e, Expression::Literal(Value::Null)))
+        {
+            return Err(MyError::Schema(
+                "Cannot add a NOT NULL column with default value NULL".to_string(),
+            ));
+        }
+        self.database().update_schema(|entry| {
+            if entry.entry_type == "table" && entry.name.eq_ignore_ascii_case(&table.table_name) {
+                entry.sql = entry
+                    .sql
+                    .as_deref()
+                    .map(|sql| alter::add_column(sql, definition));
+            }
+        })
+    }
+
+    /*
+        A column can only be dropped when nothing else depends on it. Every row is written
+        again without the column's value.
+    */
+    fn drop_column(&mut self, table: &TableSchema, column: &str) -> Result<()> {
+        let index = table
+            .column_index(column)
+            .ok_or_else(|| MyError::NoSuchColumn(format!("\"{column}\"")))?;
+        let name = &table.cols[index].name;
+        let in_constraint = |cols: &[String]| cols.iter().any(|c| c.eq_ignore_ascii_case(name));
+        for constraint in &table.cols[index].constraints {
+            match constraint {
+                ColumnConstraint::PrimaryKey { .. } => {
+                    return Err(MyError::Schema(format!(
+                        "cannot drop PRIMARY KEY column: \"{name}\""
+                    )));
+                }
+                ColumnConstraint::Unique(_) => {
+                    return Err(MyError::Schema(format!(
+                        "cannot drop UNIQUE column: \"{name}\""
+                    )));
+                }
+                _ => {}
+            }
+        }
+        for constraint in &table.constraints {
+            match constraint {
+                TableConstraint::PrimaryKey(cols, _) if in_constraint(cols) => {
+                    return Err(MyError::Schema(format!(
+                        "cannot drop PRIMARY KEY column: \"{name}\""
+                    )));
+                }
+                TableConstraint::Unique(cols, _) if in_constraint(cols) => {
+                    return Err(MyError::Schema(format!(
+                        "error in table {} after drop column: no such column: {name}",
+                        table.table_name
+                    )));
+                }
+                _ => {}
+            }
+        }
+        if table.cols.len() == 1 {
+            return Err(MyError::Schema(format!(
+                "cannot drop column \"{name}\": no other columns exist"
+            )));
+        }
+        if let Some(dependent) = self
+            .database()
+            .get_indexes(table)?
+            .iter()
+            .find(|i| i.column_indices.contains(&index))
+        {
+            return Err(MyError::Schema(format!(
+                "error in index {} after drop column: no such column: {name}",
+                dependent.index_name
+            )));
+        }
+
+        self.database().update_schema(|entry| {
+            if entry.entry_type == "table" && entry.name.eq_ignore_ascii_case(&table.table_name) {
+                entry.sql = entry
+                    .sql
+                    .as_deref()
+                    .map(|sql| alter::drop_column(sql, name));
+            }
+        })?;
+        let altered = self.database().get_table(&table.table_name)?;
+        for (rowid, payload) in btree::table_rows(self.database(), table.root_page)? {
+            let mut values = table.row_values(rowid, &payload)?;
+            values.remove(index);
+            let payload = altered.encode_row(&values);
+            btree::table_insert(self.database(), table.root_page, rowid, &payload)?;
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, table: &TableSchema, condition: Option<&Expression>) -> Result<Vec<Row>> {
+        self.scan_columns(table, condition, &vec![true; table.cols.len()])
+    }
+
+    // Like scan, but only the columns marked in `wanted` are read, the others are left NULL.
+    fn scan_columns(
+        &mut self,
+        table: &TableSchema,
+        condition: Option<&Expression>,
+        wanted: &[bool],
+    ) -> Result<Vec<Row>> {
+        let mut rows = Vec::new();
+        // Each row is filtered as it is read, only those kept are copied out of their page.
+        let database = &mut self.databases[self.current].database;
+        let mut cursor = btree::TableCursor::from(database, table.root_page)?;
+        while let Some((rowid, payload)) = cursor.next(database)? {
+            let row = Row {
+                rowid,
+                values: table.project(rowid, payload, |i| wanted[i])?,
+            };
+            let keep = match condition {
+                Some(condition) => {
+                    evaluate(condition, table, &row, &self.parameters)?.as_bool() == Some(true)
+                }
+                None => true,
+            };
+            if keep {
+                rows.push(row);
+            }
+        }
+        Ok(rows)
+    }
+}
+
+// Tables and views share one namespace.
+fn is_relation(entry_type: &str) -> bool {
+    matches!(entry_type, "table" | "view")
+}
+
+// TEMP objects go to the temp database, naming another one for them is an error.
+fn creation_schema(temp: bool, schema: &Option<String>) -> Result<Option<String>> {
+    match (temp, schema) {
+        (true, Some(schema)) if !schema.eq_ignore_ascii_case("temp") => Err(MyError::Schema(
+            "temporary table name must be unqualified".to_string(),
+        )),
+        (true, _) => Ok(Some("temp".to_string())),
+        (false, schema) => Ok(schema.clone()),
+    }
+}
+
+// The rows a WHERE clause holds for.
+fn filter(
+    rows: Vec<Row>,
+    table: &TableSchema,
+    condition: Option<&Expression>,
+    parameters: &[Value],
+) -> Result<Vec<Row>> {
+    let Some(condition) = condition else {
+        return Ok(rows);
+    };
+    let mut kept = Vec::new();
+    for row in rows {
+        if evaluate(condition, table, &row, parameters)?.as_bool() == Some(true) {
+            kept.push(row);
+        }
+    }
+    Ok(kept)
+}
+
+// An expression outside of any row, like the values of INSERT.
+fn evaluate_constant(
+    expr: &Expression,
+    encoding: TextEncoding,
+    parameters: &[Value],
+) -> Result<Value> {
+    let table = TableSchema::from_columns("", Vec::new(), encoding);
+    let row = Row {
+        rowid: 0,
+        values: Vec::new(),
+    };
+    evaluate(expr, &table, &row, parameters)
+}
+
+/*
+    The full rows INSERT adds, in the order of the columns of the table. Columns left out of
+    the column list get their default value.
+*/
+fn insert_values(
+    insert_cmd: &InsertStatement,
+    table: &TableSchema,
+    parameters: &[Value],
+) -> Result<Vec<Vec<Value>>> {
+    let positions = insert_cmd
+        .columns
+        .iter()
+        .map(|c| {
+            table.column_index(c).ok_or_else(|| {
+                MyError::Schema(format!(
+                    "table {} has no column named {c}",
+                    table.table_name
+                ))
+            })
+        })
+        .collect::<Result<Vec<usize>>>()?;
+    let mut rows = Vec::new();
+    for exprs in &insert_cmd.rows {
+        if exprs.len() != insert_cmd.rows[0].len() {
+            return Err(MyError::Schema(
+                "all VALUES must have the same number of terms".to_string(),
+            ));
+        }
+        if positions.is_empty() && exprs.len() != table.cols.len() {
+            return Err(MyError::Schema(format!(
+                "table {} has {} columns but {} values were supplied",
+                table.table_name,
+                table.cols.len(),
+                exprs.len()
+            )));
+        }
+        if !positions.is_empty() && exprs.len() != positions.len() {
+            return Err(MyError::Schema(format!(
+                "{} values for {} columns",
+                exprs.len(),
+                positions.len()
+            )));
+        }
+        let mut values = table.defaults.clone();
+        for (i, expr) in exprs.iter().enumerate() {
+            let column = if positions.is_empty() {
+                i
+            } else {
+                positions[i]
+            };
+            values[column] = evaluate_constant(expr, table.encoding, parameters)?
+                .apply_affinity(table.affinity(column));
+        }
+        rows.push(values);
+    }
+    Ok(rows)
+}
+
+/*
+    The values of the RETURNING clause for the rows a statement wrote, or deleted: the rows
+    as they were stored, after BEFORE triggers and conflict resolution had their say.
+*/
+fn returning(
+    columns: Option<&ResultColumns>,
+    table: &TableSchema,
+    rows: Vec<Row>,
+    parameters: &[Value],
+) -> Result<Vec<Vec<Value>>> {
+    match columns {
+        None => Ok(Vec::new()),
+        Some(ResultColumns::Count) => Err(MyError::Schema(
+            "misuse of aggregate function count()".to_string(),
+        )),
+        Some(ResultColumns::All) => Ok(rows.into_iter().map(|row| row.values).collect()),
+        Some(ResultColumns::Expressions(columns)) => rows
+            .iter()
+            .map(|row| {
+                columns
+                    .iter()
+                    .map(|(_, expr)| evaluate(expr, table, row, parameters))
+                    .collect()
+            })
+            .collect(),
+    }
+}
+
+// ROLLBACK and FAIL end the statement in their own way, see write().
+fn conflict_error(resolution: ConflictResolution, error: MyError) -> MyError {
+    match resolution {
+        ConflictResolution::Rollback | ConflictResolution::Fail => {
+            MyError::Conflict(resolution, Box::new(error))
+        }
+        _ => error,
+    }
+}
+
+fn same_columns(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .all(|x| b.iter().any(|y| y.eq_ignore_ascii_case(x)))
+}
+
+// The target of an upsert must be the INTEGER PRIMARY KEY or the columns of a UNIQUE index.
+fn check_upserts(upserts: &[Upsert], table: &TableSchema, indexes: &[IndexSchema]) -> Result<()> {
+    let mut targets: Vec<Vec<String>> = indexes
+        .iter()
+        .filter(|index| index.unique)
+        .map(|index| index.cols.iter().map(|c| c.name.clone()).collect())
+        .collect();
+    targets.extend(
+        table
+            .rowid_column()
+            .map(|i| vec![table.cols[i].name.clone()]),
+    );
+    for upsert in upserts {
+        if !upsert.target.is_empty() && !targets.iter().any(|t| same_columns(t, &upsert.target)) {
+            return Err(MyError::Schema(
+                "ON CONFLICT clause does not match any PRIMARY KEY or UNIQUE constraint"
+                    .to_string(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn references_column(expr: &Expression) -> bool {
+    match expr {
+        Expression::Literal(_) | Expression::Raise(..) | Expression::Parameter(_) => false,
+        Expression::Column(_) | Expression::TableColumn(..) => true,
+        Expression::Unary(_, operand) | Expression::IsNull(operand, _) => {
+            references_column(operand)
+        }
+        Expression::Binary(lhs, _, rhs) => references_column(lhs) || references_column(rhs),
+    }
+}
+
+// Mark the columns of the table an expression reads.
+fn mark_columns(expr: &Expression, table: &TableSchema, wanted: &mut [bool]) {
+    match expr {
+        Expression::Literal(_) | Expression::Raise(..) | Expression::Parameter(_) => {}
+        Expression::Column(name) | Expression::TableColumn(_, name) => {
+            if let Some(i) = table.column_index(name) {
+                wanted[i] = true;
+            }
+        }
+        Expression::Unary(_, operand) | Expression::IsNull(operand, _) => {
+            mark_columns(operand, table, wanted)
+        }
+        Expression::Binary(lhs, _, rhs) => {
+            mark_columns(lhs, table, wanted);
+            mark_columns(rhs, table, wanted);
+        }
+    }
+}
+
+// Pragma arguments are read like SQLite's atoi: a leading integer, anything else is zero.
+fn pragma_integer(argument: &str) -> i64 {
+    let argument = argument.trim();
+    let sign = argument.starts_with(['-', '+']) as usize;
+    let digits = argument[sign..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(argument.len(), |n| n + sign);
+    argument[..digits].parse().unwrap_or(0)
+}
+
+// ON, YES, TRUE or a number other than zero.
+fn pragma_boolean(argument: &str) -> bool {
+    let argument = argument.trim();
+    ["on", "yes", "true"]
+        .iter()
+        .any(|b| b.eq_ignore_ascii_case(argument))
+        || pragma_integer(argument) != 0
+}
+
+/*
+    The value of an expression for a row of the table. A parameter has the value bound to its
+    number, NULL when nothing was, the statement numbered all of them when it was prepared.
+*/
+pub fn evaluate(
+    expr: &Expression,
+    table: &TableSchema,
+    row: &Row,
+    parameters: &[Value],
+) -> Result<Value> {
+    Ok(match expr {
+        Expression::Literal(value) => value.clone(),
+        Expression::Column(name) => match table.column_index(name) {
+            Some(i) => row.values[i].clone(),
+            None if ["rowid", "oid", "_rowid_"]
+                .iter()
+                .any(|r| r.eq_ignore_ascii_case(name)) =>
+            {
+                Value::Integer(row.rowid)
+            }
+            None => return Err(MyError::NoSuchColumn(name.clone())),
+        },
+        Expression::TableColumn(table_name, name) => {
+            if !table_name.eq_ignore_ascii_case(&table.table_name) {
+                return Err(MyError::NoSuchColumn(format!("{table_name}.{name}")));
+            }
+            evaluate(&Expression::Column(name.clone()), table, row, parameters)?
+        }
+        Expression::Raise(action, message) => {
+            return Err(MyError::Raised(*action, message.clone()));
+        }
+        Expression::Parameter(Parameter::Numbered(number)) => {
+            parameters.get(number - 1).cloned().unwrap_or(Value::Null)
+        }
+        Expression::Parameter(_) => return Err(MyError::Range),
+        Expression::Unary(UnaryOperator::Negate, operand) => {
+            match numeric(evaluate(operand, table, row, parameters)?) {
+                Value::Integer(i) => i
+                    .checked_neg()
+                    .map_or(Value::Real(-(i as f64)), Value::Integer),
+                Value::Real(f) => Value::Real(-f),
+                other => other,
+            }
+        }
+        Expression::Unary(UnaryOperator::Not, operand) => {
+            match evaluate(operand, table, row, parameters)?.as_bool() {
+                Some(b) => Value::Integer(!b as i64),
+                None => Value::Null,
+            }
+        }
+        Expression::IsNull(operand, negated) => Value::Integer(
+            (evaluate(operand, table, row, parameters)?.is_null() != *negated) as i64,
+        ),
+        Expression::Binary(lhs, op, rhs) => {
+            let lhs = evaluate(lhs, table, row, parameters)?;
+            let rhs = evaluate(rhs, table, row, parameters)?;
+            binary_operation(*op, lhs, rhs, table.encoding)
+        }
+    })
+}
+
+// Text operands of arithmetic are converted to numbers, anything unparsable counts as 0.
+fn numeric(value: Value) -> Value {
+    match value {
+        Value::Text(s) => Value::parse_number(&s).unwrap_or(Value::Integer(0)),
+        Value::Blob(_) => Value::Integer(0),
+        other => other,
+    }
+}
+
+fn binary_operation(op: BinaryOperator, lhs: Value, rhs: Value, encoding: TextEncoding) -> Value {
+    use std::cmp::Ordering;
+    let boolean = |b: bool| Value::Integer(b as i64);
+    match op {
+        BinaryOperator::And => match (lhs.as_bool(), rhs.as_bool()) {
+            (Some(false), _) | (_, Some(false)) => boolean(false),
+            (Some(true), Some(true)) => boolean(true),
+            _ => Value::Null,
+        },
+        BinaryOperator::Or => match (lhs.as_bool(), rhs.as_bool()) {
+            (Some(true), _) | (_, Some(true)) => boolean(true),
+            (Some(false), Some(false)) => boolean(false),
+            _ => Value::Null,
+        },
+        BinaryOperator::Is | BinaryOperator::IsNot => {
+            let same = match (lhs.is_null(), rhs.is_null()) {
+                (true, true) => true,
+                (false, false) => lhs.compare(&rhs, encoding) == Ordering::Equal,
+                _ => false,
+            };
+            boolean(same == (op == BinaryOperator::Is))
+        }
+        _ if lhs.is_null() || rhs.is_null() => Value::Null,
+        BinaryOperator::Equal => boolean(lhs.compare(&rhs, encoding) == Ordering::Equal),
+        BinaryOperator::NotEqual => boolean(lhs.compare(&rhs, encoding) != Ordering::Equal),
+        BinaryOperator::Less => boolean(lhs.compare(&rhs, encoding) == Ordering::Less),
+        BinaryOperator::LessEqual => boolean(lhs.compare(&rhs, encoding) != Ordering::Greater),
+        BinaryOperator::Greater => boolean(lhs.compare(&rhs, encoding) == Ordering::Greater),
+        BinaryOperator::GreaterEqual => boolean(lhs.compare(&rhs, encoding) != Ordering::Less),
+        BinaryOperator::Concat => Value::Text(format!("{lhs}{rhs}")),
+        BinaryOperator::Add
+        | BinaryOperator::Subtract
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide
+        | BinaryOperator::Modulo => arithmetic(op, numeric(lhs), numeric(rhs)),
+    }
+}
+
+// Integer arithmetic falls back to floating point on overflow, division by zero yields NULL.
+fn arithmetic(op: BinaryOperator, lhs: Value, rhs: Value) -> Value {
+    if let (Value::Integer(a), Value::Integer(b)) = (&lhs, &rhs) {
+        let (a, b) = (*a, *b);
+        let result = match op {
+            BinaryOperator::Add => a.checked_add(b),
+            BinaryOperator::Subtract => a.checked_sub(b),
+            BinaryOperator::Multiply => a.checked_mul(b),
+            BinaryOperator::Divide if b == 0 => return Value::Null,
+            BinaryOperator::Divide => a.checked_div(b),
+            BinaryOperator::Modulo if b == 0 => return Value::Null,
+            // The only remainder that overflows, i64::MIN % -1, is 0 like in SQLite.
+            _ => Some(a.checked_rem(b).unwrap_or(0)),
+        };
+        if let Some(result) = result {
+            return Value::Integer(result);
+        }
+    }
+    let (a, b) = (lhs.as_f64(), rhs.as_f64());
+    match op {
+        BinaryOperator::Add => Value::Real(a + b),
+        BinaryOperator::Subtract => Value::Real(a - b),
+        BinaryOperator::Multiply => Value::Real(a * b),
+        BinaryOperator::Divide if b == 0.0 => Value::Null,
+        BinaryOperator::Divide => Value::Real(a / b),
+        _ => match (a as i64).checked_rem(b as i64) {
+            Some(remainder) => Value::Real(remainder as f64),
+            None if b as i64 == -1 => Value::Real(0.0),
+            None => Value::Null,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::page::TextEncoding;
+    use crate::parser;
+    use crate::statement::{Statement, Step};
+
+    // Run the statements one after the other, giving back the rows of the last one.
+    fn run(executor: &mut Executor, sql: &str) -> Result<Vec<String>> {
+        let mut rows = Vec::new();
+        for statement in parser::statements(sql) {
+            let mut statement = Statement::from(statement?)?;
+            rows.clear();
+            while let Step::Row(values) = statement.step(executor)? {
+                let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+                rows.push(values.join("|"));
+            }
+        }
+        Ok(rows)
+    }
+
+    // A TEMP trigger on a table of main which writes to an attached database.
+    fn executor() -> Executor {
+        let database = Database::memory(TextEncoding::Utf8).unwrap();
+        let mut executor = Executor::from(database).unwrap();
+        run(
+            &mut executor,
+            "CREATE TABLE apples(a UNIQUE); ATTACH ':memory:' AS aux; CREATE TABLE aux.log(a);
+            CREATE TEMP TRIGGER logged AFTER INSERT ON apples BEGIN INSERT INTO log VALUES (new.a); END",
+        )
+        .unwrap();
+        executor
+    }
+
+    // The rows of apples and of the log, like "2|2".
+    fn counts(executor: &mut Executor) -> String {
+        let apples = run(executor, "SELECT count(*) FROM apples").unwrap();
+        let log = run(executor, "SELECT count(*) FROM aux.log").unwrap();
+        format!("{}|{}", apples[0], log[0])
+    }
+
+    #[test]
+    fn failed_statement_undoes_trigger_writes_to_other_databases() {
+        let mut executor = executor();
+        let error = run(&mut executor, "INSERT INTO apples VALUES (1), (1)").unwrap_err();
+        assert!(matches!(error, MyError::Unique { .. }));
+        assert_eq!(counts(&mut executor), "0|0");
+
+        run(&mut executor, "INSERT INTO apples VALUES (1), (2)").unwrap();
+        assert_eq!(counts(&mut executor), "2|2");
+    }
+
+    #[test]
+    fn failed_statement_in_a_transaction_only_undoes_itself() {
+        let mut executor = executor();
+        run(&mut executor, "BEGIN; INSERT INTO apples VALUES (1)").unwrap();
+        assert!(run(&mut executor, "INSERT INTO apples VALUES (2), (1)").is_err());
+        assert_eq!(counts(&mut executor), "1|1");
+        // OR FAIL keeps the rows written before the failing one, with what their triggers did.
+        assert!(run(&mut executor, "INSERT OR FAIL INTO apples VALUES (3), (1)").is_err());
+        run(&mut executor, "COMMIT").unwrap();
+        assert_eq!(counts(&mut executor), "2|2");
+    }
+
+    #[test]
+    fn rollback_conflict_ends_the_transaction_in_every_database() {
+        let mut executor = executor();
+        run(&mut executor, "BEGIN; INSERT INTO apples VALUES (1)").unwrap();
+        assert!(run(&mut executor, "INSERT OR ROLLBACK INTO apples VALUES (1)").is_err());
+        assert_eq!(counts(&mut executor), "0|0");
+        assert!(run(&mut executor, "COMMIT").is_err());
     }
 }
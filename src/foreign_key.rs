@@ -0,0 +1,158 @@
+use std::cmp::Ordering;
+
+use crate::btree;
+use crate::database::Database;
+use crate::page::{MyError, Result, TextEncoding};
+use crate::parser::ForeignKey;
+use crate::table::TableSchema;
+use crate::value::Value;
+
+/*
+    A foreign key ties columns of a child table to a key of its parent table, the primary key
+    unless other columns are listed. Those parent columns must be the primary key or have a
+    UNIQUE index, otherwise every statement touching either table fails with a mismatch. A
+    child row holding a NULL in any of its key columns references nothing.
+*/
+pub struct ParentKey {
+    pub table: TableSchema,
+    // The parent column matching each child column of the foreign key.
+    pub columns: Vec<usize>,
+}
+
+// None when the parent table does not exist.
+pub fn parent_key(
+    db: &mut Database,
+    child: &TableSchema,
+    foreign_key: &ForeignKey,
+) -> Result<Option<ParentKey>> {
+    let parent = match db.get_table(&foreign_key.foreign_table) {
+        Ok(parent) => parent,
+        Err(MyError::NoSuchTable(_)) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let names = match foreign_key.foreign_columns.is_empty() {
+        true => parent.primary_key(),
+        false => foreign_key.foreign_columns.clone(),
+    };
+    let same_columns = |cols: &[String]| {
+        cols.len() == names.len()
+            && cols
+                .iter()
+                .all(|c| names.iter().any(|n| n.eq_ignore_ascii_case(c)))
+    };
+    let unique = same_columns(&parent.primary_key())
+        || db.get_indexes(&parent)?.iter().any(|index| {
+            let cols: Vec<String> = index.cols.iter().map(|c| c.name.clone()).collect();
+            index.unique && same_columns(&cols)
+        });
+    let columns = names
+        .iter()
+        .map(|n| parent.column_index(n))
+        .collect::<Option<Vec<usize>>>();
+    match columns {
+        Some(columns) if unique && columns.len() == foreign_key.columns.len() => {
+            Ok(Some(ParentKey {
+                table: parent,
+                columns,
+            }))
+        }
+        _ => Err(MyError::Schema(format!(
+            "foreign key mismatch - \"{}\" referencing \"{}\"",
+            child.table_name, foreign_key.foreign_table
+        ))),
+    }
+}
+
+pub fn child_columns(child: &TableSchema, foreign_key: &ForeignKey) -> Vec<usize> {
+    foreign_key
+        .columns
+        .iter()
+        .filter_map(|c| child.column_index(c))
+        .collect()
+}
+
+// The values of the key columns of a row, None when one of them is NULL.
+pub fn key_of(values: &[Value], columns: &[usize]) -> Option<Vec<Value>> {
+    let key: Vec<Value> = columns.iter().map(|i| values[*i].clone()).collect();
+    match key.iter().any(|v| v.is_null()) {
+        true => None,
+        false => Some(key),
+    }
+}
+
+pub fn same_key(a: &[Value], b: &[Value], encoding: TextEncoding) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(x, y)| x.compare(y, encoding) == Ordering::Equal)
+}
+
+/*
+    Whether a row of the table holds the key in the given columns. The values are compared
+    with the affinity of the columns applied, a key on the rowid is looked up directly.
+*/
+pub fn contains_key(
+    db: &mut Database,
+    table: &TableSchema,
+    columns: &[usize],
+    key: &[Value],
+) -> Result<bool> {
+    let key: Vec<Value> = columns
+        .iter()
+        .zip(key)
+        .map(|(i, v)| v.clone().apply_affinity(table.affinity(*i)))
+        .collect();
+    if let (Some(rowid_column), [column]) = (table.rowid_column(), columns)
+        && rowid_column == *column
+    {
+        return match key[0] {
+            Value::Integer(rowid) => btree::table_contains(db, table.root_page, rowid),
+            _ => Ok(false),
+        };
+    }
+    let mut cursor = btree::TableCursor::from(db, table.root_page)?;
+    while let Some((rowid, payload)) = cursor.next(db)? {
+        let values = table.project(rowid, payload, |i| columns.contains(&i))?;
+        if columns
+            .iter()
+            .zip(&key)
+            .all(|(i, v)| values[*i].compare(v, table.encoding) == Ordering::Equal)
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/*
+    PRAGMA foreign_key_check lists the rows of a table referencing no row of their parent:
+    table | rowid | parent | fkid, with the foreign keys numbered like foreign_key_list does.
+    When the parent table is missing, every row with a key is listed.
+*/
+pub fn check_table(db: &mut Database, table: &TableSchema) -> Result<Vec<Vec<Value>>> {
+    let mut rows = Vec::new();
+    for (id, foreign_key) in table.foreign_keys().iter().rev().enumerate() {
+        let parent = parent_key(db, table, foreign_key)?;
+        let columns = child_columns(table, foreign_key);
+        let mut cursor = btree::TableCursor::from(db, table.root_page)?;
+        while let Some((rowid, payload)) = cursor.next(db)? {
+            let values = table.project(rowid, payload, |i| columns.contains(&i))?;
+            let Some(key) = key_of(&values, &columns) else {
+                continue;
+            };
+            let found = match &parent {
+                Some(parent) => contains_key(db, &parent.table, &parent.columns, &key)?,
+                None => false,
+            };
+            if !found {
+                rows.push(vec![
+                    Value::Text(table.table_name.clone()),
+                    Value::Integer(rowid),
+                    Value::Text(foreign_key.foreign_table.clone()),
+                    Value::Integer(id as i64),
+                ]);
+            }
+        }
+    }
+    Ok(rows)
+}
@@ -0,0 +1,582 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use crate::autovacuum::{self, PtrmapType};
+use crate::btree::{self, BTreePage};
+use crate::database::Database;
+use crate::page::{MyError, PageType, Result};
+use crate::parser::ColumnConstraint;
+use crate::table::{IndexSchema, TableSchema};
+
+/*
+    PRAGMA integrity_check runs in two passes, like SQLite's.
+
+    The first walks the freelist and every b-tree listed in sqlite_schema. Each page must be
+    referenced exactly once, b-tree pages must have a valid header, cells must lie within the
+    cell content area without overlapping each other or the freeblocks, rowids must increase
+    and stay within the range given by the parent's dividers, overflow chains must have the
+    length the payload needs and all leaves must be at the same depth. In an auto-vacuum
+    database every reference is also checked against the pointer map.
+
+    The second compares the content: each index must have one entry per row of its table,
+    NOT NULL columns must not hold NULLs and UNIQUE indexes must not hold duplicates.
+    PRAGMA quick_check skips the comparison of index entries with the rows.
+
+    Messages are worded like SQLite's, and the first pass is headed by a line naming the
+    database. At most max_errors problems are reported.
+*/
+pub struct Checker<'a> {
+    db: &'a mut Database,
+    quick: bool,
+    max_errors: usize,
+    errors: Vec<String>,
+    // Indexed by page number, whether the page was already reached.
+    used: Vec<bool>,
+    usable_size: usize,
+    // The root of the b-tree being walked, the "Tree N" of messages.
+    root: u32,
+    // The entries found while walking the current b-tree, in reverse order.
+    entries: Vec<Entry>,
+    trees: HashMap<u32, Vec<Entry>>,
+    // The roots of b-trees where problems were found, their entries can't be relied on.
+    damaged: HashSet<u32>,
+}
+
+// A table row or an index key, None when its payload could not be read.
+struct Entry {
+    rowid: Option<i64>,
+    payload: Option<Vec<u8>>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn from(db: &'a mut Database, max_errors: usize, quick: bool) -> Self {
+        let page_count = db.file_header.page_count as usize;
+        let usable_size = db.file_header.usable_size();
+        Self {
+            db,
+            quick,
+            max_errors,
+            errors: Vec::new(),
+            used: vec![false; page_count + 1],
+            usable_size,
+            root: 0,
+            entries: Vec::new(),
+            trees: HashMap::new(),
+            damaged: HashSet::new(),
+        }
+    }
+
+    pub fn run(mut self) -> Result<Vec<String>> {
+        let schema = self.db.get_schema()?;
+        self.check_freelist()?;
+        let auto_vacuum = self.db.file_header.is_auto_vacuum();
+        let largest_root = self.db.file_header.largest_root_page;
+        if auto_vacuum {
+            let max_root = schema.iter().map(|e| e.root_page).max().unwrap_or(0).max(1);
+            if max_root != largest_root {
+                self.error(format!(
+                    "max rootpage ({max_root}) disagrees with header ({largest_root})"
+                ));
+            }
+        } else if self.db.file_header.incremental_vacuum {
+            self.error("incremental_vacuum enabled with a max rootpage of zero".to_string());
+        }
+        let roots = std::iter::once(1).chain(schema.iter().map(|e| e.root_page));
+        for root in roots.filter(|r| *r != 0) {
+            if self.trees.contains_key(&root) || self.is_full() {
+                continue;
+            }
+            if auto_vacuum && root > 1 {
+                self.check_ptrmap("", root, PtrmapType::RootPage, 0);
+            }
+            self.root = root;
+            let errors = self.errors.len();
+            self.check_tree("", root, 0, &mut 0, i64::MAX)?;
+            let mut entries = std::mem::take(&mut self.entries);
+            entries.reverse();
+            if errors != self.errors.len() {
+                self.damaged.insert(root);
+            }
+            self.trees.insert(root, entries);
+        }
+        for page_num in 1..self.used.len() as u32 {
+            let is_ptrmap = auto_vacuum && autovacuum::is_ptrmap_page(self.usable_size, page_num);
+            if !self.used[page_num as usize] && !is_ptrmap {
+                self.error(format!("Page {page_num}: never used"));
+            }
+            if self.used[page_num as usize] && is_ptrmap {
+                self.error(format!("Page {page_num}: pointer map referenced"));
+            }
+        }
+        let tree_errors = self.errors.len();
+
+        let encoding = self.db.file_header.text_encoding;
+        let tables: Vec<TableSchema> = schema
+            .iter()
+            .filter(|e| e.entry_type == "table" && e.root_page != 0)
+            .filter_map(|e| TableSchema::from_entry(e, encoding))
+            .collect();
+        let mut indexes = Vec::new();
+        for table in &tables {
+            indexes.push(self.db.get_indexes(table)?);
+        }
+        for (table, indexes) in tables.iter().zip(&indexes) {
+            let rows = self.trees.get(&table.root_page).map_or(0, |t| t.len());
+            for index in indexes {
+                if self.trees.get(&index.root_page).map_or(0, |t| t.len()) != rows {
+                    self.error(format!("wrong # of entries in index {}", index.index_name));
+                }
+            }
+        }
+        for (table, indexes) in tables.iter().zip(&indexes) {
+            self.check_rows(table, indexes)?;
+        }
+
+        if self.errors.is_empty() {
+            return Ok(vec!["ok".to_string()]);
+        }
+        if tree_errors != 0 {
+            self.errors
+                .insert(0, "*** in database main ***".to_string());
+        }
+        Ok(self.errors)
+    }
+
+    fn is_full(&self) -> bool {
+        self.errors.len() >= self.max_errors
+    }
+
+    fn error(&mut self, message: String) {
+        if !self.is_full() {
+            self.errors.push(message);
+        }
+    }
+
+    // Record a reference to a page, false when it can't or must not be followed.
+    fn mark_page(&mut self, prefix: &str, page_num: u32) -> bool {
+        let Some(used) = self
+            .used
+            .get_mut(page_num as usize)
+            .filter(|_| page_num != 0)
+        else {
+            self.error(format!("{prefix}invalid page number {page_num}"));
+            return false;
+        };
+        if *used {
+            self.error(format!("{prefix}2nd reference to page {page_num}"));
+            return false;
+        }
+        *used = true;
+        true
+    }
+
+    fn check_ptrmap(&mut self, prefix: &str, page_num: u32, kind: PtrmapType, parent: u32) {
+        match autovacuum::read_entry(self.db, page_num) {
+            Ok(entry) if entry.kind == kind && entry.parent == parent => {}
+            Ok(entry) => self.error(format!(
+                "{prefix}Bad ptr map entry key={page_num} expected=({},{parent}) got=({},{})",
+                kind as u8, entry.kind as u8, entry.parent
+            )),
+            Err(_) => self.error(format!("{prefix}Failed to read ptrmap key={page_num}")),
+        }
+    }
+
+    /*
+        Follow a chain of pages, either the freelist trunks or the pages of an overflow chain,
+        expecting `expected` pages in all. Returns the content of an overflow chain.
+    */
+    fn check_list(
+        &mut self,
+        prefix: &str,
+        free_list: bool,
+        first: u32,
+        expected: usize,
+    ) -> Result<Vec<u8>> {
+        let errors_at_start = self.errors.len();
+        let auto_vacuum = self.db.file_header.is_auto_vacuum();
+        let mut remaining = expected as i64;
+        let mut content = Vec::new();
+        let mut page_num = first;
+        while page_num != 0 && !self.is_full() {
+            if !self.mark_page(prefix, page_num) {
+                break;
+            }
+            remaining -= 1;
+            let data = self.db.load_page(page_num)?;
+            if free_list {
+                let leaf_count = u32::from_be_bytes(data[4..8].try_into()?) as usize;
+                if auto_vacuum {
+                    self.check_ptrmap(prefix, page_num, PtrmapType::FreePage, 0);
+                }
+                if leaf_count > self.usable_size / 4 - 2 {
+                    self.error(format!(
+                        "{prefix}freelist leaf count too big on page {page_num}"
+                    ));
+                    remaining -= 1;
+                } else {
+                    for i in 0..leaf_count {
+                        let offset = 8 + i * 4;
+                        let leaf = u32::from_be_bytes(data[offset..offset + 4].try_into()?);
+                        if auto_vacuum {
+                            self.check_ptrmap(prefix, leaf, PtrmapType::FreePage, 0);
+                        }
+                        self.mark_page(prefix, leaf);
+                    }
+                    remaining -= leaf_count as i64;
+                }
+            } else {
+                content.extend_from_slice(&data[4..self.usable_size]);
+                let next = u32::from_be_bytes(data[..4].try_into()?);
+                if auto_vacuum && remaining > 0 {
+                    self.check_ptrmap(prefix, next, PtrmapType::Overflow2, page_num);
+                }
+            }
+            page_num = u32::from_be_bytes(data[..4].try_into()?);
+        }
+        if remaining != 0 && errors_at_start == self.errors.len() {
+            self.error(format!(
+                "{prefix}{} is {} but should be {expected}",
+                if free_list {
+                    "size"
+                } else {
+                    "overflow list length"
+                },
+                expected as i64 - remaining
+            ));
+        }
+        Ok(content)
+    }
+
+    fn check_freelist(&mut self) -> Result<()> {
+        let first = self.db.file_header.first_freelist_trunk_page;
+        let count = self.db.file_header.freelist_page_count as usize;
+        self.check_list("Freelist: ", true, first, count)?;
+        Ok(())
+    }
+
+    /*
+        Check a b-tree page and everything below it, returning the depth of the subtree, or
+        zero when it could not be checked. Cells are visited from the last to the first, so
+        each rowid must be smaller than the one visited before, starting from max_key, and
+        min_key receives the smallest rowid found. The prefix is the one of the message
+        naming the reference to this page, the cell of the parent is carried into the
+        messages about the right-most child.
+    */
+    fn check_tree(
+        &mut self,
+        prefix: &str,
+        page_num: u32,
+        parent_cell: usize,
+        min_key: &mut i64,
+        max_key: i64,
+    ) -> Result<usize> {
+        if page_num == 0 || !self.mark_page(prefix, page_num) {
+            return Ok(0);
+        }
+        let root = self.root;
+        let page_prefix = format!("Tree {root} page {page_num}: ");
+        let page = match BTreePage::load_unchecked(self.db, page_num) {
+            Ok(page) if self.has_valid_header(&page) => page,
+            Ok(_) | Err(MyError::Corrupt | MyError::CorruptPage(_)) => {
+                self.error(format!(
+                    "{page_prefix}btreeInitPage() returns error code 11"
+                ));
+                return Ok(0);
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(freeblocks) = self.freeblocks(&page) else {
+            self.error(format!("{page_prefix}free space corruption"));
+            return Ok(0);
+        };
+        let auto_vacuum = self.db.file_header.is_auto_vacuum();
+        let page_type = page.page_type();
+        let content_offset = page.page_header.cell_content_offset as usize;
+        // Like SQLite, messages about an interior page of an auto-vacuum database keep the
+        // prefix of its right child's pointer map check.
+        let cell_prefix = |i: usize| {
+            if auto_vacuum && !page_type.is_leaf() {
+                format!("Tree {root} page {page_num} right child: ")
+            } else {
+                format!("Tree {root} page {page_num} cell {i}: ")
+            }
+        };
+        let mut max_key = max_key;
+        let mut key_can_be_equal = true;
+        let mut depth = 0;
+        let mut regions = Vec::new();
+        let mut coverage_check = true;
+
+        if !page_type.is_leaf() {
+            let child = page.page_header.rightmost_pointer.unwrap_or(0);
+            let prefix = cell_prefix(parent_cell);
+            if auto_vacuum {
+                self.check_ptrmap(&prefix, child, PtrmapType::BTree, page_num);
+            }
+            let mut child_min = max_key;
+            depth = self.check_tree(&prefix, child, parent_cell, &mut child_min, max_key)?;
+            max_key = child_min;
+            key_can_be_equal = false;
+        }
+        for i in (0..page.cell_count()).rev() {
+            if self.is_full() {
+                break;
+            }
+            let prefix = cell_prefix(i);
+            let offset = page.cell_offset(i);
+            if offset < content_offset || offset > self.usable_size - 4 {
+                self.error(format!(
+                    "{prefix}Offset {offset} out of range {content_offset}..{}",
+                    self.usable_size - 4
+                ));
+                coverage_check = false;
+                continue;
+            }
+            // Padded, so that a damaged cell can't make parsing read past the page.
+            let mut cell = page.data[offset..self.usable_size].to_vec();
+            cell.resize(cell.len() + self.usable_size + 16, 0);
+            let info = btree::parse_cell(page_type, &cell, self.usable_size);
+            if offset + info.size > self.usable_size {
+                self.error(format!("{prefix}Extends off end of page"));
+                coverage_check = false;
+                continue;
+            }
+            if let Some(rowid) = info.rowid {
+                let out_of_order = if key_can_be_equal {
+                    rowid > max_key
+                } else {
+                    rowid >= max_key
+                };
+                if out_of_order {
+                    self.error(format!("{prefix}Rowid {rowid} out of order"));
+                }
+                max_key = rowid;
+                key_can_be_equal = false;
+            }
+            let mut payload =
+                Some(cell[info.payload_offset..info.payload_offset + info.local_size].to_vec());
+            if let Some(overflow) = info.overflow_page {
+                if auto_vacuum {
+                    self.check_ptrmap(&prefix, overflow, PtrmapType::Overflow1, page_num);
+                }
+                let pages = (info.payload_size - info.local_size).div_ceil(self.usable_size - 4);
+                let errors = self.errors.len();
+                let content = self.check_list(&prefix, false, overflow, pages)?;
+                payload = match (payload, errors == self.errors.len()) {
+                    (Some(mut local), true) => {
+                        local.extend_from_slice(&content);
+                        local.truncate(info.payload_size);
+                        Some(local)
+                    }
+                    _ => None,
+                };
+            }
+            if page_type != PageType::TableInterior {
+                self.entries.push(Entry {
+                    rowid: info.rowid,
+                    payload,
+                });
+            }
+            if let Some(child) = info.left_child_page {
+                if auto_vacuum {
+                    self.check_ptrmap(&prefix, child, PtrmapType::BTree, page_num);
+                }
+                let mut child_min = max_key;
+                let child_depth = self.check_tree(&prefix, child, i, &mut child_min, max_key)?;
+                max_key = child_min;
+                key_can_be_equal = false;
+                if child_depth != 0 && depth != 0 && child_depth != depth {
+                    self.error(format!("{prefix}Child page depth differs"));
+                }
+                if depth == 0 {
+                    depth = child_depth;
+                }
+            } else {
+                regions.push((offset, offset + info.size.max(4) - 1));
+            }
+        }
+        *min_key = max_key;
+
+        if coverage_check && !self.is_full() {
+            if !page_type.is_leaf() {
+                regions.clear();
+                for i in 0..page.cell_count() {
+                    let offset = page.cell_offset(i);
+                    let size = btree::parse_cell(page_type, page.cell(i), self.usable_size).size;
+                    regions.push((offset, offset + size.max(4) - 1));
+                }
+            }
+            regions.extend(
+                freeblocks
+                    .iter()
+                    .map(|(start, size)| (*start, start + size - 1)),
+            );
+            regions.sort();
+            let mut fragmented = 0;
+            let mut previous_end = content_offset - 1;
+            let mut overlap = false;
+            for (start, end) in regions {
+                if previous_end >= start {
+                    self.error(format!("Multiple uses for byte {start} of page {page_num}"));
+                    overlap = true;
+                    break;
+                }
+                fragmented += start - previous_end - 1;
+                previous_end = end;
+            }
+            fragmented += self.usable_size - previous_end - 1;
+            let reported = page.page_header.fragmented_bytes_count as usize;
+            if !overlap && fragmented != reported {
+                self.error(format!(
+                    "Fragmentation of {fragmented} bytes reported as {reported} on page {page_num}"
+                ));
+            }
+        }
+        Ok(depth + 1)
+    }
+
+    // More cells than could fit into the page mean a corrupt header.
+    fn has_valid_header(&self, page: &BTreePage) -> bool {
+        let header_end = page.cell_pointer_array_offset() + 2 * page.cell_count();
+        page.cell_count() <= (self.usable_size - 8) / 6 && header_end <= self.usable_size
+    }
+
+    /*
+        The freeblocks of a page as (offset, size) pairs, None when the list is damaged: each
+        block must lie in the cell content area after the previous one, and the free space
+        counted must fit between the cell pointer array and the end of the page.
+    */
+    fn freeblocks(&self, page: &BTreePage) -> Option<Vec<(usize, usize)>> {
+        let data = &page.data;
+        let read_u16 = |offset: usize| u16::from_be_bytes([data[offset], data[offset + 1]]);
+        let content_offset = page.page_header.cell_content_offset as usize;
+        let cells_start = page.cell_pointer_array_offset() + 2 * page.cell_count();
+        let mut free = page.page_header.fragmented_bytes_count as usize + content_offset;
+        let mut blocks = Vec::new();
+        let mut offset = page.page_header.first_freeblock as usize;
+        if offset != 0 && offset < content_offset {
+            return None;
+        }
+        while offset != 0 {
+            if offset > self.usable_size - 4 {
+                return None;
+            }
+            let next = read_u16(offset) as usize;
+            let size = read_u16(offset + 2) as usize;
+            if size < 4 || offset + size > self.usable_size {
+                return None;
+            }
+            free += size;
+            blocks.push((offset, size));
+            if next != 0 && next < offset + size + 4 {
+                return None;
+            }
+            offset = next;
+        }
+        if free > self.usable_size || free < cells_start {
+            return None;
+        }
+        Some(blocks)
+    }
+
+    /*
+        Every row must have its entry in each index, and NOT NULL columns must hold a value.
+        Rows are numbered from one in rowid order, as SQLite does in its messages.
+    */
+    fn check_rows(&mut self, table: &TableSchema, indexes: &[IndexSchema]) -> Result<()> {
+        let Some(rows) = self.trees.remove(&table.root_page) else {
+            return Ok(());
+        };
+        // PRAGMA quick_check never reads index entries.
+        let mut index_keys = Vec::new();
+        for index in indexes {
+            let keys: Vec<Vec<u8>> = match self.trees.get(&index.root_page) {
+                Some(entries) if !self.quick => {
+                    entries.iter().filter_map(|e| e.payload.clone()).collect()
+                }
+                _ => Vec::new(),
+            };
+            index_keys.push(self.sorted_keys(index, keys));
+        }
+        let not_null: Vec<usize> = (0..table.cols.len())
+            .filter(|i| {
+                table.cols[*i]
+                    .constraints
+                    .iter()
+                    .any(|c| matches!(c, ColumnConstraint::NotNull(_)))
+            })
+            .collect();
+        for (n, row) in rows.iter().enumerate() {
+            if self.is_full() {
+                break;
+            }
+            let (Some(rowid), Some(payload)) = (row.rowid, &row.payload) else {
+                continue;
+            };
+            let Ok(values) = table.row_values(rowid, payload) else {
+                continue;
+            };
+            for i in &not_null {
+                if values[*i].is_null() {
+                    self.error(format!(
+                        "NULL value in {}.{}",
+                        table.table_name, table.cols[*i].name
+                    ));
+                }
+            }
+            if self.quick {
+                continue;
+            }
+            for (index, keys) in indexes.iter().zip(&index_keys) {
+                if self.damaged.contains(&index.root_page) {
+                    continue;
+                }
+                let key = index.key(rowid, &values);
+                // Every key decodes and they are in order, which sorted_keys made sure of.
+                let compare = |k: &Vec<u8>| index.compare(k, &key).unwrap_or(Ordering::Less);
+                match keys.binary_search_by(compare) {
+                    Ok(position) => {
+                        let next = keys.get(position + 1);
+                        if index.unique && next.is_some_and(|next| index.conflicts(&key, next)) {
+                            self.error(format!("non-unique entry in index {}", index.index_name));
+                        }
+                    }
+                    Err(_) => self.error(format!(
+                        "row {} missing from index {}",
+                        n + 1,
+                        index.index_name
+                    )),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        The keys of an index that can be searched for the entry of a row. An entry that does not
+        decode is reported and left out, its row then shows up as missing. Keys out of order are
+        reported too, and sorted so that each row can still be looked up.
+    */
+    fn sorted_keys(&mut self, index: &IndexSchema, entries: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let mut keys = Vec::new();
+        for (n, key) in entries.into_iter().enumerate() {
+            // Comparing a key with itself decodes every column.
+            match index.compare(&key, &key) {
+                Ok(_) => keys.push(key),
+                Err(_) => self.error(format!(
+                    "malformed entry {} in index {}",
+                    n + 1,
+                    index.index_name
+                )),
+            }
+        }
+        let in_order = keys
+            .windows(2)
+            .all(|w| matches!(index.compare(&w[0], &w[1]), Ok(Ordering::Less)));
+        if !in_order {
+            self.error(format!("index {} is out of order", index.index_name));
+            keys.sort_by(|a, b| index.compare(a, b).unwrap_or(Ordering::Equal));
+        }
+        keys
+    }
+}
@@ -0,0 +1,235 @@
+use crate::page::Result;
+use crate::utils;
+use crate::vfs::{OpenMode, Vfs, VfsFile};
+
+/*
+    Rollback Journal Format
+    The journal starts with a header padded to the sector size, followed by page records.
+
+    Journal Header
+        Offset  Size    Description
+        0       8       Header string: 0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7
+        8       4       The "Page Count" - The number of pages in the next segment of the journal, or -1 to mean all content to the end of the file
+        12      4       A random nonce for the checksum
+        16      4       Initial size of the database in pages
+        20      4       Size of a disk sector assumed by the process that wrote this journal.
+        24      4       Size of pages in this journal.
+
+    Page Record
+        Offset  Size    Description
+        0       4       The page number in the database file
+        4       N       Original content of the page prior to the start of the transaction
+        N+4     4       Checksum
+
+    The checksum is the nonce plus every 200th byte of the page, starting from the end.
+    A journal which exists, is not empty and starts with a valid header is "hot": the process
+    writing it died before committing, so the original pages must be copied back.
+*/
+pub struct Journal {
+    file: Box<dyn VfsFile>,
+    path: String,
+    // Where the next page record goes.
+    len: u64,
+    page_size: usize,
+    nonce: u32,
+    record_count: u32,
+}
+
+impl Journal {
+    const MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+    const SECTOR_SIZE: usize = 512;
+
+    pub fn path_for(db_path: &str) -> String {
+        format!("{db_path}-journal")
+    }
+
+    pub fn create(
+        vfs: &dyn Vfs,
+        db_path: &str,
+        initial_page_count: u32,
+        page_size: usize,
+    ) -> Result<Self> {
+        let path = Self::path_for(db_path);
+        let file = vfs.open(&path, OpenMode::Create)?;
+        file.truncate(0)?;
+        let nonce = utils::random_u32();
+        let mut journal = Self {
+            file,
+            path,
+            len: 0,
+            page_size,
+            nonce,
+            record_count: 0,
+        };
+        let mut header = vec![0; Self::SECTOR_SIZE];
+        header[..8].copy_from_slice(&Self::MAGIC);
+        header[12..16].copy_from_slice(&nonce.to_be_bytes());
+        header[16..20].copy_from_slice(&initial_page_count.to_be_bytes());
+        header[20..24].copy_from_slice(&(Self::SECTOR_SIZE as u32).to_be_bytes());
+        header[24..28].copy_from_slice(&(page_size as u32).to_be_bytes());
+        journal.file.write_at(&header, 0)?;
+        journal.len = header.len() as u64;
+        Ok(journal)
+    }
+
+    fn checksum(nonce: u32, data: &[u8]) -> u32 {
+        let mut checksum = nonce;
+        let mut i = data.len() as isize - 200;
+        while i > 0 {
+            checksum = checksum.wrapping_add(data[i as usize] as u32);
+            i -= 200;
+        }
+        checksum
+    }
+
+    pub fn append(&mut self, page_num: u32, data: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(self.page_size + 8);
+        record.extend_from_slice(&page_num.to_be_bytes());
+        record.extend_from_slice(data);
+        record.extend_from_slice(&Self::checksum(self.nonce, data).to_be_bytes());
+        self.file.write_at(&record, self.len)?;
+        self.len += record.len() as u64;
+        self.record_count += 1;
+        Ok(())
+    }
+
+    // Make the page records durable before the record count that validates them.
+    pub fn seal(&mut self) -> Result<()> {
+        self.file.sync()?;
+        self.file.write_at(&self.record_count.to_be_bytes(), 8)?;
+        self.file.sync()?;
+        Ok(())
+    }
+
+    // Deleting the journal is what commits the transaction.
+    pub fn delete(self, vfs: &dyn Vfs) -> Result<()> {
+        drop(self.file);
+        vfs.delete(&self.path)
+    }
+
+    // A journal which is not empty may be hot, unless its writer is still alive and holds RESERVED.
+    pub fn exists(vfs: &dyn Vfs, db_path: &str) -> bool {
+        vfs.open(&Self::path_for(db_path), OpenMode::ReadOnly)
+            .and_then(|file| file.file_size())
+            .is_ok_and(|len| len > 0)
+    }
+
+    /*
+        Roll back a hot journal left behind by a crashed writer. Returns true if one was found.
+        A journal may hold several segments, each one starting with its own header on a sector
+        boundary, when the writer had to flush pages to the database before committing.
+    */
+    pub fn recover(vfs: &dyn Vfs, db_path: &str, db_file: &dyn VfsFile) -> Result<bool> {
+        let path = Self::path_for(db_path);
+        let journal = match vfs.open(&path, OpenMode::ReadOnly) {
+            Ok(file) => file,
+            Err(_) => return Ok(false),
+        };
+        let mut content = vec![0; journal.file_size()? as usize];
+        journal.read_exact_at(&mut content, 0)?;
+        drop(journal);
+        if content.len() < 28 || content[..8] != Self::MAGIC {
+            vfs.delete(&path)?;
+            return Ok(false);
+        }
+        let read_u32 =
+            |offset: usize| u32::from_be_bytes(content[offset..offset + 4].try_into().unwrap());
+        let initial_page_count = read_u32(16) as u64;
+        let sector_size = (read_u32(20) as usize).max(Self::SECTOR_SIZE);
+        let page_size = read_u32(24) as usize;
+        let record_size = page_size + 8;
+
+        let mut header_offset = 0;
+        let mut replayed = false;
+        'segments: while header_offset + 28 <= content.len()
+            && content[header_offset..header_offset + 8] == Self::MAGIC
+        {
+            let nonce = read_u32(header_offset + 12);
+            let mut offset = header_offset + sector_size;
+            let record_count = match read_u32(header_offset + 8) {
+                u32::MAX => content.len().saturating_sub(offset) / record_size,
+                count => count as usize,
+            };
+            for _ in 0..record_count {
+                if offset + record_size > content.len() {
+                    break 'segments;
+                }
+                let page_num = read_u32(offset) as u64;
+                let data = &content[offset + 4..offset + 4 + page_size];
+                // A torn record means the crash happened while journaling, before the database was touched.
+                if page_num == 0 || read_u32(offset + 4 + page_size) != Self::checksum(nonce, data)
+                {
+                    break 'segments;
+                }
+                db_file.write_at(data, (page_num - 1) * page_size as u64)?;
+                replayed = true;
+                offset += record_size;
+            }
+            header_offset = offset.div_ceil(sector_size) * sector_size;
+        }
+        if replayed {
+            db_file.truncate(initial_page_count * page_size as u64)?;
+            db_file.sync()?;
+        }
+        vfs.delete(&path)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::MemoryVfs;
+
+    const PAGE_SIZE: usize = 512;
+
+    // A database of three pages, each filled with its own byte, and a journal of pages 1 and 2
+    // as they were when the database had two.
+    fn crashed(seal: bool) -> (MemoryVfs, Box<dyn VfsFile>) {
+        let vfs = MemoryVfs::new();
+        let pages: Vec<u8> = b"xyz".iter().flat_map(|b| vec![*b; PAGE_SIZE]).collect();
+        vfs.insert("db", pages);
+        let mut journal = Journal::create(&vfs, "db", 2, PAGE_SIZE).unwrap();
+        journal.append(1, &[b'a'; PAGE_SIZE]).unwrap();
+        journal.append(2, &[b'b'; PAGE_SIZE]).unwrap();
+        if seal {
+            journal.seal().unwrap();
+        }
+        let db_file = vfs.open("db", OpenMode::ReadWrite).unwrap();
+        (vfs, db_file)
+    }
+
+    #[test]
+    fn hot_journal_restores_pages_and_size() {
+        let (vfs, db_file) = crashed(true);
+        assert!(Journal::exists(&vfs, "db"));
+        assert!(Journal::recover(&vfs, "db", db_file.as_ref()).unwrap());
+        let mut expected = vec![b'a'; PAGE_SIZE];
+        expected.extend([b'b'; PAGE_SIZE]);
+        assert_eq!(vfs.contents("db").unwrap(), expected);
+        assert!(!Journal::exists(&vfs, "db"));
+    }
+
+    #[test]
+    fn unsealed_journal_leaves_the_database_alone() {
+        let (vfs, db_file) = crashed(false);
+        assert!(Journal::recover(&vfs, "db", db_file.as_ref()).unwrap());
+        assert_eq!(vfs.contents("db").unwrap().len(), 3 * PAGE_SIZE);
+        assert_eq!(vfs.contents("db").unwrap()[0], b'x');
+        assert!(!Journal::exists(&vfs, "db"));
+    }
+
+    #[test]
+    fn torn_record_stops_the_replay() {
+        let (vfs, db_file) = crashed(true);
+        // The last byte of the second record is its checksum.
+        let mut journal = vfs.contents(&Journal::path_for("db")).unwrap();
+        let last = journal.len() - 1;
+        journal[last] ^= 1;
+        vfs.insert(&Journal::path_for("db"), journal);
+        assert!(Journal::recover(&vfs, "db", db_file.as_ref()).unwrap());
+        let content = vfs.contents("db").unwrap();
+        assert_eq!(content.len(), 2 * PAGE_SIZE);
+        assert_eq!((content[0], content[PAGE_SIZE]), (b'a', b'y'));
+    }
+}
@@ -0,0 +1,161 @@
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::page::{MyError, Result};
+use crate::vfs::VfsFile;
+
+/*
+    SQLite coordinates processes with POSIX advisory locks on bytes of the database file which
+    are never read or written, starting at the 1GB boundary:
+        PENDING_BYTE    0x40000000      held by a writer waiting for the readers to finish
+        RESERVED_BYTE   0x40000001      held by the single process that is going to write
+        SHARED_FIRST    0x40000002      510 bytes, locked shared by readers and exclusively by a committing writer
+
+    A connection moves through these states:
+        NONE        no lock, nothing may be read
+        SHARED      the database may be read, any number of processes can hold it
+        RESERVED    the process plans to write, new readers are still allowed
+        PENDING     the process waits to commit, new readers are kept out
+        EXCLUSIVE   the process writes the database file, nobody else holds a lock
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, PartialOrd)]
+pub enum LockLevel {
+    #[default]
+    None,
+    Shared,
+    Reserved,
+    Pending,
+    Exclusive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockKind {
+    Read,
+    Write,
+    Unlock,
+}
+
+const PENDING_BYTE: u64 = 0x4000_0000;
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+const SHARED_SIZE: u64 = 510;
+
+// How long a lock held by another process is waited for before giving up with SQLITE_BUSY.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Set or clear an fcntl lock without blocking. Returns false if another process holds a conflicting lock.
+pub fn set_lock(file: &File, kind: LockKind, start: u64, len: u64) -> Result<bool> {
+    // SAFETY: flock is a plain C struct for which all zero bytes is a valid value.
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = match kind {
+        LockKind::Read => libc::F_RDLCK,
+        LockKind::Write => libc::F_WRLCK,
+        LockKind::Unlock => libc::F_UNLCK,
+    } as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start as libc::off_t;
+    flock.l_len = len as libc::off_t;
+    // SAFETY: the descriptor is owned by file and flock outlives the call.
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &flock) } == 0 {
+        return Ok(true);
+    }
+    let error = io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EAGAIN) | Some(libc::EACCES) => Ok(false),
+        _ => Err(error.into()),
+    }
+}
+
+// Keep trying to take a lock until it succeeds or the busy timeout runs out.
+pub fn retry(mut attempt: impl FnMut() -> Result<bool>) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        if attempt()? {
+            return Ok(());
+        }
+        if start.elapsed() >= BUSY_TIMEOUT {
+            return Err(MyError::Busy);
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DatabaseLock {
+    level: LockLevel,
+}
+
+impl DatabaseLock {
+    pub fn level(&self) -> LockLevel {
+        self.level
+    }
+
+    /*
+        Try once to raise the lock to the given level. A reader briefly takes PENDING_BYTE so it
+        cannot sneak in while a writer waits for EXCLUSIVE. A writer which fails to get
+        EXCLUSIVE stays in PENDING, so the readers drain out while it retries.
+    */
+    pub fn lock(&mut self, file: &dyn VfsFile, level: LockLevel) -> Result<bool> {
+        if self.level >= level {
+            return Ok(true);
+        }
+        match level {
+            LockLevel::None => Ok(true),
+            LockLevel::Shared => {
+                if !file.lock(LockKind::Read, PENDING_BYTE, 1)? {
+                    return Ok(false);
+                }
+                let locked = file.lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE)?;
+                file.lock(LockKind::Unlock, PENDING_BYTE, 1)?;
+                if locked {
+                    self.level = LockLevel::Shared;
+                }
+                Ok(locked)
+            }
+            LockLevel::Reserved => {
+                let locked = file.lock(LockKind::Write, RESERVED_BYTE, 1)?;
+                if locked {
+                    self.level = LockLevel::Reserved;
+                }
+                Ok(locked)
+            }
+            LockLevel::Pending | LockLevel::Exclusive => {
+                if self.level < LockLevel::Pending {
+                    if !file.lock(LockKind::Write, PENDING_BYTE, 1)? {
+                        return Ok(false);
+                    }
+                    self.level = LockLevel::Pending;
+                }
+                if level == LockLevel::Pending {
+                    return Ok(true);
+                }
+                let locked = file.lock(LockKind::Write, SHARED_FIRST, SHARED_SIZE)?;
+                if locked {
+                    self.level = LockLevel::Exclusive;
+                }
+                Ok(locked)
+            }
+        }
+    }
+
+    // Lower the lock to SHARED or drop it entirely.
+    pub fn unlock(&mut self, file: &dyn VfsFile, level: LockLevel) -> Result<()> {
+        if self.level <= level {
+            return Ok(());
+        }
+        if level == LockLevel::Shared {
+            if self.level == LockLevel::Exclusive {
+                file.lock(LockKind::Read, SHARED_FIRST, SHARED_SIZE)?;
+            }
+            file.lock(LockKind::Unlock, PENDING_BYTE, 2)?;
+            self.level = LockLevel::Shared;
+        } else {
+            file.lock(LockKind::Unlock, PENDING_BYTE, SHARED_SIZE + 2)?;
+            self.level = LockLevel::None;
+        }
+        Ok(())
+    }
+}
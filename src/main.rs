@@ -1,22 +1,42 @@
-mod cell;
+mod alter;
+mod autovacuum;
+mod btree;
+mod cache;
 mod database;
 mod executor;
+mod foreign_key;
+mod integrity;
+mod journal;
+mod lock;
+mod mmap;
 mod page;
-mod page_scanner;
 mod parser;
+mod pragma;
 mod record;
-mod serial_type;
+mod shm;
+mod statement;
 mod table;
+mod trigger;
 mod utils;
+mod vacuum;
+mod value;
+mod vfs;
+mod wal;
 
 use anyhow::Result;
 use database::Database;
 use executor::Executor;
-use parser::sql_query;
+use page::{MyError, TextEncoding};
+use serde_json::json;
+use statement::Statement;
 use std::{
-    io::{BufRead, BufReader, Write},
-    net::TcpListener,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    process::ExitCode,
+    rc::Rc,
 };
+use value::Value;
+use vfs::MemoryVfs;
 
 use clap::{Parser, Subcommand};
 
@@ -26,6 +46,14 @@ struct Cli {
     /// database path
     path: Option<String>,
 
+    /// memory-map up to this many bytes of the database file for reading
+    #[arg(long, global = true)]
+    mmap_size: Option<u64>,
+
+    /// load the database file into memory, changes are never written back to it
+    #[arg(long, global = true)]
+    deserialize: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -40,18 +68,52 @@ enum Commands {
 
     Run {
         statement: Option<String>,
+
+        /// value of a parameter in every statement: the next one by number, or NAME=VALUE for a named one
+        #[arg(long = "param")]
+        params: Vec<String>,
     },
 
-    Web,
+    Web {
+        /// port to listen on, on 127.0.0.1
+        #[arg(long, default_value_t = 4221)]
+        port: u16,
+    },
+}
+
+// Errors are printed without the backtrace anyhow would add, and make the process fail.
+fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            match e.downcast_ref::<MyError>() {
+                Some(MyError::Syntax(parse_error)) => {
+                    eprintln!("Error: {}", parse_error.diagnostic())
+                }
+                _ => eprintln!("Error: {e}"),
+            }
+            ExitCode::FAILURE
+        }
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
+fn run(cli: Cli) -> Result<()> {
     let db_path = match cli.path {
         Some(path) => path,
         None => String::from("./sample.db"),
     };
-    let mut database = Database::from(db_path);
+    let mut database = if db_path == Database::MEMORY {
+        Database::memory(TextEncoding::Utf8)?
+    } else if cli.deserialize {
+        let vfs = MemoryVfs::new();
+        vfs.insert(&db_path, std::fs::read(&db_path)?);
+        Database::from_vfs(Rc::new(vfs), db_path)?
+    } else {
+        Database::from(db_path)?
+    };
+    if let Some(mmap_size) = cli.mmap_size {
+        database.set_mmap_size(mmap_size)?;
+    }
 
     match cli.command {
         Commands::DbInfo => {
@@ -59,43 +121,222 @@ fn main() -> Result<()> {
             println!("database page count: {}", database.get_page_count());
         }
         Commands::Tables => {
-            let table_names = database.get_table_names();
+            let table_names = database.get_table_names()?;
             for name in table_names {
                 println!("{name}");
             }
         }
-        Commands::Run { statement } => {
+        Commands::Run { statement, params } => {
             if let Some(stem) = statement {
-                let statement = sql_query(stem.as_str()).unwrap();
-                let executor = Executor::from(database);
-                executor.execute(statement.1);
+                // Several statements separated by ';' share one connection, so transactions can span them.
+                let mut executor = Executor::from(database)?;
+                let mut statements = parser::statements(&stem)
+                    .map(|statement| Ok(Statement::from(statement?)?))
+                    .collect::<Result<Vec<Statement>>>()?;
+                bind_params(&mut statements, &params)?;
+                for statement in &mut statements {
+                    statement.execute(&mut executor)?;
+                }
             } else {
                 println!("No SQL statement to run!");
             }
         }
-        Commands::Web => {
-            main1();
+        Commands::Web { port } => {
+            main1(Executor::from(database)?, port)?;
         }
     }
 
     Ok(())
 }
 
+/*
+    The values are bound to every statement of the run, each numbered on its own: a plain value
+    goes to the next number, from 1, NAME=VALUE to the parameter of that name, in each statement
+    which has it. A value that no statement has a parameter for is an error, given before any
+    statement runs. A value is read like a pragma value, a number or a string literal, anything
+    else is taken as text.
+*/
+fn bind_params(statements: &mut [Statement], params: &[String]) -> Result<()> {
+    let mut number = 0;
+    for param in params {
+        let named = param
+            .split_once('=')
+            .filter(|(name, _)| name.starts_with([':', '@', '$']));
+        let (name, text) = match named {
+            Some((name, text)) => (name.to_string(), text),
+            None => {
+                number += 1;
+                (format!("?{number}"), param.as_str())
+            }
+        };
+        let value = match parser::pragma_value(text) {
+            Ok(("", value)) => value,
+            _ => Value::Text(text.to_string()),
+        };
+        let mut bound = false;
+        for statement in statements.iter_mut() {
+            let index = match named {
+                Some(_) => statement.parameter_index(&name),
+                None => (number <= statement.parameter_count()).then_some(number),
+            };
+            if let Some(index) = index {
+                statement.bind(index, value.clone())?;
+                bound = true;
+            }
+        }
+        if !bound {
+            return Err(MyError::Schema(format!("no such parameter: {name}")).into());
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
-async fn main1() {
-    let listener = TcpListener::bind("127.0.0.1:4221").unwrap();
+async fn main1(mut executor: Executor, port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
     for stream in listener.incoming() {
         match stream {
             Ok(mut stream) => {
-                let buf_reader = BufReader::new(&mut stream);
-                let request_lines = buf_reader.lines().into_iter().next().unwrap().unwrap();
-                println!("{}", request_lines);
-                let response = "HTTP/1.1 200 OK\r\n\r\n";
-                stream.write_all(response.as_bytes()).unwrap();
+                if let Err(e) = handle_connection(&mut stream, &mut executor) {
+                    println!("error: {}", e);
+                }
             }
             Err(e) => {
                 println!("error: {}", e);
             }
         }
     }
+    Ok(())
+}
+
+/*
+    POST /query runs the SQL in the request body. The answer is JSON: {"ok": true} once every
+    statement ran, or {"error": {"message": ...}} for the first one that failed. Syntax errors
+    also tell where they are and what was expected there. Query output goes to the console.
+    The status is 400 for a statement that is wrong, 503 when the database is locked and 500
+    when the database itself cannot be read or written.
+*/
+fn handle_connection(stream: &mut TcpStream, executor: &mut Executor) -> Result<()> {
+    let mut reader = BufReader::new(&mut *stream);
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(());
+    }
+    println!("{}", request_line.trim_end());
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            content_length = value.trim().parse()?;
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    let request: Vec<&str> = request_line.split_whitespace().take(2).collect();
+    if request != ["POST", "/query"] {
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n")?;
+        return Ok(());
+    }
+    let result = parser::statements(&String::from_utf8_lossy(&body))
+        .try_for_each(|statement| Statement::from(statement?)?.execute(executor));
+    let (status, json) = match result {
+        Ok(()) => ("200 OK", json!({ "ok": true })),
+        Err(e) => (status(&e), json!({ "error": error_json(&e) })),
+    };
+    let json = json.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{json}",
+        json.len()
+    )?;
+    Ok(())
+}
+
+fn status(error: &MyError) -> &'static str {
+    match error {
+        MyError::Busy => "503 Service Unavailable",
+        MyError::Io(_)
+        | MyError::Offset(_)
+        | MyError::Utf8(_)
+        | MyError::Slice(_)
+        | MyError::ReadOnly
+        | MyError::Corrupt
+        | MyError::CorruptPage(_)
+        | MyError::NotADatabase
+        | MyError::Unsupported(_) => "500 Internal Server Error",
+        _ => "400 Bad Request",
+    }
+}
+
+fn error_json(error: &MyError) -> serde_json::Value {
+    match error {
+        MyError::Syntax(e) => json!({
+            "message": e.to_string(),
+            "line": e.line,
+            "column": e.column,
+            "offset": e.offset,
+            "fragment": e.fragment,
+            "expected": e.expected,
+        }),
+        e => json!({ "message": e.to_string() }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use statement::Step;
+
+    fn prepare(sql: &str) -> Vec<Statement> {
+        parser::statements(sql)
+            .map(|statement| Statement::from(statement.unwrap()).unwrap())
+            .collect()
+    }
+
+    fn params(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn values_go_to_every_statement_with_the_parameter() {
+        let mut statements = prepare("SELECT ?, :a; SELECT :a; SELECT 1");
+        bind_params(&mut statements, &params(&["'it''s'", ":a=-2.5"])).unwrap();
+        let mut executor = Executor::from(Database::memory(TextEncoding::Utf8).unwrap()).unwrap();
+        let rows: Vec<String> = statements
+            .iter_mut()
+            .map(|statement| match statement.step(&mut executor).unwrap() {
+                Step::Row(values) => values
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect::<Vec<_>>()
+                    .join("|"),
+                Step::Done => String::new(),
+            })
+            .collect();
+        // The plain value also goes to ?1 of the second statement, which is :a, until :a=-2.5.
+        assert_eq!(rows, ["it's|-2.5", "-2.5", "1"]);
+    }
+
+    #[test]
+    fn values_no_parameter_takes_are_errors() {
+        for (sql, values, message) in [
+            ("SELECT ?, :a", &[":b=1"][..], "no such parameter: :b"),
+            (
+                "SELECT ?; SELECT :a",
+                &["1", "2"][..],
+                "no such parameter: ?2",
+            ),
+            ("SELECT 1", &["1"][..], "no such parameter: ?1"),
+        ] {
+            let mut statements = prepare(sql);
+            let error = bind_params(&mut statements, &params(values)).unwrap_err();
+            assert_eq!(error.to_string(), message, "{sql}");
+        }
+    }
 }
@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::ops::Deref;
+use std::os::fd::AsRawFd;
+use std::rc::Rc;
+
+use crate::page::Result;
+
+/*
+    With PRAGMA mmap_size (or --mmap-size) set, up to that many bytes of the database file are
+    mapped into memory, read only. Pages within the map are not read into buffers of their own,
+    they are borrowed from the map until somebody changes them. Writes still go through the
+    file, the map shows them as the kernel keeps both views coherent.
+
+    The map never reaches beyond the end of the file: when the file is shorter than the header
+    claims, the pages past its end are read the ordinary way, and report the same errors.
+*/
+#[derive(Debug)]
+pub struct MappedFile {
+    ptr: *const u8,
+    len: usize,
+}
+
+impl MappedFile {
+    // Map the first `len` bytes of the file, None when there is nothing to map.
+    pub fn map(file: &File, len: usize) -> Result<Option<Self>> {
+        if len == 0 {
+            return Ok(None);
+        }
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Some(Self {
+            ptr: ptr as *const u8,
+            len,
+        }))
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/*
+    The content of a page as loaded: a buffer of its own, shared with the page cache, or a part
+    of the mapped file. Either way cloning it is cheap, changing it makes a private copy first.
+*/
+#[derive(Debug, Clone)]
+pub enum PageData {
+    Owned(Rc<Vec<u8>>),
+    Mapped {
+        map: Rc<MappedFile>,
+        offset: usize,
+        len: usize,
+    },
+}
+
+impl PageData {
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        match self {
+            PageData::Owned(data) => Rc::make_mut(data),
+            PageData::Mapped { .. } => {
+                *self = PageData::Owned(Rc::new(self.to_vec()));
+                self.to_mut()
+            }
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            PageData::Owned(data) => Rc::unwrap_or_clone(data),
+            mapped => mapped.to_vec(),
+        }
+    }
+}
+
+impl Deref for PageData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            PageData::Owned(data) => data,
+            PageData::Mapped { map, offset, len } => &map[*offset..*offset + len],
+        }
+    }
+}
@@ -1,9 +1,8 @@
-use std::fs::File;
-use std::io::prelude::*;
 use thiserror::Error;
 
-use crate::cell::{Cell, TableInteriorCell};
+use crate::parser::{ConflictResolution, ParseError, RaiseAction};
 use crate::utils;
+use crate::vfs::VfsFile;
 
 // You need to set RUST_LIB_BACKTRACE=1 to enable backtrace here.
 // Running the code like "RUST_LIB_BACKTRACE=1 cargo run -- sample.db tables"
@@ -20,10 +19,86 @@ pub enum MyError {
 
     #[error("Slice error: {0}")]
     Slice(#[from] std::array::TryFromSliceError),
+
+    #[error("no such table: {0}")]
+    NoSuchTable(String),
+
+    #[error("no such column: {0}")]
+    NoSuchColumn(String),
+
+    #[error("no such index: {0}")]
+    NoSuchIndex(String),
+
+    #[error("no such database: {0}")]
+    NoSuchDatabase(String),
+
+    #[error("{0}")]
+    Schema(String),
+
+    #[error("{0}")]
+    Constraint(String),
+
+    #[error("NOT NULL constraint failed: {table}.{column}")]
+    NotNull { table: String, column: String },
+
+    // The columns of the PRIMARY KEY or UNIQUE constraint, or of the UNIQUE index.
+    #[error("UNIQUE constraint failed: {}", qualified(.table, .columns))]
+    Unique { table: String, columns: Vec<String> },
+
+    // The name of the constraint, or its expression when it has none.
+    #[error("CHECK constraint failed: {constraint}")]
+    Check { table: String, constraint: String },
+
+    #[error("FOREIGN KEY constraint failed")]
+    ForeignKey,
+
+    // A constraint failing under ROLLBACK or FAIL, which end the statement differently.
+    #[error("{1}")]
+    Conflict(ConflictResolution, Box<MyError>),
+
+    // A value bound to a parameter number the statement doesn't have.
+    #[error("column index out of range")]
+    Range,
+
+    #[error("database is read only")]
+    ReadOnly,
+
+    #[error("{0}")]
+    Transaction(String),
+
+    #[error("no such savepoint: {0}")]
+    NoSuchSavepoint(String),
+
+    #[error("database is locked")]
+    Busy,
+
+    #[error("database disk image is malformed")]
+    Corrupt,
+
+    #[error("database disk image is malformed (page {0})")]
+    CorruptPage(u32),
+
+    #[error("file is not a database")]
+    NotADatabase,
+
+    #[error("unsupported file format: {0}")]
+    Unsupported(String),
+
+    #[error("{0}")]
+    Syntax(ParseError),
+
+    // RAISE() in a trigger, with its message.
+    #[error("{1}")]
+    Raised(RaiseAction, String),
 }
 
 pub type Result<T> = core::result::Result<T, MyError>;
 
+fn qualified(table: &str, columns: &[String]) -> String {
+    let columns: Vec<String> = columns.iter().map(|c| format!("{table}.{c}")).collect();
+    columns.join(", ")
+}
+
 /*
    File header only exists in the first page.
    File Header Format
@@ -56,7 +131,19 @@ pub type Result<T> = core::result::Result<T, MyError>;
 #[derive(Debug, Clone)]
 pub struct FileHeader {
     pub page_size: u16,
+    pub read_version: u8,
+    pub reserved_bytes: u8,
+    pub file_change_counter: u32,
     pub page_count: u32,
+    pub first_freelist_trunk_page: u32,
+    pub freelist_page_count: u32,
+    pub schema_cookie: u32,
+    pub default_cache_size: i32,
+    pub largest_root_page: u32,
+    pub text_encoding: TextEncoding,
+    pub user_version: i32,
+    pub incremental_vacuum: bool,
+    pub application_id: i32,
 }
 
 /* And there are 4 types of page, the type of the page is included at the begining of page header:
@@ -65,13 +152,7 @@ pub struct FileHeader {
     A value of 10 (0x0a) means the page is a leaf index b-tree page.
     A value of 13 (0x0d) means the page is a leaf table b-tree page.
 */
-#[derive(Debug)]
-pub enum Page {
-    TableLeaf(TableLeafPage),
-    TableInterior(TableInteriorPage),
-}
-
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum PageType {
     TableLeaf,
     IndexLeaf,
@@ -87,20 +168,7 @@ pub enum PageType {
         * Unallocated space
         * The cell content area
         * The reserved region
-*/
-#[derive(Debug, Clone)]
-pub struct TableLeafPage {
-    pub page_header: PageHeader,
-    pub cells: Vec<Cell>,
-}
 
-#[derive(Debug, Clone)]
-pub struct TableInteriorPage {
-    pub page_header: PageHeader,
-    pub cells: Vec<TableInteriorCell>,
-}
-
-/*
     Page Header Layout
     Offset	Size	Description
         0	1	The one-byte flag at offset 0 indicating the b-tree page type.
@@ -123,29 +191,305 @@ pub struct PageHeader {
 impl FileHeader {
     pub const FILE_HEADER_SIZE: usize = 100;
 
-    pub fn from(file: &mut File) -> Result<Self> {
+    pub const MAGIC: &[u8] = b"SQLite format 3\0";
+
+    pub const DEFAULT_PAGE_SIZE: u16 = 4096;
+
+    // The SQLite version whose file format is written, 3.46.0.
+    pub const SQLITE_VERSION_NUMBER: u32 = 3046000;
+
+    pub fn from(file: &dyn VfsFile) -> Result<Self> {
         let mut header = [0; Self::FILE_HEADER_SIZE];
-        file.read_exact(&mut header)?;
-        Ok(Self {
+        file.read_exact_at(&mut header, 0).map_err(|e| match e {
+            MyError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                MyError::NotADatabase
+            }
+            e => e,
+        })?;
+        Self::check(&header)?;
+        Ok(Self::parse(&header))
+    }
+
+    // Refuse files which are not databases, or use parts of the format not implemented here.
+    fn check(header: &[u8]) -> Result<()> {
+        if &header[..16] != Self::MAGIC {
+            return Err(MyError::NotADatabase);
+        }
+        let page_size = u16::from_be_bytes([header[16], header[17]]);
+        if page_size == 1 {
+            return Err(MyError::Unsupported("page size 65536".to_string()));
+        }
+        if !page_size.is_power_of_two() || page_size < 512 {
+            return Err(MyError::NotADatabase);
+        }
+        if header[19] > 2 {
+            return Err(MyError::Unsupported(format!(
+                "file format version {}",
+                header[19]
+            )));
+        }
+        let schema_format = u32::from_be_bytes(header[44..48].try_into()?);
+        if schema_format > 4 {
+            return Err(MyError::Unsupported(format!(
+                "schema format {schema_format}"
+            )));
+        }
+        // Pages must leave room for at least 480 bytes besides the reserved space.
+        if (page_size as usize) < header[20] as usize + 480 {
+            return Err(MyError::NotADatabase);
+        }
+        Ok(())
+    }
+
+    pub fn parse(header: &[u8]) -> Self {
+        let read_u32 = |offset: usize| {
+            u32::from_be_bytes([
+                header[offset],
+                header[offset + 1],
+                header[offset + 2],
+                header[offset + 3],
+            ])
+        };
+        Self {
             page_size: u16::from_be_bytes([header[16], header[17]]),
-            page_count: u32::from_be_bytes([header[28], header[29], header[30], header[31]]),
-        })
+            read_version: header[19],
+            reserved_bytes: header[20],
+            file_change_counter: read_u32(24),
+            page_count: read_u32(28),
+            first_freelist_trunk_page: read_u32(32),
+            freelist_page_count: read_u32(36),
+            schema_cookie: read_u32(40),
+            default_cache_size: read_u32(48) as i32,
+            largest_root_page: read_u32(52),
+            text_encoding: TextEncoding::from(read_u32(56)),
+            user_version: read_u32(60) as i32,
+            incremental_vacuum: read_u32(64) != 0,
+            application_id: read_u32(68) as i32,
+        }
+    }
+
+    /*
+        The database size at offset 28 only counts if it is not zero and the version-valid-for
+        number at offset 92 matches the change counter, writers older than SQLite 3.7.0 did not
+        keep it up to date. Otherwise the size of the file gives the page count. Like SQLite,
+        a database larger than its file is corrupt.
+    */
+    pub fn parse_sized(first_page: &[u8], file_size: u64) -> Result<Self> {
+        let mut header = Self::parse(first_page);
+        let file_pages = file_size / header.page_size as u64;
+        let version_valid_for = u32::from_be_bytes(first_page[92..96].try_into()?);
+        if header.page_count == 0 || version_valid_for != header.file_change_counter {
+            header.page_count = u32::try_from(file_pages).map_err(|_| MyError::Corrupt)?;
+        } else if header.page_count as u64 > file_pages {
+            return Err(MyError::Corrupt);
+        }
+        Ok(header)
+    }
+
+    // Write the mutable fields back into the first page. The version-valid-for number must
+    // follow the change counter, otherwise readers ignore the in-header database size.
+    pub fn write_to(&self, page: &mut [u8]) {
+        page[24..28].copy_from_slice(&self.file_change_counter.to_be_bytes());
+        page[28..32].copy_from_slice(&self.page_count.to_be_bytes());
+        page[32..36].copy_from_slice(&self.first_freelist_trunk_page.to_be_bytes());
+        page[36..40].copy_from_slice(&self.freelist_page_count.to_be_bytes());
+        page[40..44].copy_from_slice(&self.schema_cookie.to_be_bytes());
+        page[48..52].copy_from_slice(&self.default_cache_size.to_be_bytes());
+        page[52..56].copy_from_slice(&self.largest_root_page.to_be_bytes());
+        page[60..64].copy_from_slice(&self.user_version.to_be_bytes());
+        page[64..68].copy_from_slice(&(self.incremental_vacuum as u32).to_be_bytes());
+        page[68..72].copy_from_slice(&self.application_id.to_be_bytes());
+        page[92..96].copy_from_slice(&self.file_change_counter.to_be_bytes());
+    }
+
+    /*
+        The first page of a new database: a header for 4096 bytes pages in rollback journal
+        mode, followed by the empty leaf page of sqlite_schema.
+    */
+    pub fn empty_database(encoding: TextEncoding) -> Vec<u8> {
+        let page_size = Self::DEFAULT_PAGE_SIZE;
+        let mut page = vec![0; page_size as usize];
+        page[..16].copy_from_slice(Self::MAGIC);
+        page[16..18].copy_from_slice(&page_size.to_be_bytes());
+        page[18..24].copy_from_slice(&[1, 1, 0, 64, 32, 32]);
+        page[44..48].copy_from_slice(&4u32.to_be_bytes());
+        page[56..60].copy_from_slice(&encoding.value().to_be_bytes());
+        page[96..100].copy_from_slice(&Self::SQLITE_VERSION_NUMBER.to_be_bytes());
+        Self {
+            page_size,
+            read_version: 1,
+            reserved_bytes: 0,
+            file_change_counter: 1,
+            page_count: 1,
+            first_freelist_trunk_page: 0,
+            freelist_page_count: 0,
+            schema_cookie: 0,
+            default_cache_size: 0,
+            largest_root_page: 0,
+            text_encoding: encoding,
+            user_version: 0,
+            incremental_vacuum: false,
+            application_id: 0,
+        }
+        .write_to(&mut page);
+        PageHeader {
+            page_type: PageType::TableLeaf,
+            first_freeblock: 0,
+            cell_count: 0,
+            cell_content_offset: page_size as u32,
+            fragmented_bytes_count: 0,
+            rightmost_pointer: None,
+        }
+        .write_to(&mut page[Self::FILE_HEADER_SIZE..]);
+        page
+    }
+
+    // File format version numbers 1 and 2 stand for the legacy rollback journal and WAL.
+    pub fn is_wal(&self) -> bool {
+        self.read_version == 2
+    }
+
+    // Only auto-vacuum databases have pointer-map pages.
+    pub fn is_auto_vacuum(&self) -> bool {
+        self.largest_root_page != 0
+    }
+
+    // The part of every page b-trees and freelists use, the rest is left to extensions.
+    pub fn usable_size(&self) -> usize {
+        self.page_size as usize - self.reserved_bytes as usize
+    }
+}
+
+/*
+    Every text value in a database is stored in the one encoding named by the header. A value of
+    0 is only found in a new, still empty database and stands for UTF-8. The bytes of a string
+    are what BINARY collation compares, so in a UTF-16 database text sorts by its UTF-16 form.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextEncoding {
+    Utf8,
+    Utf16le,
+    Utf16be,
+}
+
+impl TextEncoding {
+    pub fn from(value: u32) -> Self {
+        match value {
+            2 => TextEncoding::Utf16le,
+            3 => TextEncoding::Utf16be,
+            _ => TextEncoding::Utf8,
+        }
+    }
+
+    pub fn value(&self) -> u32 {
+        match self {
+            TextEncoding::Utf8 => 1,
+            TextEncoding::Utf16le => 2,
+            TextEncoding::Utf16be => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TextEncoding::Utf8 => "UTF-8",
+            TextEncoding::Utf16le => "UTF-16le",
+            TextEncoding::Utf16be => "UTF-16be",
+        }
+    }
+
+    // Invalid sequences and a dangling odd byte decode as U+FFFD, as they do in sqlite.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let units = |from_bytes: fn([u8; 2]) -> u16| {
+            let (chunks, rest) = bytes.as_chunks::<2>();
+            let units = chunks.iter().map(|c| from_bytes(*c));
+            let mut text = char::decode_utf16(units)
+                .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                .collect::<String>();
+            if !rest.is_empty() {
+                text.push(char::REPLACEMENT_CHARACTER);
+            }
+            text
+        };
+        match self {
+            TextEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            TextEncoding::Utf16le => units(u16::from_le_bytes),
+            TextEncoding::Utf16be => units(u16::from_be_bytes),
+        }
+    }
+
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        match self {
+            TextEncoding::Utf8 => text.as_bytes().to_vec(),
+            TextEncoding::Utf16le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            TextEncoding::Utf16be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+        }
+    }
+
+    // BINARY collation: the stored bytes compared with memcmp.
+    pub fn compare(&self, a: &str, b: &str) -> std::cmp::Ordering {
+        match self {
+            TextEncoding::Utf8 => a.as_bytes().cmp(b.as_bytes()),
+            _ => self.encode(a).cmp(&self.encode(b)),
+        }
+    }
+}
+
+impl PageType {
+    pub fn is_leaf(&self) -> bool {
+        matches!(self, PageType::TableLeaf | PageType::IndexLeaf)
+    }
+
+    pub fn is_table(&self) -> bool {
+        matches!(self, PageType::TableLeaf | PageType::TableInterior)
+    }
+
+    pub fn flag(&self) -> u8 {
+        match self {
+            PageType::IndexInterior => 2,
+            PageType::TableInterior => 5,
+            PageType::IndexLeaf => 10,
+            PageType::TableLeaf => 13,
+        }
     }
 }
 
 impl PageHeader {
     pub fn from(buffer: &[u8]) -> Result<Self> {
-        let cell_content_offset = u32::from_be_bytes([buffer[5], buffer[6], buffer[7], buffer[8]]);
+        if buffer.len() < 12 {
+            return Err(MyError::Corrupt);
+        }
+        let page_type = utils::get_page_type(buffer[0]).ok_or(MyError::Corrupt)?;
+        // A zero value for the cell content offset is interpreted as 65536.
+        let cell_content_offset = match u16::from_be_bytes([buffer[5], buffer[6]]) {
+            0 => 65536,
+            offset => offset as u32,
+        };
+        let rightmost_pointer = if page_type.is_leaf() {
+            None
+        } else {
+            Some(u32::from_be_bytes(buffer[8..12].try_into()?))
+        };
         Ok(Self {
-            page_type: utils::get_page_type(buffer[0]),
+            page_type,
             first_freeblock: u16::from_be_bytes([buffer[1], buffer[2]]),
             cell_count: u16::from_be_bytes([buffer[3], buffer[4]]),
             cell_content_offset,
-            fragmented_bytes_count: buffer[9],
-            rightmost_pointer: None,
+            fragmented_bytes_count: buffer[7],
+            rightmost_pointer,
         })
     }
 
+    pub fn write_to(&self, buffer: &mut [u8]) {
+        buffer[0] = self.page_type.flag();
+        buffer[1..3].copy_from_slice(&self.first_freeblock.to_be_bytes());
+        buffer[3..5].copy_from_slice(&self.cell_count.to_be_bytes());
+        buffer[5..7].copy_from_slice(&(self.cell_content_offset as u16).to_be_bytes());
+        buffer[7] = self.fragmented_bytes_count;
+        if let Some(pointer) = self.rightmost_pointer {
+            buffer[8..12].copy_from_slice(&pointer.to_be_bytes());
+        }
+    }
+
     pub fn get_header_size(&self) -> usize {
         if self.rightmost_pointer.is_some() {
             12
@@ -154,72 +498,3 @@ impl PageHeader {
         }
     }
 }
-
-impl TableLeafPage {
-    pub fn from(buffer: &[u8], first_page: bool) -> Self {
-        let page_header = PageHeader::from(buffer).unwrap();
-        let cells = Self::get_cells_from(
-            buffer,
-            page_header.cell_count as usize,
-            page_header.get_header_size(),
-            first_page,
-        );
-        Self { page_header, cells }
-    }
-
-    // Notice that the offset int the cell_pointer is relative to the beginning of the page.
-    // For the first page, the buffer passed in has already skipped the file header. So we
-    // need to substract the file header size from the offset.
-    fn get_cells_from(
-        buffer: &[u8],
-        cell_count: usize,
-        header_size: usize,
-        first_page: bool,
-    ) -> Vec<Cell> {
-        let mut cells: Vec<Cell> = Vec::new();
-        let cell_pointers = &buffer[header_size..header_size + cell_count * 2];
-        for arr in cell_pointers.chunks_exact(2) {
-            let offset = u16::from_be_bytes(arr.try_into().unwrap())
-                - if first_page {
-                    FileHeader::FILE_HEADER_SIZE as u16
-                } else {
-                    0
-                };
-            cells.push(Cell::from(&buffer[offset as usize..]).unwrap());
-        }
-        cells
-    }
-}
-
-impl TableInteriorPage {
-    pub fn from(buffer: &[u8], first_page: bool) -> Self {
-        let page_header = PageHeader::from(buffer).unwrap();
-        let cells = Self::get_table_interior_cells_from(
-            buffer,
-            page_header.cell_count as usize,
-            page_header.get_header_size(),
-            first_page,
-        );
-        Self { page_header, cells }
-    }
-
-    fn get_table_interior_cells_from(
-        buffer: &[u8],
-        cell_count: usize,
-        header_size: usize,
-        fisrt_page: bool,
-    ) -> Vec<TableInteriorCell> {
-        let mut cells: Vec<TableInteriorCell> = Vec::new();
-        let cell_pointers = &buffer[header_size..header_size + cell_count * 2];
-        for arr in cell_pointers.chunks_exact(2) {
-            let offset = u16::from_be_bytes(arr.try_into().unwrap())
-                - if fisrt_page {
-                    FileHeader::FILE_HEADER_SIZE as u16
-                } else {
-                    0
-                };
-            cells.push(TableInteriorCell::from(&buffer[offset as usize..]).unwrap());
-        }
-        cells
-    }
-}
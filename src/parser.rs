@@ -1,130 +1,2178 @@
-use nom::IResult;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::fmt;
+
+use nom::InputLength;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, tag_no_case, take_while1};
-use nom::character::complete::{alpha1, multispace0, multispace1};
+use nom::bytes::complete::{tag_no_case, take_while, take_while1};
+use nom::character::complete::{char, digit0, digit1, multispace0, multispace1, satisfy};
 use nom::character::is_alphanumeric;
-use nom::combinator::{map, opt};
-use nom::multi::{many0, many1};
-use nom::sequence::{delimited, preceded, terminated, tuple};
+use nom::combinator::{consumed, cut, eof, map, not, opt, peek, recognize, value};
+use nom::error::ErrorKind;
+use nom::multi::{many0, many1, separated_list1};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
+
+use crate::page::{MyError, Result};
+use crate::value::Value;
+
+type IResult<I, O> = nom::IResult<I, O, Failure<I>>;
+
+/*
+    The error of the parsers below: where parsing failed and the tokens that would have been
+    accepted there. Of the branches tried by alt, the one that got furthest wins, so that the
+    error points at the actual mistake rather than at the start of the statement.
+*/
+#[derive(Debug, PartialEq)]
+pub struct Failure<I> {
+    input: I,
+    expected: Vec<String>,
+}
+
+impl<I: InputLength> nom::error::ParseError<I> for Failure<I> {
+    fn from_error_kind(input: I, _kind: ErrorKind) -> Self {
+        Self {
+            input,
+            expected: Vec::new(),
+        }
+    }
+
+    fn append(_input: I, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn from_char(input: I, c: char) -> Self {
+        Self {
+            input,
+            expected: vec![c.to_string()],
+        }
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match self.input.input_len().cmp(&other.input.input_len()) {
+            Ordering::Less => self,
+            Ordering::Greater => other,
+            Ordering::Equal => {
+                for token in other.expected {
+                    if !self.expected.contains(&token) {
+                        self.expected.push(token);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+thread_local! {
+    /*
+        The furthest point of the statement being parsed where a parser failed, as the length
+        of the input left there, with every token expected at that point. A failure under opt,
+        many0 or the operator loop of binary_chain never becomes the error of the statement,
+        yet it may be the one that got furthest.
+    */
+    static FURTHEST: RefCell<Option<(usize, Vec<String>)>> = const { RefCell::new(None) };
+}
 
-#[derive(Debug)]
+// Add what a failing parser expected to the furthest failure, unless another one got further.
+fn note_failure(left: usize, expected: &[String]) {
+    FURTHEST.with_borrow_mut(|furthest| match furthest {
+        Some((furthest_left, tokens)) if *furthest_left == left => {
+            for token in expected {
+                if !tokens.contains(token) {
+                    tokens.push(token.clone());
+                }
+            }
+        }
+        Some((furthest_left, _)) if *furthest_left < left => {}
+        _ => *furthest = Some((left, expected.to_vec())),
+    });
+}
+
+/*
+    A syntax error as reported to the user. Line and column count from 1, the column in
+    characters. The fragment is the token where parsing stopped, empty at the end of the input.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub fragment: String,
+    pub expected: Vec<String>,
+    // The whole line holding the error, for showing it underlined.
+    pub source_line: String,
+}
+
+impl ParseError {
+    fn from(sql: &str, failure: Failure<&str>) -> Self {
+        let rest = failure.input.trim_start();
+        let offset = sql.len() - rest.len();
+        let line_start = sql[..offset].rfind('\n').map_or(0, |n| n + 1);
+        let line_end = sql[offset..].find('\n').map_or(sql.len(), |n| offset + n);
+        let fragment_length = match rest.chars().next() {
+            None => 0,
+            Some(c) if is_sql_identifier(c) => rest
+                .find(|c: char| !is_sql_identifier(c))
+                .unwrap_or(rest.len()),
+            Some(c) => c.len_utf8(),
+        };
+        Self {
+            offset,
+            line: sql[..offset].matches('\n').count() + 1,
+            column: sql[line_start..offset].chars().count() + 1,
+            fragment: rest[..fragment_length].to_string(),
+            expected: failure.expected,
+            source_line: sql[line_start..line_end].trim_end_matches('\r').to_string(),
+        }
+    }
+
+    /*
+        The message followed by the line in error, with the fragment underlined:
+            near "FORM": syntax error at line 1, column 10
+              SELECT * FORM apples
+                       ^^^^ expected FROM, WHERE or ";"
+    */
+    pub fn diagnostic(&self) -> String {
+        let mut text = format!("{self} at line {}, column {}\n", self.line, self.column);
+        text.push_str(&format!("  {}\n", self.source_line));
+        let underline = "^".repeat(self.fragment.chars().count().max(1));
+        text.push_str(&format!("  {}{underline}", " ".repeat(self.column - 1)));
+        if !self.expected.is_empty() {
+            text.push_str(&format!(" expected {}", self.expected_text()));
+        }
+        text
+    }
+
+    // Keywords and kinds of tokens as they are, punctuation quoted: FROM, identifier or "(".
+    pub fn expected_text(&self) -> String {
+        let tokens: Vec<String> = self
+            .expected
+            .iter()
+            .map(|t| match t.chars().all(is_sql_identifier) {
+                true => t.clone(),
+                false => format!("\"{t}\""),
+            })
+            .collect();
+        match tokens.split_last() {
+            Some((last, [])) => last.clone(),
+            Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+            None => String::new(),
+        }
+    }
+}
+
+// Worded like SQLite's messages.
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.fragment.is_empty() {
+            write!(f, "incomplete input")
+        } else {
+            write!(f, "near \"{}\": syntax error", self.fragment)
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Clone)]
 pub enum SqlStatement {
     SELECT(SelectStatement),
     CREATE(CreateStatement),
+    VIEW(CreateViewStatement),
+    TRIGGER(CreateTriggerStatement),
+    INDEX(CreateIndexStatement),
+    DROP(DropStatement),
+    ALTER(AlterTableStatement),
+    INSERT(InsertStatement),
+    UPDATE(UpdateStatement),
+    DELETE(DeleteStatement),
+    BEGIN(TransactionMode),
+    COMMIT,
+    ROLLBACK(Option<String>),
+    SAVEPOINT(String),
+    RELEASE(String),
+    PRAGMA(PragmaStatement),
+    VACUUM(VacuumStatement),
+    ATTACH(AttachStatement),
+    DETACH(String),
 }
 
-#[derive(Debug)]
+/*
+    SELECT result-column, ... [FROM [schema-name.]table-name] [WHERE expr]
+    Without FROM the table name is empty and the query has a single row.
+*/
+#[derive(Debug, Clone)]
 pub struct SelectStatement {
+    pub schema: Option<String>,
     pub table: String,
-    pub fields: Vec<String>,
-    pub condition: String,
+    pub columns: ResultColumns,
+    pub condition: Option<Expression>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ResultColumns {
+    // COUNT(*)
+    Count,
+    // *
+    All,
+    // Each expression with the name of its column: the column it reads, or its text.
+    Expressions(Vec<(String, Expression)>),
 }
 
-#[derive(Debug)]
+/*
+    CREATE [TEMP | TEMPORARY] TABLE [IF NOT EXISTS] [schema-name.]table-name ( column-def, ... )
+    Like for indexes, the sql stored in sqlite_schema is "CREATE TABLE" followed by the
+    statement as written from the table name on.
+*/
+#[derive(Debug, Clone)]
 pub struct CreateStatement {
+    pub schema: Option<String>,
     pub table_name: String,
-    pub cols: Vec<String>,
+    pub temp: bool,
+    pub if_not_exists: bool,
+    pub cols: Vec<ColumnDefinition>,
+    pub constraints: Vec<TableConstraint>,
+    pub sql: String,
+}
+
+// CREATE [TEMP | TEMPORARY] VIEW [IF NOT EXISTS] [schema-name.]view-name [( column-name, ... )] AS select-stmt
+#[derive(Debug, Clone)]
+pub struct CreateViewStatement {
+    pub schema: Option<String>,
+    pub view_name: String,
+    pub temp: bool,
+    pub if_not_exists: bool,
+    pub columns: Vec<String>,
+    pub select: SelectStatement,
+    pub sql: String,
+}
+
+/*
+    INSERT [OR conflict-resolution] INTO [schema-name.]table-name [( column-name, ... )]
+        VALUES ( expr, ... ), ... [upsert-clause ...] [RETURNING result-column, ...]
+    Without a column list the values go to all the columns of the table, in order. REPLACE
+    INTO is INSERT OR REPLACE INTO.
+*/
+#[derive(Debug, Clone)]
+pub struct InsertStatement {
+    pub schema: Option<String>,
+    pub table: String,
+    pub conflict: Option<ConflictResolution>,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Expression>>,
+    pub upserts: Vec<Upsert>,
+    pub returning: Option<ResultColumns>,
+}
+
+/*
+    ON CONFLICT [( column-name, ... )] DO NOTHING
+    ON CONFLICT [( column-name, ... )] DO UPDATE SET column-name = expr, ... [WHERE expr]
+    Without columns the clause applies to any UNIQUE or PRIMARY KEY constraint. In DO UPDATE
+    the row that could not be inserted is named "excluded".
+*/
+#[derive(Debug, Clone)]
+pub struct Upsert {
+    pub target: Vec<String>,
+    pub action: UpsertAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum UpsertAction {
+    Nothing,
+    Update(Vec<(String, Expression)>, Option<Expression>),
+}
+
+/*
+    UPDATE [OR conflict-resolution] [schema-name.]table-name SET column-name = expr, ...
+        [WHERE expr] [RETURNING result-column, ...]
+*/
+#[derive(Debug, Clone)]
+pub struct UpdateStatement {
+    pub schema: Option<String>,
+    pub table: String,
+    pub conflict: Option<ConflictResolution>,
+    pub assignments: Vec<(String, Expression)>,
+    pub condition: Option<Expression>,
+    pub returning: Option<ResultColumns>,
+}
+
+// DELETE FROM [schema-name.]table-name [WHERE expr] [RETURNING result-column, ...]
+#[derive(Debug, Clone)]
+pub struct DeleteStatement {
+    pub schema: Option<String>,
+    pub table: String,
+    pub condition: Option<Expression>,
+    pub returning: Option<ResultColumns>,
+}
+
+/*
+    PRAGMA [schema-name.]pragma-name [= pragma-value | (pragma-value)]
+    A pragma value is a signed number, a string literal or a name, names are kept as text.
+*/
+#[derive(Debug, Clone)]
+pub struct PragmaStatement {
+    pub schema: Option<String>,
+    pub name: String,
+    pub value: Option<Value>,
+}
+
+// VACUUM [schema-name] [INTO filename]
+#[derive(Debug, Clone)]
+pub struct VacuumStatement {
+    pub schema: Option<String>,
+    pub into: Option<String>,
+}
+
+/*
+    ATTACH [DATABASE] filename AS schema-name
+    DETACH [DATABASE] schema-name
+    The file name is a string literal, ':memory:' attaches a new database in memory.
+*/
+#[derive(Debug, Clone)]
+pub struct AttachStatement {
+    pub path: String,
+    pub schema: String,
+}
+
+/*
+    A deferred transaction does not touch the database until the first read or write, an
+    immediate one starts writing right away and an exclusive one also keeps readers out.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionMode {
+    Deferred,
+    Immediate,
+    Exclusive,
+}
+
+#[derive(Debug, Clone)]
+pub struct ColumnDefinition {
+    pub name: String,
+    pub type_name: Option<String>,
+    pub constraints: Vec<ColumnConstraint>,
+}
+
+#[derive(Debug, Clone)]
+pub enum ColumnConstraint {
+    PrimaryKey {
+        autoincrement: bool,
+        on_conflict: Option<ConflictResolution>,
+    },
+    NotNull(Option<ConflictResolution>),
+    Unique(Option<ConflictResolution>),
+    // The expression together with its text as written, without enclosing parentheses.
+    Default(Expression, String),
+    // Parsed so that tables using it can be read, every column compares with BINARY.
+    Collate(#[allow(dead_code)] String),
+    References(ForeignKey),
+    Check(Check),
+}
+
+#[derive(Debug, Clone)]
+pub enum TableConstraint {
+    PrimaryKey(Vec<String>, Option<ConflictResolution>),
+    Unique(Vec<String>, Option<ConflictResolution>),
+    ForeignKey(ForeignKey),
+    Check(Check),
+}
+
+/*
+    [CONSTRAINT name] CHECK ( expr )
+    A row breaks the constraint when the expression is false, NULL passes. The error names the
+    constraint, or gives the expression as written when it has no name.
+*/
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: Option<String>,
+    pub expr: Expression,
+    pub text: String,
+}
+
+/*
+    What happens to a statement writing a row that breaks a constraint. ROLLBACK ends the
+    transaction, ABORT (the default) undoes the statement, FAIL keeps the rows the statement
+    already wrote, IGNORE skips the row and REPLACE deletes the rows in the way, or for NOT NULL
+    uses the default value of the column.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConflictResolution {
+    Rollback,
+    Abort,
+    Fail,
+    Ignore,
+    Replace,
+}
+
+/*
+    REFERENCES foreign-table [( column, ... )] [ON DELETE action] [ON UPDATE action] [MATCH name]
+        [[NOT] DEFERRABLE [INITIALLY DEFERRED | INITIALLY IMMEDIATE]]
+    Without columns the primary key of the foreign table is referenced. A column constraint
+    holds the column it is attached to. MATCH is parsed but ignored, like SQLite does.
+*/
+#[derive(Debug, Clone)]
+pub struct ForeignKey {
+    pub columns: Vec<String>,
+    pub foreign_table: String,
+    pub foreign_columns: Vec<String>,
+    pub on_delete: ForeignKeyAction,
+    pub on_update: ForeignKeyAction,
+    pub deferred: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ForeignKeyAction {
+    NoAction,
+    Restrict,
+    SetNull,
+    SetDefault,
+    Cascade,
+}
+
+impl ForeignKeyAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForeignKeyAction::NoAction => "NO ACTION",
+            ForeignKeyAction::Restrict => "RESTRICT",
+            ForeignKeyAction::SetNull => "SET NULL",
+            ForeignKeyAction::SetDefault => "SET DEFAULT",
+            ForeignKeyAction::Cascade => "CASCADE",
+        }
+    }
+}
+
+/*
+    CREATE [UNIQUE] INDEX [IF NOT EXISTS] [schema-name.]index-name ON table-name ( indexed-column, ... )
+    Each indexed column may be followed by ASC or DESC. The sql is the text stored in
+    sqlite_schema, which SQLite rewrites as "CREATE [UNIQUE] INDEX" followed by the statement
+    as written from the index name on. The table is always in the schema of the index.
+*/
+#[derive(Debug, Clone)]
+pub struct CreateIndexStatement {
+    pub schema: Option<String>,
+    pub index_name: String,
+    pub table_name: String,
+    pub unique: bool,
+    pub if_not_exists: bool,
+    pub cols: Vec<IndexedColumn>,
+    pub sql: String,
+}
+
+/*
+    CREATE [TEMP | TEMPORARY] TRIGGER [IF NOT EXISTS] [schema-name.]trigger-name
+        [BEFORE | AFTER | INSTEAD OF] {DELETE | INSERT | UPDATE [OF column-name, ...]}
+        ON table-name [FOR EACH ROW] [WHEN expr] BEGIN statement; ... END
+    Triggers always fire for each row, BEFORE is the default. The statements of the body are
+    INSERT, UPDATE, DELETE or SELECT, they read the row through NEW and OLD.
+*/
+#[derive(Debug, Clone)]
+pub struct CreateTriggerStatement {
+    pub schema: Option<String>,
+    pub trigger_name: String,
+    pub temp: bool,
+    pub if_not_exists: bool,
+    pub timing: TriggerTiming,
+    pub event: TriggerEvent,
+    pub table_name: String,
+    pub condition: Option<Expression>,
+    pub body: Vec<SqlStatement>,
+    pub sql: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerTiming {
+    Before,
+    After,
+    InsteadOf,
+}
+
+impl TriggerTiming {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TriggerTiming::Before => "BEFORE",
+            TriggerTiming::After => "AFTER",
+            TriggerTiming::InsteadOf => "INSTEAD OF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TriggerEvent {
+    Insert,
+    Delete,
+    // The columns of UPDATE OF, an empty list fires on any update.
+    Update(Vec<String>),
+}
+
+// DROP {TABLE | INDEX | VIEW | TRIGGER} [IF EXISTS] [schema-name.]name
+#[derive(Debug, Clone)]
+pub struct DropStatement {
+    pub object_type: ObjectType,
+    pub schema: Option<String>,
+    pub name: String,
+    pub if_exists: bool,
+}
+
+/*
+    ALTER TABLE [schema-name.]table-name RENAME TO new-table-name
+    ALTER TABLE table-name RENAME [COLUMN] column-name TO new-column-name
+    ALTER TABLE table-name ADD [COLUMN] column-def
+    ALTER TABLE table-name DROP [COLUMN] column-name
+    The text of an added column definition is kept, it is appended to the stored CREATE TABLE.
+*/
+#[derive(Debug, Clone)]
+pub struct AlterTableStatement {
+    pub schema: Option<String>,
+    pub table_name: String,
+    pub action: AlterAction,
+}
+
+#[derive(Debug, Clone)]
+pub enum AlterAction {
+    RenameTable(String),
+    RenameColumn(String, String),
+    AddColumn(ColumnDefinition, String),
+    DropColumn(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    Table,
+    Index,
+    View,
+    Trigger,
+}
+
+impl SqlStatement {
+    /*
+        The expressions of INSERT, UPDATE, DELETE and SELECT, those of RETURNING included, in
+        the order they are written.
+    */
+    pub fn expressions(&mut self) -> Vec<&mut Expression> {
+        let mut expressions: Vec<&mut Expression> = Vec::new();
+        let returning = match self {
+            SqlStatement::INSERT(cmd) => {
+                expressions.extend(cmd.rows.iter_mut().flatten());
+                for upsert in &mut cmd.upserts {
+                    if let UpsertAction::Update(assignments, condition) = &mut upsert.action {
+                        expressions.extend(assignments.iter_mut().map(|(_, expr)| expr));
+                        expressions.extend(condition.as_mut());
+                    }
+                }
+                cmd.returning.as_mut()
+            }
+            SqlStatement::UPDATE(cmd) => {
+                expressions.extend(cmd.assignments.iter_mut().map(|(_, expr)| expr));
+                expressions.extend(cmd.condition.as_mut());
+                cmd.returning.as_mut()
+            }
+            SqlStatement::DELETE(cmd) => {
+                expressions.extend(cmd.condition.as_mut());
+                cmd.returning.as_mut()
+            }
+            SqlStatement::SELECT(cmd) => return cmd.expressions(),
+            _ => None,
+        };
+        if let Some(ResultColumns::Expressions(columns)) = returning {
+            expressions.extend(columns.iter_mut().map(|(_, expr)| expr));
+        }
+        expressions
+    }
+}
+
+impl SelectStatement {
+    // The result columns, then the WHERE clause.
+    pub fn expressions(&mut self) -> Vec<&mut Expression> {
+        let mut expressions: Vec<&mut Expression> = Vec::new();
+        if let ResultColumns::Expressions(columns) = &mut self.columns {
+            expressions.extend(columns.iter_mut().map(|(_, expr)| expr));
+        }
+        expressions.extend(self.condition.as_mut());
+        expressions
+    }
+}
+
+impl ObjectType {
+    // The type of the object as stored in sqlite_schema.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ObjectType::Table => "table",
+            ObjectType::Index => "index",
+            ObjectType::View => "view",
+            ObjectType::Trigger => "trigger",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexedColumn {
+    pub name: String,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Literal(Value),
+    Column(String),
+    // A column qualified with its table, such as NEW.a or OLD.a in a trigger.
+    TableColumn(String, String),
+    Unary(UnaryOperator, Box<Expression>),
+    Binary(Box<Expression>, BinaryOperator, Box<Expression>),
+    IsNull(Box<Expression>, bool),
+    // RAISE(IGNORE) has no message.
+    Raise(RaiseAction, String),
+    Parameter(Parameter),
+}
+
+impl Expression {
+    // Visit the expression, then those within it from left to right as they are written.
+    pub fn walk<F>(&self, action: &mut F)
+    where
+        F: FnMut(&Expression),
+    {
+        action(self);
+        match self {
+            Expression::Unary(_, operand) | Expression::IsNull(operand, _) => operand.walk(action),
+            Expression::Binary(lhs, _, rhs) => {
+                lhs.walk(action);
+                rhs.walk(action);
+            }
+            Expression::Literal(_)
+            | Expression::Column(_)
+            | Expression::TableColumn(..)
+            | Expression::Raise(..)
+            | Expression::Parameter(_) => {}
+        }
+    }
+
+    pub fn walk_mut<F>(&mut self, action: &mut F)
+    where
+        F: FnMut(&mut Expression),
+    {
+        action(self);
+        match self {
+            Expression::Unary(_, operand) | Expression::IsNull(operand, _) => {
+                operand.walk_mut(action)
+            }
+            Expression::Binary(lhs, _, rhs) => {
+                lhs.walk_mut(action);
+                rhs.walk_mut(action);
+            }
+            Expression::Literal(_)
+            | Expression::Column(_)
+            | Expression::TableColumn(..)
+            | Expression::Raise(..)
+            | Expression::Parameter(_) => {}
+        }
+    }
+}
+
+/*
+    ?, ?NNN, :name, @name or $name: a value bound to the statement before it runs. Names keep
+    their prefix, :a and @a are different parameters.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parameter {
+    Next,
+    Numbered(usize),
+    Named(String),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RaiseAction {
+    Ignore,
+    Rollback,
+    Abort,
+    Fail,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UnaryOperator {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BinaryOperator {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Is,
+    IsNot,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Concat,
 }
 
 fn selection(input: &str) -> IResult<&str, SelectStatement> {
-    let (remaining, (_, _, fields, _, _, _, table, _, condition)) = tuple((
-        tag_no_case("select".as_bytes()),
-        multispace1,
-        alpha1,
-        multispace1,
-        tag_no_case("from".as_bytes()),
+    let (remaining, (_, _, columns, from, condition)) = tuple((
+        keyword("select"),
         multispace1,
-        alpha1,
-        opt(multispace1),
+        result_columns,
+        // Without FROM the statement has to end, or go on with WHERE, right after the columns.
+        alt((
+            map(
+                preceded(
+                    tuple((multispace1, keyword("from"), multispace1)),
+                    qualified_name,
+                ),
+                Some,
+            ),
+            value(
+                None,
+                peek(pair(multispace0, alt((keyword("where"), tag(";"), eof)))),
+            ),
+        )),
         opt(where_condition),
     ))(input)?;
-    let condition = match condition {
-        Some(c) => c,
-        None => "".to_string(),
-    };
+    let (schema, table) = from.unwrap_or_default();
     Ok((
         remaining,
         SelectStatement {
-            table: table.to_owned(),
-            fields: Vec::from([fields.to_owned()]),
+            schema,
+            table,
+            columns,
             condition,
         },
     ))
 }
 
+fn result_columns(input: &str) -> IResult<&str, ResultColumns> {
+    alt((
+        map(
+            tuple((
+                keyword("count"),
+                multispace0,
+                tag("("),
+                multispace0,
+                tag("*"),
+                multispace0,
+                tag(")"),
+            )),
+            |_| ResultColumns::Count,
+        ),
+        map(tag("*"), |_| ResultColumns::All),
+        map(
+            separated_list1(ws_sep_comma, consumed(or_expression)),
+            |columns| {
+                let columns = columns
+                    .into_iter()
+                    .map(|(text, expr)| match &expr {
+                        Expression::Column(name) | Expression::TableColumn(_, name) => {
+                            (name.clone(), expr)
+                        }
+                        _ => (text.to_string(), expr),
+                    })
+                    .collect();
+                ResultColumns::Expressions(columns)
+            },
+        ),
+    ))(input)
+}
+
 fn creation(input: &str) -> IResult<&str, CreateStatement> {
-    let (remaining, (_, _, _, _, table_name, _, _, _, cols, _, _)) = tuple((
-        tag_no_case("create".as_bytes()),
-        multispace1,
-        tag_no_case("table".as_bytes()),
+    let (
+        remaining,
+        (_, temp, _, _, if_not_exists, schema, (definition, (table_name, _, _, _, specification))),
+    ) = tuple((
+        keyword("create"),
+        opt(preceded(multispace1, temporary)),
+        preceded(multispace1, keyword("table")),
         multispace1,
-        take_while1(is_sql_identifier),
-        multispace0,
-        tag("(".as_bytes()),
-        multispace0,
-        field_specification_list,
-        multispace0,
-        tag(")".as_bytes()),
+        opt(if_not_exists),
+        opt(schema_prefix),
+        consumed(tuple((
+            identifier,
+            multispace0,
+            tag("("),
+            multispace0,
+            terminated(field_specification_list, pair(multispace0, tag(")"))),
+        ))),
     ))(input)?;
+    let (cols, constraints) = specification;
     Ok((
         remaining,
         CreateStatement {
-            table_name: table_name.to_string(),
+            schema,
+            table_name,
+            temp: temp.is_some(),
+            if_not_exists: if_not_exists.is_some(),
             cols,
+            constraints,
+            sql: format!("CREATE TABLE {definition}"),
         },
     ))
 }
 
-pub fn sql_query(input: &str) -> IResult<&str, SqlStatement> {
+fn view_creation(input: &str) -> IResult<&str, CreateViewStatement> {
+    let (
+        remaining,
+        (_, temp, _, _, if_not_exists, schema, (definition, (view_name, columns, _, _, select))),
+    ) = tuple((
+        keyword("create"),
+        opt(preceded(multispace1, temporary)),
+        preceded(multispace1, keyword("view")),
+        multispace1,
+        opt(if_not_exists),
+        opt(schema_prefix),
+        consumed(tuple((
+            identifier,
+            opt(preceded(
+                multispace0,
+                delimited(
+                    pair(tag("("), multispace0),
+                    separated_list1(ws_sep_comma, identifier),
+                    pair(multispace0, tag(")")),
+                ),
+            )),
+            preceded(multispace0, keyword("as")),
+            multispace1,
+            selection,
+        ))),
+    ))(input)?;
+    Ok((
+        remaining,
+        CreateViewStatement {
+            schema,
+            view_name,
+            temp: temp.is_some(),
+            if_not_exists: if_not_exists.is_some(),
+            columns: columns.unwrap_or_default(),
+            select,
+            sql: format!("CREATE VIEW {definition}"),
+        },
+    ))
+}
+
+fn trigger_creation(input: &str) -> IResult<&str, CreateTriggerStatement> {
+    let (
+        remaining,
+        (
+            _,
+            temp,
+            _,
+            _,
+            if_not_exists,
+            schema,
+            (definition, (trigger_name, timing, event, _, table_name, _, condition, body, _)),
+        ),
+    ) = tuple((
+        keyword("create"),
+        opt(preceded(multispace1, temporary)),
+        preceded(multispace1, keyword("trigger")),
+        multispace1,
+        opt(if_not_exists),
+        opt(schema_prefix),
+        consumed(tuple((
+            identifier,
+            opt(preceded(multispace1, trigger_timing)),
+            preceded(multispace1, trigger_event),
+            preceded(multispace1, keyword("on")),
+            preceded(multispace1, identifier),
+            opt(tuple((
+                multispace1,
+                keyword("for"),
+                multispace1,
+                keyword("each"),
+                multispace1,
+                keyword("row"),
+            ))),
+            opt(preceded(pair(multispace1, keyword("when")), expression)),
+            preceded(
+                pair(multispace1, keyword("begin")),
+                many1(terminated(
+                    preceded(multispace0, trigger_step),
+                    pair(multispace0, tag(";")),
+                )),
+            ),
+            preceded(multispace0, keyword("end")),
+        ))),
+    ))(input)?;
+    Ok((
+        remaining,
+        CreateTriggerStatement {
+            schema,
+            trigger_name,
+            temp: temp.is_some(),
+            if_not_exists: if_not_exists.is_some(),
+            timing: timing.unwrap_or(TriggerTiming::Before),
+            event,
+            table_name,
+            condition,
+            body,
+            sql: format!("CREATE TRIGGER {definition}"),
+        },
+    ))
+}
+
+fn trigger_timing(i: &str) -> IResult<&str, TriggerTiming> {
     alt((
-        map(selection, |r| SqlStatement::SELECT(r)),
-        map(creation, |r| SqlStatement::CREATE(r)),
-    ))(input)
+        value(TriggerTiming::Before, keyword("before")),
+        value(TriggerTiming::After, keyword("after")),
+        value(
+            TriggerTiming::InsteadOf,
+            tuple((keyword("instead"), multispace1, keyword("of"))),
+        ),
+    ))(i)
+}
+
+fn trigger_event(i: &str) -> IResult<&str, TriggerEvent> {
+    alt((
+        value(TriggerEvent::Insert, keyword("insert")),
+        value(TriggerEvent::Delete, keyword("delete")),
+        map(
+            preceded(
+                keyword("update"),
+                opt(preceded(
+                    tuple((multispace1, keyword("of"), multispace1)),
+                    separated_list1(ws_sep_comma, identifier),
+                )),
+            ),
+            |columns| TriggerEvent::Update(columns.unwrap_or_default()),
+        ),
+    ))(i)
+}
+
+fn trigger_step(i: &str) -> IResult<&str, SqlStatement> {
+    alt((
+        map(insertion, SqlStatement::INSERT),
+        map(update, SqlStatement::UPDATE),
+        map(deletion, SqlStatement::DELETE),
+        map(selection, SqlStatement::SELECT),
+    ))(i)
+}
+
+fn temporary(i: &str) -> IResult<&str, &str> {
+    alt((keyword("temporary"), keyword("temp")))(i)
+}
+
+pub fn index_creation(input: &str) -> IResult<&str, CreateIndexStatement> {
+    let (
+        remaining,
+        (
+            _,
+            unique,
+            _,
+            _,
+            if_not_exists,
+            schema,
+            (definition, (index_name, _, _, _, table_name, _, cols)),
+        ),
+    ) = tuple((
+        keyword("create"),
+        opt(preceded(multispace1, keyword("unique"))),
+        preceded(multispace1, keyword("index")),
+        multispace1,
+        opt(if_not_exists),
+        opt(schema_prefix),
+        consumed(tuple((
+            identifier,
+            multispace1,
+            keyword("on"),
+            multispace0,
+            identifier,
+            multispace0,
+            delimited(
+                pair(tag("("), multispace0),
+                separated_list1(ws_sep_comma, indexed_column),
+                pair(multispace0, tag(")")),
+            ),
+        ))),
+    ))(input)?;
+    let sql = match unique {
+        Some(_) => format!("CREATE UNIQUE INDEX {definition}"),
+        None => format!("CREATE INDEX {definition}"),
+    };
+    Ok((
+        remaining,
+        CreateIndexStatement {
+            schema,
+            index_name,
+            table_name,
+            unique: unique.is_some(),
+            if_not_exists: if_not_exists.is_some(),
+            cols,
+            sql,
+        },
+    ))
+}
+
+fn drop(input: &str) -> IResult<&str, DropStatement> {
+    let (remaining, (_, _, object_type, _, if_exists, (schema, name))) = tuple((
+        keyword("drop"),
+        multispace1,
+        alt((
+            value(ObjectType::Table, keyword("table")),
+            value(ObjectType::Index, keyword("index")),
+            value(ObjectType::View, keyword("view")),
+            value(ObjectType::Trigger, keyword("trigger")),
+        )),
+        multispace1,
+        opt(tuple((
+            keyword("if"),
+            multispace1,
+            keyword("exists"),
+            multispace1,
+        ))),
+        qualified_name,
+    ))(input)?;
+    Ok((
+        remaining,
+        DropStatement {
+            object_type,
+            schema,
+            name,
+            if_exists: if_exists.is_some(),
+        },
+    ))
+}
+
+fn alteration(input: &str) -> IResult<&str, AlterTableStatement> {
+    let (remaining, (_, _, _, _, (schema, table_name), _, action)) = tuple((
+        keyword("alter"),
+        multispace1,
+        keyword("table"),
+        multispace1,
+        qualified_name,
+        multispace1,
+        alt((
+            map(
+                preceded(
+                    tuple((keyword("rename"), multispace1, keyword("to"), multispace1)),
+                    identifier,
+                ),
+                AlterAction::RenameTable,
+            ),
+            map(
+                tuple((
+                    keyword("rename"),
+                    multispace1,
+                    opt_column_keyword,
+                    identifier,
+                    tuple((multispace1, keyword("to"), multispace1)),
+                    identifier,
+                )),
+                |(_, _, _, old, _, new)| AlterAction::RenameColumn(old, new),
+            ),
+            map(
+                preceded(
+                    tuple((keyword("add"), multispace1, opt_column_keyword)),
+                    consumed(field_specification),
+                ),
+                |(sql, column)| AlterAction::AddColumn(column, sql.to_string()),
+            ),
+            map(
+                preceded(
+                    tuple((keyword("drop"), multispace1, opt_column_keyword)),
+                    identifier,
+                ),
+                AlterAction::DropColumn,
+            ),
+        )),
+    ))(input)?;
+    Ok((
+        remaining,
+        AlterTableStatement {
+            schema,
+            table_name,
+            action,
+        },
+    ))
+}
+
+fn opt_column_keyword(i: &str) -> IResult<&str, ()> {
+    value((), opt(pair(keyword("column"), multispace1)))(i)
+}
+
+fn indexed_column(i: &str) -> IResult<&str, IndexedColumn> {
+    let (remaining, (name, _, order)) = tuple((
+        identifier,
+        opt(preceded(
+            multispace1,
+            pair(keyword("collate"), preceded(multispace1, identifier)),
+        )),
+        opt(preceded(
+            multispace1,
+            alt((value(false, keyword("asc")), value(true, keyword("desc")))),
+        )),
+    ))(i)?;
+    Ok((
+        remaining,
+        IndexedColumn {
+            name,
+            descending: order.unwrap_or(false),
+        },
+    ))
 }
 
-pub fn where_condition(input: &str) -> IResult<&str, String> {
-    let (remaining, condition) = preceded(
-        tag_no_case("where".as_bytes()),
-        preceded(multispace1, alpha1),
-    )(input)?;
-    Ok((remaining, condition.to_string()))
+fn if_not_exists(i: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        tuple((
+            keyword("if"),
+            multispace1,
+            keyword("not"),
+            multispace1,
+            keyword("exists"),
+            multispace1,
+        )),
+    )(i)
 }
 
-fn field_specification_list(i: &str) -> IResult<&str, Vec<String>> {
-    many1(terminated(field_specification, opt(ws_sep_comma)))(i)
+fn insertion(input: &str) -> IResult<&str, InsertStatement> {
+    let (remaining, (conflict, _, _, _, (schema, table), columns, _, _, _, rows, upserts)) =
+        tuple((
+            alt((
+                map(
+                    pair(
+                        keyword("insert"),
+                        opt(preceded(
+                            tuple((multispace1, keyword("or"), multispace1)),
+                            conflict_resolution,
+                        )),
+                    ),
+                    |(_, conflict)| conflict,
+                ),
+                value(Some(ConflictResolution::Replace), keyword("replace")),
+            )),
+            multispace1,
+            keyword("into"),
+            multispace1,
+            qualified_name,
+            opt(preceded(
+                multispace0,
+                parenthesized(separated_list1(ws_sep_comma, identifier)),
+            )),
+            multispace0,
+            keyword("values"),
+            multispace0,
+            separated_list1(
+                ws_sep_comma,
+                parenthesized(separated_list1(ws_sep_comma, expression)),
+            ),
+            many0(preceded(multispace1, upsert)),
+        ))(input)?;
+    Ok((
+        remaining,
+        InsertStatement {
+            schema,
+            table,
+            conflict,
+            columns: columns.unwrap_or_default(),
+            rows,
+            upserts,
+            returning: None,
+        },
+    ))
 }
 
-fn field_specification(i: &str) -> IResult<&str, String> {
-    let (remaining_input, (col, _, _, _)) = tuple((
-        alpha1,
-        opt(delimited(multispace1, type_identifier, multispace0)),
-        many0(column_constraint),
-        opt(ws_sep_comma),
+fn upsert(i: &str) -> IResult<&str, Upsert> {
+    let (remaining, (_, target, _, _, _, action)) = tuple((
+        tuple((keyword("on"), multispace1, keyword("conflict"))),
+        opt(preceded(
+            multispace0,
+            parenthesized(separated_list1(ws_sep_comma, identifier)),
+        )),
+        multispace0,
+        keyword("do"),
+        multispace1,
+        alt((
+            value(UpsertAction::Nothing, keyword("nothing")),
+            map(
+                preceded(
+                    tuple((keyword("update"), multispace1, keyword("set"), multispace1)),
+                    pair(
+                        separated_list1(ws_sep_comma, assignment),
+                        opt(where_condition),
+                    ),
+                ),
+                |(assignments, condition)| UpsertAction::Update(assignments, condition),
+            ),
+        )),
     ))(i)?;
+    Ok((
+        remaining,
+        Upsert {
+            target: target.unwrap_or_default(),
+            action,
+        },
+    ))
+}
 
-    Ok((remaining_input, col.to_string()))
+fn conflict_resolution(i: &str) -> IResult<&str, ConflictResolution> {
+    alt((
+        value(ConflictResolution::Rollback, keyword("rollback")),
+        value(ConflictResolution::Abort, keyword("abort")),
+        value(ConflictResolution::Fail, keyword("fail")),
+        value(ConflictResolution::Ignore, keyword("ignore")),
+        value(ConflictResolution::Replace, keyword("replace")),
+    ))(i)
 }
 
-fn type_identifier(i: &str) -> IResult<&str, String> {
+// The ON CONFLICT clause of a PRIMARY KEY, NOT NULL or UNIQUE constraint.
+fn conflict_clause(i: &str) -> IResult<&str, Option<ConflictResolution>> {
+    opt(preceded(
+        tuple((
+            multispace1,
+            keyword("on"),
+            multispace1,
+            keyword("conflict"),
+            multispace1,
+        )),
+        conflict_resolution,
+    ))(i)
+}
+
+fn update(input: &str) -> IResult<&str, UpdateStatement> {
+    let (remaining, (_, conflict, _, (schema, table), _, _, _, assignments, condition)) =
+        tuple((
+            keyword("update"),
+            opt(preceded(
+                tuple((multispace1, keyword("or"), multispace1)),
+                conflict_resolution,
+            )),
+            multispace1,
+            qualified_name,
+            multispace1,
+            keyword("set"),
+            multispace1,
+            separated_list1(ws_sep_comma, assignment),
+            opt(where_condition),
+        ))(input)?;
+    Ok((
+        remaining,
+        UpdateStatement {
+            schema,
+            table,
+            conflict,
+            assignments,
+            condition,
+            returning: None,
+        },
+    ))
+}
+
+// RETURNING result-column, ...
+fn returning(i: &str) -> IResult<&str, ResultColumns> {
+    preceded(
+        tuple((multispace1, keyword("returning"), multispace1)),
+        result_columns,
+    )(i)
+}
+
+fn assignment(i: &str) -> IResult<&str, (String, Expression)> {
+    let (remaining, (col, _, _, expr)) = tuple((identifier, multispace0, tag("="), expression))(i)?;
+    Ok((remaining, (col, expr)))
+}
+
+fn deletion(input: &str) -> IResult<&str, DeleteStatement> {
+    let (remaining, (_, _, _, _, (schema, table), condition)) = tuple((
+        keyword("delete"),
+        multispace1,
+        keyword("from"),
+        multispace1,
+        qualified_name,
+        opt(where_condition),
+    ))(input)?;
+    Ok((
+        remaining,
+        DeleteStatement {
+            schema,
+            table,
+            condition,
+            returning: None,
+        },
+    ))
+}
+
+/*
+    BEGIN [DEFERRED | IMMEDIATE | EXCLUSIVE] [TRANSACTION]
+    COMMIT | END [TRANSACTION]
+    ROLLBACK [TRANSACTION] [TO [SAVEPOINT] savepoint-name]
+    SAVEPOINT savepoint-name
+    RELEASE [SAVEPOINT] savepoint-name
+*/
+fn begin(input: &str) -> IResult<&str, TransactionMode> {
+    let (remaining, (_, mode, _)) = tuple((
+        keyword("begin"),
+        opt(preceded(
+            multispace1,
+            alt((
+                value(TransactionMode::Deferred, keyword("deferred")),
+                value(TransactionMode::Immediate, keyword("immediate")),
+                value(TransactionMode::Exclusive, keyword("exclusive")),
+            )),
+        )),
+        opt(preceded(multispace1, keyword("transaction"))),
+    ))(input)?;
+    Ok((remaining, mode.unwrap_or(TransactionMode::Deferred)))
+}
+
+fn commit(input: &str) -> IResult<&str, ()> {
+    value(
+        (),
+        pair(
+            alt((keyword("commit"), keyword("end"))),
+            opt(preceded(multispace1, keyword("transaction"))),
+        ),
+    )(input)
+}
+
+fn rollback(input: &str) -> IResult<&str, Option<String>> {
+    preceded(
+        pair(
+            keyword("rollback"),
+            opt(preceded(multispace1, keyword("transaction"))),
+        ),
+        opt(preceded(
+            tuple((
+                multispace1,
+                keyword("to"),
+                opt(preceded(multispace1, keyword("savepoint"))),
+                multispace1,
+            )),
+            identifier,
+        )),
+    )(input)
+}
+
+fn savepoint(input: &str) -> IResult<&str, String> {
+    preceded(pair(keyword("savepoint"), multispace1), identifier)(input)
+}
+
+fn release(input: &str) -> IResult<&str, String> {
+    preceded(
+        tuple((
+            keyword("release"),
+            multispace1,
+            opt(pair(keyword("savepoint"), multispace1)),
+        )),
+        identifier,
+    )(input)
+}
+
+fn pragma(input: &str) -> IResult<&str, PragmaStatement> {
+    let (remaining, (_, _, (schema, name), value)) = tuple((
+        keyword("pragma"),
+        multispace1,
+        qualified_name,
+        opt(alt((
+            preceded(
+                pair(multispace0, char('=')),
+                cut(preceded(multispace0, pragma_value)),
+            ),
+            preceded(
+                pair(multispace0, char('(')),
+                cut(terminated(
+                    delimited(multispace0, pragma_value, multispace0),
+                    tag(")"),
+                )),
+            ),
+        ))),
+    ))(input)?;
+    Ok((
+        remaining,
+        PragmaStatement {
+            schema,
+            name,
+            value,
+        },
+    ))
+}
+
+fn vacuum(input: &str) -> IResult<&str, VacuumStatement> {
+    let (remaining, (_, schema, into)) = tuple((
+        keyword("vacuum"),
+        opt(preceded(
+            pair(multispace1, not(peek(keyword("into")))),
+            identifier,
+        )),
+        opt(preceded(
+            tuple((multispace1, keyword("into"), multispace1)),
+            string_literal,
+        )),
+    ))(input)?;
+    Ok((remaining, VacuumStatement { schema, into }))
+}
+
+fn attach(input: &str) -> IResult<&str, AttachStatement> {
+    let (remaining, (_, _, path, _, schema)) = tuple((
+        keyword("attach"),
+        opt(preceded(multispace1, keyword("database"))),
+        preceded(multispace1, string_literal),
+        tuple((multispace1, keyword("as"), multispace1)),
+        identifier,
+    ))(input)?;
+    Ok((remaining, AttachStatement { path, schema }))
+}
+
+fn detach(input: &str) -> IResult<&str, String> {
+    preceded(
+        tuple((
+            keyword("detach"),
+            opt(preceded(multispace1, keyword("database"))),
+            multispace1,
+        )),
+        identifier,
+    )(input)
+}
+
+pub fn pragma_value(i: &str) -> IResult<&str, Value> {
     alt((
-        map(tag_no_case("text".as_bytes()), |_| "text".to_string()),
-        map(tag_no_case("integer".as_bytes()), |_| "integer".to_string()),
+        map(preceded(char('-'), number), |v| match v {
+            Value::Integer(i) => Value::Integer(-i),
+            Value::Real(f) => Value::Real(-f),
+            other => other,
+        }),
+        preceded(opt(char('+')), literal),
+        map(identifier, Value::Text),
     ))(i)
 }
 
-pub fn column_constraint(i: &str) -> IResult<&str, String> {
+pub fn sql_query(input: &str) -> IResult<&str, SqlStatement> {
+    terminated(
+        preceded(
+            multispace0,
+            alt((
+                map(selection, SqlStatement::SELECT),
+                map(creation, SqlStatement::CREATE),
+                map(view_creation, SqlStatement::VIEW),
+                map(trigger_creation, SqlStatement::TRIGGER),
+                map(index_creation, SqlStatement::INDEX),
+                map(drop, SqlStatement::DROP),
+                map(alteration, SqlStatement::ALTER),
+                // RETURNING is not allowed in the statements of a trigger.
+                map(pair(insertion, opt(returning)), |(cmd, returning)| {
+                    SqlStatement::INSERT(InsertStatement { returning, ..cmd })
+                }),
+                map(pair(update, opt(returning)), |(cmd, returning)| {
+                    SqlStatement::UPDATE(UpdateStatement { returning, ..cmd })
+                }),
+                map(pair(deletion, opt(returning)), |(cmd, returning)| {
+                    SqlStatement::DELETE(DeleteStatement { returning, ..cmd })
+                }),
+                map(begin, SqlStatement::BEGIN),
+                map(commit, |_| SqlStatement::COMMIT),
+                map(rollback, SqlStatement::ROLLBACK),
+                map(savepoint, SqlStatement::SAVEPOINT),
+                map(release, SqlStatement::RELEASE),
+                map(pragma, SqlStatement::PRAGMA),
+                map(vacuum, SqlStatement::VACUUM),
+                map(attach, SqlStatement::ATTACH),
+                map(detach, SqlStatement::DETACH),
+            )),
+        ),
+        pair(multispace0, alt((tag(";"), eof))),
+    )(input)
+}
+
+/*
+    The statements of a piece of SQL, in order. The first one that doesn't parse ends the
+    sequence with its error, positioned within the whole text.
+*/
+pub fn statements(sql: &str) -> impl Iterator<Item = Result<SqlStatement>> + '_ {
+    let mut rest = sql;
+    std::iter::from_fn(move || {
+        if rest.trim().is_empty() {
+            return None;
+        }
+        FURTHEST.set(None);
+        match sql_query(rest) {
+            Ok((remaining, statement)) => {
+                rest = remaining;
+                Some(Ok(statement))
+            }
+            Err(nom::Err::Error(failure) | nom::Err::Failure(failure)) => {
+                rest = "";
+                let failure = match FURTHEST.take() {
+                    Some((left, expected)) if left <= failure.input.len() => Failure {
+                        input: &sql[sql.len() - left..],
+                        expected,
+                    },
+                    _ => failure,
+                };
+                Some(Err(MyError::Syntax(ParseError::from(sql, failure))))
+            }
+            Err(nom::Err::Incomplete(_)) => {
+                let failure = Failure {
+                    input: "",
+                    expected: Vec::new(),
+                };
+                rest = "";
+                Some(Err(MyError::Syntax(ParseError::from(sql, failure))))
+            }
+        }
+    })
+}
+
+pub fn where_condition(input: &str) -> IResult<&str, Expression> {
+    preceded(
+        pair(multispace1, keyword("where")),
+        // Past WHERE a failing condition is the error, not the end of the statement.
+        cut(preceded(multispace1, expression)),
+    )(input)
+}
+
+/*
+    Operator precedence, from the lowest to the highest:
+        OR
+        AND
+        NOT
+        =  ==  !=  <>  IS  IS NOT  <  <=  >  >=
+        +  -
+        *  /  %
+        ||
+        unary -
+*/
+pub fn expression(i: &str) -> IResult<&str, Expression> {
+    preceded(multispace0, or_expression)(i)
+}
+
+fn binary_chain<'a>(
+    mut i: &'a str,
+    operand: fn(&'a str) -> IResult<&'a str, Expression>,
+    operator: fn(&'a str) -> IResult<&'a str, BinaryOperator>,
+) -> IResult<&'a str, Expression> {
+    let (remaining, mut lhs) = operand(i)?;
+    i = remaining;
+    loop {
+        match preceded(multispace0, expecting("operator", operator))(i) {
+            Ok((remaining, op)) => {
+                let (remaining, rhs) = preceded(multispace0, operand)(remaining)?;
+                lhs = Expression::Binary(Box::new(lhs), op, Box::new(rhs));
+                i = remaining;
+            }
+            Err(_) => return Ok((i, lhs)),
+        }
+    }
+}
+
+fn or_expression(i: &str) -> IResult<&str, Expression> {
+    binary_chain(i, and_expression, |i| {
+        value(BinaryOperator::Or, keyword("or"))(i)
+    })
+}
+
+fn and_expression(i: &str) -> IResult<&str, Expression> {
+    binary_chain(i, not_expression, |i| {
+        value(BinaryOperator::And, keyword("and"))(i)
+    })
+}
+
+fn not_expression(i: &str) -> IResult<&str, Expression> {
     alt((
         map(
-            delimited(multispace0, tag_no_case("autoincrement"), multispace0),
-            |_| "".to_string(),
+            preceded(pair(keyword("not"), multispace0), not_expression),
+            |e| Expression::Unary(UnaryOperator::Not, Box::new(e)),
         ),
+        comparison,
+    ))(i)
+}
+
+fn comparison(i: &str) -> IResult<&str, Expression> {
+    let (i, lhs) = binary_chain(i, additive, comparison_operator)?;
+    let is_null = preceded(
+        multispace1,
+        expecting(
+            "operator",
+            alt((
+                value(false, tuple((keyword("is"), multispace1, keyword("null")))),
+                value(
+                    true,
+                    tuple((
+                        keyword("is"),
+                        multispace1,
+                        keyword("not"),
+                        multispace1,
+                        keyword("null"),
+                    )),
+                ),
+                value(false, keyword("isnull")),
+                value(true, keyword("notnull")),
+            )),
+        ),
+    )(i);
+    match is_null {
+        Ok((remaining, negated)) => Ok((remaining, Expression::IsNull(Box::new(lhs), negated))),
+        Err(_) => Ok((i, lhs)),
+    }
+}
+
+fn comparison_operator(i: &str) -> IResult<&str, BinaryOperator> {
+    alt((
+        value(BinaryOperator::Equal, tag("==")),
+        value(BinaryOperator::Equal, tag("=")),
+        value(BinaryOperator::NotEqual, tag("!=")),
+        value(BinaryOperator::NotEqual, tag("<>")),
+        value(BinaryOperator::LessEqual, tag("<=")),
+        value(BinaryOperator::GreaterEqual, tag(">=")),
+        value(BinaryOperator::Less, tag("<")),
+        value(BinaryOperator::Greater, tag(">")),
+        // IS NULL is handled by the caller, so only take IS when it is not followed by NULL.
+        value(
+            BinaryOperator::IsNot,
+            tuple((
+                keyword("is"),
+                multispace1,
+                keyword("not"),
+                not(peek(preceded(multispace1, keyword("null")))),
+            )),
+        ),
+        value(
+            BinaryOperator::Is,
+            terminated(
+                keyword("is"),
+                not(peek(preceded(
+                    multispace1,
+                    alt((keyword("null"), keyword("not"))),
+                ))),
+            ),
+        ),
+    ))(i)
+}
+
+fn additive(i: &str) -> IResult<&str, Expression> {
+    binary_chain(i, multiplicative, |i| {
+        alt((
+            value(BinaryOperator::Add, tag("+")),
+            value(BinaryOperator::Subtract, tag("-")),
+        ))(i)
+    })
+}
+
+fn multiplicative(i: &str) -> IResult<&str, Expression> {
+    binary_chain(i, concatenation, |i| {
+        alt((
+            value(BinaryOperator::Multiply, tag("*")),
+            value(BinaryOperator::Divide, tag("/")),
+            value(BinaryOperator::Modulo, tag("%")),
+        ))(i)
+    })
+}
+
+fn concatenation(i: &str) -> IResult<&str, Expression> {
+    binary_chain(i, unary, |i| value(BinaryOperator::Concat, tag("||"))(i))
+}
+
+fn unary(i: &str) -> IResult<&str, Expression> {
+    alt((
+        map(preceded(pair(tag("-"), multispace0), unary), |e| {
+            Expression::Unary(UnaryOperator::Negate, Box::new(e))
+        }),
+        preceded(pair(tag("+"), multispace0), unary),
+        primary,
+    ))(i)
+}
+
+fn primary(i: &str) -> IResult<&str, Expression> {
+    expecting(
+        "expression",
+        alt((
+            delimited(
+                pair(tag("("), multispace0),
+                or_expression,
+                pair(multispace0, tag(")")),
+            ),
+            map(literal, Expression::Literal),
+            map(parameter, Expression::Parameter),
+            raise,
+            map(pair(schema_prefix, identifier), |(table, column)| {
+                Expression::TableColumn(table, column)
+            }),
+            map(identifier, Expression::Column),
+        )),
+    )(i)
+}
+
+// RAISE(IGNORE) | RAISE({ROLLBACK | ABORT | FAIL}, error-message)
+fn raise(i: &str) -> IResult<&str, Expression> {
+    let action = alt((
+        value(RaiseAction::Rollback, keyword("rollback")),
+        value(RaiseAction::Abort, keyword("abort")),
+        value(RaiseAction::Fail, keyword("fail")),
+    ));
+    preceded(
+        tuple((keyword("raise"), multispace0, tag("("), multispace0)),
+        terminated(
+            alt((
+                map(keyword("ignore"), |_| {
+                    Expression::Raise(RaiseAction::Ignore, String::new())
+                }),
+                map(
+                    separated_pair(action, ws_sep_comma, string_literal),
+                    |(action, message)| Expression::Raise(action, message),
+                ),
+            )),
+            pair(multispace0, tag(")")),
+        ),
+    )(i)
+}
+
+fn parameter(i: &str) -> IResult<&str, Parameter> {
+    alt((
+        // A number too large for usize is out of range like ?0.
+        map(preceded(char('?'), digit1), |n: &str| {
+            Parameter::Numbered(n.parse().unwrap_or(0))
+        }),
+        value(Parameter::Next, char('?')),
+        map(
+            recognize(pair(
+                satisfy(|c| ":@$".contains(c)),
+                take_while1(is_sql_identifier),
+            )),
+            |name: &str| Parameter::Named(name.to_string()),
+        ),
+    ))(i)
+}
+
+pub fn literal(i: &str) -> IResult<&str, Value> {
+    alt((
+        value(Value::Null, keyword("null")),
         map(
-            delimited(multispace0, tag_no_case("primary key"), multispace0),
-            |_| "".to_string(),
+            preceded(
+                alt((tag("x"), tag("X"))),
+                delimited(
+                    char('\''),
+                    take_while(|c: char| c.is_ascii_hexdigit()),
+                    char('\''),
+                ),
+            ),
+            |hex: &str| {
+                Value::Blob(
+                    (0..hex.len() / 2)
+                        .map(|n| u8::from_str_radix(&hex[n * 2..n * 2 + 2], 16).unwrap())
+                        .collect(),
+                )
+            },
+        ),
+        map(string_literal, Value::Text),
+        number,
+    ))(i)
+}
+
+fn string_literal(i: &str) -> IResult<&str, String> {
+    map(
+        delimited(
+            char('\''),
+            many0(alt((value("'", tag("''")), take_while1(|c| c != '\'')))),
+            char('\''),
+        ),
+        |parts| parts.concat(),
+    )(i)
+}
+
+fn number(i: &str) -> IResult<&str, Value> {
+    let (remaining, text) = terminated(
+        recognize(tuple((
+            alt((
+                recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+                recognize(pair(char('.'), digit1)),
+            )),
+            opt(tuple((
+                alt((char('e'), char('E'))),
+                opt(alt((char('+'), char('-')))),
+                digit1,
+            ))),
+        ))),
+        not(peek(satisfy(is_sql_identifier))),
+    )(i)?;
+    let number = match text.parse::<i64>() {
+        Ok(integer) => Value::Integer(integer),
+        Err(_) => Value::Real(text.parse::<f64>().unwrap_or(f64::INFINITY)),
+    };
+    Ok((remaining, number))
+}
+
+fn field_specification_list(
+    i: &str,
+) -> IResult<&str, (Vec<ColumnDefinition>, Vec<TableConstraint>)> {
+    let (remaining, (cols, constraints)) = pair(
+        separated_list1(ws_sep_comma, field_specification),
+        many0(preceded(ws_sep_comma, table_constraint)),
+    )(i)?;
+    Ok((remaining, (cols, constraints)))
+}
+
+fn field_specification(i: &str) -> IResult<&str, ColumnDefinition> {
+    let (remaining_input, (name, type_name, mut constraints)) = tuple((
+        preceded(not(peek(table_constraint_keyword)), identifier),
+        opt(preceded(multispace1, type_identifier)),
+        many0(preceded(multispace1, column_constraint)),
+    ))(i)?;
+    for constraint in &mut constraints {
+        if let ColumnConstraint::References(foreign_key) = constraint {
+            foreign_key.columns = vec![name.clone()];
+        }
+    }
+
+    Ok((
+        remaining_input,
+        ColumnDefinition {
+            name,
+            type_name,
+            constraints,
+        },
+    ))
+}
+
+// A type name is one or more words, optionally followed by a size like VARCHAR(20) or DECIMAL(10, 5).
+fn type_identifier(i: &str) -> IResult<&str, String> {
+    let (remaining, (words, size)) = pair(
+        separated_list1(
+            multispace1,
+            preceded(
+                not(peek(constraint_keyword)),
+                take_while1(is_sql_identifier),
+            ),
         ),
+        opt(recognize(tuple((
+            multispace0,
+            tag("("),
+            take_while(|c| c != ')'),
+            tag(")"),
+        )))),
+    )(i)?;
+    let mut type_name = words.join(" ");
+    if let Some(size) = size {
+        type_name.push_str(size.trim_start());
+    }
+    Ok((remaining, type_name))
+}
+
+fn constraint_keyword(i: &str) -> IResult<&str, &str> {
+    alt((
+        keyword("constraint"),
+        keyword("primary"),
+        keyword("not"),
+        keyword("null"),
+        keyword("unique"),
+        keyword("default"),
+        keyword("collate"),
+        keyword("autoincrement"),
+        keyword("references"),
+        keyword("check"),
+    ))(i)
+}
+
+fn table_constraint_keyword(i: &str) -> IResult<&str, &str> {
+    alt((
+        keyword("constraint"),
+        keyword("primary"),
+        keyword("unique"),
+        keyword("foreign"),
+        keyword("check"),
     ))(i)
 }
 
+pub fn column_constraint(i: &str) -> IResult<&str, ColumnConstraint> {
+    let (remaining, (name, constraint)) = pair(
+        opt(constraint_name),
+        alt((
+            map(
+                tuple((
+                    keyword("primary"),
+                    multispace1,
+                    keyword("key"),
+                    opt(preceded(
+                        multispace1,
+                        alt((keyword("asc"), keyword("desc"))),
+                    )),
+                    conflict_clause,
+                    opt(preceded(multispace1, keyword("autoincrement"))),
+                )),
+                |(_, _, _, _, on_conflict, autoincrement)| ColumnConstraint::PrimaryKey {
+                    autoincrement: autoincrement.is_some(),
+                    on_conflict,
+                },
+            ),
+            map(
+                preceded(
+                    tuple((keyword("not"), multispace1, keyword("null"))),
+                    conflict_clause,
+                ),
+                ColumnConstraint::NotNull,
+            ),
+            map(
+                preceded(keyword("unique"), conflict_clause),
+                ColumnConstraint::Unique,
+            ),
+            map(
+                preceded(
+                    pair(keyword("default"), multispace0),
+                    alt((
+                        delimited(
+                            pair(tag("("), multispace0),
+                            consumed(or_expression),
+                            pair(multispace0, tag(")")),
+                        ),
+                        consumed(unary),
+                    )),
+                ),
+                |(text, expr)| ColumnConstraint::Default(expr, text.to_string()),
+            ),
+            map(
+                preceded(pair(keyword("collate"), multispace1), identifier),
+                ColumnConstraint::Collate,
+            ),
+            map(foreign_key_clause, ColumnConstraint::References),
+            map(check, |(text, expr)| {
+                ColumnConstraint::Check(Check {
+                    name: None,
+                    expr,
+                    text: text.to_string(),
+                })
+            }),
+        )),
+    )(i)?;
+    let constraint = match constraint {
+        ColumnConstraint::Check(check) => ColumnConstraint::Check(Check { name, ..check }),
+        other => other,
+    };
+    Ok((remaining, constraint))
+}
+
+// CONSTRAINT name, which only CHECK constraints keep.
+fn constraint_name(i: &str) -> IResult<&str, String> {
+    delimited(
+        pair(keyword("constraint"), multispace1),
+        identifier,
+        multispace1,
+    )(i)
+}
+
+// CHECK ( expr ), giving the expression and its text.
+fn check(i: &str) -> IResult<&str, (&str, Expression)> {
+    preceded(
+        pair(keyword("check"), multispace0),
+        delimited(
+            pair(tag("("), multispace0),
+            consumed(or_expression),
+            pair(multispace0, tag(")")),
+        ),
+    )(i)
+}
+
+fn foreign_key_clause(i: &str) -> IResult<&str, ForeignKey> {
+    let action = || {
+        alt((
+            value(
+                ForeignKeyAction::SetNull,
+                tuple((keyword("set"), multispace1, keyword("null"))),
+            ),
+            value(
+                ForeignKeyAction::SetDefault,
+                tuple((keyword("set"), multispace1, keyword("default"))),
+            ),
+            value(ForeignKeyAction::Cascade, keyword("cascade")),
+            value(ForeignKeyAction::Restrict, keyword("restrict")),
+            value(
+                ForeignKeyAction::NoAction,
+                tuple((keyword("no"), multispace1, keyword("action"))),
+            ),
+        ))
+    };
+    // ON DELETE and ON UPDATE are told apart by the flag, MATCH contributes nothing.
+    let clause = alt((
+        map(
+            tuple((
+                keyword("on"),
+                multispace1,
+                alt((
+                    value(true, keyword("delete")),
+                    value(false, keyword("update")),
+                )),
+                multispace1,
+                action(),
+            )),
+            |(_, _, delete, _, action)| Some((delete, action)),
+        ),
+        value(None, tuple((keyword("match"), multispace1, identifier))),
+    ));
+    let (remaining, (_, _, foreign_table, foreign_columns, clauses, deferrable)) = tuple((
+        keyword("references"),
+        multispace1,
+        identifier,
+        opt(delimited(
+            tuple((multispace0, tag("("), multispace0)),
+            separated_list1(ws_sep_comma, identifier),
+            pair(multispace0, tag(")")),
+        )),
+        many0(preceded(multispace1, clause)),
+        opt(preceded(
+            multispace1,
+            tuple((
+                opt(pair(keyword("not"), multispace1)),
+                keyword("deferrable"),
+                opt(preceded(
+                    tuple((multispace1, keyword("initially"), multispace1)),
+                    alt((
+                        value(true, keyword("deferred")),
+                        value(false, keyword("immediate")),
+                    )),
+                )),
+            )),
+        )),
+    ))(i)?;
+    let mut foreign_key = ForeignKey {
+        columns: Vec::new(),
+        foreign_table,
+        foreign_columns: foreign_columns.unwrap_or_default(),
+        on_delete: ForeignKeyAction::NoAction,
+        on_update: ForeignKeyAction::NoAction,
+        deferred: matches!(deferrable, Some((None, _, Some(true)))),
+    };
+    for (delete, action) in clauses.into_iter().flatten() {
+        if delete {
+            foreign_key.on_delete = action;
+        } else {
+            foreign_key.on_update = action;
+        }
+    }
+    Ok((remaining, foreign_key))
+}
+
+fn table_constraint(i: &str) -> IResult<&str, TableConstraint> {
+    let column_list = || {
+        delimited(
+            tuple((multispace0, tag("("), multispace0)),
+            separated_list1(ws_sep_comma, map(indexed_column, |c| c.name)),
+            pair(multispace0, tag(")")),
+        )
+    };
+    let (remaining, (name, constraint)) = pair(
+        opt(constraint_name),
+        alt((
+            map(
+                preceded(
+                    tuple((keyword("primary"), multispace1, keyword("key"))),
+                    pair(column_list(), conflict_clause),
+                ),
+                |(columns, on_conflict)| TableConstraint::PrimaryKey(columns, on_conflict),
+            ),
+            map(
+                preceded(keyword("unique"), pair(column_list(), conflict_clause)),
+                |(columns, on_conflict)| TableConstraint::Unique(columns, on_conflict),
+            ),
+            map(
+                tuple((
+                    keyword("foreign"),
+                    multispace1,
+                    keyword("key"),
+                    column_list(),
+                    multispace0,
+                    foreign_key_clause,
+                )),
+                |(_, _, _, columns, _, foreign_key)| {
+                    TableConstraint::ForeignKey(ForeignKey {
+                        columns,
+                        ..foreign_key
+                    })
+                },
+            ),
+            map(check, |(text, expr)| {
+                TableConstraint::Check(Check {
+                    name: None,
+                    expr,
+                    text: text.to_string(),
+                })
+            }),
+        )),
+    )(i)?;
+    let constraint = match constraint {
+        TableConstraint::Check(check) => TableConstraint::Check(Check { name, ..check }),
+        other => other,
+    };
+    Ok((remaining, constraint))
+}
+
+// [schema-name.]name
+fn qualified_name(i: &str) -> IResult<&str, (Option<String>, String)> {
+    pair(opt(schema_prefix), identifier)(i)
+}
+
+fn schema_prefix(i: &str) -> IResult<&str, String> {
+    terminated(identifier, delimited(multispace0, tag("."), multispace0))(i)
+}
+
+pub fn identifier(i: &str) -> IResult<&str, String> {
+    expecting(
+        "identifier",
+        alt((
+            map(
+                delimited(char('"'), take_while(|c| c != '"'), char('"')),
+                String::from,
+            ),
+            map(
+                delimited(char('`'), take_while(|c| c != '`'), char('`')),
+                String::from,
+            ),
+            map(
+                delimited(char('['), take_while(|c| c != ']'), char(']')),
+                String::from,
+            ),
+            map(take_while1(is_sql_identifier), String::from),
+        )),
+    )(i)
+}
+
+// Match a keyword case-insensitively, making sure it is not just the prefix of a longer word.
+fn keyword<'a>(kw: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    let mut parser = terminated(tag_no_case(kw), not(peek(satisfy(is_sql_identifier))));
+    move |i| {
+        parser(i).map_err(|e| {
+            e.map(|_: Failure<&str>| {
+                let expected = vec![kw.to_uppercase()];
+                note_failure(i.len(), &expected);
+                Failure { input: i, expected }
+            })
+        })
+    }
+}
+
+fn tag<'a>(t: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    expecting(t, nom::bytes::complete::tag(t))
+}
+
+/*
+    Name what a parser looks for in the errors where it fails without getting anywhere. The
+    tokens it tried there are replaced by the name, like all the operators by "operator".
+*/
+fn expecting<'a, O>(
+    name: &'static str,
+    mut parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    move |i| {
+        let furthest = FURTHEST.with_borrow(|f| f.clone());
+        parser(i).map_err(|e| {
+            e.map(|failure| match failure.input.len() == i.len() {
+                true => {
+                    let expected = vec![name.to_string()];
+                    FURTHEST.set(furthest);
+                    note_failure(i.len(), &expected);
+                    Failure { input: i, expected }
+                }
+                false => failure,
+            })
+        })
+    }
+}
+
+fn parenthesized<'a, O>(
+    parser: impl FnMut(&'a str) -> IResult<&'a str, O>,
+) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    delimited(
+        pair(tag("("), multispace0),
+        parser,
+        pair(multispace0, tag(")")),
+    )
+}
+
 fn ws_sep_comma(i: &str) -> IResult<&str, &str> {
     delimited(multispace0, tag(","), multispace0)(i)
 }
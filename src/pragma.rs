@@ -0,0 +1,160 @@
+use crate::database::Database;
+use crate::page::{MyError, Result};
+use crate::parser::ColumnConstraint;
+use crate::table::IndexSchema;
+use crate::value::Value;
+
+/*
+    The pragmas describing the schema return one row per column, index or foreign key, like a
+    query. An unknown table or index gives no rows rather than an error, as in SQLite.
+*/
+type Rows = Result<Vec<Vec<Value>>>;
+
+// Type names SQLite recognizes are shown in upper case, any other as written.
+fn type_name(declared: Option<&str>) -> String {
+    let Some(declared) = declared else {
+        return String::new();
+    };
+    let known = ["INT", "INTEGER", "REAL", "TEXT", "BLOB", "NUMERIC", "ANY"];
+    match known.iter().find(|k| k.eq_ignore_ascii_case(declared)) {
+        Some(known) => known.to_string(),
+        None => declared.to_string(),
+    }
+}
+
+// cid | name | type | notnull | dflt_value | pk, followed by hidden for table_xinfo.
+pub fn table_info(db: &mut Database, table_name: &str, extended: bool) -> Rows {
+    let table = match db.get_table(table_name) {
+        Ok(table) => table,
+        Err(MyError::NoSuchTable(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let primary_key = table.primary_key();
+    let mut rows = Vec::new();
+    for (cid, col) in table.cols.iter().enumerate() {
+        let not_null = col
+            .constraints
+            .iter()
+            .any(|c| matches!(c, ColumnConstraint::NotNull(_)));
+        let default = col.constraints.iter().find_map(|c| match c {
+            ColumnConstraint::Default(_, text) => Some(Value::Text(text.clone())),
+            _ => None,
+        });
+        let pk = primary_key
+            .iter()
+            .position(|name| name.eq_ignore_ascii_case(&col.name))
+            .map_or(0, |i| i + 1);
+        let mut row = vec![
+            Value::Integer(cid as i64),
+            Value::Text(col.name.clone()),
+            Value::Text(type_name(col.type_name.as_deref())),
+            Value::Integer(not_null as i64),
+            default.unwrap_or(Value::Null),
+            Value::Integer(pk as i64),
+        ];
+        if extended {
+            row.push(Value::Integer(0));
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/*
+    seq | name | unique | origin | partial, the most recently created index first. The origin
+    is "c" for CREATE INDEX, "u" for a UNIQUE constraint and "pk" for a PRIMARY KEY.
+*/
+pub fn index_list(db: &mut Database, table_name: &str) -> Rows {
+    let table = match db.get_table(table_name) {
+        Ok(table) => table,
+        Err(MyError::NoSuchTable(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let schema = db.get_schema()?;
+    let primary_key = table.primary_key();
+    let mut rows = Vec::new();
+    for (seq, index) in db.get_indexes(&table)?.iter().rev().enumerate() {
+        let created = schema
+            .iter()
+            .any(|e| e.name.eq_ignore_ascii_case(&index.index_name) && e.sql.is_some());
+        let is_primary_key = index.cols.len() == primary_key.len()
+            && index
+                .cols
+                .iter()
+                .zip(&primary_key)
+                .all(|(c, name)| c.name.eq_ignore_ascii_case(name));
+        let origin = match (created, is_primary_key) {
+            (true, _) => "c",
+            (false, true) => "pk",
+            (false, false) => "u",
+        };
+        rows.push(vec![
+            Value::Integer(seq as i64),
+            Value::Text(index.index_name.clone()),
+            Value::Integer(index.unique as i64),
+            Value::Text(origin.to_string()),
+            Value::Integer(0),
+        ]);
+    }
+    Ok(rows)
+}
+
+// seqno | cid | name for each indexed column.
+pub fn index_info(db: &mut Database, index_name: &str) -> Rows {
+    let Some(entry) = db
+        .get_schema()?
+        .into_iter()
+        .find(|e| e.entry_type == "index" && e.name.eq_ignore_ascii_case(index_name))
+    else {
+        return Ok(Vec::new());
+    };
+    let table = db.get_table(&entry.table_name)?;
+    let Some(index) = IndexSchema::from_entry(&entry, &table) else {
+        return Ok(Vec::new());
+    };
+    Ok(index
+        .column_indices
+        .iter()
+        .enumerate()
+        .map(|(seqno, cid)| {
+            vec![
+                Value::Integer(seqno as i64),
+                Value::Integer(*cid as i64),
+                Value::Text(table.cols[*cid].name.clone()),
+            ]
+        })
+        .collect())
+}
+
+/*
+    id | seq | table | from | to | on_update | on_delete | match, one row per column of each
+    foreign key. Keys are numbered from the last one declared, the referenced column is NULL
+    when the key refers to the primary key of the parent table.
+*/
+pub fn foreign_key_list(db: &mut Database, table_name: &str) -> Rows {
+    let table = match db.get_table(table_name) {
+        Ok(table) => table,
+        Err(MyError::NoSuchTable(_)) => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut rows = Vec::new();
+    for (id, foreign_key) in table.foreign_keys().iter().rev().enumerate() {
+        for (seq, from) in foreign_key.columns.iter().enumerate() {
+            let to = foreign_key
+                .foreign_columns
+                .get(seq)
+                .map_or(Value::Null, |c| Value::Text(c.clone()));
+            rows.push(vec![
+                Value::Integer(id as i64),
+                Value::Integer(seq as i64),
+                Value::Text(foreign_key.foreign_table.clone()),
+                Value::Text(from.clone()),
+                to,
+                Value::Text(foreign_key.on_update.name().to_string()),
+                Value::Text(foreign_key.on_delete.name().to_string()),
+                Value::Text("NONE".to_string()),
+            ]);
+        }
+    }
+    Ok(rows)
+}
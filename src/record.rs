@@ -1,9 +1,6 @@
-use core::str;
-
-use crate::serial_type::SerialType;
-
-use crate::page::Result;
-use crate::utils::read_variant;
+use crate::page::{MyError, Result, TextEncoding};
+use crate::utils::{read_variant, write_variant};
+use crate::value::Value;
 
 /*
    Record Format
@@ -12,127 +9,250 @@ use crate::utils::read_variant;
       - Serial type code for each column in the record, in order (varint)
      * Body:
       - The value of each column in the record, in order (format varies based on serial type code)
+   Records are written with Record::encode and read in place with RecordView.
 */
-#[derive(Debug, Clone)]
-pub struct Record {
-    pub columns: Vec<Column>,
-}
+pub struct Record;
 
+/*
+    A record read in place. Only the header is parsed up front, checking that every column lies
+    within the data; a column is decoded when it is asked for, so reading one column of a wide
+    row does not pay for the others.
+*/
 #[derive(Debug, Clone)]
-pub struct Column {
-    pub offset: usize,
-    pub serial_type: SerialType,
+pub struct RecordView<'a> {
+    data: &'a [u8],
+    encoding: TextEncoding,
+    // The serial type and the offset of every column.
+    columns: Vec<(u64, usize)>,
 }
 
-impl Record {
-    pub fn from(data: &[u8]) -> Result<Self> {
+impl<'a> RecordView<'a> {
+    pub fn from(data: &'a [u8], encoding: TextEncoding) -> Result<Self> {
         let mut columns = Vec::new();
-        let (record_head_size, first_type_offset) = read_variant(&data[..]);
-        let mut column_pointer = record_head_size as usize;
+        let (record_head_size, first_type_offset) = read_variant(data);
+        let record_head_size = usize::try_from(record_head_size)?;
+        if record_head_size > data.len() || first_type_offset > record_head_size {
+            return Err(MyError::Corrupt);
+        }
+        let mut column_pointer = record_head_size;
         let mut serial_type_pointer: usize = first_type_offset;
-        while serial_type_pointer != record_head_size as usize {
+        while serial_type_pointer < record_head_size {
             let (serial_type, bytes_read) = read_variant(&data[serial_type_pointer..]);
-            let mut size_of_column = 0 as usize;
-            let st: SerialType;
-            if serial_type >= 12 && serial_type % 2 == 0 {
-                size_of_column = (serial_type as usize - 12) / 2;
-                st = SerialType::Blob(Box::from(
-                    &data[column_pointer..column_pointer + size_of_column],
-                ));
-            } else if serial_type >= 13 && serial_type % 2 != 0 {
-                size_of_column = (serial_type as usize - 13) / 2;
-                let strv = str::from_utf8(&data[column_pointer..column_pointer + size_of_column]);
-                st = SerialType::String(String::from(strv.unwrap()));
-            } else {
-                st = match serial_type {
-                    0 => SerialType::Null,
-                    1 => {
-                        size_of_column = 1;
-                        SerialType::I8(data[column_pointer] as i8)
-                    }
-                    2 => {
-                        size_of_column = 2;
-                        SerialType::I16(i16::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    3 => {
-                        size_of_column = 3;
-                        SerialType::I24(i32::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    4 => {
-                        size_of_column = 4;
-                        SerialType::I32(i32::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    5 => {
-                        size_of_column = 6;
-                        SerialType::I48(i64::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    6 => {
-                        size_of_column = 8;
-                        SerialType::I64(i64::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    7 => {
-                        size_of_column = 8;
-                        SerialType::Float(f64::from_be_bytes(
-                            data[column_pointer..column_pointer + size_of_column]
-                                .try_into()
-                                .unwrap(),
-                        ))
-                    }
-                    8 => SerialType::Zero,
-                    9 => SerialType::One,
-                    _ => panic!("invalid serial type {}", serial_type),
-                };
+            // 10 and 11 are reserved for internal use, negative values can't be sizes.
+            let serial_type = u64::try_from(serial_type).map_err(|_| MyError::Corrupt)?;
+            if serial_type == 10 || serial_type == 11 {
+                return Err(MyError::Corrupt);
             }
-            let col = Column {
-                offset: column_pointer as usize,
-                serial_type: st,
-            };
-
-            columns.push(col);
+            columns.push((serial_type, column_pointer));
             serial_type_pointer += bytes_read;
-            column_pointer += size_of_column;
+            column_pointer = column_pointer
+                .checked_add(Self::size_of(serial_type))
+                .ok_or(MyError::Corrupt)?;
         }
+        // A payload cut short reads as corrupt rather than past the end of the data.
+        if serial_type_pointer != record_head_size || column_pointer > data.len() {
+            return Err(MyError::Corrupt);
+        }
+        Ok(Self {
+            data,
+            encoding,
+            columns,
+        })
+    }
+
+    fn size_of(serial_type: u64) -> usize {
+        match serial_type {
+            0 | 8 | 9 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            n => (n as usize - 12) / 2,
+        }
+    }
 
-        Ok(Self { columns })
+    pub fn len(&self) -> usize {
+        self.columns.len()
+    }
+
+    fn bytes(&self, index: usize) -> &'a [u8] {
+        let (serial_type, offset) = self.columns[index];
+        &self.data[offset..offset + Self::size_of(serial_type)]
+    }
+
+    /*
+        Type            Size	    Meaning
+        0	            0	        Value is a NULL.
+        1	            1	        Value is an 8-bit twos-complement integer.
+        2	            2	        Value is a big-endian 16-bit twos-complement integer.
+        3	            3	        Value is a big-endian 24-bit twos-complement integer.
+        4	            4	        Value is a big-endian 32-bit twos-complement integer.
+        5	            6	        Value is a big-endian 48-bit twos-complement integer.
+        6	            8	        Value is a big-endian 64-bit twos-complement integer.
+        7	            8	        Value is a big-endian IEEE 754-2008 64-bit floating point number.
+        8	            0	        Value is the integer 0. (Only available for schema format 4 and higher.)
+        9	            0	        Value is the integer 1. (Only available for schema format 4 and higher.)
+        10,11           variable	Reserved for internal use. These serial type codes will never appear in a well-formed database file, but they might be used in transient and temporary database files that SQLite sometimes generates for its own use. The meanings of these codes can shift from one release of SQLite to the next.
+        N≥12 and even	(N-12)/2	Value is a BLOB that is (N-12)/2 bytes in length.
+        N≥13 and odd	(N-13)/2	Value is a string in the text encoding and (N-13)/2 bytes in length. The nul terminator is not stored.
+    */
+    pub fn value(&self, index: usize) -> Result<Value> {
+        let bytes = self.bytes(index);
+        Ok(match self.columns[index].0 {
+            0 => Value::Null,
+            1..=6 => {
+                // Sign-extended from the 1 to 8 bytes stored.
+                let fill = if bytes[0] & 0x80 == 0 { 0 } else { 0xff };
+                let mut extended = [fill; 8];
+                extended[8 - bytes.len()..].copy_from_slice(bytes);
+                Value::Integer(i64::from_be_bytes(extended))
+            }
+            7 => Value::Real(f64::from_be_bytes(bytes.try_into()?)),
+            8 => Value::Integer(0),
+            9 => Value::Integer(1),
+            n if n % 2 == 0 => Value::Blob(bytes.to_vec()),
+            _ => Value::Text(self.encoding.decode(bytes)),
+        })
+    }
+
+    pub fn values(&self) -> Result<Vec<Value>> {
+        (0..self.len()).map(|i| self.value(i)).collect()
+    }
+}
+
+impl Record {
+    // Serialize the values into the record format, picking the smallest serial type that can
+    // hold each integer. Text is stored in the encoding of the database.
+    pub fn encode(values: &[Value], encoding: TextEncoding) -> Vec<u8> {
+        let mut types: Vec<u8> = Vec::new();
+        let mut body: Vec<u8> = Vec::new();
+        for value in values {
+            let serial_type: i64 = match value {
+                Value::Null => 0,
+                Value::Integer(0) => 8,
+                Value::Integer(1) => 9,
+                Value::Integer(i) => {
+                    let (serial_type, size) = match *i {
+                        -128..=127 => (1, 1),
+                        -32768..=32767 => (2, 2),
+                        -8388608..=8388607 => (3, 3),
+                        -2147483648..=2147483647 => (4, 4),
+                        -140737488355328..=140737488355327 => (5, 6),
+                        _ => (6, 8),
+                    };
+                    body.extend_from_slice(&i.to_be_bytes()[8 - size..]);
+                    serial_type
+                }
+                Value::Real(f) => {
+                    body.extend_from_slice(&f.to_be_bytes());
+                    7
+                }
+                Value::Text(s) => {
+                    let bytes = encoding.encode(s);
+                    body.extend_from_slice(&bytes);
+                    bytes.len() as i64 * 2 + 13
+                }
+                Value::Blob(b) => {
+                    body.extend_from_slice(b);
+                    b.len() as i64 * 2 + 12
+                }
+            };
+            types.extend(write_variant(serial_type));
+        }
+        // The header size includes the varint holding it, which may itself grow by a byte.
+        let mut header_size = types.len() + 1;
+        if write_variant(header_size as i64).len() > 1 {
+            header_size += write_variant(header_size as i64 + 1).len() - 1;
+        }
+        let mut record = write_variant(header_size as i64);
+        record.extend(types);
+        record.extend(body);
+        record
     }
 }
 
-impl Column {
-    pub fn value(&self) -> String {
-        match &self.serial_type {
-            SerialType::Null => String::from("NULL"),
-            SerialType::One => String::from("1"),
-            SerialType::Zero => String::from("0"),
-            SerialType::I8(v) => v.to_string(),
-            SerialType::I16(v) => v.to_string(),
-            SerialType::I24(v) => v.to_string(),
-            SerialType::I48(v) => v.to_string(),
-            SerialType::I32(v) => v.to_string(),
-            SerialType::I64(v) => v.to_string(),
-            SerialType::Float(v) => v.to_string(),
-            SerialType::String(v) => v.clone(),
-            SerialType::Blob(v) => String::from_utf8(v.clone().into_vec()).unwrap(),
-            //_ => panic!("Unspported type"),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(values: &[Value], encoding: TextEncoding) -> Vec<Value> {
+        let record = Record::encode(values, encoding);
+        RecordView::from(&record, encoding)
+            .unwrap()
+            .values()
+            .unwrap()
+    }
+
+    #[test]
+    fn integers_keep_their_sign_at_every_width() {
+        let values: Vec<Value> = [
+            0,
+            1,
+            2,
+            -1,
+            127,
+            -128,
+            128,
+            -129,
+            32767,
+            -32768,
+            8388607,
+            -8388608,
+            2147483647,
+            -2147483648,
+            140737488355327,
+            -140737488355328,
+            i64::MAX,
+            i64::MIN,
+        ]
+        .into_iter()
+        .map(Value::Integer)
+        .collect();
+        let decoded = round_trip(&values, TextEncoding::Utf8);
+        assert_eq!(format!("{decoded:?}"), format!("{values:?}"));
+    }
+
+    #[test]
+    fn text_blobs_and_reals_round_trip() {
+        let values = vec![
+            Value::Null,
+            Value::Real(-0.5),
+            Value::Text("日本語".to_string()),
+            Value::Blob(vec![0, 255, 1]),
+            Value::Text(String::new()),
+        ];
+        for encoding in [
+            TextEncoding::Utf8,
+            TextEncoding::Utf16le,
+            TextEncoding::Utf16be,
+        ] {
+            let decoded = round_trip(&values, encoding);
+            assert_eq!(format!("{decoded:?}"), format!("{values:?}"));
+        }
+    }
+
+    #[test]
+    fn header_size_grows_past_one_byte() {
+        let values = vec![Value::Integer(7); 200];
+        let record = Record::encode(&values, TextEncoding::Utf8);
+        assert_eq!(read_variant(&record), (202, 2));
+        let view = RecordView::from(&record, TextEncoding::Utf8).unwrap();
+        assert_eq!(view.len(), 200);
+        assert!(matches!(view.value(199), Ok(Value::Integer(7))));
+    }
+
+    #[test]
+    fn reserved_and_truncated_records_are_corrupt() {
+        // Serial types 10 and 11 are reserved.
+        for serial_type in [10, 11] {
+            let record = [2, serial_type];
+            assert!(matches!(
+                RecordView::from(&record, TextEncoding::Utf8),
+                Err(MyError::Corrupt)
+            ));
         }
+        // A 4 byte integer with only 3 bytes left, and a header longer than the record.
+        let record = Record::encode(&[Value::Integer(1 << 20)], TextEncoding::Utf8);
+        assert!(RecordView::from(&record[..record.len() - 1], TextEncoding::Utf8).is_err());
+        assert!(RecordView::from(&[5, 1], TextEncoding::Utf8).is_err());
     }
 }
@@ -0,0 +1,301 @@
+use crate::lock::{self, LockKind};
+use crate::page::{MyError, Result};
+use crate::vfs::{OpenMode, Vfs, VfsFile};
+use crate::wal;
+
+/*
+    The wal-index lives in the -shm file next to the WAL, shared by every process using the
+    database, so that a reader knows which frames are committed without scanning the log.
+
+    Wal-index Layout
+        Offset  Size    Description
+        0       48      The header of the last commit
+        48      48      A second copy of the header, readers retry until both copies agree
+        96      4       nBackfill: the number of frames already copied into the database file
+        100     20      aReadMark[5]: the last frame visible to the reader holding each read lock
+        120     8       Lock bytes: WRITE, CKPT, RECOVER and READ(0) to READ(4)
+        128     4       nBackfillAttempted
+        132     4       Unused
+        136             Page numbers of the first 4062 frames, then their hash table
+
+    Wal-index Header
+        Offset  Size    Description
+        0       4       Version number, 3007000
+        4       4       Unused
+        8       4       Counter incremented by every transaction
+        12      1       Non-zero once the header is initialized
+        13      1       Non-zero if the WAL checksums are big-endian
+        14      2       Page size, 65536 is stored as 1
+        16      4       The last valid frame in the WAL
+        20      4       Size of the database in pages
+        24      8       Checksum of the last frame
+        32      8       Salts copied from the WAL header
+        40      8       Checksum over the first 40 bytes of the header
+
+    Everything except the salts is in native byte order. The file is divided into 32KB blocks,
+    each holding the page numbers of 4096 frames (4062 in the first block, after the header)
+    followed by 8192 u16 hash slots. A page is hashed to (page * 383) % 8192, collisions take
+    the next slot, and a slot holds the position of the frame within its block.
+
+    Byte 128 is also the "DMS" lock: every process holds it shared while it uses the wal-index,
+    the first process to open it can lock it exclusively and knows the content is stale.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WalIndexHeader {
+    pub change: u32,
+    pub big_endian_checksum: bool,
+    pub page_size: u32,
+    pub max_frame: u32,
+    pub page_count: u32,
+    pub frame_checksum: (u32, u32),
+    pub salt: (u32, u32),
+}
+
+#[derive(Debug)]
+pub struct WalIndex {
+    file: Box<dyn VfsFile>,
+}
+
+pub const WRITE_LOCK: u64 = 0;
+pub const CHECKPOINT_LOCK: u64 = 1;
+pub const READERS: usize = 5;
+pub const READ_MARK_NOT_USED: u32 = 0xffffffff;
+
+pub fn read_lock(i: usize) -> u64 {
+    3 + i as u64
+}
+
+const VERSION: u32 = 3007000;
+const HEADER_SIZE: usize = 48;
+const BACKFILL_OFFSET: u64 = 96;
+const READ_MARK_OFFSET: u64 = 100;
+const LOCK_OFFSET: u64 = 120;
+const DMS_OFFSET: u64 = 128;
+const BACKFILL_ATTEMPTED_OFFSET: u64 = 128;
+const INDEX_HEADER_SIZE: u64 = 136;
+const BLOCK_SIZE: u64 = 32768;
+const FRAMES_PER_BLOCK: u32 = 4096;
+const FRAMES_IN_FIRST_BLOCK: u32 = 4062;
+const HASH_SLOTS: usize = 8192;
+const HASH_OFFSET: usize = 16384;
+
+impl WalIndexHeader {
+    fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            header[offset..offset + 4].copy_from_slice(&value.to_ne_bytes())
+        };
+        put(0, VERSION);
+        put(8, self.change);
+        put(16, self.max_frame);
+        put(20, self.page_count);
+        put(24, self.frame_checksum.0);
+        put(28, self.frame_checksum.1);
+        header[12] = 1;
+        header[13] = self.big_endian_checksum as u8;
+        let page_size = ((self.page_size & 0xff00) | (self.page_size >> 16)) as u16;
+        header[14..16].copy_from_slice(&page_size.to_ne_bytes());
+        header[32..36].copy_from_slice(&self.salt.0.to_be_bytes());
+        header[36..40].copy_from_slice(&self.salt.1.to_be_bytes());
+        let checksum = wal::checksum(cfg!(target_endian = "big"), &header[..40], (0, 0));
+        header[40..44].copy_from_slice(&checksum.0.to_ne_bytes());
+        header[44..48].copy_from_slice(&checksum.1.to_ne_bytes());
+        header
+    }
+
+    fn decode(header: &[u8]) -> Option<Self> {
+        let get =
+            |offset: usize| u32::from_ne_bytes(header[offset..offset + 4].try_into().unwrap());
+        let checksum = wal::checksum(cfg!(target_endian = "big"), &header[..40], (0, 0));
+        if header[12] == 0 || checksum != (get(40), get(44)) {
+            return None;
+        }
+        let page_size = u16::from_ne_bytes([header[14], header[15]]) as u32;
+        Some(Self {
+            change: get(8),
+            big_endian_checksum: header[13] != 0,
+            page_size: (page_size & 0xfe00) + ((page_size & 0x0001) << 16),
+            max_frame: get(16),
+            page_count: get(20),
+            frame_checksum: (get(24), get(28)),
+            salt: (
+                u32::from_be_bytes(header[32..36].try_into().unwrap()),
+                u32::from_be_bytes(header[36..40].try_into().unwrap()),
+            ),
+        })
+    }
+}
+
+impl WalIndex {
+    pub fn path_for(db_path: &str) -> String {
+        format!("{db_path}-shm")
+    }
+
+    pub fn open(vfs: &dyn Vfs, db_path: &str) -> Result<Self> {
+        let file = vfs.open(&Self::path_for(db_path), OpenMode::Create)?;
+        if file.lock(LockKind::Write, DMS_OFFSET, 1)? {
+            // Nobody else has the wal-index open, whatever it holds is left over from a crash.
+            file.truncate(0)?;
+            file.lock(LockKind::Read, DMS_OFFSET, 1)?;
+        } else {
+            lock::retry(|| file.lock(LockKind::Read, DMS_OFFSET, 1))?;
+        }
+        Ok(Self { file })
+    }
+
+    // Try once to take, or release, one of the wal-index locks.
+    pub fn lock(&self, slot: u64, kind: LockKind) -> Result<bool> {
+        self.file.lock(kind, LOCK_OFFSET + slot, 1)
+    }
+
+    pub fn lock_range(&self, slot: u64, count: u64, kind: LockKind) -> Result<bool> {
+        self.file.lock(kind, LOCK_OFFSET + slot, count)
+    }
+
+    fn read_u32(&self, offset: u64) -> Result<u32> {
+        let mut bytes = [0; 4];
+        match self.file.read_exact_at(&mut bytes, offset) {
+            Ok(()) => Ok(u32::from_ne_bytes(bytes)),
+            Err(MyError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write_u32(&self, offset: u64, value: u32) -> Result<()> {
+        self.file.write_at(&value.to_ne_bytes(), offset)?;
+        Ok(())
+    }
+
+    // None unless both copies of the header are initialized and identical.
+    pub fn read_header(&self) -> Result<Option<WalIndexHeader>> {
+        let mut headers = [0; 2 * HEADER_SIZE];
+        if self.file.read_exact_at(&mut headers, 0).is_err() {
+            return Ok(None);
+        }
+        let (first, second) = headers.split_at(HEADER_SIZE);
+        if first != second {
+            return Ok(None);
+        }
+        Ok(WalIndexHeader::decode(first))
+    }
+
+    // The second copy is written first, so a reader never sees two equal but torn copies.
+    pub fn write_header(&self, header: &WalIndexHeader) -> Result<()> {
+        let encoded = header.encode();
+        self.file.write_at(&encoded, HEADER_SIZE as u64)?;
+        self.file.write_at(&encoded, 0)?;
+        Ok(())
+    }
+
+    pub fn backfilled(&self) -> Result<u32> {
+        self.read_u32(BACKFILL_OFFSET)
+    }
+
+    pub fn set_backfilled(&self, frames: u32) -> Result<()> {
+        self.write_u32(BACKFILL_OFFSET, frames)
+    }
+
+    pub fn set_backfill_attempted(&self, frames: u32) -> Result<()> {
+        self.write_u32(BACKFILL_ATTEMPTED_OFFSET, frames)
+    }
+
+    pub fn read_mark(&self, i: usize) -> Result<u32> {
+        self.read_u32(READ_MARK_OFFSET + 4 * i as u64)
+    }
+
+    pub fn set_read_mark(&self, i: usize, frame: u32) -> Result<()> {
+        self.write_u32(READ_MARK_OFFSET + 4 * i as u64, frame)
+    }
+
+    // The block holding a frame and the number of frames stored in the blocks before it.
+    fn locate(frame: u32) -> (u64, u32) {
+        let block = (frame + FRAMES_PER_BLOCK - FRAMES_IN_FIRST_BLOCK - 1) / FRAMES_PER_BLOCK;
+        let zero = match block {
+            0 => 0,
+            _ => FRAMES_IN_FIRST_BLOCK + (block - 1) * FRAMES_PER_BLOCK,
+        };
+        (block as u64, zero)
+    }
+
+    /*
+        Record the page numbers of consecutive frames starting at first_frame. Entries left in a
+        block by frames that were never committed, or by a log that has since been restarted,
+        are cleared before they can be confused with the new ones.
+    */
+    pub fn append(&self, first_frame: u32, pages: &[u32]) -> Result<()> {
+        let mut current: Option<(u64, Vec<u8>)> = None;
+        for (i, page) in pages.iter().enumerate() {
+            let frame = first_frame + i as u32;
+            let (block, zero) = Self::locate(frame);
+            if current.as_ref().is_none_or(|(b, _)| *b != block) {
+                if let Some((b, data)) = current.take() {
+                    self.write_block(b, &data)?;
+                }
+                current = Some((block, self.read_block(block)?));
+            }
+            let Some((_, data)) = current.as_mut() else {
+                continue;
+            };
+            let start = if block == 0 {
+                INDEX_HEADER_SIZE as usize
+            } else {
+                0
+            };
+            let position = (frame - zero) as usize;
+            let entry = start + (position - 1) * 4;
+            if position == 1 {
+                data[start..].fill(0);
+            } else if data[entry..entry + 4] != [0; 4] {
+                Self::clear_after(data, start, position - 1);
+            }
+            data[entry..entry + 4].copy_from_slice(&page.to_ne_bytes());
+            let mut slot = (*page as usize).wrapping_mul(383) & (HASH_SLOTS - 1);
+            while u16::from_ne_bytes([
+                data[HASH_OFFSET + 2 * slot],
+                data[HASH_OFFSET + 2 * slot + 1],
+            ]) != 0
+            {
+                slot = (slot + 1) & (HASH_SLOTS - 1);
+            }
+            data[HASH_OFFSET + 2 * slot..HASH_OFFSET + 2 * slot + 2]
+                .copy_from_slice(&(position as u16).to_ne_bytes());
+        }
+        if let Some((block, data)) = current {
+            self.write_block(block, &data)?;
+        }
+        Ok(())
+    }
+
+    // Drop the entries of a block past its first `keep` frames.
+    fn clear_after(data: &mut [u8], start: usize, keep: usize) {
+        for slot in 0..HASH_SLOTS {
+            let offset = HASH_OFFSET + 2 * slot;
+            let position = u16::from_ne_bytes([data[offset], data[offset + 1]]) as usize;
+            if position > keep {
+                data[offset..offset + 2].fill(0);
+            }
+        }
+        data[start + keep * 4..HASH_OFFSET].fill(0);
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>> {
+        let mut data = vec![0; BLOCK_SIZE as usize];
+        let offset = block * BLOCK_SIZE;
+        let mut read = 0;
+        while read < data.len() {
+            match self.file.read_at(&mut data[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        Ok(data)
+    }
+
+    // The header and read marks at the start of the first block belong to other writers.
+    fn write_block(&self, block: u64, data: &[u8]) -> Result<()> {
+        let start = if block == 0 { INDEX_HEADER_SIZE } else { 0 };
+        self.file
+            .write_at(&data[start as usize..], block * BLOCK_SIZE + start)?;
+        Ok(())
+    }
+}
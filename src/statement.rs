@@ -0,0 +1,289 @@
+use crate::executor::Executor;
+use crate::page::{MyError, Result};
+use crate::parser::{
+    AlterAction, ColumnConstraint, ColumnDefinition, Expression, Parameter, SqlStatement,
+    TableConstraint,
+};
+use crate::trigger;
+use crate::value::Value;
+
+/*
+    A prepared statement: parsed and checked once, then run any number of times with other
+    values bound to its parameters, which each run reads as it evaluates them. Parameters are
+    numbered from 1 like in SQLite: ?NNN is number NNN, ? the one after the largest number so
+    far, and a name gets the next number where it first appears and keeps it. A parameter
+    nothing was bound to is NULL. Only the statements which read or write rows have
+    parameters, what is kept in the schema cannot refer to them.
+*/
+pub struct Statement {
+    statement: SqlStatement,
+    // The name of each parameter by number, None for those written as ? or ?NNN.
+    names: Vec<Option<String>>,
+    values: Vec<Value>,
+    // The rows of the run in progress, None until step runs the statement.
+    rows: Option<std::vec::IntoIter<Vec<Value>>>,
+}
+
+pub enum Step {
+    Row(Vec<Value>),
+    Done,
+}
+
+impl Statement {
+    // Like SQLite's default SQLITE_MAX_VARIABLE_NUMBER.
+    const MAX_PARAMETERS: usize = 32766;
+
+    pub fn from(mut statement: SqlStatement) -> Result<Self> {
+        check_definitions(&mut statement)?;
+        trigger::check_raise(&mut statement)?;
+        let mut names: Vec<Option<String>> = Vec::new();
+        for expr in statement.expressions() {
+            let mut result = Ok(());
+            expr.walk_mut(&mut |expr| {
+                let Expression::Parameter(parameter) = expr else {
+                    return;
+                };
+                let number = match parameter {
+                    Parameter::Next => names.len() + 1,
+                    Parameter::Numbered(number) => *number,
+                    Parameter::Named(name) => {
+                        match names.iter().position(|n| n.as_ref() == Some(name)) {
+                            Some(i) => i + 1,
+                            None => {
+                                names.push(Some(name.clone()));
+                                names.len()
+                            }
+                        }
+                    }
+                };
+                if !(1..=Self::MAX_PARAMETERS).contains(&number) {
+                    result = Err(MyError::Schema(format!(
+                        "variable number must be between ?1 and ?{}",
+                        Self::MAX_PARAMETERS
+                    )));
+                    return;
+                }
+                if names.len() < number {
+                    names.resize(number, None);
+                }
+                *parameter = Parameter::Numbered(number);
+            });
+            result?;
+        }
+        Ok(Self {
+            statement,
+            values: vec![Value::Null; names.len()],
+            names,
+            rows: None,
+        })
+    }
+
+    // The largest parameter number.
+    pub fn parameter_count(&self) -> usize {
+        self.names.len()
+    }
+
+    // The number of a named parameter, written with its prefix like :name.
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        self.names
+            .iter()
+            .position(|n| n.as_deref() == Some(name))
+            .map(|i| i + 1)
+    }
+
+    // The value is used from the next time the statement runs.
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        match index.checked_sub(1).and_then(|i| self.values.get_mut(i)) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(MyError::Range),
+        }
+    }
+
+    // The next step runs the statement again, with the values bound by then.
+    pub fn reset(&mut self) {
+        self.rows = None;
+    }
+
+    /*
+        The first step runs the whole statement, then each step gives back one of its rows.
+        Done repeats until the statement is reset.
+    */
+    pub fn step(&mut self, executor: &mut Executor) -> Result<Step> {
+        if self.rows.is_none() {
+            self.rows = Some(executor.run(&self.statement, &self.values)?.into_iter());
+        }
+        match self.rows.as_mut().and_then(|rows| rows.next()) {
+            Some(values) => Ok(Step::Row(values)),
+            None => Ok(Step::Done),
+        }
+    }
+
+    // Run the statement and print its rows, one line each with the values separated by '|'.
+    pub fn execute(&mut self, executor: &mut Executor) -> Result<()> {
+        self.reset();
+        while let Step::Row(values) = self.step(executor)? {
+            let columns: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            println!("{}", columns.join("|"));
+        }
+        Ok(())
+    }
+}
+
+// The errors SQLite gives for parameters in the definitions of tables, views and triggers.
+fn check_definitions(statement: &mut SqlStatement) -> Result<()> {
+    let message = match statement {
+        SqlStatement::CREATE(cmd) => cmd.cols.iter().find_map(column_error).or_else(|| {
+            cmd.constraints
+                .iter()
+                .any(|c| matches!(c, TableConstraint::Check(check) if has_parameter(&check.expr)))
+                .then(|| "parameters prohibited in CHECK constraints".to_string())
+        }),
+        SqlStatement::ALTER(cmd) => match &cmd.action {
+            AlterAction::AddColumn(column, _) => column_error(column),
+            _ => None,
+        },
+        SqlStatement::VIEW(cmd) => {
+            let mut expressions = cmd.select.expressions().into_iter();
+            expressions
+                .any(|e| has_parameter(e))
+                .then(|| "parameters are not allowed in views".to_string())
+        }
+        SqlStatement::TRIGGER(cmd) => {
+            let body = cmd.body.iter_mut().flat_map(|s| s.expressions());
+            let mut expressions = cmd.condition.iter_mut().chain(body);
+            expressions
+                .any(|e| has_parameter(e))
+                .then(|| "trigger cannot use variables".to_string())
+        }
+        _ => None,
+    };
+    match message {
+        Some(message) => Err(MyError::Schema(message)),
+        None => Ok(()),
+    }
+}
+
+fn column_error(column: &ColumnDefinition) -> Option<String> {
+    column
+        .constraints
+        .iter()
+        .find_map(|constraint| match constraint {
+            ColumnConstraint::Default(expr, _) if has_parameter(expr) => Some(format!(
+                "default value of column [{}] is not constant",
+                column.name
+            )),
+            ColumnConstraint::Check(check) if has_parameter(&check.expr) => {
+                Some("parameters prohibited in CHECK constraints".to_string())
+            }
+            _ => None,
+        })
+}
+
+fn has_parameter(expr: &Expression) -> bool {
+    let mut found = false;
+    expr.walk(&mut |e| found |= matches!(e, Expression::Parameter(_)));
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use crate::page::TextEncoding;
+    use crate::parser;
+
+    fn prepare(sql: &str) -> Result<Statement> {
+        Statement::from(parser::statements(sql).next().unwrap()?)
+    }
+
+    fn rows(statement: &mut Statement, executor: &mut Executor) -> Vec<String> {
+        statement.reset();
+        let mut rows = Vec::new();
+        while let Step::Row(values) = statement.step(executor).unwrap() {
+            let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+            rows.push(values.join("|"));
+        }
+        rows
+    }
+
+    #[test]
+    fn parameters_are_numbered_like_sqlite() {
+        let statement = prepare("SELECT ?, :a, ?5, ?, :a, @b").unwrap();
+        assert_eq!(statement.parameter_count(), 7);
+        assert_eq!(statement.parameter_index(":a"), Some(2));
+        assert_eq!(statement.parameter_index("@b"), Some(7));
+        assert_eq!(statement.parameter_index("?5"), None);
+        assert_eq!(statement.parameter_index(":b"), None);
+
+        for sql in ["SELECT ?0", "SELECT ?32767"] {
+            let error = prepare(sql).err().unwrap();
+            assert_eq!(
+                error.to_string(),
+                "variable number must be between ?1 and ?32766"
+            );
+        }
+    }
+
+    #[test]
+    fn bound_values_are_read_by_each_run() {
+        let database = Database::memory(TextEncoding::Utf8).unwrap();
+        let mut executor = Executor::from(database).unwrap();
+        let mut statement = prepare("SELECT ?1 + ?2, :x").unwrap();
+        assert_eq!(rows(&mut statement, &mut executor), ["|"]);
+        statement.bind(1, Value::Integer(1)).unwrap();
+        statement.bind(2, Value::Integer(2)).unwrap();
+        statement.bind(3, Value::Text("x".to_string())).unwrap();
+        assert_eq!(rows(&mut statement, &mut executor), ["3|x"]);
+        statement.bind(2, Value::Integer(40)).unwrap();
+        assert_eq!(rows(&mut statement, &mut executor), ["41|x"]);
+
+        assert!(matches!(
+            statement.bind(0, Value::Null),
+            Err(MyError::Range)
+        ));
+        assert!(matches!(
+            statement.bind(4, Value::Null),
+            Err(MyError::Range)
+        ));
+    }
+
+    #[test]
+    fn definitions_cannot_have_parameters() {
+        for (sql, message) in [
+            (
+                "CREATE TABLE t(a DEFAULT ?)",
+                "default value of column [a] is not constant",
+            ),
+            (
+                "CREATE TABLE t(a CHECK (a > ?))",
+                "parameters prohibited in CHECK constraints",
+            ),
+            (
+                "CREATE TABLE t(a, CHECK (a > :x))",
+                "parameters prohibited in CHECK constraints",
+            ),
+            (
+                "ALTER TABLE t ADD COLUMN b DEFAULT ?",
+                "default value of column [b] is not constant",
+            ),
+            (
+                "CREATE VIEW v AS SELECT a FROM t WHERE a = ?",
+                "parameters are not allowed in views",
+            ),
+            (
+                "CREATE TRIGGER r AFTER INSERT ON t BEGIN DELETE FROM t WHERE a = ?; END",
+                "trigger cannot use variables",
+            ),
+            (
+                "CREATE TRIGGER r AFTER INSERT ON t WHEN new.a = ? BEGIN SELECT 1; END",
+                "trigger cannot use variables",
+            ),
+        ] {
+            let error = prepare(sql).err().unwrap();
+            assert_eq!(error.to_string(), message, "{sql}");
+        }
+    }
+}
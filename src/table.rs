@@ -1,23 +1,468 @@
-use crate::parser::{SqlStatement, sql_query};
+use std::cmp::Ordering;
+
+use crate::executor::{self, Row};
+use crate::page::{MyError, Result, TextEncoding};
+use crate::parser::{
+    Check, ColumnConstraint, ColumnDefinition, ConflictResolution, ForeignKey, IndexedColumn,
+    SqlStatement, TableConstraint, index_creation, sql_query,
+};
+use crate::record::{Record, RecordView};
+use crate::value::{Affinity, Value};
+
+/*
+    One row of the sqlite_schema table:
+        type        'table', 'index', 'view' or 'trigger'
+        name        the name of the object
+        tbl_name    the table the object belongs to, a table's tbl_name is its own name
+        rootpage    the root page of the b-tree for tables and indexes, 0 otherwise
+        sql         the original CREATE statement, NULL for automatically created indexes
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaEntry {
+    pub entry_type: String,
+    pub name: String,
+    pub table_name: String,
+    pub root_page: u32,
+    pub sql: Option<String>,
+}
+
+impl SchemaEntry {
+    pub fn from(values: &[Value]) -> Self {
+        let text = |i: usize| match values.get(i) {
+            Some(Value::Null) | None => None,
+            Some(v) => Some(v.to_string()),
+        };
+        Self {
+            entry_type: text(0).unwrap_or_default(),
+            name: text(1).unwrap_or_default(),
+            table_name: text(2).unwrap_or_default(),
+            root_page: values.get(3).and_then(|v| v.as_i64()).unwrap_or(0) as u32,
+            sql: text(4),
+        }
+    }
+
+    pub fn encode(&self, encoding: TextEncoding) -> Vec<u8> {
+        Record::encode(
+            &[
+                Value::Text(self.entry_type.clone()),
+                Value::Text(self.name.clone()),
+                Value::Text(self.table_name.clone()),
+                Value::Integer(self.root_page as i64),
+                self.sql.clone().map_or(Value::Null, Value::Text),
+            ],
+            encoding,
+        )
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct TableSchema {
     pub table_name: String,
-    pub cols: Vec<String>,
+    pub root_page: u32,
+    pub cols: Vec<ColumnDefinition>,
+    pub constraints: Vec<TableConstraint>,
+    pub defaults: Vec<Value>,
+    // The text encoding of the database the table is in, which its records are stored in.
+    pub encoding: TextEncoding,
 }
 
 impl TableSchema {
-    pub fn from(sql: &String) -> Self {
-        let (_, sql_cmd) = sql_query(sql).unwrap();
-        let mut table_name: String = String::new();
-        let mut cols: Vec<String> = Vec::new();
-        match sql_cmd {
-            SqlStatement::CREATE(cs) => {
-                table_name = cs.table_name;
-                cols = cs.cols;
-            }
-            _ => println!("Something is wrong, the schema is not a creation sql."),
-        }
-        Self { table_name, cols }
+    pub const SCHEMA_TABLE_SQL: &'static str =
+        "CREATE TABLE sqlite_schema(type text, name text, tbl_name text, rootpage int, sql text)";
+    pub const SEQUENCE_TABLE_SQL: &'static str = "CREATE TABLE sqlite_sequence(name,seq)";
+    // The schema table of the temp database, which is found by these names from any database.
+    pub const TEMP_SCHEMA_NAMES: [&'static str; 2] = ["sqlite_temp_schema", "sqlite_temp_master"];
+
+    pub fn from(sql: &str, root_page: u32, encoding: TextEncoding) -> Option<Self> {
+        match sql_query(sql) {
+            Ok((_, SqlStatement::CREATE(cs))) => {
+                let mut table = Self {
+                    table_name: cs.table_name,
+                    root_page,
+                    cols: cs.cols,
+                    constraints: cs.constraints,
+                    defaults: Vec::new(),
+                    encoding,
+                };
+                table.defaults = (0..table.cols.len())
+                    .map(|i| table.default_value(i))
+                    .collect();
+                Some(table)
+            }
+            _ => {
+                println!("Something is wrong, the schema is not a creation sql.");
+                None
+            }
+        }
+    }
+
+    pub fn from_entry(entry: &SchemaEntry, encoding: TextEncoding) -> Option<Self> {
+        Self::from(entry.sql.as_deref()?, entry.root_page, encoding)
+    }
+
+    // The columns of a query, which are untyped and have no constraints.
+    pub fn from_columns(table_name: &str, names: Vec<String>, encoding: TextEncoding) -> Self {
+        Self {
+            table_name: table_name.to_string(),
+            root_page: 0,
+            defaults: vec![Value::Null; names.len()],
+            cols: names
+                .into_iter()
+                .map(|name| ColumnDefinition {
+                    name,
+                    type_name: None,
+                    constraints: Vec::new(),
+                })
+                .collect(),
+            constraints: Vec::new(),
+            encoding,
+        }
+    }
+
+    pub fn column_index(&self, name: &str) -> Option<usize> {
+        self.cols
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn affinity(&self, index: usize) -> Affinity {
+        Affinity::from(self.cols[index].type_name.as_deref())
+    }
+
+    // The value of the DEFAULT clause of a column, with the column's affinity, or NULL.
+    fn default_value(&self, index: usize) -> Value {
+        let row = Row {
+            rowid: 0,
+            values: vec![Value::Null; self.cols.len()],
+        };
+        self.cols[index]
+            .constraints
+            .iter()
+            .find_map(|c| match c {
+                ColumnConstraint::Default(expr, _) => {
+                    executor::evaluate(expr, self, &row, &[]).ok()
+                }
+                _ => None,
+            })
+            .map_or(Value::Null, |v| v.apply_affinity(self.affinity(index)))
+    }
+
+    /*
+        A column declared as "INTEGER PRIMARY KEY" is an alias for the rowid. Its value is not
+        stored in the record (the record holds a NULL in its place), it is the key of the cell.
+    */
+    pub fn rowid_column(&self) -> Option<usize> {
+        let is_integer = |i: usize| {
+            self.cols[i]
+                .type_name
+                .as_deref()
+                .is_some_and(|t| t.eq_ignore_ascii_case("integer"))
+        };
+        for (i, col) in self.cols.iter().enumerate() {
+            if col
+                .constraints
+                .iter()
+                .any(|c| matches!(c, ColumnConstraint::PrimaryKey { .. }))
+            {
+                return if is_integer(i) { Some(i) } else { None };
+            }
+        }
+        for constraint in &self.constraints {
+            if let TableConstraint::PrimaryKey(cols, _) = constraint {
+                if cols.len() != 1 {
+                    return None;
+                }
+                return self.column_index(&cols[0]).filter(|i| is_integer(*i));
+            }
+        }
+        None
+    }
+
+    // Whether the rowid alias is declared AUTOINCREMENT, rowids are then never used twice.
+    pub fn is_autoincrement(&self) -> bool {
+        self.rowid_column().is_some_and(|i| {
+            self.cols[i].constraints.iter().any(|c| {
+                matches!(
+                    c,
+                    ColumnConstraint::PrimaryKey {
+                        autoincrement: true,
+                        ..
+                    }
+                )
+            })
+        })
+    }
+
+    // Decode a stored row into one value per column, filling in the rowid alias.
+    pub fn row_values(&self, rowid: i64, payload: &[u8]) -> Result<Vec<Value>> {
+        self.project(rowid, payload, |_| true)
+    }
+
+    /*
+        Like row_values, but only the columns `wanted` asks for are decoded, the others are left
+        NULL. A record written before ALTER TABLE ADD COLUMN is shorter than its table, the
+        columns it lacks read as their default values.
+    */
+    pub fn project<F>(&self, rowid: i64, payload: &[u8], wanted: F) -> Result<Vec<Value>>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let record = RecordView::from(payload, self.encoding)?;
+        let rowid_column = self.rowid_column();
+        let mut values = vec![Value::Null; self.cols.len()];
+        for (i, value) in values.iter_mut().enumerate() {
+            if Some(i) == rowid_column {
+                *value = Value::Integer(rowid);
+            } else if !wanted(i) {
+                continue;
+            } else if i < record.len() {
+                *value = record.value(i)?;
+            } else {
+                *value = self.defaults.get(i).cloned().unwrap_or(Value::Null);
+            }
+        }
+        Ok(values)
+    }
+
+    pub fn encode_row(&self, values: &[Value]) -> Vec<u8> {
+        let mut values = values.to_vec();
+        if let Some(i) = self.rowid_column() {
+            values[i] = Value::Null;
+        }
+        Record::encode(&values, self.encoding)
+    }
+
+    // The columns of the PRIMARY KEY, declared on a column or for the table, in key order.
+    pub fn primary_key(&self) -> Vec<String> {
+        for col in &self.cols {
+            if col
+                .constraints
+                .iter()
+                .any(|c| matches!(c, ColumnConstraint::PrimaryKey { .. }))
+            {
+                return vec![col.name.clone()];
+            }
+        }
+        self.constraints
+            .iter()
+            .find_map(|c| match c {
+                TableConstraint::PrimaryKey(cols, _) => Some(cols.clone()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    // Foreign keys in the order they are declared, column constraints first.
+    pub fn foreign_keys(&self) -> Vec<ForeignKey> {
+        let column_keys = self.cols.iter().flat_map(|col| {
+            col.constraints.iter().filter_map(|c| match c {
+                ColumnConstraint::References(foreign_key) => Some(foreign_key.clone()),
+                _ => None,
+            })
+        });
+        let table_keys = self.constraints.iter().filter_map(|c| match c {
+            TableConstraint::ForeignKey(foreign_key) => Some(foreign_key.clone()),
+            _ => None,
+        });
+        column_keys.chain(table_keys).collect()
+    }
+
+    /*
+        Every UNIQUE or PRIMARY KEY constraint (except INTEGER PRIMARY KEY) is backed by an index
+        named sqlite_autoindex_TABLE_N, numbered in the order the constraints appear in the
+        definition, column constraints before table constraints.
+    */
+    pub fn unique_column_sets(&self) -> Vec<Vec<String>> {
+        self.unique_constraints()
+            .into_iter()
+            .map(|(cols, _)| cols)
+            .collect()
+    }
+
+    // The columns of each UNIQUE or PRIMARY KEY constraint with an index, and its ON CONFLICT.
+    pub fn unique_constraints(&self) -> Vec<(Vec<String>, Option<ConflictResolution>)> {
+        let rowid_column = self.rowid_column();
+        let mut constraints = Vec::new();
+        for (i, col) in self.cols.iter().enumerate() {
+            for constraint in &col.constraints {
+                match constraint {
+                    ColumnConstraint::PrimaryKey { on_conflict, .. } if rowid_column != Some(i) => {
+                        constraints.push((vec![col.name.clone()], *on_conflict))
+                    }
+                    ColumnConstraint::Unique(on_conflict) => {
+                        constraints.push((vec![col.name.clone()], *on_conflict))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for constraint in &self.constraints {
+            match constraint {
+                TableConstraint::PrimaryKey(cols, on_conflict) if rowid_column.is_none() => {
+                    constraints.push((cols.clone(), *on_conflict))
+                }
+                TableConstraint::Unique(cols, on_conflict) => {
+                    constraints.push((cols.clone(), *on_conflict))
+                }
+                _ => {}
+            }
+        }
+        constraints
+    }
+
+    // The ON CONFLICT of the PRIMARY KEY, which for an INTEGER PRIMARY KEY guards the rowid.
+    pub fn primary_key_on_conflict(&self) -> Option<ConflictResolution> {
+        let mut constraints = self.cols.iter().flat_map(|col| &col.constraints);
+        let column_key = constraints.find_map(|c| match c {
+            ColumnConstraint::PrimaryKey { on_conflict, .. } => Some(*on_conflict),
+            _ => None,
+        });
+        let table_key = || {
+            self.constraints.iter().find_map(|c| match c {
+                TableConstraint::PrimaryKey(_, on_conflict) => Some(*on_conflict),
+                _ => None,
+            })
+        };
+        column_key.or_else(table_key).flatten()
+    }
+
+    // The ON CONFLICT of a NOT NULL column, None when the column may hold NULL.
+    pub fn not_null(&self, index: usize) -> Option<Option<ConflictResolution>> {
+        self.cols[index].constraints.iter().find_map(|c| match c {
+            ColumnConstraint::NotNull(on_conflict) => Some(*on_conflict),
+            _ => None,
+        })
+    }
+
+    // CHECK constraints in the order they are declared, column constraints first.
+    pub fn checks(&self) -> Vec<Check> {
+        let column_checks = self.cols.iter().flat_map(|col| {
+            col.constraints.iter().filter_map(|c| match c {
+                ColumnConstraint::Check(check) => Some(check.clone()),
+                _ => None,
+            })
+        });
+        let table_checks = self.constraints.iter().filter_map(|c| match c {
+            TableConstraint::Check(check) => Some(check.clone()),
+            _ => None,
+        });
+        column_checks.chain(table_checks).collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IndexSchema {
+    pub index_name: String,
+    pub table_name: String,
+    pub root_page: u32,
+    pub unique: bool,
+    pub cols: Vec<IndexedColumn>,
+    pub column_indices: Vec<usize>,
+    pub encoding: TextEncoding,
+    // The ON CONFLICT of the constraint an automatic index was created for.
+    pub on_conflict: Option<ConflictResolution>,
+}
+
+impl IndexSchema {
+    pub fn from_entry(entry: &SchemaEntry, table: &TableSchema) -> Option<Self> {
+        let (unique, cols, on_conflict) = match &entry.sql {
+            Some(sql) => {
+                let (_, statement) = index_creation(sql).ok()?;
+                (statement.unique, statement.cols, None)
+            }
+            None => {
+                let number: usize = entry.name.rsplit('_').next()?.parse().ok()?;
+                let (names, on_conflict) =
+                    table.unique_constraints().into_iter().nth(number - 1)?;
+                let cols = names
+                    .into_iter()
+                    .map(|name| IndexedColumn {
+                        name,
+                        descending: false,
+                    })
+                    .collect();
+                (true, cols, on_conflict)
+            }
+        };
+        let column_indices = cols
+            .iter()
+            .map(|c| table.column_index(&c.name))
+            .collect::<Option<Vec<usize>>>()?;
+        Some(Self {
+            index_name: entry.name.clone(),
+            table_name: table.table_name.clone(),
+            root_page: entry.root_page,
+            unique,
+            cols,
+            column_indices,
+            encoding: table.encoding,
+            on_conflict,
+        })
+    }
+
+    // An index entry is a record of the indexed columns followed by the rowid.
+    pub fn key(&self, rowid: i64, values: &[Value]) -> Vec<u8> {
+        let mut key: Vec<Value> = self
+            .column_indices
+            .iter()
+            .map(|i| values[*i].clone())
+            .collect();
+        key.push(Value::Integer(rowid));
+        Record::encode(&key, self.encoding)
+    }
+
+    /*
+        Two entries of a UNIQUE index conflict when all their indexed columns are equal. NULLs
+        are distinct from each other, so entries holding a NULL never conflict.
+    */
+    pub fn conflicts(&self, a: &[u8], b: &[u8]) -> bool {
+        let (Ok(a), Ok(b)) = (
+            RecordView::from(a, self.encoding),
+            RecordView::from(b, self.encoding),
+        ) else {
+            return false;
+        };
+        let count = self.column_indices.len().min(a.len()).min(b.len());
+        (0..count).all(|i| match (a.value(i), b.value(i)) {
+            (Ok(x), Ok(y)) => !x.is_null() && x.compare(&y, self.encoding) == Ordering::Equal,
+            _ => false,
+        })
+    }
+
+    pub fn unique_error(&self) -> MyError {
+        MyError::Unique {
+            table: self.table_name.clone(),
+            columns: self.cols.iter().map(|c| c.name.clone()).collect(),
+        }
+    }
+
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Result<Ordering> {
+        self.compare_first(a, b, usize::MAX)
+    }
+
+    // Compares the indexed columns only, to find the entry holding them whatever its rowid.
+    pub fn compare_columns(&self, a: &[u8], b: &[u8]) -> Result<Ordering> {
+        self.compare_first(a, b, self.column_indices.len())
+    }
+
+    // Columns are decoded one pair at a time, up to the first that differs. A record that does
+    // not decode is corrupt, it has no place in the order.
+    fn compare_first(&self, a: &[u8], b: &[u8], count: usize) -> Result<Ordering> {
+        let a = RecordView::from(a, self.encoding)?;
+        let b = RecordView::from(b, self.encoding)?;
+        let (a_len, b_len) = (a.len().min(count), b.len().min(count));
+        for i in 0..a_len.min(b_len) {
+            let ordering = a.value(i)?.compare(&b.value(i)?, self.encoding);
+            let descending = self.cols.get(i).is_some_and(|c| c.descending);
+            let ordering = if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            };
+            if ordering != Ordering::Equal {
+                return Ok(ordering);
+            }
+        }
+        Ok(a_len.cmp(&b_len))
     }
 }
@@ -0,0 +1,148 @@
+use crate::executor::Row;
+use crate::page::{MyError, Result};
+use crate::parser::{CreateTriggerStatement, Expression, SqlStatement, TriggerEvent, sql_query};
+use crate::table::{SchemaEntry, TableSchema};
+use crate::value::Value;
+
+/*
+    A trigger is stored as its CREATE TRIGGER statement and parsed again by every statement
+    that may fire it. It fires once for each row the statement inserts, updates or deletes:
+    every NEW.column and OLD.column of its WHEN clause and body is replaced by the value of
+    the row, then the body runs like any other statement.
+*/
+pub fn parse(entry: &SchemaEntry) -> Result<CreateTriggerStatement> {
+    match entry.sql.as_deref().map(sql_query) {
+        Some(Ok((_, SqlStatement::TRIGGER(trigger)))) => Ok(trigger),
+        _ => Err(MyError::Schema(format!(
+            "malformed database schema ({})",
+            entry.name
+        ))),
+    }
+}
+
+// UPDATE OF fires when the statement sets one of its columns.
+pub fn fires_on(trigger: &TriggerEvent, event: &TriggerEvent) -> bool {
+    match (trigger, event) {
+        (TriggerEvent::Insert, TriggerEvent::Insert) => true,
+        (TriggerEvent::Delete, TriggerEvent::Delete) => true,
+        (TriggerEvent::Update(columns), TriggerEvent::Update(assigned)) => {
+            columns.is_empty()
+                || columns
+                    .iter()
+                    .any(|c| assigned.iter().any(|a| a.eq_ignore_ascii_case(c)))
+        }
+        _ => false,
+    }
+}
+
+// The body of a trigger may only name tables of the database the trigger is in.
+pub fn check_body(trigger: &CreateTriggerStatement) -> Result<()> {
+    let qualified = trigger.body.iter().any(|statement| match statement {
+        SqlStatement::INSERT(cmd) => cmd.schema.is_some(),
+        SqlStatement::UPDATE(cmd) => cmd.schema.is_some(),
+        SqlStatement::DELETE(cmd) => cmd.schema.is_some(),
+        _ => false,
+    });
+    match qualified {
+        true => Err(MyError::Schema(
+            "qualified table names are not allowed on INSERT, UPDATE, and DELETE statements within triggers"
+                .to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+// RAISE() only makes sense in the body of a trigger.
+pub fn check_raise(statement: &mut SqlStatement) -> Result<()> {
+    match statement
+        .expressions()
+        .into_iter()
+        .any(|e| contains_raise(e))
+    {
+        true => Err(MyError::Schema(
+            "RAISE() may only be used within a trigger-program".to_string(),
+        )),
+        false => Ok(()),
+    }
+}
+
+fn contains_raise(expr: &Expression) -> bool {
+    match expr {
+        Expression::Raise(..) => true,
+        Expression::Unary(_, operand) | Expression::IsNull(operand, _) => contains_raise(operand),
+        Expression::Binary(lhs, _, rhs) => contains_raise(lhs) || contains_raise(rhs),
+        Expression::Literal(_)
+        | Expression::Column(_)
+        | Expression::TableColumn(..)
+        | Expression::Parameter(_) => false,
+    }
+}
+
+// The row references of a statement of the body, NEW is None for DELETE and OLD for INSERT.
+pub struct RowReferences<'a> {
+    pub table: &'a TableSchema,
+    pub old: Option<&'a Row>,
+    pub new: Option<&'a Row>,
+}
+
+impl RowReferences<'_> {
+    pub fn bind_statement(&self, statement: &mut SqlStatement) -> Result<()> {
+        for expr in statement.expressions() {
+            self.bind(expr)?;
+        }
+        Ok(())
+    }
+
+    pub fn bind(&self, expr: &mut Expression) -> Result<()> {
+        bind_rows(expr, self.table, &[("new", self.new), ("old", self.old)])
+    }
+}
+
+// The row an upsert could not insert, named "excluded" in its DO UPDATE clause.
+pub fn bind_excluded(expr: &mut Expression, table: &TableSchema, excluded: &Row) -> Result<()> {
+    bind_rows(expr, table, &[("excluded", Some(excluded))])
+}
+
+// Replaces each name.column naming one of the rows by the value of the row.
+fn bind_rows(
+    expr: &mut Expression,
+    table: &TableSchema,
+    rows: &[(&str, Option<&Row>)],
+) -> Result<()> {
+    match expr {
+        Expression::TableColumn(name, column) => {
+            let Some((_, row)) = rows.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) else {
+                return Ok(());
+            };
+            match row.and_then(|row| value(table, row, column)) {
+                Some(value) => *expr = Expression::Literal(value),
+                None => return Err(MyError::NoSuchColumn(format!("{name}.{column}"))),
+            }
+        }
+        Expression::Unary(_, operand) | Expression::IsNull(operand, _) => {
+            bind_rows(operand, table, rows)?
+        }
+        Expression::Binary(lhs, _, rhs) => {
+            bind_rows(lhs, table, rows)?;
+            bind_rows(rhs, table, rows)?;
+        }
+        Expression::Literal(_)
+        | Expression::Column(_)
+        | Expression::Raise(..)
+        | Expression::Parameter(_) => {}
+    }
+    Ok(())
+}
+
+fn value(table: &TableSchema, row: &Row, column: &str) -> Option<Value> {
+    match table.column_index(column) {
+        Some(i) => row.values.get(i).cloned(),
+        None if ["rowid", "oid", "_rowid_"]
+            .iter()
+            .any(|r| r.eq_ignore_ascii_case(column)) =>
+        {
+            Some(Value::Integer(row.rowid))
+        }
+        None => None,
+    }
+}
@@ -1,65 +1,7 @@
-use core::panic;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::page::PageType;
 
-pub struct ArrayIter<'a, T, const N: usize> {
-    iter: std::slice::ChunksExact<'a, T>,
-}
-
-impl<'a, const N: usize, T> Iterator for ArrayIter<'a, T, N> {
-    type Item = &'a [T; N];
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.iter.next().map(|x| x.try_into().unwrap())
-    }
-}
-
-pub trait MyCoolArrayStuff<T> {
-    fn as_array_iter<'a, const N: usize>(&'a self) -> ArrayIter<'a, T, N>;
-}
-
-impl<T> MyCoolArrayStuff<T> for [T] {
-    fn as_array_iter<'a, const N: usize>(&'a self) -> ArrayIter<'a, T, N> {
-        ArrayIter {
-            iter: self.chunks_exact(N),
-        }
-    }
-}
-
-struct ArrayIter2<'a, T, const N: usize> {
-    underlying: &'a [T],
-    index: usize,
-}
-
-impl<'a, const N: usize, T> Iterator for ArrayIter2<'a, T, N> {
-    type Item = &'a [T; N];
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index + N <= self.underlying.len() {
-            let result = self.underlying[self.index..self.index + N]
-                .try_into()
-                .unwrap();
-            self.index += N;
-            Some(result)
-        } else {
-            None
-        }
-    }
-}
-
-trait MyCoolArrayStuff2<T> {
-    fn as_array_iter2<'a, const N: usize>(&'a self) -> ArrayIter2<'a, T, N>;
-}
-
-impl<T> MyCoolArrayStuff2<T> for [T] {
-    fn as_array_iter2<'a, const N: usize>(&'a self) -> ArrayIter2<'a, T, N> {
-        ArrayIter2 {
-            underlying: self,
-            index: 0,
-        }
-    }
-}
-
 pub fn read_variant(bytes: &[u8]) -> (i64, usize) {
     let mut varint: i64 = 0;
     let mut bytes_read: usize = 0;
@@ -78,12 +20,47 @@ pub fn read_variant(bytes: &[u8]) -> (i64, usize) {
     (varint, bytes_read)
 }
 
-pub fn get_page_type(t: u8) -> PageType {
+pub fn get_page_type(t: u8) -> Option<PageType> {
     match t {
-        2 => PageType::IndexInterior,
-        5 => PageType::TableInterior,
-        10 => PageType::IndexLeaf,
-        13 => PageType::TableLeaf,
-        _ => panic!("Something wrong"),
+        2 => Some(PageType::IndexInterior),
+        5 => Some(PageType::TableInterior),
+        10 => Some(PageType::IndexLeaf),
+        13 => Some(PageType::TableLeaf),
+        _ => None,
     }
 }
+
+// The inverse of read_variant. Values which need more than 56 bits use all 8 bits of the
+// ninth byte, exactly like the decoder expects.
+pub fn write_variant(value: i64) -> Vec<u8> {
+    let value = value as u64;
+    if value & (0xff00_0000 << 32) != 0 {
+        let mut bytes = vec![0; 9];
+        bytes[8] = value as u8;
+        let mut v = value >> 8;
+        for i in (0..8).rev() {
+            bytes[i] = (v as u8 & 0b0111_1111) | 0b1000_0000;
+            v >>= 7;
+        }
+        return bytes;
+    }
+    let mut bytes = Vec::new();
+    let mut v = value;
+    loop {
+        bytes.push((v as u8 & 0b0111_1111) | 0b1000_0000);
+        v >>= 7;
+        if v == 0 {
+            break;
+        }
+    }
+    bytes[0] &= 0b0111_1111;
+    bytes.reverse();
+    bytes
+}
+
+// Salts and nonces only need to differ between runs, the clock is random enough for that.
+pub fn random_u32() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_nanos() ^ d.as_secs() as u32)
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::path::Path;
+
+use crate::autovacuum::AutoVacuum;
+use crate::btree;
+use crate::database::Database;
+use crate::page::{FileHeader, MyError, PageHeader, PageType, Result};
+use crate::parser::TransactionMode;
+use crate::table::SchemaEntry;
+
+/*
+    VACUUM rebuilds the database into a new file where every b-tree is packed into consecutive
+    pages and nothing is left on the freelist. Rowids are kept. Like SQLite, the copy takes the
+    schema cookie plus one and keeps the other fields of the header, such as the user version
+    and the application id, but it is always in rollback journal mode.
+
+    VACUUM INTO leaves the copy in the named file, which must not exist or be empty. A plain
+    VACUUM builds it in a temporary file and then writes its pages over the database within
+    the current transaction, so the database shrinks in a single commit. An auto_vacuum
+    setting made since the database was created is applied to the copy.
+*/
+pub fn vacuum_into(source: &mut Database, path: &str) -> Result<()> {
+    if fs::metadata(path).is_ok_and(|m| m.len() > 0) {
+        return Err(MyError::Schema("output file already exists".to_string()));
+    }
+    let mut first_page = source.load_page(1)?.into_vec();
+    first_page[18] = 1;
+    first_page[19] = 1;
+    first_page[FileHeader::FILE_HEADER_SIZE..].fill(0);
+    PageHeader {
+        page_type: PageType::TableLeaf,
+        first_freeblock: 0,
+        cell_count: 0,
+        cell_content_offset: source.file_header.usable_size() as u32,
+        fragmented_bytes_count: 0,
+        rightmost_pointer: None,
+    }
+    .write_to(&mut first_page[FileHeader::FILE_HEADER_SIZE..]);
+    let auto_vacuum = source
+        .pending_auto_vacuum
+        .unwrap_or_else(|| source.auto_vacuum());
+    FileHeader {
+        page_count: 1,
+        first_freelist_trunk_page: 0,
+        freelist_page_count: 0,
+        largest_root_page: (auto_vacuum != AutoVacuum::None) as u32,
+        incremental_vacuum: auto_vacuum == AutoVacuum::Incremental,
+        ..source.file_header.clone()
+    }
+    .write_to(&mut first_page);
+    fs::write(path, &first_page)?;
+
+    let mut target = Database::from(path.to_string())?;
+    target.begin(TransactionMode::Exclusive)?;
+    for entry in source.get_schema()? {
+        let root_page = match entry.root_page {
+            0 => 0,
+            root_page => copy_tree(source, &mut target, root_page)?,
+        };
+        target.add_schema_entry(&SchemaEntry { root_page, ..entry })?;
+    }
+    target.file_header.schema_cookie = source.file_header.schema_cookie.wrapping_add(1);
+    target.commit()
+}
+
+pub fn vacuum(db: &mut Database) -> Result<()> {
+    let path = std::env::temp_dir().join(format!("rqlite_vacuum_{}.db", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    let _ = fs::remove_file(&path);
+    let result = vacuum_into(db, &path).and_then(|_| copy_back(db, &path));
+    fs::remove_file(&path)?;
+    result
+}
+
+// The database keeps its own journal mode, everything else comes from the compacted copy.
+fn copy_back(db: &mut Database, path: impl AsRef<Path>) -> Result<()> {
+    let data = fs::read(path)?;
+    let page_size = db.file_header.page_size as usize;
+    let versions = db.load_page(1)?[18..20].to_vec();
+    let compacted = FileHeader::parse(&data[..FileHeader::FILE_HEADER_SIZE]);
+    for (i, page) in data.chunks(page_size).enumerate() {
+        let mut page = page.to_vec();
+        if i == 0 {
+            page[18..20].copy_from_slice(&versions);
+        }
+        db.store_page(i as u32 + 1, &page)?;
+    }
+    db.file_header.page_count = compacted.page_count;
+    db.file_header.first_freelist_trunk_page = 0;
+    db.file_header.freelist_page_count = 0;
+    db.file_header.schema_cookie = compacted.schema_cookie;
+    db.file_header.largest_root_page = compacted.largest_root_page;
+    db.file_header.incremental_vacuum = compacted.incremental_vacuum;
+    db.pending_auto_vacuum = None;
+    Ok(())
+}
+
+// Copy a b-tree, table or index, into a new b-tree of the target and return its root page.
+fn copy_tree(source: &mut Database, target: &mut Database, root_page: u32) -> Result<u32> {
+    if btree::root_type(source, root_page)?.is_table() {
+        let rows = btree::table_rows(source, root_page)?;
+        let new_root = btree::create_tree(target, PageType::TableLeaf)?;
+        btree::table_build(target, new_root, &rows)?;
+        Ok(new_root)
+    } else {
+        let keys = btree::index_scan(source, root_page)?;
+        let new_root = btree::create_tree(target, PageType::IndexLeaf)?;
+        btree::index_build(target, new_root, &keys)?;
+        Ok(new_root)
+    }
+}
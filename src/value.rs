@@ -0,0 +1,179 @@
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+use crate::page::TextEncoding;
+
+/*
+    A value as seen by the SQL layer. Unlike the serial types of a record, which say how a value
+    is stored on disk, a value only keeps the five storage classes of SQLite:
+        NULL, INTEGER, REAL, TEXT and BLOB
+    When sorting or comparing, values of different storage classes follow this order:
+        NULL < INTEGER/REAL < TEXT < BLOB
+*/
+#[derive(Debug, Clone)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+/*
+    Every column has a type affinity, which is derived from the declared type:
+        1. If the declared type contains the string "INT" then it is assigned INTEGER affinity.
+        2. If the declared type contains any of the strings "CHAR", "CLOB", or "TEXT" then that column has TEXT affinity.
+        3. If the declared type contains the string "BLOB" or if no type is specified then the column has affinity BLOB.
+        4. If the declared type contains any of the strings "REAL", "FLOA", or "DOUB" then the column has REAL affinity.
+        5. Otherwise, the affinity is NUMERIC.
+*/
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Affinity {
+    Integer,
+    Text,
+    Blob,
+    Real,
+    Numeric,
+}
+
+impl Affinity {
+    pub fn from(declared_type: Option<&str>) -> Self {
+        let declared_type = match declared_type {
+            Some(t) => t.to_uppercase(),
+            None => return Affinity::Blob,
+        };
+        if declared_type.contains("INT") {
+            Affinity::Integer
+        } else if ["CHAR", "CLOB", "TEXT"]
+            .iter()
+            .any(|s| declared_type.contains(s))
+        {
+            Affinity::Text
+        } else if declared_type.contains("BLOB") || declared_type.is_empty() {
+            Affinity::Blob
+        } else if ["REAL", "FLOA", "DOUB"]
+            .iter()
+            .any(|s| declared_type.contains(s))
+        {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+}
+
+impl Value {
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    // The truth value of an expression result, NULL is neither true nor false.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Null => None,
+            Value::Integer(i) => Some(*i != 0),
+            Value::Real(f) => Some(*f != 0.0),
+            Value::Text(s) => Some(Self::parse_number(s).is_some_and(|v| v.as_f64() != 0.0)),
+            Value::Blob(_) => Some(false),
+        }
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Integer(i) => *i as f64,
+            Value::Real(f) => *f,
+            Value::Text(s) => Self::parse_number(s).map_or(0.0, |v| v.as_f64()),
+            _ => 0.0,
+        }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            Value::Real(f) if f.fract() == 0.0 => Some(*f as i64),
+            Value::Text(s) => Self::parse_number(s).and_then(|v| v.as_i64()),
+            _ => None,
+        }
+    }
+
+    pub fn parse_number(s: &str) -> Option<Value> {
+        let s = s.trim();
+        if let Ok(i) = s.parse::<i64>() {
+            Some(Value::Integer(i))
+        } else if s.chars().any(|c| c.is_ascii_digit()) {
+            s.parse::<f64>().ok().map(Value::Real)
+        } else {
+            None
+        }
+    }
+
+    // Convert the value into the storage class preferred by the column affinity.
+    pub fn apply_affinity(self, affinity: Affinity) -> Self {
+        match (affinity, self) {
+            (Affinity::Text, Value::Integer(i)) => Value::Text(i.to_string()),
+            (Affinity::Text, Value::Real(f)) => Value::Text(Value::Real(f).to_string()),
+            (Affinity::Integer | Affinity::Numeric, Value::Text(s)) => {
+                match Self::parse_number(&s) {
+                    Some(Value::Real(f)) if f.fract() == 0.0 && f.abs() < 9.2e18 => {
+                        Value::Integer(f as i64)
+                    }
+                    Some(v) => v,
+                    None => Value::Text(s),
+                }
+            }
+            (Affinity::Integer | Affinity::Numeric, Value::Real(f))
+                if f.fract() == 0.0 && f.abs() < 9.2e18 =>
+            {
+                Value::Integer(f as i64)
+            }
+            (Affinity::Real, Value::Text(s)) => match Self::parse_number(&s) {
+                Some(v) => Value::Real(v.as_f64()),
+                None => Value::Text(s),
+            },
+            (Affinity::Real, Value::Integer(i)) => Value::Real(i as f64),
+            (_, v) => v,
+        }
+    }
+
+    fn class_rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        }
+    }
+
+    // Total order used by ORDER BY, index keys and comparison operators. Text compares in the
+    // encoding of the database it belongs to.
+    pub fn compare(&self, other: &Value, encoding: TextEncoding) -> Ordering {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a.cmp(b),
+            (Value::Integer(_) | Value::Real(_), Value::Integer(_) | Value::Real(_)) => self
+                .as_f64()
+                .partial_cmp(&other.as_f64())
+                .unwrap_or(Ordering::Equal),
+            (Value::Text(a), Value::Text(b)) => encoding.compare(a, b),
+            (Value::Blob(a), Value::Blob(b)) => a.cmp(b),
+            _ => self.class_rank().cmp(&other.class_rank()),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, ""),
+            Value::Integer(i) => write!(f, "{i}"),
+            Value::Real(r) => {
+                if r.fract() == 0.0 && r.abs() < 1e15 {
+                    write!(f, "{r:.1}")
+                } else {
+                    write!(f, "{r}")
+                }
+            }
+            Value::Text(s) => write!(f, "{s}"),
+            Value::Blob(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+        }
+    }
+}
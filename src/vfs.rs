@@ -0,0 +1,236 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::rc::Rc;
+
+use crate::lock::{self, LockKind};
+use crate::mmap::MappedFile;
+use crate::page::{MyError, Result};
+
+/*
+    Every byte of a database, its journal, its log and its wal-index goes through a VFS, which
+    opens files by path and hands out handles to read, write, sync, lock and truncate them. The
+    b-tree code never sees a file, so databases can live anywhere a VFS can put them:
+        OsVfs       files of the operating system, locked with POSIX advisory locks
+        MemoryVfs   byte buffers, e.g. a database file loaded into memory
+    Reads and writes are positional, a handle has no cursor.
+*/
+pub trait Vfs: std::fmt::Debug {
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn VfsFile>>;
+    fn delete(&self, path: &str) -> Result<()>;
+
+    // The same for every path naming the file, None when there is no such file.
+    fn file_id(&self, _path: &str) -> Option<(u64, u64)> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpenMode {
+    ReadOnly,
+    ReadWrite,
+    // Read and write, creating the file when it does not exist.
+    Create,
+}
+
+pub trait VfsFile: std::fmt::Debug {
+    // Read from the given offset, returns fewer bytes than asked for at the end of the file.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize>;
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()>;
+    fn sync(&self) -> Result<()>;
+    // Set or clear a lock on a range of bytes, false when another connection holds a conflicting one.
+    fn lock(&self, kind: LockKind, start: u64, len: u64) -> Result<bool>;
+    fn truncate(&self, size: u64) -> Result<()>;
+    fn file_size(&self) -> Result<u64>;
+
+    // Files which cannot be mapped into memory are read the ordinary way.
+    fn map(&self, _len: usize) -> Result<Option<MappedFile>> {
+        Ok(None)
+    }
+
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read_at(&mut buf[read..], offset + read as u64)? {
+                0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct OsVfs;
+
+#[derive(Debug)]
+struct OsFile {
+    file: File,
+}
+
+impl Vfs for OsVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(mode != OpenMode::ReadOnly)
+            .create(mode == OpenMode::Create)
+            .truncate(false)
+            .open(path)?;
+        Ok(Box::new(OsFile { file }))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    // The device and inode, what POSIX locks belong to.
+    fn file_id(&self, path: &str) -> Option<(u64, u64)> {
+        fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+    }
+}
+
+impl VfsFile for OsFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        Ok(self.file.read_at(buf, offset)?)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        Ok(self.file.write_all_at(data, offset)?)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.file.sync_all()?)
+    }
+
+    fn lock(&self, kind: LockKind, start: u64, len: u64) -> Result<bool> {
+        lock::set_lock(&self.file, kind, start, len)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        Ok(self.file.set_len(size)?)
+    }
+
+    fn file_size(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn map(&self, len: usize) -> Result<Option<MappedFile>> {
+        MappedFile::map(&self.file, len)
+    }
+}
+
+/*
+    Files kept in memory, gone when the last handle to the VFS is dropped. Every connection
+    opened on the same MemoryVfs sees the same files. Locks are always granted: like the POSIX
+    locks of OsVfs, which never keep a process away from its own locks, they only matter
+    between processes, and no other process can see these files.
+*/
+#[derive(Debug, Default)]
+pub struct MemoryVfs {
+    files: RefCell<HashMap<String, Rc<RefCell<Vec<u8>>>>>,
+}
+
+#[derive(Debug)]
+struct MemoryFile {
+    data: Rc<RefCell<Vec<u8>>>,
+    writable: bool,
+}
+
+impl MemoryVfs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Put a file, such as a whole database image, into the VFS.
+    pub fn insert(&self, path: &str, data: Vec<u8>) {
+        self.files
+            .borrow_mut()
+            .insert(path.to_string(), Rc::new(RefCell::new(data)));
+    }
+
+    // The current content of a file, None when there is no such file.
+    #[cfg(test)]
+    pub fn contents(&self, path: &str) -> Option<Vec<u8>> {
+        self.files
+            .borrow()
+            .get(path)
+            .map(|data| data.borrow().clone())
+    }
+}
+
+impl Vfs for MemoryVfs {
+    fn open(&self, path: &str, mode: OpenMode) -> Result<Box<dyn VfsFile>> {
+        let mut files = self.files.borrow_mut();
+        let data = match files.get(path) {
+            Some(data) => Rc::clone(data),
+            None if mode == OpenMode::Create => {
+                let data = Rc::new(RefCell::new(Vec::new()));
+                files.insert(path.to_string(), Rc::clone(&data));
+                data
+            }
+            None => return Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        };
+        Ok(Box::new(MemoryFile {
+            data,
+            writable: mode != OpenMode::ReadOnly,
+        }))
+    }
+
+    fn delete(&self, path: &str) -> Result<()> {
+        match self.files.borrow_mut().remove(path) {
+            Some(_) => Ok(()),
+            None => Err(io::Error::from(io::ErrorKind::NotFound).into()),
+        }
+    }
+}
+
+impl MemoryFile {
+    fn check_writable(&self) -> Result<()> {
+        match self.writable {
+            true => Ok(()),
+            false => Err(MyError::ReadOnly),
+        }
+    }
+}
+
+impl VfsFile for MemoryFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<usize> {
+        let data = self.data.borrow();
+        let start = (offset as usize).min(data.len());
+        let len = buf.len().min(data.len() - start);
+        buf[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, data: &[u8], offset: u64) -> Result<()> {
+        self.check_writable()?;
+        let mut content = self.data.borrow_mut();
+        let end = offset as usize + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset as usize..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn lock(&self, _kind: LockKind, _start: u64, _len: u64) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        self.check_writable()?;
+        self.data.borrow_mut().resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn file_size(&self) -> Result<u64> {
+        Ok(self.data.borrow().len() as u64)
+    }
+}
@@ -0,0 +1,681 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::lock::{self, LockKind};
+use crate::page::{MyError, Result};
+use crate::shm::{
+    CHECKPOINT_LOCK, READ_MARK_NOT_USED, READERS, WRITE_LOCK, WalIndex, WalIndexHeader, read_lock,
+};
+use crate::utils;
+use crate::vfs::{OpenMode, Vfs, VfsFile};
+
+/*
+    Write-Ahead Log Format
+    The WAL file starts with a 32 bytes header followed by zero or more frames.
+
+    WAL Header
+        Offset  Size    Description
+        0       4       Magic number. 0x377f0682 or 0x377f0683
+        4       4       File format version. Currently 3007000.
+        8       4       Database page size. Example: 1024
+        12      4       Checkpoint sequence number
+        16      4       Salt-1: random integer incremented with each checkpoint
+        20      4       Salt-2: a different random number for each checkpoint
+        24      4       Checksum-1: First part of a checksum on the first 24 bytes of header
+        28      4       Checksum-2: Second part of the checksum on the first 24 bytes of header
+
+    WAL Frame Header
+        Offset  Size    Description
+        0       4       Page number
+        4       4       For commit records, the size of the database file in pages after the commit. For all other records, zero.
+        8       4       Salt-1 copied from the WAL header
+        12      4       Salt-2 copied from the WAL header
+        16      4       Checksum-1: Cumulative checksum up through and including this page
+        20      4       Checksum-2: Second half of the cumulative checksum.
+
+    A frame is valid if its salts match the header and its checksum, which covers the first 8
+    bytes of the frame header and the page content and is seeded with the checksum of the
+    previous frame, is correct. Only frames up to the last valid commit frame are used.
+
+    Which frames are committed is published in the shared wal-index (see shm.rs). A reader
+    holds one of the READ locks for as long as it uses a snapshot, and the read mark of that
+    lock tells checkpoints how many frames it can see, so they never overwrite a page it still
+    needs. READ(0) means the reader ignores the log, every frame was already checkpointed.
+*/
+#[derive(Debug)]
+pub struct Wal {
+    file: Box<dyn VfsFile>,
+    // None when the database is opened read only, the log is then read directly.
+    shm: Option<WalIndex>,
+    page_size: usize,
+    checkpoint_sequence: u32,
+    // The wal-index header of the current (or last) snapshot.
+    header: WalIndexHeader,
+    // The header the local index was built for.
+    snapshot: Option<WalIndexHeader>,
+    // The latest frame of every page in the snapshot.
+    index: HashMap<u32, u32>,
+    reading: bool,
+    read_lock: Option<usize>,
+    write_lock: bool,
+}
+
+/*
+    PASSIVE copies as many frames as possible without waiting on other connections, FULL waits
+    for writers and RESTART also for readers, so the next writer starts the log from the top.
+    TRUNCATE additionally truncates the log file to zero bytes.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CheckpointMode {
+    Passive,
+    Full,
+    Restart,
+    Truncate,
+}
+
+impl CheckpointMode {
+    // Unknown modes fall back to PASSIVE, like SQLite does.
+    pub fn from(name: &str) -> Self {
+        match name.to_ascii_lowercase().as_str() {
+            "full" => CheckpointMode::Full,
+            "restart" => CheckpointMode::Restart,
+            "truncate" => CheckpointMode::Truncate,
+            _ => CheckpointMode::Passive,
+        }
+    }
+}
+
+// Checksums are computed over pairs of 32-bit words, in the byte order chosen by the WAL magic.
+pub fn checksum(big_endian: bool, data: &[u8], seed: (u32, u32)) -> (u32, u32) {
+    let (mut s0, mut s1) = seed;
+    for words in data.as_chunks::<8>().0 {
+        let word = |i: usize| {
+            let bytes = [words[i], words[i + 1], words[i + 2], words[i + 3]];
+            if big_endian {
+                u32::from_be_bytes(bytes)
+            } else {
+                u32::from_le_bytes(bytes)
+            }
+        };
+        s0 = s0.wrapping_add(word(0)).wrapping_add(s1);
+        s1 = s1.wrapping_add(word(4)).wrapping_add(s0);
+    }
+    (s0, s1)
+}
+
+impl Wal {
+    const MAGIC: u32 = 0x377f0682;
+    const VERSION: u32 = 3007000;
+    const HEADER_SIZE: usize = 32;
+    const FRAME_HEADER_SIZE: usize = 24;
+
+    pub fn path_for(db_path: &str) -> String {
+        format!("{db_path}-wal")
+    }
+
+    pub fn open(vfs: &dyn Vfs, db_path: &str, page_size: usize, read_only: bool) -> Result<Self> {
+        let mode = match read_only {
+            true => OpenMode::ReadOnly,
+            false => OpenMode::Create,
+        };
+        let file = vfs.open(&Self::path_for(db_path), mode)?;
+        let shm = match read_only {
+            true => None,
+            false => Some(WalIndex::open(vfs, db_path)?),
+        };
+        Ok(Self {
+            file,
+            shm,
+            page_size,
+            checkpoint_sequence: 0,
+            header: WalIndexHeader::default(),
+            snapshot: None,
+            index: HashMap::new(),
+            reading: false,
+            read_lock: None,
+            write_lock: false,
+        })
+    }
+
+    fn frame_size(&self) -> usize {
+        Self::FRAME_HEADER_SIZE + self.page_size
+    }
+
+    fn frame_offset(&self, frame: u32) -> u64 {
+        (Self::HEADER_SIZE + (frame as usize - 1) * self.frame_size()) as u64
+    }
+
+    fn read_at(&self, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        let mut read = 0;
+        while read < len {
+            match self.file.read_at(&mut data[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        data.truncate(read);
+        Ok(data)
+    }
+
+    /*
+        Read the log up to its last valid commit, or only its first max_frame frames. Returns
+        the header describing it and the page number of every frame.
+    */
+    fn scan(&self, max_frame: Option<u32>) -> Result<(WalIndexHeader, Vec<u32>)> {
+        let mut header = WalIndexHeader {
+            page_size: self.page_size as u32,
+            ..Default::default()
+        };
+        let mut pages = Vec::new();
+        let len = match max_frame {
+            Some(frame) => self.frame_offset(frame + 1),
+            None => self.file.file_size()?,
+        };
+        let content = self.read_at(0, len as usize)?;
+        if content.len() < Self::HEADER_SIZE {
+            return Ok((header, pages));
+        }
+        let read_u32 =
+            |offset: usize| u32::from_be_bytes(content[offset..offset + 4].try_into().unwrap());
+        let magic = read_u32(0);
+        if magic & !1 != Self::MAGIC || read_u32(8) as usize != self.page_size {
+            return Ok((header, pages));
+        }
+        let big_endian = magic & 1 == 1;
+        let mut running = checksum(big_endian, &content[..24], (0, 0));
+        if running != (read_u32(24), read_u32(28)) {
+            return Ok((header, pages));
+        }
+        header.big_endian_checksum = big_endian;
+        header.salt = (read_u32(16), read_u32(20));
+        header.frame_checksum = running;
+
+        let mut pending = Vec::new();
+        let mut offset = Self::HEADER_SIZE;
+        while offset + self.frame_size() <= content.len() {
+            let frame_header = &content[offset..offset + Self::FRAME_HEADER_SIZE];
+            let page = &content[offset + Self::FRAME_HEADER_SIZE..offset + self.frame_size()];
+            if (read_u32(offset + 8), read_u32(offset + 12)) != header.salt {
+                break;
+            }
+            running = checksum(
+                big_endian,
+                page,
+                checksum(big_endian, &frame_header[..8], running),
+            );
+            if running != (read_u32(offset + 16), read_u32(offset + 20)) {
+                break;
+            }
+            pending.push(read_u32(offset));
+            let db_size = read_u32(offset + 4);
+            if db_size != 0 {
+                pages.append(&mut pending);
+                header.max_frame = pages.len() as u32;
+                header.page_count = db_size;
+                header.frame_checksum = running;
+            }
+            offset += self.frame_size();
+        }
+        Ok((header, pages))
+    }
+
+    // Build the local index of a snapshot, unless it is the one already loaded.
+    fn load_snapshot(&mut self, header: WalIndexHeader) -> Result<()> {
+        if self.snapshot != Some(header) {
+            let (_, pages) = self.scan(Some(header.max_frame))?;
+            self.index = pages
+                .iter()
+                .enumerate()
+                .map(|(i, page)| (*page, i as u32 + 1))
+                .collect();
+            self.snapshot = Some(header);
+        }
+        self.header = header;
+        Ok(())
+    }
+
+    /*
+        Rebuild the wal-index from the log, after a crash or when this is the first process to
+        open it. Holding the WRITE and RECOVER locks keeps everybody else out meanwhile.
+    */
+    fn recover(&self, shm: &WalIndex, holding_checkpoint: bool) -> Result<()> {
+        if !self.write_lock {
+            lock::retry(|| shm.lock(WRITE_LOCK, LockKind::Write))?;
+        }
+        let first = CHECKPOINT_LOCK + holding_checkpoint as u64;
+        let count = read_lock(0) - first;
+        let locked = lock::retry(|| shm.lock_range(first, count, LockKind::Write));
+        if locked.is_ok() && shm.read_header()?.is_none() {
+            let (header, pages) = self.scan(None)?;
+            shm.append(1, &pages)?;
+            shm.write_header(&header)?;
+            shm.set_backfilled(0)?;
+            shm.set_backfill_attempted(header.max_frame)?;
+            shm.set_read_mark(0, 0)?;
+            for i in 1..READERS {
+                if shm.lock(read_lock(i), LockKind::Write)? {
+                    let mark = match i {
+                        1 if header.max_frame > 0 => header.max_frame,
+                        _ => READ_MARK_NOT_USED,
+                    };
+                    shm.set_read_mark(i, mark)?;
+                    shm.lock(read_lock(i), LockKind::Unlock)?;
+                }
+            }
+        }
+        if locked.is_ok() {
+            shm.lock_range(first, count, LockKind::Unlock)?;
+        }
+        if !self.write_lock {
+            shm.lock(WRITE_LOCK, LockKind::Unlock)?;
+        }
+        locked
+    }
+
+    pub fn is_reading(&self) -> bool {
+        self.reading
+    }
+
+    // Start reading the latest committed snapshot and keep it until end_read.
+    pub fn begin_read(&mut self) -> Result<()> {
+        if self.reading {
+            return Ok(());
+        }
+        if self.shm.is_none() {
+            let (header, pages) = self.scan(None)?;
+            self.index = pages
+                .iter()
+                .enumerate()
+                .map(|(i, page)| (*page, i as u32 + 1))
+                .collect();
+            self.header = header;
+            self.snapshot = Some(header);
+        } else {
+            lock::retry(|| self.try_begin_read())?;
+        }
+        self.reading = true;
+        Ok(())
+    }
+
+    /*
+        Pick a read lock for the current header: READ(0) if every frame was checkpointed,
+        otherwise a lock whose read mark is the last frame, setting one if none matches. The
+        header is checked again once the lock is held, as a writer may have committed meanwhile.
+    */
+    fn try_begin_read(&mut self) -> Result<bool> {
+        let Some(shm) = self.shm.as_ref() else {
+            return Ok(true);
+        };
+        let Some(header) = shm.read_header()? else {
+            self.recover(shm, false)?;
+            return Ok(false);
+        };
+        if header.max_frame == shm.backfilled()? {
+            if !shm.lock(read_lock(0), LockKind::Read)? {
+                return Ok(false);
+            }
+            if shm.read_header()? != Some(header) {
+                shm.lock(read_lock(0), LockKind::Unlock)?;
+                return Ok(false);
+            }
+            self.read_lock = Some(0);
+            self.header = header;
+            self.snapshot = None;
+            self.index.clear();
+            return Ok(true);
+        }
+
+        let mut best: Option<(usize, u32)> = None;
+        for i in 1..READERS {
+            let mark = shm.read_mark(i)?;
+            if mark <= header.max_frame && best.is_none_or(|(_, m)| mark > m) {
+                best = Some((i, mark));
+            }
+        }
+        if best.is_none_or(|(_, m)| m < header.max_frame) {
+            for i in 1..READERS {
+                if shm.lock(read_lock(i), LockKind::Write)? {
+                    shm.set_read_mark(i, header.max_frame)?;
+                    shm.lock(read_lock(i), LockKind::Unlock)?;
+                    best = Some((i, header.max_frame));
+                    break;
+                }
+            }
+        }
+        let Some((slot, mark)) = best else {
+            return Ok(false);
+        };
+        if !shm.lock(read_lock(slot), LockKind::Read)? {
+            return Ok(false);
+        }
+        if shm.read_mark(slot)? != mark || shm.read_header()? != Some(header) {
+            shm.lock(read_lock(slot), LockKind::Unlock)?;
+            return Ok(false);
+        }
+        self.read_lock = Some(slot);
+        self.load_snapshot(header)?;
+        Ok(true)
+    }
+
+    pub fn end_read(&mut self) -> Result<()> {
+        self.end_write()?;
+        if let (Some(shm), Some(slot)) = (self.shm.as_ref(), self.read_lock.take()) {
+            shm.lock(read_lock(slot), LockKind::Unlock)?;
+        }
+        self.reading = false;
+        Ok(())
+    }
+
+    /*
+        Try once to take the WRITE lock. It is given back if another process committed since
+        our snapshot was taken, a write based on an old snapshot would lose that commit.
+    */
+    pub fn begin_write(&mut self) -> Result<bool> {
+        if self.write_lock {
+            return Ok(true);
+        }
+        let Some(shm) = self.shm.as_ref() else {
+            return Err(MyError::ReadOnly);
+        };
+        if !shm.lock(WRITE_LOCK, LockKind::Write)? {
+            return Ok(false);
+        }
+        if shm.read_header()? != Some(self.header) {
+            shm.lock(WRITE_LOCK, LockKind::Unlock)?;
+            return Ok(false);
+        }
+        self.write_lock = true;
+        Ok(true)
+    }
+
+    fn end_write(&mut self) -> Result<()> {
+        if let Some(shm) = self.shm.as_ref()
+            && self.write_lock
+        {
+            shm.lock(WRITE_LOCK, LockKind::Unlock)?;
+        }
+        self.write_lock = false;
+        Ok(())
+    }
+
+    pub fn salt(&self) -> (u32, u32) {
+        self.header.salt
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        self.header.max_frame
+    }
+
+    // The database size recorded by the last commit, if the snapshot uses the log.
+    pub fn db_size(&self) -> Option<u32> {
+        (!self.index.is_empty()).then_some(self.header.page_count)
+    }
+
+    pub fn read_page(&mut self, page_num: u32) -> Result<Option<Vec<u8>>> {
+        let Some(&frame) = self.index.get(&page_num) else {
+            return Ok(None);
+        };
+        let offset = self.frame_offset(frame) + Self::FRAME_HEADER_SIZE as u64;
+        let mut data = vec![0; self.page_size];
+        self.file.read_exact_at(&mut data, offset)?;
+        Ok(Some(data))
+    }
+
+    /*
+        Start the log over with new salts, so the frames left in the file become invalid.
+        Only done while no reader uses the log, which the caller makes sure of.
+    */
+    fn restart(&mut self, shm: &WalIndex, header: &mut WalIndexHeader) -> Result<()> {
+        let sequence = self.read_at(12, 4)?;
+        self.checkpoint_sequence = match sequence.try_into() {
+            Ok(bytes) => u32::from_be_bytes(bytes).wrapping_add(1),
+            Err(_) => 0,
+        };
+        header.max_frame = 0;
+        header.change = header.change.wrapping_add(1);
+        header.salt = (header.salt.0.wrapping_add(1), utils::random_u32());
+        shm.write_header(header)?;
+        shm.set_backfilled(0)?;
+        shm.set_backfill_attempted(0)?;
+        shm.set_read_mark(1, 0)?;
+        for i in 2..READERS {
+            shm.set_read_mark(i, READ_MARK_NOT_USED)?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&self, header: &mut WalIndexHeader) -> Result<()> {
+        if header.salt == (0, 0) {
+            header.salt = (utils::random_u32(), utils::random_u32());
+        }
+        header.big_endian_checksum = true;
+        let mut data = Vec::with_capacity(Self::HEADER_SIZE);
+        data.extend_from_slice(&(Self::MAGIC | 1).to_be_bytes());
+        data.extend_from_slice(&Self::VERSION.to_be_bytes());
+        data.extend_from_slice(&(self.page_size as u32).to_be_bytes());
+        data.extend_from_slice(&self.checkpoint_sequence.to_be_bytes());
+        data.extend_from_slice(&header.salt.0.to_be_bytes());
+        data.extend_from_slice(&header.salt.1.to_be_bytes());
+        header.frame_checksum = checksum(true, &data, (0, 0));
+        data.extend_from_slice(&header.frame_checksum.0.to_be_bytes());
+        data.extend_from_slice(&header.frame_checksum.1.to_be_bytes());
+        self.file.write_at(&data, 0)?;
+        Ok(())
+    }
+
+    /*
+        Append one frame per page, the last one marked as the commit, sync the log and publish
+        the commit in the wal-index. Requires the WRITE lock. A reader on READ(0) knows every
+        frame was checkpointed, so it restarts the log if no other reader is using it.
+    */
+    pub fn commit(&mut self, pages: &BTreeMap<u32, Vec<u8>>, db_size: u32) -> Result<()> {
+        let Some(shm) = self.shm.take() else {
+            return Err(MyError::ReadOnly);
+        };
+        let result = self.append(&shm, pages, db_size);
+        self.shm = Some(shm);
+        result
+    }
+
+    fn append(
+        &mut self,
+        shm: &WalIndex,
+        pages: &BTreeMap<u32, Vec<u8>>,
+        db_size: u32,
+    ) -> Result<()> {
+        let mut header = self.header;
+        if self.read_lock == Some(0)
+            && header.max_frame > 0
+            && shm.lock_range(read_lock(1), READERS as u64 - 1, LockKind::Write)?
+        {
+            let restarted = self.restart(shm, &mut header);
+            shm.lock_range(read_lock(1), READERS as u64 - 1, LockKind::Unlock)?;
+            restarted?;
+            self.index.clear();
+        }
+        if header.max_frame == 0 {
+            self.write_header(&mut header)?;
+        }
+        let big_endian = header.big_endian_checksum;
+        let mut frames = Vec::with_capacity(pages.len() * self.frame_size());
+        let mut running = header.frame_checksum;
+        for (i, (page_num, data)) in pages.iter().enumerate() {
+            let commit = if i + 1 == pages.len() { db_size } else { 0 };
+            let mut frame_header = Vec::with_capacity(Self::FRAME_HEADER_SIZE);
+            frame_header.extend_from_slice(&page_num.to_be_bytes());
+            frame_header.extend_from_slice(&commit.to_be_bytes());
+            frame_header.extend_from_slice(&header.salt.0.to_be_bytes());
+            frame_header.extend_from_slice(&header.salt.1.to_be_bytes());
+            running = checksum(
+                big_endian,
+                data,
+                checksum(big_endian, &frame_header[..8], running),
+            );
+            frame_header.extend_from_slice(&running.0.to_be_bytes());
+            frame_header.extend_from_slice(&running.1.to_be_bytes());
+            frames.extend_from_slice(&frame_header);
+            frames.extend_from_slice(data);
+        }
+        let first_frame = header.max_frame + 1;
+        self.file
+            .write_at(&frames, self.frame_offset(first_frame))?;
+        self.file.sync()?;
+
+        let page_nums: Vec<u32> = pages.keys().copied().collect();
+        shm.append(first_frame, &page_nums)?;
+        for (i, page_num) in page_nums.iter().enumerate() {
+            self.index.insert(*page_num, first_frame + i as u32);
+        }
+        header.max_frame += page_nums.len() as u32;
+        header.page_size = self.page_size as u32;
+        header.page_count = db_size;
+        header.frame_checksum = running;
+        header.change = header.change.wrapping_add(1);
+        shm.write_header(&header)?;
+        self.header = header;
+        self.snapshot = Some(header);
+        Ok(())
+    }
+
+    /*
+        Copy the latest version of every page back into the database file, but never beyond
+        the frames some reader may still need. Returns whether the checkpoint was blocked, the
+        number of frames in the log and the number of frames copied, as PRAGMA wal_checkpoint
+        reports. Must not be called while reading a snapshot.
+    */
+    pub fn checkpoint(
+        &mut self,
+        db_file: &dyn VfsFile,
+        mode: CheckpointMode,
+    ) -> Result<(bool, i64, i64)> {
+        let Some(shm) = self.shm.take() else {
+            return Err(MyError::ReadOnly);
+        };
+        let result = match shm.lock(CHECKPOINT_LOCK, LockKind::Write) {
+            Ok(true) => {
+                let result = self.backfill(&shm, db_file, mode);
+                shm.lock(CHECKPOINT_LOCK, LockKind::Unlock).and(result)
+            }
+            Ok(false) => Ok((true, -1, -1)),
+            Err(e) => Err(e),
+        };
+        self.shm = Some(shm);
+        result
+    }
+
+    fn backfill(
+        &mut self,
+        shm: &WalIndex,
+        db_file: &dyn VfsFile,
+        mode: CheckpointMode,
+    ) -> Result<(bool, i64, i64)> {
+        // FULL and above wait for the writer, and fall back to PASSIVE if it does not finish.
+        let mut mode = mode;
+        if mode != CheckpointMode::Passive {
+            match lock::retry(|| shm.lock(WRITE_LOCK, LockKind::Write)) {
+                Ok(()) => self.write_lock = true,
+                Err(MyError::Busy) => mode = CheckpointMode::Passive,
+                Err(e) => return Err(e),
+            }
+        }
+        let result = self.backfill_locked(shm, db_file, mode);
+        if self.write_lock {
+            shm.lock(WRITE_LOCK, LockKind::Unlock)?;
+            self.write_lock = false;
+        }
+        result
+    }
+
+    fn backfill_locked(
+        &mut self,
+        shm: &WalIndex,
+        db_file: &dyn VfsFile,
+        mode: CheckpointMode,
+    ) -> Result<(bool, i64, i64)> {
+        let wait = mode != CheckpointMode::Passive;
+        let take = |slot: u64, count: u64| -> Result<bool> {
+            match wait {
+                true => match lock::retry(|| shm.lock_range(slot, count, LockKind::Write)) {
+                    Ok(()) => Ok(true),
+                    Err(MyError::Busy) => Ok(false),
+                    Err(e) => Err(e),
+                },
+                false => shm.lock_range(slot, count, LockKind::Write),
+            }
+        };
+        let mut header = match shm.read_header()? {
+            Some(header) => header,
+            None => {
+                self.recover(shm, true)?;
+                shm.read_header()?.unwrap_or_default()
+            }
+        };
+
+        // Frames past the read mark of a busy reader are part of a snapshot it does not see.
+        let mut safe_frame = header.max_frame;
+        for i in 1..READERS {
+            let mark = shm.read_mark(i)?;
+            if safe_frame > mark {
+                if take(read_lock(i), 1)? {
+                    let mark = if i == 1 {
+                        safe_frame
+                    } else {
+                        READ_MARK_NOT_USED
+                    };
+                    shm.set_read_mark(i, mark)?;
+                    shm.lock(read_lock(i), LockKind::Unlock)?;
+                } else {
+                    safe_frame = mark;
+                }
+            }
+        }
+
+        let backfilled = shm.backfilled()?;
+        if backfilled < safe_frame && take(read_lock(0), 1)? {
+            self.file.sync()?;
+            let mut latest = BTreeMap::new();
+            for frame in backfilled + 1..=safe_frame {
+                let page = self.read_at(self.frame_offset(frame), 4)?;
+                if let Ok(bytes) = page.try_into() {
+                    latest.insert(u32::from_be_bytes(bytes), frame);
+                }
+            }
+            let page_size = self.page_size as u64;
+            let mut data = vec![0; self.page_size];
+            for (page_num, frame) in latest {
+                if page_num > header.page_count {
+                    continue;
+                }
+                let offset = self.frame_offset(frame) + Self::FRAME_HEADER_SIZE as u64;
+                self.file.read_exact_at(&mut data, offset)?;
+                db_file.write_at(&data, (page_num as u64 - 1) * page_size)?;
+            }
+            if safe_frame == header.max_frame {
+                db_file.truncate(header.page_count as u64 * page_size)?;
+            }
+            db_file.sync()?;
+            shm.set_backfilled(safe_frame)?;
+            shm.set_backfill_attempted(safe_frame)?;
+            shm.lock(read_lock(0), LockKind::Unlock)?;
+        }
+
+        let mut busy = false;
+        if wait {
+            if shm.backfilled()? < header.max_frame {
+                busy = true;
+            } else if matches!(mode, CheckpointMode::Restart | CheckpointMode::Truncate) {
+                if take(read_lock(1), READERS as u64 - 1)? {
+                    if mode == CheckpointMode::Truncate {
+                        self.restart(shm, &mut header)?;
+                        self.file.truncate(0)?;
+                        self.file.sync()?;
+                    }
+                    shm.lock_range(read_lock(1), READERS as u64 - 1, LockKind::Unlock)?;
+                } else {
+                    busy = true;
+                }
+            }
+        }
+        Ok((busy, header.max_frame as i64, shm.backfilled()? as i64))
+    }
+}
@@ -1,9 +1,29 @@
 use assert_cmd::{Command, cargo::CommandCargoExt};
-use std::process::{Child, Command as StdCommand};
+use predicates::prelude::*;
+use std::io::{Read, Write};
+use std::process::Command as StdCommand;
+
+// A whole line of the output, so that "2" does not match "12".
+fn line(text: &str) -> impl Predicate<str> {
+    let text = text.to_string();
+    predicate::function(move |output: &str| output.lines().any(|line| line == text))
+}
+
+// A checked-in database, by absolute path so no test depends on the directory it runs in.
+fn fixture(name: &str) -> String {
+    format!("{}/{name}", env!("CARGO_MANIFEST_DIR"))
+}
+
+// Write tests work on a private copy so the checked-in databases stay untouched.
+fn copy_database(source: &str, name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("rqlite_{}_{name}.db", std::process::id()));
+    std::fs::copy(fixture(source), &path).unwrap();
+    path.to_str().unwrap().to_string()
+}
 
 #[test]
 fn test_sample_db_info() {
-    let mut cmd = Command::cargo_bin("RQLite").unwrap();
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
     cmd.arg("sample.db")
         .arg("db-info")
         .assert()
@@ -14,7 +34,7 @@ fn test_sample_db_info() {
 
 #[test]
 fn test_sample_table() {
-    let mut cmd = Command::cargo_bin("RQLite").unwrap();
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
     cmd.arg("sample.db")
         .arg("tables")
         .assert()
@@ -26,11 +46,1184 @@ fn test_sample_table() {
 
 #[test]
 fn test_web() {
-    let mut cmd = StdCommand::cargo_bin("RQLite")
+    let mut cmd = StdCommand::cargo_bin("RQlite")
         .unwrap()
         .arg("sample.db")
         .arg("web")
         .spawn()
         .unwrap();
     cmd.kill().unwrap();
+    cmd.wait().unwrap();
+}
+
+#[test]
+fn test_web_query() {
+    let db_path = copy_database("sample.db", "web_query");
+    let mut cmd = StdCommand::cargo_bin("RQlite")
+        .unwrap()
+        .arg(&db_path)
+        .arg("web")
+        .arg("--port")
+        .arg("4222")
+        .spawn()
+        .unwrap();
+    let post = |sql: &str| {
+        let mut stream = (0..50)
+            .find_map(|_| {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                std::net::TcpStream::connect("127.0.0.1:4222").ok()
+            })
+            .unwrap();
+        write!(
+            stream,
+            "POST /query HTTP/1.1\r\nContent-Length: {}\r\n\r\n{sql}",
+            sql.len()
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = post("SELEC * FROM apples");
+    assert!(response.starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(response.contains(r#""line":1"#));
+    assert!(response.contains(r#""column":1"#));
+    assert!(response.contains(r#""fragment":"SELEC""#));
+    assert!(post("SELECT * FROM nothing").starts_with("HTTP/1.1 400 Bad Request"));
+    assert!(post("SELECT * FROM apples").ends_with(r#"{"ok":true}"#));
+
+    // A reader in another process holds SHARED, so the write cannot get its lock.
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&db_path)
+        .unwrap();
+    lock_bytes(&file, libc::F_RDLCK, 0x4000_0002, 510);
+    let response = post("DELETE FROM apples");
+    assert!(response.starts_with("HTTP/1.1 503 Service Unavailable"));
+    assert!(response.contains("database is locked"));
+    lock_bytes(&file, libc::F_UNLCK, 0, 0);
+
+    // A page that is not a b-tree page is a fault of the database, not of the request. The
+    // change counter tells the server its cached pages are stale.
+    let mut bytes = std::fs::read(&db_path).unwrap();
+    bytes[27] = bytes[27].wrapping_add(1);
+    bytes[4096] = 0;
+    std::fs::write(&db_path, bytes).unwrap();
+    let response = post("SELECT * FROM apples");
+    assert!(
+        response.starts_with("HTTP/1.1 500 Internal Server Error"),
+        "{response}"
+    );
+    cmd.kill().unwrap();
+    cmd.wait().unwrap();
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_update() {
+    let db_path = copy_database("sample.db", "update");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("UPDATE apples SET color = 'Green', name = name || '!' WHERE id >= 3")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT * FROM apples")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("2|Fuji|Red"))
+        .stdout(predicates::str::contains("3|Honeycrisp!|Green"))
+        .stdout(predicates::str::contains("4|Golden Delicious!|Green"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_delete() {
+    let db_path = copy_database("sample.db", "delete");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("DELETE FROM oranges WHERE id = 2 OR name = 'Valencia Orange'")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT name FROM oranges")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Mandarin"))
+        .stdout(predicates::str::contains("Tangelo").not())
+        .stdout(predicates::str::contains("Valencia").not());
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_remainder_overflow() {
+    // The one remainder that overflows an integer is 0, like in SQLite, not a crash.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(fixture("sample.db"))
+        .arg("run")
+        .arg("SELECT (-9223372036854775807 - 1) % -1")
+        .assert()
+        .success()
+        .stdout(predicates::str::diff("0\n"));
+}
+
+#[test]
+fn test_header_database_size() {
+    let db_path = copy_database("sample.db", "header_size");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert().success()
+    };
+
+    // Older writers leave the size zero, or stale behind a version-valid-for number which no
+    // longer matches the change counter. Either way the size of the file counts.
+    let original = std::fs::read(&db_path).unwrap();
+    for size in [0, 2] {
+        let mut bytes = original.clone();
+        bytes[28..32].copy_from_slice(&u32::to_be_bytes(size));
+        if size != 0 {
+            bytes[92..96].fill(0);
+        }
+        std::fs::write(&db_path, &bytes).unwrap();
+        run("INSERT INTO apples (name, color) VALUES ('Gala', 'Red')");
+        run("SELECT name FROM apples WHERE id = 5").stdout(predicates::str::diff("Gala\n"));
+        run("PRAGMA integrity_check").stdout(predicates::str::diff("ok\n"));
+    }
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_delete_rebalances_tree() {
+    let db_path = copy_database("superheroes.db", "rebalance");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("DELETE FROM superheroes WHERE id % 10 <> 0")
+        .assert()
+        .success();
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM superheroes")
+        .assert()
+        .success()
+        .stdout(line("689"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_transaction_rollback() {
+    let db_path = copy_database("sample.db", "rollback");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("BEGIN; DELETE FROM apples; SELECT COUNT(*) FROM apples; ROLLBACK; SELECT COUNT(*) FROM apples")
+        .assert()
+        .success()
+        .stdout(predicates::str::diff("0\n4\n"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_savepoint() {
+    let db_path = copy_database("sample.db", "savepoint");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SAVEPOINT a; DELETE FROM apples WHERE id = 1; SAVEPOINT b; DELETE FROM apples; ROLLBACK TO b; RELEASE a")
+        .assert()
+        .success();
+    assert!(!std::path::Path::new(&format!("{db_path}-journal")).exists());
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM apples")
+        .assert()
+        .success()
+        .stdout(line("3"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+// A crash after the database was partly written leaves a hot journal holding the original pages.
+#[test]
+fn test_hot_journal_recovery() {
+    let db_path = copy_database("sample.db", "hot_journal");
+    let mut db = std::fs::read(&db_path).unwrap();
+    let original_page = db[4096..8192].to_vec();
+
+    let nonce: u32 = 7;
+    let mut journal = vec![0; 512];
+    journal[..8].copy_from_slice(&[0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7]);
+    journal[8..12].copy_from_slice(&1u32.to_be_bytes());
+    journal[12..16].copy_from_slice(&nonce.to_be_bytes());
+    journal[16..20].copy_from_slice(&4u32.to_be_bytes());
+    journal[20..24].copy_from_slice(&512u32.to_be_bytes());
+    journal[24..28].copy_from_slice(&4096u32.to_be_bytes());
+    let checksum = (1..=20).fold(nonce, |sum, i| sum + original_page[4096 - 200 * i] as u32);
+    journal.extend_from_slice(&2u32.to_be_bytes());
+    journal.extend_from_slice(&original_page);
+    journal.extend_from_slice(&checksum.to_be_bytes());
+    std::fs::write(format!("{db_path}-journal"), journal).unwrap();
+
+    db[4096..8192].fill(0);
+    db.extend_from_slice(&[0; 4096]);
+    std::fs::write(&db_path, db).unwrap();
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT name FROM apples WHERE id = 2")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("Fuji"));
+    assert!(!std::path::Path::new(&format!("{db_path}-journal")).exists());
+    assert_eq!(std::fs::metadata(&db_path).unwrap().len(), 4 * 4096);
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_wal_mode() {
+    let db_path = copy_database("sample.db", "wal");
+    let mut db = std::fs::read(&db_path).unwrap();
+    // File format version numbers of 2 put the database in WAL mode.
+    db[18] = 2;
+    db[19] = 2;
+    std::fs::write(&db_path, &db).unwrap();
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("UPDATE apples SET name = 'Gala' WHERE id = 2; DELETE FROM oranges WHERE id > 3")
+        .assert()
+        .success();
+    assert_eq!(std::fs::read(&db_path).unwrap(), db);
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT name FROM apples WHERE id = 2; SELECT COUNT(*) FROM oranges; PRAGMA wal_checkpoint(TRUNCATE)")
+        .assert()
+        .success()
+        .stdout(line("Gala"))
+        .stdout(line("3"))
+        .stdout(predicates::str::ends_with("0|0|0\n"));
+    assert_eq!(
+        std::fs::metadata(format!("{db_path}-wal")).unwrap().len(),
+        0
+    );
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT name FROM apples WHERE id = 2")
+        .assert()
+        .success()
+        .stdout(line("Gala"));
+    std::fs::remove_file(format!("{db_path}-wal")).unwrap();
+    std::fs::remove_file(format!("{db_path}-shm")).unwrap();
+    std::fs::remove_file(db_path).unwrap();
+}
+
+// Take an fcntl lock on a range of bytes of the file, as another SQLite process would.
+fn lock_bytes(file: &std::fs::File, kind: libc::c_int, start: i64, len: i64) {
+    use std::os::fd::AsRawFd;
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = kind as libc::c_short;
+    flock.l_whence = libc::SEEK_SET as libc::c_short;
+    flock.l_start = start;
+    flock.l_len = len;
+    assert_eq!(
+        unsafe { libc::fcntl(file.as_raw_fd(), libc::F_SETLK, &flock) },
+        0
+    );
+}
+
+#[test]
+fn test_locked_database() {
+    let db_path = copy_database("sample.db", "locked");
+    let original = std::fs::read(&db_path).unwrap();
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&db_path)
+        .unwrap();
+    // A reader in another process holds SHARED, a writer has to wait for it to finish.
+    lock_bytes(&file, libc::F_RDLCK, 0x4000_0002, 510);
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("UPDATE apples SET name = 'Gala' WHERE id = 2")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("database is locked"));
+    assert_eq!(std::fs::read(&db_path).unwrap(), original);
+    assert!(!std::path::Path::new(&format!("{db_path}-journal")).exists());
+
+    // Readers share the database, even with a writer holding RESERVED.
+    lock_bytes(&file, libc::F_WRLCK, 0x4000_0001, 1);
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT name FROM apples WHERE id = 2")
+        .assert()
+        .success()
+        .stdout(line("Fuji"));
+
+    // The writer gives up its locks while the next one waits for them.
+    let writer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        lock_bytes(&file, libc::F_UNLCK, 0, 0);
+    });
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("UPDATE apples SET name = 'Gala' WHERE id = 2; SELECT name FROM apples WHERE id = 2")
+        .assert()
+        .success()
+        .stdout(line("Gala"));
+    writer.join().unwrap();
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_create_and_drop_index() {
+    let db_path = copy_database("sample.db", "index");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+    let schema_cookie = || {
+        let bytes = std::fs::read(&db_path).unwrap();
+        u32::from_be_bytes(bytes[40..44].try_into().unwrap())
+    };
+    let cookie = schema_cookie();
+
+    run("CREATE UNIQUE INDEX IF NOT EXISTS idx_name ON apples (name)").success();
+    run("CREATE UNIQUE INDEX IF NOT EXISTS idx_name ON apples (name)").success();
+    run("CREATE INDEX idx_name ON apples (color)")
+        .failure()
+        .stderr(predicates::str::contains("index idx_name already exists"));
+    run("UPDATE apples SET color = 'Red' WHERE id = 3").success();
+    run("CREATE UNIQUE INDEX idx_color ON apples (color)")
+        .failure()
+        .stderr(predicates::str::contains(
+            "UNIQUE constraint failed: apples.color",
+        ));
+    run("SELECT name, tbl_name, sql FROM sqlite_schema WHERE type = 'index'")
+        .success()
+        .stdout(predicates::str::contains(
+            "idx_name|apples|CREATE UNIQUE INDEX idx_name ON apples (name)",
+        ));
+    assert_eq!(schema_cookie(), cookie + 1);
+
+    run("DROP INDEX idx_name").success();
+    run("DROP INDEX idx_name")
+        .failure()
+        .stderr(predicates::str::contains("no such index: idx_name"));
+    run("DROP TABLE oranges").success();
+    run("DROP TABLE IF EXISTS oranges").success();
+    run("SELECT name FROM sqlite_schema")
+        .success()
+        .stdout(predicates::str::contains("apples"))
+        .stdout(predicates::str::contains("oranges").not());
+    assert_eq!(schema_cookie(), cookie + 3);
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_alter_table() {
+    let db_path = copy_database("sample.db", "alter");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+
+    run("ALTER TABLE apples RENAME TO fruits").success();
+    run("ALTER TABLE fruits RENAME COLUMN color TO colour").success();
+    run("ALTER TABLE fruits ADD COLUMN price REAL DEFAULT 2").success();
+    run("SELECT id, colour, price FROM fruits")
+        .success()
+        .stdout(predicates::str::contains("2|Red|2.0"));
+    run("ALTER TABLE fruits DROP COLUMN name").success();
+    run("SELECT * FROM fruits")
+        .success()
+        .stdout(predicates::str::contains("4|Yellow|2.0"));
+    run("SELECT sql FROM sqlite_schema WHERE name = 'fruits'")
+        .success()
+        .stdout(predicates::str::contains(
+            "colour text, price REAL DEFAULT 2",
+        ));
+    run("SELECT name FROM sqlite_sequence")
+        .success()
+        .stdout(predicates::str::contains("fruits"));
+    run("ALTER TABLE fruits DROP COLUMN id")
+        .failure()
+        .stderr(predicates::str::contains(
+            "cannot drop PRIMARY KEY column: \"id\"",
+        ));
+    run("ALTER TABLE fruits ADD COLUMN stock NOT NULL")
+        .failure()
+        .stderr(predicates::str::contains(
+            "Cannot add a NOT NULL column with default value NULL",
+        ));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_vacuum() {
+    let db_path = copy_database("sample.db", "vacuum");
+    let copy_path = db_path.replace(".db", "_copy.db");
+    let run = |path: &str, sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(path).arg("run").arg(sql).assert()
+    };
+    let header_u32 = |path: &str, offset: usize| {
+        let bytes = std::fs::read(path).unwrap();
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    };
+
+    run(&db_path, "DROP TABLE oranges").success();
+    assert_eq!(header_u32(&db_path, 28), 4);
+    assert_eq!(header_u32(&db_path, 36), 1);
+
+    run(&db_path, &format!("VACUUM INTO '{copy_path}'")).success();
+    run(&db_path, &format!("VACUUM INTO '{copy_path}'"))
+        .failure()
+        .stderr(predicates::str::contains("output file already exists"));
+    run(&copy_path, "SELECT name FROM apples")
+        .success()
+        .stdout(predicates::str::contains("Golden Delicious"));
+    assert_eq!(header_u32(&copy_path, 28), 3);
+
+    run(&db_path, "VACUUM").success();
+    assert_eq!(std::fs::metadata(&db_path).unwrap().len(), 3 * 4096);
+    assert_eq!(header_u32(&db_path, 36), 0);
+    run(&db_path, "SELECT name FROM apples")
+        .success()
+        .stdout(predicates::str::contains("Golden Delicious"));
+    std::fs::remove_file(db_path).unwrap();
+    std::fs::remove_file(copy_path).unwrap();
+}
+
+#[test]
+fn test_incremental_vacuum() {
+    let db_path = copy_database("sample.db", "incremental_vacuum");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+    let header_u32 = |offset: usize| {
+        let bytes = std::fs::read(&db_path).unwrap();
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    };
+
+    // Auto-vacuum can only be turned on by a VACUUM, which adds the pointer-map page 2.
+    run("PRAGMA auto_vacuum = INCREMENTAL; VACUUM; PRAGMA auto_vacuum")
+        .success()
+        .stdout(predicates::str::ends_with("2\n"));
+    assert_eq!(header_u32(28), 5);
+    assert_eq!(header_u32(52), 5);
+    assert_eq!(header_u32(64), 1);
+
+    // The dropped root is filled with the largest one, the freed page stays until asked for.
+    run("DROP TABLE oranges").success();
+    assert_eq!(header_u32(28), 5);
+    assert_eq!(header_u32(36), 1);
+    assert_eq!(header_u32(52), 4);
+
+    run("PRAGMA incremental_vacuum(10)").success();
+    assert_eq!(header_u32(28), 4);
+    assert_eq!(header_u32(36), 0);
+    assert_eq!(std::fs::metadata(&db_path).unwrap().len(), 4 * 4096);
+    run("SELECT name FROM apples")
+        .success()
+        .stdout(predicates::str::contains("Golden Delicious"));
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+// Dropping the index of a table moves sqlite_sequence, the largest root, into its page.
+#[test]
+fn test_drop_table_moves_sequence() {
+    let db_path = copy_database("sample.db", "drop_sequence");
+    let other_path = db_path.replace("drop_sequence", "drop_sequence_av");
+    let run = |path: &str, sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(path).arg("run").arg(sql).assert()
+    };
+
+    run(&db_path, &format!("ATTACH '{other_path}' AS aux; CREATE TABLE aux.t(a UNIQUE); CREATE TABLE aux.s(id INTEGER PRIMARY KEY AUTOINCREMENT, b); INSERT INTO aux.s(b) VALUES (1)")).success();
+    run(&other_path, "PRAGMA auto_vacuum = FULL; VACUUM; SELECT rootpage FROM sqlite_schema WHERE name = 'sqlite_sequence'")
+        .success()
+        .stdout(predicates::str::diff("6\n"));
+    run(
+        &other_path,
+        "DROP TABLE t; SELECT * FROM sqlite_sequence; PRAGMA integrity_check",
+    )
+    .success()
+    .stdout(predicates::str::diff("s|1\nok\n"));
+    std::fs::remove_file(&other_path).unwrap();
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_pragmas() {
+    let db_path = copy_database("sample.db", "pragmas");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+
+    run("PRAGMA table_info(apples)")
+        .success()
+        .stdout(predicates::str::contains(
+            "0|id|INTEGER|0||1\n1|name|TEXT|0||0\n",
+        ));
+    run("PRAGMA page_size; PRAGMA page_count; PRAGMA encoding")
+        .success()
+        .stdout(predicates::str::contains("4096\n"))
+        .stdout(predicates::str::contains("UTF-8\n"));
+
+    run("CREATE UNIQUE INDEX apples_name ON apples (name)").success();
+    run("PRAGMA index_list(apples); PRAGMA index_info(apples_name)")
+        .success()
+        .stdout(predicates::str::contains("0|apples_name|1|c|0\n"))
+        .stdout(predicates::str::contains("0|1|name\n"));
+
+    run("PRAGMA user_version = 42").success();
+    run("PRAGMA user_version")
+        .success()
+        .stdout(predicates::str::ends_with("42\n"));
+    let bytes = std::fs::read(&db_path).unwrap();
+    assert_eq!(bytes[60..64], 42u32.to_be_bytes());
+
+    run("PRAGMA integrity_check")
+        .success()
+        .stdout(predicates::str::ends_with("ok\n"));
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_integrity_check() {
+    let db_path = copy_database("sample.db", "integrity_check");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+
+    run("CREATE INDEX apples_color ON apples (color)").success();
+    run("PRAGMA integrity_check")
+        .success()
+        .stdout(predicates::str::ends_with("ok\n"));
+
+    // A reserved serial type in the first index entry, Blush Red of the third row. The entry
+    // can't match any row, nor can it stand in for one.
+    let output = run("SELECT rootpage FROM sqlite_schema WHERE name = 'apples_color'")
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+    let root: usize = String::from_utf8(output).unwrap().trim().parse().unwrap();
+    let original = std::fs::read(&db_path).unwrap();
+    let mut bytes = original.clone();
+    let page = (root - 1) * 4096;
+    let cell = u16::from_be_bytes([bytes[page + 8], bytes[page + 9]]) as usize;
+    bytes[page + cell + 2] = 10;
+    std::fs::write(&db_path, bytes).unwrap();
+    run("PRAGMA integrity_check")
+        .success()
+        .stdout(predicates::str::diff(
+            "malformed entry 1 in index apples_color\nrow 3 missing from index apples_color\n",
+        ));
+
+    // The first two entries swapped, every row still has its entry.
+    let mut bytes = original.clone();
+    bytes[page + 8..page + 12].rotate_left(2);
+    std::fs::write(&db_path, bytes).unwrap();
+    run("PRAGMA integrity_check")
+        .success()
+        .stdout(predicates::str::diff(
+            "index apples_color is out of order\n",
+        ));
+    std::fs::write(&db_path, original).unwrap();
+
+    // Claim fragmented bytes on the first page of the apples table.
+    let mut bytes = std::fs::read(&db_path).unwrap();
+    bytes[4096 + 7] = 3;
+    std::fs::write(&db_path, bytes).unwrap();
+    run("PRAGMA quick_check")
+        .success()
+        .stdout(predicates::str::contains("*** in database main ***\n"))
+        .stdout(predicates::str::contains(
+            "Fragmentation of 0 bytes reported as 3 on page 2\n",
+        ));
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_errors() {
+    let db_path = copy_database("sample.db", "errors");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert()
+    };
+
+    run("SELEC * FROM apples")
+        .failure()
+        .stderr(predicates::str::contains("near \"SELEC\": syntax error"))
+        .stderr(predicates::str::contains("panicked").not());
+    run("SELECT * FORM apples")
+        .failure()
+        .stderr(predicates::str::contains("at line 1, column 10"))
+        .stderr(predicates::str::contains("^^^^ expected FROM"));
+    // Valid SQL this parser does not support fails where it stops, expecting all that could
+    // have come there, including what optional clauses looked for.
+    run("SELECT sum(b) FROM apples")
+        .failure()
+        .stderr(predicates::str::contains("at line 1, column 11"))
+        .stderr(predicates::str::contains(
+            r#"^ expected ".", operator, ",", WHERE or ";""#,
+        ));
+    run("SELECT name FROM apples ORDER BY name")
+        .failure()
+        .stderr(predicates::str::contains(
+            r#"^^^^^ expected ".", WHERE or ";""#,
+        ));
+
+    // A freeblock over the cell pointer array, more fragmented bytes than SQLite allows, or a
+    // cell content area at 0, which stands for 65536 and is past the end of the page.
+    let original = std::fs::read(&db_path).unwrap();
+    for (offset, value) in [(4096 + 2, &[8][..]), (4096 + 7, &[61]), (4096 + 5, &[0, 0])] {
+        let mut bytes = original.clone();
+        bytes[offset..offset + value.len()].copy_from_slice(value);
+        std::fs::write(&db_path, &bytes).unwrap();
+        for sql in [
+            "SELECT * FROM apples",
+            "INSERT INTO apples (name) VALUES ('Gala')",
+        ] {
+            run(sql).failure().stderr(predicates::str::contains(
+                "database disk image is malformed (page 2)",
+            ));
+        }
+    }
+    std::fs::write(&db_path, &original).unwrap();
+
+    // A header claiming more pages than the file has, as many as a page number can count.
+    let mut bytes = original.clone();
+    bytes[28..32].copy_from_slice(&u32::MAX.to_be_bytes());
+    std::fs::write(&db_path, &bytes).unwrap();
+    for sql in ["INSERT INTO apples (name) VALUES ('Gala')", "VACUUM"] {
+        run(sql)
+            .failure()
+            .stderr(predicates::str::contains(
+                "database disk image is malformed",
+            ))
+            .stderr(predicates::str::contains("panicked").not());
+    }
+    std::fs::write(&db_path, &original).unwrap();
+
+    // Serial type 10 is reserved, a record using it is corrupt.
+    let mut bytes = std::fs::read(&db_path).unwrap();
+    let cell = u16::from_be_bytes([bytes[4096 + 8], bytes[4096 + 9]]) as usize;
+    bytes[4096 + cell + 3] = 10;
+    std::fs::write(&db_path, &bytes).unwrap();
+    run("SELECT * FROM apples")
+        .failure()
+        .stderr(predicates::str::contains(
+            "database disk image is malformed",
+        ))
+        .stderr(predicates::str::contains("panicked").not());
+
+    std::fs::write(&db_path, b"not a database").unwrap();
+    run("SELECT * FROM apples")
+        .failure()
+        .stderr(predicates::str::contains("file is not a database"));
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_utf16() {
+    let db_path = copy_database("tests/fixtures/utf16.db", "utf16");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert().success()
+    };
+
+    run("PRAGMA encoding").stdout(predicates::str::contains("UTF-16le"));
+    run("SELECT word FROM words WHERE id = 3").stdout(predicates::str::contains("日本語"));
+    run("UPDATE words SET word = word || '✓' WHERE id = 4");
+    run("SELECT word FROM words WHERE id = 4").stdout(predicates::str::contains("😀✓"));
+    // The UNIQUE index sorts by the UTF-16le bytes, where "āb" comes before "apple".
+    run("PRAGMA integrity_check").stdout(predicates::str::contains("ok"));
+    run("SELECT word FROM words WHERE word < 'apple'").stdout(predicates::str::contains("āb"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_reserved_bytes() {
+    // 32 bytes at the end of every page are reserved, leaving 992 of each 1024 byte page.
+    let db_path = copy_database("tests/fixtures/reserved.db", "reserved");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert().success()
+    };
+
+    run("SELECT COUNT(*) FROM notes").stdout(predicates::str::contains("20"));
+    run("UPDATE notes SET body = body || body WHERE id > 10");
+    run("DELETE FROM notes WHERE id % 4 = 0");
+    run("SELECT body FROM notes WHERE id = 19")
+        .stdout(predicates::str::contains("note 19").count(2));
+    run("PRAGMA integrity_check").stdout(predicates::str::contains("ok"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_cache_size() {
+    let db_path = copy_database("superheroes.db", "cache_size");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path).arg("run").arg(sql).assert().success()
+    };
+
+    run("PRAGMA cache_size").stdout(predicates::str::contains("-2000"));
+    // A cache of two pages is evicting all the time, the results stay the same.
+    run("PRAGMA cache_size = 2; PRAGMA cache_size; DELETE FROM superheroes WHERE id % 3 = 0; SELECT COUNT(*) FROM superheroes")
+        .stdout(line("2"))
+        .stdout(line("4597"));
+    run("PRAGMA default_cache_size = -300");
+    run("PRAGMA cache_size").stdout(predicates::str::contains("300"));
+    run("PRAGMA integrity_check").stdout(predicates::str::contains("ok"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_mmap() {
+    let db_path = copy_database("superheroes.db", "mmap");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path)
+            .arg("--mmap-size")
+            .arg("268435456")
+            .arg("run")
+            .arg(sql)
+            .assert()
+    };
+
+    run("PRAGMA mmap_size; SELECT COUNT(*) FROM superheroes")
+        .success()
+        .stdout(predicates::str::contains("268435456"))
+        .stdout(predicates::str::contains("6895"));
+    run("PRAGMA mmap_size = 0; PRAGMA mmap_size = 65536; DELETE FROM superheroes WHERE id > 100; VACUUM")
+        .success();
+    run("SELECT COUNT(*) FROM superheroes; PRAGMA integrity_check")
+        .success()
+        .stdout(line("100"))
+        .stdout(line("ok"));
+
+    // Pages the file is too short to hold are not in the map either, they read as corrupt.
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .open(&db_path)
+        .unwrap();
+    file.set_len(4096 * 3).unwrap();
+    run("SELECT COUNT(*) FROM superheroes")
+        .failure()
+        .stderr(predicates::str::contains(
+            "database disk image is malformed",
+        ));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_projection() {
+    // Only the selected columns and those of the condition are decoded.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(fixture("sample.db"))
+        .arg("run")
+        .arg("SELECT id, name FROM apples WHERE color = 'Red'")
+        .assert()
+        .success()
+        .stdout(line("2|Fuji"))
+        .stdout(predicates::str::contains("Blush").not());
+
+    let db_path = copy_database("sample.db", "projection");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("ALTER TABLE apples ADD COLUMN stock DEFAULT 7; SELECT stock, id FROM apples WHERE id = 3")
+        .assert()
+        .success()
+        .stdout(line("7|3"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_deserialize() {
+    // The database is read into memory, its journal and log live there too, the file is left alone.
+    let db_path = copy_database("superheroes.db", "deserialize");
+    let original = std::fs::read(&db_path).unwrap();
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("--deserialize")
+        .arg("run")
+        .arg("DELETE FROM superheroes WHERE id > 10; PRAGMA journal_mode = WAL; DELETE FROM superheroes WHERE id > 5; SELECT COUNT(*) FROM superheroes; PRAGMA integrity_check")
+        .assert()
+        .success()
+        .stdout(line("wal"))
+        .stdout(line("5"))
+        .stdout(line("ok"));
+    assert_eq!(std::fs::read(&db_path).unwrap(), original);
+    assert!(!std::path::Path::new(&format!("{db_path}-wal")).exists());
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_attach() {
+    let db_path = copy_database("superheroes.db", "attach_main");
+    let other_path = copy_database("sample.db", "attach_other");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg(format!(
+            "ATTACH DATABASE '{other_path}' AS other; ATTACH ':memory:' AS scratch; PRAGMA database_list; DELETE FROM other.apples WHERE id > 2; SELECT COUNT(*) FROM other.apples; SELECT COUNT(*) FROM superheroes; PRAGMA scratch.page_count; DETACH other; SELECT * FROM other.apples"
+        ))
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("2|other|"))
+        .stdout(line("3|scratch|"))
+        .stdout(line("2"))
+        .stdout(line("6895"))
+        .stderr(predicates::str::contains("no such table: other.apples"));
+
+    // Attached again, the file would share the locks main holds, and both could write it.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&other_path)
+        .arg("run")
+        .arg(format!(
+            "CREATE INDEX apples_color ON apples (color); ATTACH '{other_path}' AS b; BEGIN; DELETE FROM main.apples WHERE id < 2; INSERT INTO b.apples (name, color) VALUES ('dup', 'Red'); COMMIT"
+        ))
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(format!(
+            "database {other_path} is already in use as main"
+        )));
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&other_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM apples; PRAGMA integrity_check")
+        .assert()
+        .success()
+        .stdout(predicates::str::diff("2\nok\n"));
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(":memory:")
+        .arg("run")
+        .arg("PRAGMA database_list; DETACH main")
+        .assert()
+        .failure()
+        .stdout(line("0|main|"))
+        .stderr(predicates::str::contains("cannot detach database main"));
+    std::fs::remove_file(db_path).unwrap();
+    std::fs::remove_file(other_path).unwrap();
+}
+
+#[test]
+fn test_create_table() {
+    let db_path = copy_database("sample.db", "create_table");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE notes(id integer primary key autoincrement, note text unique); CREATE TABLE IF NOT EXISTS notes(x); SELECT type, name, rootpage FROM sqlite_schema; CREATE TABLE apples(a)")
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("table|notes|5"))
+        .stdout(predicates::str::contains("index|sqlite_autoindex_notes_1|6"))
+        .stderr(predicates::str::contains("table apples already exists"));
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE t(a, A)")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("duplicate column name: A"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_insert() {
+    let db_path = copy_database("sample.db", "insert");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("INSERT INTO apples(name, color) VALUES ('Gala', 'Red'), ('Envy', 'Dark ' || 'Red'); SELECT * FROM apples WHERE id > 4; SELECT seq FROM sqlite_sequence WHERE name = 'apples'; INSERT INTO apples VALUES ('Jazz')")
+        .assert()
+        .failure()
+        .stdout(predicates::str::contains("5|Gala|Red\n6|Envy|Dark Red\n"))
+        .stdout(predicates::str::contains("\n6\n"))
+        .stderr(predicates::str::contains(
+            "table apples has 3 columns but 1 values were supplied",
+        ));
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("INSERT INTO apples(id, name) VALUES (2, 'Braeburn')")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "UNIQUE constraint failed: apples.id",
+        ));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_views_and_temp() {
+    let db_path = copy_database("sample.db", "views");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE VIEW red(fruit) AS SELECT name FROM apples WHERE color <> 'Yellow'; CREATE TEMP TABLE notes(id integer primary key, note text unique); CREATE TEMP VIEW reds AS SELECT fruit FROM red WHERE fruit <> 'Fuji'; SELECT * FROM reds; SELECT name FROM sqlite_temp_master; PRAGMA database_list; DELETE FROM red")
+        .assert()
+        .failure()
+        .stdout(line("Honeycrisp"))
+        .stdout(line("sqlite_autoindex_notes_1"))
+        .stdout(line("1|temp|"))
+        .stderr(predicates::str::contains(
+            "cannot modify red because it is a view",
+        ));
+
+    // Temp objects are gone with the connection, views stay in the schema.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM red; SELECT type, name FROM sqlite_schema WHERE type = 'view'; DROP TABLE red")
+        .assert()
+        .failure()
+        .stdout(line("3"))
+        .stdout(line("view|red"))
+        .stderr(predicates::str::contains("use DROP VIEW to delete view red"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_triggers() {
+    let db_path = copy_database("sample.db", "triggers");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE log(entry text); CREATE TRIGGER audit AFTER INSERT ON apples BEGIN INSERT INTO log VALUES ('added ' || new.name); END; CREATE TRIGGER keep BEFORE DELETE ON apples BEGIN SELECT RAISE(ABORT, 'apples are kept'); END; INSERT INTO apples(name, color) VALUES ('Gala', 'Red'); SELECT * FROM log; DELETE FROM apples")
+        .assert()
+        .failure()
+        .stdout(line("added Gala"))
+        .stderr(predicates::str::contains("apples are kept"));
+
+    // The aborted DELETE left the table alone.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM apples; DROP TRIGGER keep; SELECT name FROM sqlite_schema WHERE type = 'trigger'")
+        .assert()
+        .success()
+        .stdout(line("5"))
+        .stdout(line("audit"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_foreign_keys() {
+    let db_path = copy_database("sample.db", "foreign_keys");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE owners(id integer primary key, name text); CREATE TABLE trees(owner integer REFERENCES owners ON DELETE CASCADE, apple integer REFERENCES apples(id) ON DELETE RESTRICT); INSERT INTO owners VALUES (1, 'Ann'), (2, 'Bob'); INSERT INTO trees VALUES (1, 1), (2, 2), (3, 1); PRAGMA foreign_key_check; PRAGMA foreign_keys = ON; DELETE FROM owners WHERE id = 1; SELECT COUNT(*) FROM trees; INSERT INTO trees VALUES (4, 1)")
+        .assert()
+        .failure()
+        .stdout(line("trees|3|owners|1"))
+        .stdout(line("2"))
+        .stderr(predicates::str::contains("FOREIGN KEY constraint failed"));
+
+    // The setting is per connection, and the failed INSERT was undone.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM trees; PRAGMA foreign_keys; PRAGMA foreign_keys = 1; DELETE FROM apples WHERE id = 2")
+        .assert()
+        .failure()
+        .stdout(line("2"))
+        .stdout(line("0"))
+        .stderr(predicates::str::contains("FOREIGN KEY constraint failed"));
+
+    // SET DEFAULT to the key of the parent row being deleted leaves the child without one.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("PRAGMA foreign_keys = ON; CREATE TABLE p(id integer primary key); CREATE TABLE c(id integer primary key, pid integer DEFAULT 3 REFERENCES p ON DELETE SET DEFAULT); INSERT INTO p VALUES (1), (2), (3); INSERT INTO c VALUES (10, 1); DELETE FROM p WHERE id = 1; SELECT pid FROM c; DELETE FROM p WHERE id = 3")
+        .assert()
+        .failure()
+        .stdout(predicates::str::diff("3\n"))
+        .stderr(predicates::str::contains("FOREIGN KEY constraint failed"));
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT id FROM p; PRAGMA foreign_key_check")
+        .assert()
+        .success()
+        .stdout(line("3"))
+        .stdout(predicates::str::contains("c|10|p|").not());
+    std::fs::remove_file(db_path).unwrap();
+}
+
+// A trigger writing to another database is part of the statement, and is undone with it.
+#[test]
+fn test_trigger_across_databases() {
+    let db_path = copy_database("sample.db", "trigger_main");
+    let other_path = db_path.replace("trigger_main", "trigger_log");
+    let run = |sql: &str| {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        let attach = format!(
+            "ATTACH '{other_path}' AS aux; CREATE TEMP TRIGGER audit AFTER INSERT ON apples BEGIN INSERT INTO log VALUES (new.name); END; "
+        );
+        cmd.arg(&db_path).arg("run").arg(attach + sql).assert()
+    };
+
+    run("CREATE TABLE aux.log(entry); INSERT INTO apples(id, name) VALUES (10, 'Gala'), (1, 'Fuji')")
+        .failure()
+        .stderr(predicates::str::contains("UNIQUE constraint failed: apples.id"));
+    run("SELECT COUNT(*) FROM log")
+        .success()
+        .stdout(predicates::str::diff("0\n"));
+    run("INSERT INTO apples(id, name) VALUES (10, 'Gala'), (11, 'Envy'); SELECT COUNT(*) FROM log")
+        .success()
+        .stdout(predicates::str::diff("2\n"));
+    std::fs::remove_file(&other_path).unwrap();
+    std::fs::remove_file(&db_path).unwrap();
+}
+
+#[test]
+fn test_constraints() {
+    let db_path = copy_database("sample.db", "constraints");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE t(a integer primary key, b text unique, c integer not null default 7 check (c > 0)); INSERT INTO t VALUES (1, 'x', 1), (2, 'y', 2); INSERT OR IGNORE INTO t VALUES (3, 'x', 3); REPLACE INTO t VALUES (4, 'y', NULL); INSERT INTO t VALUES (5, 'x', 5) ON CONFLICT(b) DO UPDATE SET c = excluded.c + c; SELECT * FROM t; INSERT INTO t VALUES (6, 'z', 0)")
+        .assert()
+        .failure()
+        .stdout(line("1|x|6"))
+        .stdout(line("4|y|7"))
+        .stderr(predicates::str::contains("CHECK constraint failed: c > 0"));
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT COUNT(*) FROM t; UPDATE t SET b = 'x' WHERE a = 4")
+        .assert()
+        .failure()
+        .stdout(line("2"))
+        .stderr(predicates::str::contains("UNIQUE constraint failed: t.b"));
+
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("INSERT INTO t(a, b) VALUES (8, NULL); INSERT INTO t(b, c) VALUES ('w', NULL)")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("NOT NULL constraint failed: t.c"));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_returning() {
+    let db_path = copy_database("sample.db", "returning");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE t(a integer primary key, b); INSERT INTO t(b) VALUES (10), (20) RETURNING a, b * 2; UPDATE t SET b = b + 1 WHERE a = 2 RETURNING *; DELETE FROM t WHERE a = 1 RETURNING b; UPDATE t SET b = 0 RETURNING COUNT(*)")
+        .assert()
+        .failure()
+        .stdout(line("1|20"))
+        .stdout(line("2|40"))
+        .stdout(line("2|21"))
+        .stdout(line("10"))
+        .stderr(predicates::str::contains(
+            "misuse of aggregate function count()",
+        ));
+    std::fs::remove_file(db_path).unwrap();
+}
+
+#[test]
+fn test_parameters() {
+    let db_path = copy_database("sample.db", "parameters");
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE TABLE t(a, b); INSERT INTO t VALUES (?, :name); INSERT INTO t VALUES (?2, ?1 + 1); SELECT b FROM t WHERE a = ?2; SELECT ?3, :limit")
+        .arg("--param")
+        .arg("5")
+        .arg("--param")
+        .arg("'it''s'")
+        .arg("--param")
+        .arg(":limit=-2.5")
+        .assert()
+        .success()
+        .stdout(predicates::str::diff("6\n|-2.5\n"));
+
+    // A value no statement has a parameter for stops the run before anything is written.
+    for (param, error) in [
+        (":other=1", "no such parameter: :other"),
+        ("2", "no such parameter: ?2"),
+    ] {
+        let mut cmd = Command::cargo_bin("RQlite").unwrap();
+        cmd.arg(&db_path)
+            .arg("run")
+            .arg("INSERT INTO t VALUES (1, 2); SELECT :name")
+            .arg("--param")
+            .arg("1")
+            .arg("--param")
+            .arg(param)
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains(error));
+    }
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT count(*) FROM t; SELECT ?0")
+        .assert()
+        .failure()
+        .stdout(predicates::str::is_empty())
+        .stderr(predicates::str::contains(
+            "variable number must be between ?1 and ?32766",
+        ));
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("SELECT count(*) FROM t")
+        .assert()
+        .success()
+        .stdout(predicates::str::diff("2\n"));
+
+    // What is kept in the schema is there after the statement, with no values bound to it.
+    let mut cmd = Command::cargo_bin("RQlite").unwrap();
+    cmd.arg(&db_path)
+        .arg("run")
+        .arg("CREATE VIEW v AS SELECT a FROM t WHERE b = ?")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "parameters are not allowed in views",
+        ));
+    std::fs::remove_file(db_path).unwrap();
 }